@@ -0,0 +1,186 @@
+//! Azure Functions custom handler serving classifications: the Functions host
+//! starts this binary, as configured in `host.json`, and sends it requests on
+//! `FUNCTIONS_CUSTOMHANDLER_PORT`.
+//!
+//! With `enableForwardingHttpRequest`, HTTP triggers are forwarded as they
+//! are, to `/api/<function>`. Otherwise the host posts invocations to
+//! `/<function>`, with the trigger in a JSON envelope, and expects the HTTP
+//! output binding in one back. The `req` and `res` bindings of
+//! `classify/function.json` are expected there.
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use tf_serve::{
+    ClassifierOptions, HttpRequest, HttpResponse, HttpServer, ImageClassifier, ServerOptions,
+};
+
+/// Invocation of a function by the host
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InvokeRequest {
+    data: InvokeData,
+}
+
+#[derive(Deserialize)]
+struct InvokeData {
+    req: TriggerRequest,
+}
+
+/// HTTP trigger of an invocation
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TriggerRequest {
+    url: String,
+    method: String,
+
+    #[serde(default)]
+    headers: HashMap<String, Vec<String>>,
+
+    #[serde(default)]
+    body: Option<serde_json::Value>,
+}
+
+/// Outputs of an invocation
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct InvokeResponse {
+    outputs: InvokeOutputs,
+    logs: Vec<String>,
+    return_value: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct InvokeOutputs {
+    res: OutputResponse,
+}
+
+/// HTTP output binding of an invocation
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutputResponse {
+    status_code: u16,
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+impl TriggerRequest {
+    fn into_request(self) -> HttpRequest {
+        // Absolute URL of the function, down to its path and query string
+        let url = match self.url.find("://") {
+            Some(pos) => match self.url[pos + 3..].find('/') {
+                Some(path) => &self.url[pos + 3 + path..],
+                None => "/",
+            },
+            None => &self.url,
+        };
+
+        let headers = self
+            .headers
+            .into_iter()
+            .map(|(name, values)| (name, values.join(", ")))
+            .collect();
+
+        let mut request = HttpRequest::new(&self.method, url, headers, vec![]);
+
+        request.body = match self.body {
+            None | Some(serde_json::Value::Null) => vec![],
+            Some(serde_json::Value::String(body)) => {
+                // The host passes bodies on as text, so images have to be
+                // sent base64 encoded
+                let is_json = request
+                    .header("content-type")
+                    .map_or(false, |value| value.contains("json"));
+
+                match base64::decode(body.trim()) {
+                    Ok(data) if !is_json => data,
+                    _ => body.into_bytes(),
+                }
+            }
+            Some(body) => serde_json::to_vec(&body).unwrap_or_default(),
+        };
+
+        request
+    }
+}
+
+impl From<HttpResponse> for InvokeResponse {
+    fn from(response: HttpResponse) -> Self {
+        InvokeResponse {
+            outputs: InvokeOutputs {
+                res: OutputResponse {
+                    status_code: response.status,
+                    headers: response.headers.into_iter().collect(),
+                    body: String::from_utf8_lossy(&response.body).into_owned(),
+                },
+            },
+            logs: vec![],
+            return_value: None,
+        }
+    }
+}
+
+/// Respond to the host, to either a forwarded request or an invocation
+fn handle(server: &HttpServer, request: &HttpRequest) -> HttpResponse {
+    if request.path.starts_with("/api/") {
+        return server.handle(request);
+    }
+
+    match serde_json::from_slice::<InvokeRequest>(&request.body) {
+        Ok(invocation) => {
+            let response = server.handle(&invocation.data.req.into_request());
+            HttpResponse::json(200, &InvokeResponse::from(response))
+        }
+        Err(err) => HttpResponse::error(
+            400,
+            "invalid_argument",
+            &format!("Invalid invocation of {}: {}", request.path, err),
+            None,
+        ),
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    let port = env::var("FUNCTIONS_CUSTOMHANDLER_PORT").unwrap_or_else(|_| "8080".to_owned());
+
+    // The host runs handlers from the root of the function app
+    let export_dir = PathBuf::from(env::var("TF_MODEL_DIR").unwrap_or_else(|_| "model".to_owned()));
+    let tags_path = env::var("TF_MODEL_LABELS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| export_dir.join("labels.txt"));
+
+    let options = ServerOptions::from_env();
+
+    let mut init = tf_serve::Timer::new_start("Loading model");
+
+    let classifier =
+        ImageClassifier::with_options(&export_dir, &tags_path, &ClassifierOptions::from_env())?;
+
+    let threads = options.threads;
+    // Invocations are base64 encoded and wrapped in JSON
+    let max_body_size = options.max_body_size * 4 / 3 + 4096;
+    let mut server = HttpServer::new(Arc::new(classifier), options);
+    server.load_models_from_env()?;
+    server.warm_up()?;
+    let server = Arc::new(server);
+
+    init.stop();
+
+    // The host only reaches the handler locally
+    let addr = format!("127.0.0.1:{}", port);
+    info!("Serving on {}", addr);
+
+    Ok(tf_serve::serve_http(
+        &addr,
+        threads,
+        max_body_size,
+        move |request| handle(&server, request),
+    )?)
+}
@@ -0,0 +1,131 @@
+//! Cloud Run service, or Cloud Functions function, serving classifications
+//! on `$PORT`, as the functions framework contract has it.
+//!
+//! Besides the requests of any HTTP frontend, it classifies the objects of
+//! Cloud Storage notifications: CloudEvents of Eventarc triggers, and
+//! background events of first generation functions, the data of both being
+//! the metadata of the object. The object is then read as `gs://bucket/name`
+//! with the service account of the service, its bucket being one of
+//! `TF_IMAGE_BUCKETS`.
+
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use log::info;
+use serde::Deserialize;
+use tf_serve::cloudevents::{self, CloudEvent};
+use tf_serve::{
+    ClassifierOptions, HttpRequest, HttpResponse, HttpServer, ImageClassifier, ServerOptions,
+};
+
+/// Prefix of the types of Cloud Storage CloudEvents
+const STORAGE_EVENT_TYPE: &str = "google.cloud.storage.object.";
+
+/// Metadata of a Cloud Storage object, as far as finding it is concerned
+#[derive(Deserialize)]
+struct StorageObject {
+    bucket: String,
+    name: String,
+}
+
+impl StorageObject {
+    /// Request to classify the object
+    fn classify_request(&self, request: &HttpRequest) -> HttpRequest {
+        let body = serde_json::json!({ "url": format!("gs://{}/{}", self.bucket, self.name) });
+
+        HttpRequest {
+            method: "POST".to_owned(),
+            path: request.path.clone(),
+            query: request.query.clone(),
+            headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+            body: body.to_string().into_bytes(),
+        }
+    }
+}
+
+/// Background event of first generation functions
+#[derive(Deserialize)]
+struct BackgroundEvent {
+    data: StorageObject,
+}
+
+/// Request of a Cloud Storage CloudEvent, in binary mode, with the object
+/// to classify as data
+fn storage_event(event: &CloudEvent, data: &HttpRequest) -> Option<HttpRequest> {
+    if !event.event_type.starts_with(STORAGE_EVENT_TYPE) {
+        return None;
+    }
+
+    let object: StorageObject = serde_json::from_slice(&data.body).ok()?;
+    let mut request = object.classify_request(data);
+
+    request.headers.extend(vec![
+        ("ce-specversion".to_owned(), event.specversion.clone()),
+        ("ce-id".to_owned(), event.id.clone()),
+        ("ce-source".to_owned(), event.source.clone()),
+        ("ce-type".to_owned(), event.event_type.clone()),
+        // Eventarc sets the subject to `objects/<name>`
+        (
+            "ce-subject".to_owned(),
+            event.subject.clone().unwrap_or_else(|| object.name.clone()),
+        ),
+    ]);
+
+    Some(request)
+}
+
+/// Respond to a request, classifying the objects of storage notifications
+fn handle(server: &HttpServer, request: &HttpRequest) -> HttpResponse {
+    if request.method == "POST" && cloudevents::is_cloud_event(request) {
+        if let Ok((event, data)) = cloudevents::parse(request) {
+            if let Some(request) = storage_event(&event, &data) {
+                return server.handle(&request);
+            }
+        }
+    } else if request.method == "POST" {
+        if let Ok(event) = serde_json::from_slice::<BackgroundEvent>(&request.body) {
+            return server.handle(&event.data.classify_request(request));
+        }
+    }
+
+    server.handle(request)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    let port = env::var("PORT").unwrap_or_else(|_| "8080".to_owned());
+
+    let export_dir =
+        PathBuf::from(env::var("TF_MODEL_DIR").unwrap_or_else(|_| "/model".to_owned()));
+    let tags_path = env::var("TF_MODEL_LABELS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| export_dir.join("labels.txt"));
+
+    let options = ServerOptions::from_env();
+
+    let mut init = tf_serve::Timer::new_start("Loading model");
+
+    let classifier =
+        ImageClassifier::with_options(&export_dir, &tags_path, &ClassifierOptions::from_env())?;
+
+    let (threads, max_body_size) = (options.threads, options.max_body_size);
+    let mut server = HttpServer::new(Arc::new(classifier), options);
+    server.load_models_from_env()?;
+    server.warm_up()?;
+    let server = Arc::new(server);
+
+    init.stop();
+
+    let addr = format!("0.0.0.0:{}", port);
+    info!("Serving on {}", addr);
+
+    Ok(tf_serve::serve_http(
+        &addr,
+        threads,
+        max_body_size,
+        move |request| handle(&server, request),
+    )?)
+}
@@ -0,0 +1,258 @@
+//! Worker classifying the images referenced by the messages of a Kafka
+//! topic, and producing the results to another.
+//!
+//! Messages are image URLs, bare or as `{"url": ...}`. The messages of every
+//! poll are classified in batches of `TF_BATCH_SIZE` images, on up to
+//! `TF_WORKERS` threads, and their results are produced to the output topic
+//! under the keys of the messages: the classification or the error, along
+//! with the `url` of the image.
+//!
+//! Offsets are committed only once the results of a poll are produced. If
+//! producing or committing fails the worker exits, for it to be restarted
+//! from the last committed offsets, so that every message gets a result at
+//! least once.
+//!
+//! Configured by the environment:
+//!
+//! - `KAFKA_BROKERS`: comma-separated `host:port` of brokers,
+//!   `localhost:9092` by default.
+//! - `KAFKA_INPUT_TOPIC`, `KAFKA_OUTPUT_TOPIC`: topics of images and results.
+//! - `KAFKA_GROUP`: consumer group the offsets are committed for,
+//!   `tf-classify` by default.
+//! - `TF_BATCH_SIZE`, `TF_WORKERS`: 16 and 1 by default.
+//! - `RESULT_SINK`: where results are also written, under
+//!   `{topic}/{partition}/{offset}`, as by `tf_serve::result_sink`.
+//! - `TF_MODEL_DIR`, `TF_MODEL_LABELS`: the model, `/var/task/model` and
+//!   its `labels.txt` by default.
+
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
+use kafka::producer::{Producer, Record, RequiredAcks};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use tf_serve::{
+    env_parse, Classification, ClassifierOptions, ClassifyOptions, ErrorBody, ImageClassifier,
+    ResultRecord, ResultSink,
+};
+
+/// Value of a required environment variable
+fn env_required(name: &str) -> Result<String, String> {
+    env::var(name).map_err(|_| format!("{} is not set", name))
+}
+
+/// Image URL of a message, either the bare URL or `{"url": ...}`
+fn image_url(value: &[u8]) -> Option<String> {
+    let body = std::str::from_utf8(value).ok()?.trim();
+
+    if body.starts_with('{') {
+        let value: Value = serde_json::from_str(body).ok()?;
+        return value.get("url")?.as_str().map(str::to_owned);
+    }
+
+    Some(body.to_owned()).filter(|url| !url.is_empty())
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Outcome {
+    Classified(Classification),
+    Failed(ErrorBody),
+}
+
+/// Result of a message, as produced to the output topic
+#[derive(Serialize)]
+struct Output {
+    url: Option<String>,
+
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+impl Output {
+    /// Record of the result for a sink
+    fn record<'a>(&'a self, id: &'a str) -> ResultRecord<'a> {
+        let (classification, error) = match &self.outcome {
+            Outcome::Classified(classification) => (Some(classification), None),
+            Outcome::Failed(body) => (None, Some(&body.error)),
+        };
+
+        ResultRecord {
+            id,
+            request_id: None,
+            source: self.url.as_deref(),
+            classification,
+            error,
+        }
+    }
+}
+
+/// Classify the images at `urls` in batches of `batch_size`, on up to
+/// `workers` threads, keeping their order
+fn classify_all(
+    classifier: &Arc<ImageClassifier>,
+    urls: Vec<String>,
+    batch_size: usize,
+    workers: usize,
+) -> Vec<Outcome> {
+    let batches: Arc<Vec<Vec<String>>> = Arc::new(
+        urls.chunks(batch_size.max(1))
+            .map(<[String]>::to_vec)
+            .collect(),
+    );
+    let next = Arc::new(AtomicUsize::new(0));
+
+    let threads: Vec<_> = (0..workers.max(1).min(batches.len()))
+        .map(|_| {
+            let (classifier, batches, next) = (classifier.clone(), batches.clone(), next.clone());
+
+            thread::spawn(move || {
+                let mut classified = vec![];
+
+                loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let batch = match batches.get(index) {
+                        Some(batch) => batch,
+                        None => return classified,
+                    };
+
+                    let outcomes: Vec<Outcome> = classifier
+                        .classify_batch_from_urls(batch, &ClassifyOptions::default())
+                        .into_iter()
+                        .map(|result| match result {
+                            Ok(classification) => Outcome::Classified(classification),
+                            Err(err) => Outcome::Failed(ErrorBody::from(&err)),
+                        })
+                        .collect();
+
+                    classified.push((index, outcomes));
+                }
+            })
+        })
+        .collect();
+
+    let mut classified: Vec<(usize, Vec<Outcome>)> = threads
+        .into_iter()
+        .flat_map(|thread| thread.join().expect("Classification thread panicked"))
+        .collect();
+    classified.sort_by_key(|&(index, _)| index);
+
+    classified
+        .into_iter()
+        .flat_map(|(_, outcomes)| outcomes)
+        .collect()
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    let brokers: Vec<String> = env::var("KAFKA_BROKERS")
+        .unwrap_or_else(|_| "localhost:9092".to_owned())
+        .split(',')
+        .map(|broker| broker.trim().to_owned())
+        .collect();
+    let input_topic = env_required("KAFKA_INPUT_TOPIC")?;
+    let output_topic = env_required("KAFKA_OUTPUT_TOPIC")?;
+    let group = env::var("KAFKA_GROUP").unwrap_or_else(|_| "tf-classify".to_owned());
+    let batch_size = env_parse("TF_BATCH_SIZE").unwrap_or(16);
+    let workers = env_parse("TF_WORKERS").unwrap_or(1);
+    let sink = tf_serve::result_sink_from_env()?;
+
+    let export_dir =
+        PathBuf::from(env::var("TF_MODEL_DIR").unwrap_or_else(|_| "/var/task/model".to_owned()));
+    let tags_path = env::var("TF_MODEL_LABELS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| export_dir.join("labels.txt"));
+
+    let mut init = tf_serve::Timer::new_start("Loading model");
+    let classifier = Arc::new(ImageClassifier::with_options(
+        &export_dir,
+        &tags_path,
+        &ClassifierOptions::from_env(),
+    )?);
+    classifier.warm_up()?;
+    init.stop();
+
+    let mut consumer = Consumer::from_hosts(brokers.clone())
+        .with_topic(input_topic.clone())
+        .with_group(group)
+        .with_fallback_offset(FetchOffset::Earliest)
+        .with_offset_storage(GroupOffsetStorage::Kafka)
+        .create()?;
+    let mut producer = Producer::from_hosts(brokers)
+        .with_ack_timeout(Duration::from_secs(5))
+        .with_required_acks(RequiredAcks::All)
+        .create()?;
+
+    info!(
+        "Classifying images of {} into {}",
+        input_topic, output_topic
+    );
+
+    loop {
+        let sets = consumer.poll()?;
+        if sets.is_empty() {
+            continue;
+        }
+
+        let messages: Vec<(String, Vec<u8>, Option<String>)> = sets
+            .iter()
+            .flat_map(|set| {
+                set.messages().iter().map(move |message| {
+                    let id = format!("{}/{}/{}", set.topic(), set.partition(), message.offset);
+                    (id, message.key.to_vec(), image_url(message.value))
+                })
+            })
+            .collect();
+
+        let urls = messages
+            .iter()
+            .filter_map(|(_, _, url)| url.clone())
+            .collect();
+        let mut outcomes = classify_all(&classifier, urls, batch_size, workers).into_iter();
+
+        let outputs = messages
+            .into_iter()
+            .map(|(id, key, url)| {
+                let outcome = match url {
+                    Some(_) => outcomes.next().expect("Result of every image"),
+                    None => Outcome::Failed(ErrorBody::new(
+                        "invalid_argument",
+                        "Message without image URL",
+                    )),
+                };
+                let output = Output { url, outcome };
+
+                if let Some(sink) = &sink {
+                    if let Err(err) = sink.write(&output.record(&id)) {
+                        warn!("Could not write result of {}: {}", id, err);
+                    }
+                }
+
+                Ok((key, serde_json::to_vec(&output)?))
+            })
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+
+        let records: Vec<_> = outputs
+            .iter()
+            .map(|(key, value)| {
+                Record::from_key_value(&output_topic, key.as_slice(), value.as_slice())
+            })
+            .collect();
+        producer.send_all(&records)?;
+
+        for set in sets.iter() {
+            consumer.consume_messageset(set)?;
+        }
+        consumer.commit_consumed()?;
+
+        info!("Classified {} images of {}", records.len(), input_topic);
+    }
+}
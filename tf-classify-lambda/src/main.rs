@@ -1,32 +1,434 @@
 use lambda_http::{
     handler,
-    lambda_runtime::{self, Context, Error},
-    IntoResponse, Request, Response,
+    lambda_runtime::{self, handler_fn, Context, Error},
+    Body, IntoResponse, Request, RequestExt, Response,
 };
 
-use log::debug;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::path::PathBuf;
-use tf_serve::ImageClassifier;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tensorflow::Status;
+use tf_serve::{
+    http_status, multipart, retry_after, wire, AlertMonitor, AlertOptions, AuthRegistry, BuildInfo,
+    CacheOptions, CalibrationOptions, ClassifierOptions, ClassifyOptions, Credentials, DataStorage,
+    DegradableClassifier, Detector, EmfLogger, ErrorBody, HttpOptions, ImageCache, ImageClassifier,
+    ImageLimits, OtlpExporter, Pipeline, Preprocessing, S3Storage, Segmenter, SnsAlerts,
+    StaticKeys, Storage, TraceContext, WebhookAlerts,
+};
+use xray::{Trace, XRay};
+
+mod s3;
+mod sqs;
+mod xray;
 
 extern crate base64;
 extern crate serde_json;
 
+/// Models served by the function
+struct Models {
+    classifier: Arc<ImageClassifier>,
+
+    /// Primary classifier backed by a fallback model, if one is configured
+    fallback: Option<DegradableClassifier>,
+    detector: Option<Detector>,
+    segmenter: Option<Segmenter>,
+    auth: AuthRegistry,
+
+    /// X-Ray daemon, when active tracing is enabled
+    xray: Option<XRay>,
+
+    /// OpenTelemetry collector, when configured by the `OTEL_*` environment
+    /// variables
+    otlp: Option<OtlpExporter>,
+
+    /// Error rate and latency alerts, if a destination is configured
+    alerts: Option<AlertMonitor>,
+
+    /// Embedded metrics logged for CloudWatch, if a namespace is configured
+    metrics: Option<EmfLogger>,
+
+    /// Capability report served on /version
+    report: serde_json::Value,
+
+    /// Largest accepted request body, and fetched image
+    max_body_size: usize,
+
+    /// Longest time to spend on a request, if shorter than the invocation
+    /// allows
+    timeout: Option<Duration>,
+
+    /// Time the init phase took, loading and warming up the models
+    model_load_ms: u64,
+}
+
+/// Whether the process has handled a request yet
+static INVOKED: AtomicBool = AtomicBool::new(false);
+
+/// Invocation metadata added to JSON responses, to tell the latency of cold
+/// starts apart from that of inference
+#[derive(Serialize)]
+struct Invocation<'a> {
+    /// Whether this is the first request of the execution environment
+    cold_start: bool,
+    model_load_ms: u64,
+    request_id: &'a str,
+}
+
+/// JSON response with the metadata of its invocation
+#[derive(Serialize)]
+struct WithInvocation<'a, T: Serialize> {
+    #[serde(flatten)]
+    result: &'a T,
+
+    #[serde(flatten)]
+    invocation: &'a Invocation<'a>,
+}
+
+impl<'a> Invocation<'a> {
+    /// Metadata of the invocation of `ctx`, the first one of the process
+    /// being the cold start
+    fn new(ctx: &'a Context, models: &Models) -> Self {
+        Invocation {
+            cold_start: !INVOKED.swap(true, Ordering::SeqCst),
+            model_load_ms: models.model_load_ms,
+            request_id: &ctx.request_id,
+        }
+    }
+
+    fn to_json<T: Serialize>(&self, result: &T) -> serde_json::Result<String> {
+        serde_json::to_string(&WithInvocation {
+            result,
+            invocation: self,
+        })
+    }
+}
+
+/// JSON body of a classification request, as an alternative to the raw
+/// image: `{"url": "..."}` or `{"image_b64": "...", "top_k": 5}`
+#[derive(Deserialize)]
+struct ClassifyRequest {
+    url: Option<String>,
+    image_b64: Option<String>,
+
+    #[serde(flatten)]
+    options: ClassifyOptions,
+}
+
+/// Largest number of images of a batch request
+const MAX_BATCH_SIZE: usize = 32;
+
+/// Default largest request body, the payload limit of Lambda
+const DEFAULT_MAX_BODY_SIZE: usize = 6 << 20;
+
+/// Time kept from the invocation deadline to respond in
+const RESPONSE_MARGIN: Duration = Duration::from_millis(200);
+
+/// Images of a batch request
+enum Batch<'a> {
+    Raw(Vec<&'a [u8]>),
+    Urls(Vec<String>),
+}
+
+/// Image to classify
+enum ImageSource<'a> {
+    Raw(Cow<'a, [u8]>),
+    Url(String),
+}
+
+/// Model directory and labels file from the environment, if configured
+fn model_paths(dir_var: &str, labels_var: &str) -> Option<(PathBuf, PathBuf)> {
+    let export_dir = PathBuf::from(std::env::var(dir_var).ok()?);
+    let tags_path = std::env::var(labels_var)
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| export_dir.join("labels.txt"));
+
+    Some((export_dir, tags_path))
+}
+
+/// Alerts to the webhook at `TF_ALERT_WEBHOOK` and the SNS topic at
+/// `TF_ALERT_SNS_TOPIC`, if either is set
+fn alert_monitor() -> Option<AlertMonitor> {
+    let webhook = std::env::var("TF_ALERT_WEBHOOK").ok();
+    let topic = std::env::var("TF_ALERT_SNS_TOPIC").ok();
+
+    if webhook.is_none() && topic.is_none() {
+        return None;
+    }
+
+    let defaults = AlertOptions::default();
+    let env = |name: &str| std::env::var(name).ok();
+
+    let mut monitor = AlertMonitor::new(AlertOptions {
+        max_error_rate: env("TF_ALERT_MAX_ERROR_RATE")
+            .and_then(|rate| rate.parse().ok())
+            .or(defaults.max_error_rate),
+        max_p99_latency: env("TF_ALERT_MAX_P99_MS")
+            .and_then(|ms| ms.parse().ok())
+            .map(Duration::from_millis),
+        ..defaults
+    });
+
+    let client = reqwest::Client::new();
+    if let Some(url) = webhook {
+        monitor.add_sink(Arc::new(WebhookAlerts::new(client.clone(), &url)));
+    }
+    if let Some(topic) = topic {
+        monitor.add_sink(Arc::new(SnsAlerts::new(client, &topic)));
+    }
+
+    Some(monitor)
+}
+
+/// Localized labels files in `dir`, named after their language
+/// (`labels/de.txt`)
+fn label_translations(dir: &str) -> Vec<(String, String)> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let lang = path.file_stem()?.to_str()?.to_owned();
+
+            Some((lang, path.to_string_lossy().into_owned()))
+        })
+        .collect()
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     env_logger::init();
 
+    // Everything up to the runtime loop happens in the Lambda init phase,
+    // which provisioned concurrency runs ahead of the first event
+    let mut init = tf_serve::Timer::new_start("Initializing function");
+    let init_started = Instant::now();
+
+    let max_body_size = std::env::var("TF_MAX_BODY_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BODY_SIZE);
+    let timeout = std::env::var("TF_REQUEST_TIMEOUT_MS")
+        .ok()
+        .and_then(|timeout| timeout.parse().ok())
+        .map(Duration::from_millis);
+
     let export_dir = PathBuf::from("/mnt/libraries/resnet50");
     let tags_path = PathBuf::from("/mnt/libraries/resnet50/ImageNetLabels.txt");
-    let classifier = ImageClassifier::new(&export_dir, &tags_path)?;
+    let options = ClassifierOptions {
+        device: std::env::var("TF_DEVICE")
+            .ok()
+            .and_then(|device| device.parse().ok())
+            .unwrap_or_default(),
+        preprocessing: Preprocessing {
+            auto_orient: std::env::var("TF_AUTO_ORIENT").map_or(true, |value| value != "0"),
+            ..Default::default()
+        },
+        embedding_op: std::env::var("TF_EMBEDDING_OP").ok(),
+        translations: std::env::var("TF_LABELS_DIR")
+            .map(|dir| label_translations(&dir))
+            .unwrap_or_default(),
+        http: HttpOptions::from_env(),
+        calibration: std::env::var("TF_CALIBRATION_LOCATION")
+            .ok()
+            .map(|location| CalibrationOptions {
+                rate: std::env::var("TF_CALIBRATION_RATE")
+                    .ok()
+                    .and_then(|rate| rate.parse().ok())
+                    .unwrap_or(0.01),
+                location,
+            }),
+        cache: std::env::var("TF_RESULT_CACHE_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .map(|capacity| CacheOptions {
+                capacity,
+                ttl: std::env::var("TF_RESULT_CACHE_TTL_SECS")
+                    .ok()
+                    .and_then(|ttl| ttl.parse().ok())
+                    .map(Duration::from_secs)
+                    .unwrap_or_else(|| CacheOptions::default().ttl),
+            }),
+        image_cache: std::env::var("TF_IMAGE_CACHE_MB")
+            .ok()
+            .and_then(|size| size.parse::<usize>().ok())
+            .map(|size| Arc::new(ImageCache::new(size << 20))),
+        limits: ImageLimits {
+            max_pixels: std::env::var("TF_MAX_IMAGE_PIXELS")
+                .ok()
+                .and_then(|pixels| pixels.parse().ok())
+                .unwrap_or_else(|| ImageLimits::default().max_pixels),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let classifier = Arc::new(ImageClassifier::with_options(
+        &export_dir,
+        &tags_path,
+        &options,
+    )?);
 
     debug!("Loaded model in memory");
 
-    let classifier_ref = &classifier;
+    let fallback = match model_paths("TF_FALLBACK_DIR", "TF_FALLBACK_LABELS") {
+        None => None,
+        Some((export_dir, tags_path)) => {
+            let fallback_options = ClassifierOptions {
+                device: options.device,
+                http: options.http.clone(),
+                image_cache: options.image_cache.clone(),
+                limits: options.limits,
+                ..Default::default()
+            };
+            let fallback = DegradableClassifier::new(ImageClassifier::with_options(
+                &export_dir,
+                &tags_path,
+                &fallback_options,
+            )?);
+            fallback.set_primary(Some(classifier.clone()));
+            debug!("Loaded fallback model in memory");
 
-    let handler_closure = move |event: Request, ctx: Context| async move {
-        handle_request(event, ctx, classifier_ref)
+            Some(fallback)
+        }
     };
 
+    let detector = Detector::from_env()?;
+    if detector.is_some() {
+        debug!("Loaded detection model in memory");
+    }
+
+    let segmenter = Segmenter::from_env()?;
+    if segmenter.is_some() {
+        debug!("Loaded segmentation model in memory");
+    }
+
+    let mut warm_up = tf_serve::Timer::new_start("Warming up models");
+
+    classifier.warm_up()?;
+    if let Some(fallback) = &fallback {
+        fallback.fallback().warm_up()?;
+    }
+    if let Some(detector) = &detector {
+        detector.warm_up()?;
+    }
+    if let Some(segmenter) = &segmenter {
+        segmenter.warm_up()?;
+    }
+
+    warm_up.stop();
+
+    let build = BuildInfo::current();
+    info!(
+        "tf-serve {} ({}), TensorFlow {}",
+        build.version,
+        build.git_commit.unwrap_or("unknown commit"),
+        build.tensorflow
+    );
+
+    let report = serde_json::json!({
+        "handler": env!("CARGO_PKG_VERSION"),
+        "build": build,
+        "models": {
+            "classifier": {
+                "export_dir": export_dir,
+                "classes": classifier.labels().len(),
+                "languages": classifier.languages(),
+            },
+            "fallback": fallback.as_ref().map(|fallback| {
+                serde_json::json!({ "classes": fallback.fallback().labels().len() })
+            }),
+            "detector": detector.as_ref().map(|detector| {
+                serde_json::json!({ "classes": detector.labels().len() })
+            }),
+            "segmenter": segmenter.as_ref().map(|segmenter| {
+                serde_json::json!({ "classes": segmenter.labels().len() })
+            }),
+        },
+    });
+
+    let mut auth = AuthRegistry::new();
+    if let Ok(keys) = std::env::var("TF_API_KEYS") {
+        let mut static_keys = StaticKeys::new();
+        for (i, key) in keys.split(',').filter(|key| !key.is_empty()).enumerate() {
+            static_keys.insert(key, &format!("key-{}", i));
+        }
+        auth.register(Arc::new(static_keys));
+    }
+
+    let models = Models {
+        classifier,
+        fallback,
+        detector,
+        segmenter,
+        auth,
+        xray: XRay::from_env(),
+        otlp: OtlpExporter::from_env(),
+        alerts: alert_monitor(),
+        metrics: EmfLogger::from_env(),
+        report,
+        max_body_size,
+        timeout,
+        model_load_ms: init_started.elapsed().as_millis() as u64,
+    };
+    let models_ref = &models;
+
+    init.stop();
+
+    // Consume S3 ObjectCreated notifications or SQS batches instead of HTTP
+    // requests
+    let event_source = std::env::var("TF_EVENT_SOURCE").unwrap_or_default();
+    if event_source == "s3" || event_source == "sqs" {
+        let storage = S3Storage::default();
+        let storage_ref = &storage;
+        let results_bucket = std::env::var("TF_RESULTS_BUCKET").ok();
+        let results_bucket_ref = results_bucket.as_deref();
+        let event_source_ref = event_source.as_str();
+
+        let event_closure = move |event: serde_json::Value, ctx: Context| async move {
+            let trace = TraceContext::from_xray(&ctx.xray_trace_id).map(|context| {
+                Trace::new(context, models_ref.xray.as_ref(), models_ref.otlp.as_ref())
+            });
+            let deadline = request_deadline(&ctx, models_ref.timeout);
+
+            if event_source_ref == "s3" {
+                s3::handle_event(
+                    event,
+                    &models_ref.classifier,
+                    storage_ref,
+                    results_bucket_ref,
+                    trace.as_ref(),
+                    models_ref.alerts.as_ref(),
+                    deadline,
+                )
+            } else {
+                sqs::handle_event(
+                    event,
+                    &models_ref.classifier,
+                    storage_ref,
+                    results_bucket_ref,
+                    trace.as_ref(),
+                    models_ref.alerts.as_ref(),
+                    deadline,
+                )
+            }
+        };
+
+        debug!("Dispatching {} event handler", event_source);
+        lambda_runtime::run(handler_fn(event_closure)).await?;
+
+        return Ok(());
+    }
+
+    let handler_closure =
+        move |event: Request, ctx: Context| async move { handle_request(event, ctx, models_ref) };
+
     debug!("Dispatching handler");
     lambda_runtime::run(handler(handler_closure)).await?;
 
@@ -35,27 +437,683 @@ async fn main() -> Result<(), Error> {
 
 fn handle_request(
     event: Request,
-    _ctx: Context,
-    classifier: &ImageClassifier,
+    ctx: Context,
+    models: &Models,
 ) -> Result<impl IntoResponse, Error> {
     debug!("Inside handler");
     debug!("Received request: {:#?}", event);
 
     let mut t = tf_serve::Timer::new_start("Handling request");
 
-    let raw: &[u8] = event.body();
+    if let Err(err) = models.auth.authenticate(&credentials(&event)) {
+        return status_response(&err);
+    }
+
+    // Text bodies may be base64, which only shrinks when decoded
+    if event.body().len() > models.max_body_size {
+        return error_response(
+            413,
+            &ErrorBody::new(
+                "resource_exhausted",
+                &format!("Body larger than {} bytes", models.max_body_size),
+            ),
+        );
+    }
+
+    let path = event.uri().path();
+    let deadline = request_deadline(&ctx, models.timeout);
+    let trace = trace_context(&event, &ctx)
+        .map(|context| Trace::new(context, models.xray.as_ref(), models.otlp.as_ref()));
+
+    // Scheduled keep-warm pings, which leave the cold start to the first
+    // request
+    if path.ends_with("/warmup") {
+        models.classifier.warm_up()?;
+
+        return Ok(Response::builder()
+            .status(204)
+            .body(Body::Empty)
+            .expect("Failed to render response"));
+    }
+
+    // After rejected requests and pings, for the first request served to
+    // be reported as the cold start
+    let invocation = Invocation::new(&ctx, models);
+
+    let response = if path.ends_with("/version") {
+        Response::builder()
+            .status(200)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&models.report)?.into())
+            .expect("Failed to render response")
+    } else if path.ends_with("/batch") {
+        handle_batch(&event, &models.classifier, trace.as_ref(), deadline)?
+    } else if path.ends_with("/thumbnail") {
+        handle_thumbnail(
+            &event,
+            &models.classifier,
+            trace.as_ref(),
+            &invocation,
+            deadline,
+        )?
+    } else if path.ends_with("/labels") {
+        handle_labels(&event, &models.classifier)?
+    } else if path.ends_with("/embed") {
+        handle_embed(&event, &models.classifier)?
+    } else if path.ends_with("/detect") {
+        handle_detect(&event, models.detector.as_ref())?
+    } else if path.ends_with("/pipeline") {
+        handle_pipeline(&event, models, trace.as_ref(), &invocation, deadline)?
+    } else if path.ends_with("/segment") {
+        handle_segment(&event, models.segmenter.as_ref())?
+    } else {
+        handle_classify(&event, models, trace.as_ref(), &invocation, deadline)?
+    };
+
+    t.stop();
 
-    let response = match classifier.classify_from_raw(&raw) {
-        Err(err) => Response::builder()
-            .body(format!("Classification failure: '{}'", err))
+    Ok(response)
+}
+
+fn handle_classify(
+    event: &Request,
+    models: &Models,
+    trace: Option<&Trace>,
+    invocation: &Invocation,
+    deadline: Instant,
+) -> Result<Response<Body>, Error> {
+    let (image, mut options) = match classify_request(event) {
+        Ok(request) => request,
+        Err(err) => return error_response(400, &ErrorBody::new("invalid_argument", &err)),
+    };
+    options.deadline = Some(deadline);
+
+    if let ImageSource::Url(_) = image {
+        options.trace = trace.map(Trace::outbound);
+    }
+
+    let started = Instant::now();
+    let start = xray::now();
+    let result = match (&image, &models.fallback) {
+        (ImageSource::Raw(raw), Some(fallback)) => {
+            fallback.classify_from_raw_with_options(raw, &options)
+        }
+        (ImageSource::Raw(raw), None) => models
+            .classifier
+            .classify_from_raw_with_options(raw, &options),
+        (ImageSource::Url(url), Some(fallback)) => {
+            fallback.classify_from_url_with_options(url, &options)
+        }
+        (ImageSource::Url(url), None) => models
+            .classifier
+            .classify_from_url_with_options(url, &options),
+    };
+    if let (Some(trace), Ok(classification)) = (trace, &result) {
+        trace.record(start, &classification.timings(), options.trace.as_ref());
+    }
+    if let Some(alerts) = &models.alerts {
+        alerts.record(started.elapsed(), result.is_ok());
+    }
+    if let (Some(metrics), Ok(classification)) = (&models.metrics, &result) {
+        let image_size = match &image {
+            ImageSource::Raw(raw) => Some(raw.len()),
+            ImageSource::Url(_) => None,
+        };
+        metrics.record(classification, image_size);
+    }
+
+    let response = match result {
+        Err(err) => status_response(&err)?,
+        Ok(classification) if accepts(event, "text/plain") => Response::builder()
+            .status(200)
+            .header("content-type", "text/plain")
+            .body(
+                format!(
+                    "{}\t{}\n",
+                    classification.tag(),
+                    classification.probability()
+                )
+                .into(),
+            )
             .expect("Failed to render response"),
         Ok(classification) => Response::builder()
             .status(200)
-            .body(serde_json::to_string(&classification)?)
+            .header("content-type", "application/json")
+            .body(invocation.to_json(&classification)?.into())
             .expect("Failed to render response"),
     };
 
-    t.stop();
+    Ok(response)
+}
+
+fn handle_batch(
+    event: &Request,
+    classifier: &ImageClassifier,
+    trace: Option<&Trace>,
+    deadline: Instant,
+) -> Result<Response<Body>, Error> {
+    let request = || -> Result<(Batch, ClassifyOptions), String> {
+        let (batch, options) = batch_request(event)?;
+        let size = match &batch {
+            Batch::Raw(images) => images.len(),
+            Batch::Urls(urls) => urls.len(),
+        };
+
+        if size == 0 || size > MAX_BATCH_SIZE {
+            return Err(format!(
+                "Expected between 1 and {} images, got {}",
+                MAX_BATCH_SIZE, size
+            ));
+        }
+
+        Ok((batch, options))
+    };
+
+    let (batch, mut options) = match request() {
+        Ok(request) => request,
+        Err(err) => return error_response(400, &ErrorBody::new("invalid_argument", &err)),
+    };
+    options.deadline = Some(deadline);
+
+    let started = Instant::now();
+    let start = xray::now();
+    let results = match batch {
+        Batch::Raw(images) => classifier.classify_batch_from_raw(&images, &options),
+        Batch::Urls(urls) => {
+            options.trace = trace.map(Trace::outbound);
+            classifier.classify_batch_from_urls(&urls, &options)
+        }
+    };
+
+    if let Some(trace) = trace {
+        let elapsed = started.elapsed().as_millis() as i64;
+        trace.record(start, &[("batch", elapsed)], None);
+    }
+
+    let items = results
+        .into_iter()
+        .map(|result| match result {
+            Ok(classification) => serde_json::to_value(&classification),
+            Err(err) => serde_json::to_value(ErrorBody::from(&err)),
+        })
+        .collect::<Result<Vec<serde_json::Value>, _>>()?;
+
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&items)?.into())
+        .expect("Failed to render response"))
+}
+
+fn handle_thumbnail(
+    event: &Request,
+    classifier: &ImageClassifier,
+    trace: Option<&Trace>,
+    invocation: &Invocation,
+    deadline: Instant,
+) -> Result<Response<Body>, Error> {
+    let params = || -> Result<(ClassifyOptions, u32, u8), String> {
+        Ok((
+            classify_options(event)?,
+            query_param(event, "size")?.unwrap_or(256),
+            query_param(event, "quality")?.unwrap_or(75),
+        ))
+    };
+
+    let (mut options, size, quality) = match params() {
+        Ok(params) => params,
+        Err(err) => return error_response(400, &ErrorBody::new("invalid_argument", &err)),
+    };
+    options.deadline = Some(deadline);
+
+    let raw = image_body(event);
+
+    let start = xray::now();
+    let response = match classifier.classify_with_thumbnail_from_raw(&raw, &options, size, quality)
+    {
+        Err(err) => status_response(&err)?,
+        Ok((classification, jpeg)) => {
+            if let Some(trace) = trace {
+                trace.record(start, &classification.timings(), None);
+            }
+
+            // Unique enough not to occur in the JPEG data
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos());
+            let boundary = format!("tf-serve-{:x}-{:x}", std::process::id(), nanos);
+            let json = invocation.to_json(&classification)?;
+
+            let mut body: Vec<u8> = vec![];
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\ncontent-type: application/json\r\n\r\n{}\r\n",
+                    boundary, json
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(
+                format!("--{}\r\ncontent-type: image/jpeg\r\n\r\n", boundary).as_bytes(),
+            );
+            body.extend_from_slice(&jpeg);
+            body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+            Response::builder()
+                .status(200)
+                .header(
+                    "content-type",
+                    format!("multipart/mixed; boundary={}", boundary),
+                )
+                .body(body.into())
+                .expect("Failed to render response")
+        }
+    };
+
+    Ok(response)
+}
+
+fn handle_embed(event: &Request, classifier: &ImageClassifier) -> Result<Response<Body>, Error> {
+    let raw = image_body(event);
+
+    let response = match classifier.embed_from_raw(&raw) {
+        Err(err) => status_response(&err)?,
+        Ok(embedding) if accepts(event, "application/octet-stream") => {
+            let mut body = vec![];
+            wire::write_embedding(&mut body, &embedding)?;
+
+            Response::builder()
+                .status(200)
+                .header("content-type", "application/octet-stream")
+                .body(body.into())
+                .expect("Failed to render response")
+        }
+        Ok(embedding) => Response::builder()
+            .status(200)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&embedding)?.into())
+            .expect("Failed to render response"),
+    };
+
+    Ok(response)
+}
+
+fn handle_detect(event: &Request, detector: Option<&Detector>) -> Result<Response<Body>, Error> {
+    let detector = match detector {
+        Some(detector) => detector,
+        None => {
+            return error_response(
+                404,
+                &ErrorBody::new("not_found", "No detection model loaded"),
+            )
+        }
+    };
+
+    let raw = image_body(event);
+
+    let response = if accepts(event, "image/png") {
+        match detector.annotated_png_from_raw(&raw) {
+            Err(err) => status_response(&err)?,
+            Ok(png) => Response::builder()
+                .status(200)
+                .header("content-type", "image/png")
+                .body(png.into())
+                .expect("Failed to render response"),
+        }
+    } else {
+        match detector.detect_from_raw(&raw) {
+            Err(err) => status_response(&err)?,
+            Ok(detections) => Response::builder()
+                .status(200)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&detections)?.into())
+                .expect("Failed to render response"),
+        }
+    };
+
+    Ok(response)
+}
+
+fn handle_pipeline(
+    event: &Request,
+    models: &Models,
+    trace: Option<&Trace>,
+    invocation: &Invocation,
+    deadline: Instant,
+) -> Result<Response<Body>, Error> {
+    let detector = match models.detector.as_ref() {
+        Some(detector) => detector,
+        None => {
+            return error_response(
+                404,
+                &ErrorBody::new("not_found", "No detection model loaded"),
+            )
+        }
+    };
+
+    let options = match classify_options(event) {
+        Ok(options) => ClassifyOptions {
+            deadline: Some(deadline),
+            ..options
+        },
+        Err(err) => return error_response(400, &ErrorBody::new("invalid_argument", &err)),
+    };
+
+    let raw = image_body(event);
+
+    let pipeline = Pipeline::new(detector, &models.classifier);
+
+    let start = xray::now();
+    let response = match pipeline.run_from_raw(&raw, &options) {
+        Err(err) => status_response(&err)?,
+        Ok(result) => {
+            if let Some(trace) = trace {
+                trace.record(start, &result.timings(), None);
+            }
+
+            Response::builder()
+                .status(200)
+                .header("content-type", "application/json")
+                .body(invocation.to_json(&result)?.into())
+                .expect("Failed to render response")
+        }
+    };
 
     Ok(response)
 }
+
+fn handle_segment(event: &Request, segmenter: Option<&Segmenter>) -> Result<Response<Body>, Error> {
+    let segmenter = match segmenter {
+        Some(segmenter) => segmenter,
+        None => {
+            return error_response(
+                404,
+                &ErrorBody::new("not_found", "No segmentation model loaded"),
+            )
+        }
+    };
+
+    let raw = image_body(event);
+
+    let response = if accepts(event, "image/png") {
+        match segmenter.mask_png_from_raw(&raw) {
+            Err(err) => status_response(&err)?,
+            Ok(png) => Response::builder()
+                .status(200)
+                .header("content-type", "image/png")
+                .body(png.into())
+                .expect("Failed to render response"),
+        }
+    } else {
+        match segmenter.segment_from_raw(&raw) {
+            Err(err) => status_response(&err)?,
+            Ok(segmentation) => Response::builder()
+                .status(200)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&segmentation)?.into())
+                .expect("Failed to render response"),
+        }
+    };
+
+    Ok(response)
+}
+
+fn handle_labels(event: &Request, classifier: &ImageClassifier) -> Result<Response<Body>, Error> {
+    let params = event.query_string_parameters();
+    let prefix = params.get("prefix").unwrap_or("");
+
+    let labels = match classifier.labels_in(params.get("lang")) {
+        Ok(labels) => labels,
+        Err(err) => return status_response(&err),
+    };
+
+    let matches = labels.search(prefix);
+
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&matches)?.into())
+        .expect("Failed to render response"))
+}
+
+/// JSON error response, `{"error": {"code": "...", "message": "..."}}`
+fn error_response(status: u16, body: &ErrorBody) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(body)?.into())
+        .expect("Failed to render response"))
+}
+
+/// JSON error response of a failure, with the HTTP status of its code
+fn status_response(err: &Status) -> Result<Response<Body>, Error> {
+    let mut response = error_response(http_status(err.code()), &ErrorBody::from(err))?;
+
+    if let Some(secs) = retry_after(err.code()) {
+        response.headers_mut().insert("retry-after", secs.into());
+    }
+
+    Ok(response)
+}
+
+/// Deadline of a request: the invocation deadline, less the time to respond,
+/// or the configured timeout if sooner
+fn request_deadline(ctx: &Context, timeout: Option<Duration>) -> Instant {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let remaining = Duration::from_millis(ctx.deadline.saturating_sub(now))
+        .checked_sub(RESPONSE_MARGIN)
+        .unwrap_or_default();
+
+    Instant::now() + timeout.map_or(remaining, |timeout| timeout.min(remaining))
+}
+
+/// Credentials from the `X-Api-Key` and `Authorization: Bearer` headers
+fn credentials(event: &Request) -> Credentials {
+    let header = |name: &str| {
+        event
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+
+    Credentials {
+        api_key: header("x-api-key"),
+        bearer: header("authorization").and_then(|value| {
+            let value = value.trim();
+            if value.len() > 7 && value[..7].eq_ignore_ascii_case("bearer ") {
+                Some(value[7..].trim())
+            } else {
+                None
+            }
+        }),
+        ..Default::default()
+    }
+}
+
+/// Trace of the invocation, from the Lambda context or else the trace
+/// headers of the request
+fn trace_context(event: &Request, ctx: &Context) -> Option<TraceContext> {
+    let header = |name: &str| {
+        event
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+
+    // The runtime also passes the trace ID of the invocation in the
+    // environment, as the X-Ray SDKs expect it
+    TraceContext::from_xray(&ctx.xray_trace_id)
+        .or_else(|| {
+            std::env::var("_X_AMZN_TRACE_ID")
+                .ok()
+                .and_then(|id| TraceContext::from_xray(&id))
+        })
+        .or_else(|| header("x-amzn-trace-id").and_then(TraceContext::from_xray))
+        .or_else(|| header("traceparent").and_then(TraceContext::from_traceparent))
+}
+
+/// Image bytes of the request body. API Gateway passes binary bodies on
+/// decoded, but clients sending base64 as text (or without binary media types
+/// configured) end up with a text body, which is decoded here.
+fn image_body(event: &Request) -> Cow<[u8]> {
+    match event.body() {
+        Body::Text(text) => {
+            let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+
+            if text.starts_with("data:") {
+                if let Ok(data) = DataStorage.read(&text) {
+                    return Cow::Owned(data);
+                }
+            }
+
+            match base64::decode(&text) {
+                Ok(data) => Cow::Owned(data),
+                Err(_) => Cow::Borrowed(&event.body()[..]),
+            }
+        }
+        body => Cow::Borrowed(&body[..]),
+    }
+}
+
+/// Whether the body of the request is of `content_type`
+fn has_content_type(event: &Request, content_type: &str) -> bool {
+    event
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map_or(false, |value| {
+            value.trim().eq_ignore_ascii_case(content_type)
+        })
+}
+
+/// Whether the Accept header of the request lists `content_type`
+fn accepts(event: &Request, content_type: &str) -> bool {
+    event
+        .headers()
+        .get("accept")
+        .and_then(|accept| accept.to_str().ok())
+        .map_or(false, |accept| accept.contains(content_type))
+}
+
+/// Parse a single query string parameter of the request
+fn query_param<T: FromStr>(event: &Request, name: &str) -> Result<Option<T>, String> {
+    match event.query_string_parameters().get(name) {
+        None => Ok(None),
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid {}: '{}'", name, value)),
+    }
+}
+
+/// Parse classification options from the query string of the request
+fn classify_options(event: &Request) -> Result<ClassifyOptions, String> {
+    let defaults = ClassifyOptions::default();
+
+    Ok(ClassifyOptions {
+        top_k: query_param(event, "top_k")?.unwrap_or(defaults.top_k),
+        min_probability: query_param(event, "min_probability")?.unwrap_or(defaults.min_probability),
+        logits: query_param(event, "logits")?.unwrap_or(defaults.logits),
+        ambiguity_margin: query_param(event, "ambiguity_margin")?,
+        lang: query_param(event, "lang")?,
+        frames: query_param(event, "frames")?.unwrap_or(defaults.frames),
+        tta: query_param(event, "tta")?.unwrap_or(defaults.tta),
+        roi: query_param(event, "roi")?,
+        ..defaults
+    })
+}
+
+/// Image and options of a classification request: a JSON `ClassifyRequest`
+/// when sent as `application/json`, a form upload when sent as
+/// `multipart/form-data`, otherwise the raw image with options in the query
+/// string
+fn classify_request(event: &Request) -> Result<(ImageSource, ClassifyOptions), String> {
+    let boundary = event
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .and_then(multipart::boundary);
+    if let Some(boundary) = boundary {
+        return form_request(event, &boundary);
+    }
+
+    if !has_content_type(event, "application/json") {
+        return Ok((
+            ImageSource::Raw(image_body(event)),
+            classify_options(event)?,
+        ));
+    }
+
+    let request: ClassifyRequest =
+        serde_json::from_slice(event.body()).map_err(|err| format!("Invalid request: {}", err))?;
+
+    match (request.url, request.image_b64) {
+        (Some(url), None) => Ok((ImageSource::Url(url), request.options)),
+        (None, Some(data)) => {
+            let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+            let image = base64::decode(&data).map_err(|_| "Invalid image_b64".to_owned())?;
+
+            Ok((ImageSource::Raw(Cow::Owned(image)), request.options))
+        }
+        _ => Err("Expected exactly one of 'url' and 'image_b64'".to_owned()),
+    }
+}
+
+/// Image and options of a form upload: the `image` field, along with JSON
+/// `ClassifyOptions` in an optional `options` field. Without that field,
+/// options come from the query string.
+fn form_request<'a>(
+    event: &'a Request,
+    boundary: &str,
+) -> Result<(ImageSource<'a>, ClassifyOptions), String> {
+    let parts = multipart::parse(event.body(), boundary).map_err(|err| format!("{}", err))?;
+    let field = |name: &str| parts.iter().find(|part| part.name.as_deref() == Some(name));
+
+    let image = field("image").ok_or("Missing 'image' field")?;
+    let options = match field("options") {
+        Some(part) => {
+            serde_json::from_slice(part.data).map_err(|err| format!("Invalid options: {}", err))?
+        }
+        None => classify_options(event)?,
+    };
+
+    Ok((ImageSource::Raw(Cow::Borrowed(image.data)), options))
+}
+
+/// Images and options of a batch request: the `image` fields of a form
+/// upload, with options as in `form_request`, or else a JSON array of URLs,
+/// with options in the query string
+fn batch_request(event: &Request) -> Result<(Batch, ClassifyOptions), String> {
+    let boundary = event
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .and_then(multipart::boundary);
+
+    if let Some(boundary) = boundary {
+        let parts = multipart::parse(event.body(), &boundary).map_err(|err| format!("{}", err))?;
+
+        let options = match parts
+            .iter()
+            .find(|part| part.name.as_deref() == Some("options"))
+        {
+            Some(part) => serde_json::from_slice(part.data)
+                .map_err(|err| format!("Invalid options: {}", err))?,
+            None => classify_options(event)?,
+        };
+        let images = parts
+            .iter()
+            .filter(|part| part.name.as_deref() == Some("image"))
+            .map(|part| part.data)
+            .collect();
+
+        return Ok((Batch::Raw(images), options));
+    }
+
+    let urls = serde_json::from_slice(event.body())
+        .map_err(|err| format!("Expected a JSON array of URLs: {}", err))?;
+
+    Ok((Batch::Urls(urls), classify_options(event)?))
+}
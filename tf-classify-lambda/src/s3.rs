@@ -0,0 +1,109 @@
+use std::time::Instant;
+
+use lambda_http::lambda_runtime::Error;
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use tf_serve::{percent_decode, AlertMonitor, ClassifyOptions, ImageClassifier, S3Storage};
+
+use crate::xray::{self, Trace};
+
+#[derive(Deserialize)]
+struct S3Event {
+    #[serde(rename = "Records", default)]
+    records: Vec<S3Record>,
+}
+
+#[derive(Deserialize)]
+struct S3Record {
+    #[serde(rename = "eventName", default)]
+    event_name: String,
+    s3: S3Entity,
+}
+
+#[derive(Deserialize)]
+struct S3Entity {
+    bucket: S3Bucket,
+    object: S3Object,
+}
+
+#[derive(Deserialize)]
+struct S3Bucket {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct S3Object {
+    key: String,
+}
+
+/// Classify every object created in an S3 event and store the result as
+/// `<key>.json`, in `results_bucket` or next to the object
+pub fn handle_event(
+    event: Value,
+    classifier: &ImageClassifier,
+    s3: &S3Storage,
+    results_bucket: Option<&str>,
+    trace: Option<&Trace>,
+    alerts: Option<&AlertMonitor>,
+    deadline: Instant,
+) -> Result<Value, Error> {
+    let event: S3Event = serde_json::from_value(event)?;
+
+    let mut classified = 0;
+
+    for record in event.records {
+        if !record.event_name.starts_with("ObjectCreated") {
+            continue;
+        }
+
+        let bucket = record.s3.bucket.name;
+        // URL-encoded, with `+` for spaces
+        let key = percent_decode(&record.s3.object.key);
+
+        // Results written next to their images trigger events as well
+        if key.ends_with(".json") {
+            continue;
+        }
+
+        let location = format!("s3://{}/{}", bucket, key);
+        let result = format!("s3://{}/{}.json", results_bucket.unwrap_or(&bucket), key);
+
+        debug!("Classifying {}", location);
+
+        let options = ClassifyOptions {
+            trace: trace.map(Trace::outbound),
+            deadline: Some(deadline),
+            ..Default::default()
+        };
+
+        let started = Instant::now();
+        let start = xray::now();
+        let outcome = classifier.classify_from_url_with_options(&location, &options);
+        if let Some(alerts) = alerts {
+            alerts.record(started.elapsed(), outcome.is_ok());
+        }
+
+        let classification = match outcome {
+            Ok(classification) => classification,
+            Err(err) => {
+                warn!("Could not classify {}: {}", location, err);
+                continue;
+            }
+        };
+
+        if let Some(trace) = trace {
+            trace.record(start, &classification.timings(), options.trace.as_ref());
+        }
+
+        s3.write(
+            &result,
+            serde_json::to_string(&classification)?.as_bytes(),
+            "application/json",
+        )?;
+
+        classified += 1;
+    }
+
+    Ok(serde_json::json!({ "classified": classified }))
+}
@@ -0,0 +1,113 @@
+use std::time::Instant;
+
+use lambda_http::lambda_runtime::Error;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use tf_serve::{AlertMonitor, Classification, ClassifyOptions, ImageClassifier, S3Storage};
+
+use crate::xray::{self, Trace};
+
+#[derive(Deserialize)]
+struct SqsEvent {
+    #[serde(rename = "Records", default)]
+    records: Vec<SqsMessage>,
+}
+
+#[derive(Deserialize)]
+struct SqsMessage {
+    #[serde(rename = "messageId")]
+    message_id: String,
+    body: String,
+}
+
+/// Image location of a message body, either the bare URL or `{"url": ...}`
+fn image_url(body: &str) -> Option<String> {
+    let body = body.trim();
+
+    if body.starts_with('{') {
+        let value: Value = serde_json::from_str(body).ok()?;
+        return value.get("url")?.as_str().map(str::to_owned);
+    }
+
+    Some(body.to_owned()).filter(|url| !url.is_empty())
+}
+
+/// Classify the images of the messages of an SQS batch together, fetching
+/// them concurrently. Results go to `<message id>.json` in `results_bucket`,
+/// or to the log. Failed messages are reported in `batchItemFailures` so
+/// that only they are retried.
+pub fn handle_event(
+    event: Value,
+    classifier: &ImageClassifier,
+    s3: &S3Storage,
+    results_bucket: Option<&str>,
+    trace: Option<&Trace>,
+    alerts: Option<&AlertMonitor>,
+    deadline: Instant,
+) -> Result<Value, Error> {
+    let event: SqsEvent = serde_json::from_value(event)?;
+
+    let mut failures = vec![];
+    let mut fail = |message: &SqsMessage, err: &dyn std::fmt::Display| {
+        warn!("Could not process message {}: {}", message.message_id, err);
+        failures.push(serde_json::json!({ "itemIdentifier": message.message_id }));
+    };
+
+    let mut messages = vec![];
+    let mut urls = vec![];
+    for message in &event.records {
+        match image_url(&message.body) {
+            Some(url) => {
+                messages.push(message);
+                urls.push(url);
+            }
+            None => fail(message, &"Message without image URL"),
+        }
+    }
+
+    let options = ClassifyOptions {
+        trace: trace.map(Trace::outbound),
+        deadline: Some(deadline),
+        ..Default::default()
+    };
+
+    let started = Instant::now();
+    let start = xray::now();
+    let outcomes = if urls.is_empty() {
+        vec![]
+    } else {
+        classifier.classify_batch_from_urls(&urls, &options)
+    };
+
+    for ((message, url), outcome) in messages.into_iter().zip(&urls).zip(outcomes) {
+        if let Some(alerts) = alerts {
+            alerts.record(started.elapsed(), outcome.is_ok());
+        }
+
+        let write = |classification: Classification| -> Result<(), Error> {
+            if let Some(trace) = trace {
+                trace.record(start, &classification.timings(), options.trace.as_ref());
+            }
+
+            let result = serde_json::to_string(&classification)?;
+
+            match results_bucket {
+                Some(bucket) => s3.write(
+                    &format!("s3://{}/{}.json", bucket, message.message_id),
+                    result.as_bytes(),
+                    "application/json",
+                )?,
+                None => info!("Classified {}: {}", url, result),
+            }
+
+            Ok(())
+        };
+
+        if let Err(err) = outcome.map_err(Error::from).and_then(write) {
+            fail(message, &err);
+        }
+    }
+
+    Ok(serde_json::json!({ "batchItemFailures": failures }))
+}
@@ -0,0 +1,131 @@
+use std::net::UdpSocket;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::json;
+use tf_serve::{new_span_id, OtlpExporter, Span, TraceContext};
+
+/// Seconds since the epoch, the timestamp format of X-Ray
+pub fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64())
+}
+
+/// Client of the X-Ray daemon that Lambda runs along the function when
+/// active tracing is enabled
+pub struct XRay {
+    socket: UdpSocket,
+    daemon: String,
+}
+
+impl XRay {
+    /// Client of the daemon at `AWS_XRAY_DAEMON_ADDRESS`, if set
+    pub fn from_env() -> Option<Self> {
+        let daemon = std::env::var("AWS_XRAY_DAEMON_ADDRESS").ok()?;
+        let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+
+        Some(XRay { socket, daemon })
+    }
+
+    /// Send a segment document
+    fn send(&self, document: &serde_json::Value) {
+        let packet = format!("{{\"format\": \"json\", \"version\": 1}}\n{}", document);
+
+        if let Err(err) = self.socket.send_to(packet.as_bytes(), &self.daemon) {
+            warn!("Could not send X-Ray subsegment: {}", err);
+        }
+    }
+}
+
+/// Trace of an invocation, whose segment Lambda creates
+pub struct Trace<'a> {
+    context: TraceContext,
+    xray: Option<&'a XRay>,
+
+    /// Exporter of the spans of the stages, when configured by the `OTEL_*`
+    /// environment variables
+    otlp: Option<&'a OtlpExporter>,
+}
+
+impl<'a> Trace<'a> {
+    pub fn new(
+        context: TraceContext,
+        xray: Option<&'a XRay>,
+        otlp: Option<&'a OtlpExporter>,
+    ) -> Self {
+        Trace {
+            context,
+            xray,
+            otlp,
+        }
+    }
+
+    /// Context to propagate to an outbound request, under a new subsegment
+    pub fn outbound(&self) -> TraceContext {
+        self.context.child()
+    }
+
+    /// Record the stages of a classification started at `start` as
+    /// subsegments of the invocation, and spans under it. The fetch stage
+    /// gets the ID propagated with `outbound`, so that the trace of the image
+    /// server nests under it.
+    pub fn record(&self, start: f64, stages: &[(&str, i64)], outbound: Option<&TraceContext>) {
+        if let (Some(otlp), Some(parent_id)) = (self.otlp, &self.context.parent_id) {
+            let segment = Span {
+                name: "invocation".to_owned(),
+                span_id: parent_id.clone(),
+                parent_id: None,
+                start: UNIX_EPOCH + Duration::from_secs_f64(start.max(0.0)),
+                end: SystemTime::now(),
+                attributes: vec![],
+                error: None,
+            };
+
+            // Spans of the stages, as Lambda reports the invocation itself
+            let context = TraceContext {
+                parent_id: None,
+                ..self.context.clone()
+            };
+            otlp.export(&context, &Span::stages(&segment, stages, outbound));
+        }
+
+        let xray = match self.xray {
+            Some(xray) if self.context.sampled => xray,
+            _ => return,
+        };
+
+        let parent_id = match &self.context.parent_id {
+            Some(parent_id) => parent_id,
+            None => return,
+        };
+
+        let trace_id = self.context.xray_trace_id();
+        let mut time = start;
+
+        for &(name, duration) in stages {
+            // Stages that did not run, like the fetch of an uploaded image
+            if duration <= 0 {
+                continue;
+            }
+
+            let end = time + duration as f64 / 1000.0;
+            let id = match (name, outbound.and_then(|trace| trace.parent_id.as_ref())) {
+                ("fetch", Some(id)) => id.clone(),
+                _ => new_span_id(),
+            };
+
+            xray.send(&json!({
+                "name": name,
+                "id": id,
+                "trace_id": trace_id,
+                "parent_id": parent_id,
+                "start_time": time,
+                "end_time": end,
+                "type": "subsegment",
+            }));
+
+            time = end;
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! MQTT client classifying the snapshots published by IoT and edge cameras,
+//! and publishing the classifications back.
+//!
+//! Every message of the image topic is taken for an encoded image, and its
+//! JSON classification or error is published to the result topic. The
+//! client reconnects whenever the connection drops, resuming its session so
+//! that messages published with QoS 1 or 2 meanwhile are not lost.
+//! Snapshots arriving faster than they are classified are dropped rather
+//! than queued, so that results do not lag behind the cameras.
+//!
+//! Configured by the environment:
+//!
+//! - `MQTT_HOST`, `MQTT_PORT`: broker, `localhost:1883` by default.
+//! - `MQTT_CLIENT_ID`: `tf-classify` by default, and unique per replica.
+//! - `MQTT_USERNAME`, `MQTT_PASSWORD`: credentials, if any.
+//! - `MQTT_TOPIC`: filter of image topics, `snapshots/#` by default.
+//! - `MQTT_RESULT_TOPIC`: topic of results, where `{topic}` is replaced by
+//!   that of the image, `{topic}/classification` by default.
+//! - `MQTT_QOS`: 0, 1 (by default) or 2, for subscribing and publishing.
+//! - `RESULT_SINK`: where results are also written, under the request ID,
+//!   as by `tf_serve::result_sink`.
+//! - `TF_MODEL_DIR`, `TF_MODEL_LABELS`: the model, `/var/task/model` and
+//!   its `labels.txt` by default.
+
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+use tf_serve::{
+    env_parse, ClassifierOptions, HttpRequest, HttpServer, ImageClassifier, ServerOptions,
+};
+
+/// Snapshots waiting to be classified, beyond which new ones are dropped
+const MAX_PENDING: usize = 8;
+
+fn qos(level: u8) -> Result<QoS, String> {
+    match level {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        _ => Err(format!("Invalid MQTT_QOS {}, expected 0, 1 or 2", level)),
+    }
+}
+
+/// Classify the snapshots received, and publish their results
+fn classify(
+    server: &HttpServer,
+    mut client: Client,
+    snapshots: mpsc::Receiver<(String, Vec<u8>)>,
+    result_topic: &str,
+    qos: QoS,
+) {
+    for (topic, image) in snapshots {
+        let response = server.handle(&HttpRequest::new("POST", "/", vec![], image));
+        let result_topic = result_topic.replace("{topic}", &topic);
+
+        debug!("Publishing result of {} to {}", topic, result_topic);
+        if let Err(err) = client.publish(&result_topic, qos, false, response.body) {
+            warn!("Could not publish to {}: {}", result_topic, err);
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    let host = env::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_owned());
+    let port = env_parse("MQTT_PORT").unwrap_or(1883);
+    let client_id = env::var("MQTT_CLIENT_ID").unwrap_or_else(|_| "tf-classify".to_owned());
+    let topic = env::var("MQTT_TOPIC").unwrap_or_else(|_| "snapshots/#".to_owned());
+    let result_topic =
+        env::var("MQTT_RESULT_TOPIC").unwrap_or_else(|_| "{topic}/classification".to_owned());
+    let qos = qos(env_parse("MQTT_QOS").unwrap_or(1))?;
+
+    let export_dir =
+        PathBuf::from(env::var("TF_MODEL_DIR").unwrap_or_else(|_| "/var/task/model".to_owned()));
+    let tags_path = env::var("TF_MODEL_LABELS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| export_dir.join("labels.txt"));
+
+    let options = ServerOptions {
+        ..ServerOptions::from_env()
+    };
+    let max_packet_size = options.max_body_size;
+
+    let mut init = tf_serve::Timer::new_start("Loading model");
+    let classifier =
+        ImageClassifier::with_options(&export_dir, &tags_path, &ClassifierOptions::from_env())?;
+    classifier.warm_up()?;
+    let server = Arc::new(HttpServer::new(Arc::new(classifier), options));
+    init.stop();
+
+    let mut mqtt_options = MqttOptions::new(client_id, host.clone(), port);
+    mqtt_options
+        .set_keep_alive(30)
+        .set_clean_session(false)
+        .set_max_packet_size(max_packet_size, max_packet_size);
+    if let (Ok(username), Ok(password)) = (env::var("MQTT_USERNAME"), env::var("MQTT_PASSWORD")) {
+        mqtt_options.set_credentials(username, password);
+    }
+
+    let (mut client, mut connection) = Client::new(mqtt_options, 16);
+
+    // Classified away from the event loop, which has to keep polling for
+    // the connection to stay alive
+    let (sender, snapshots) = mpsc::sync_channel(MAX_PENDING);
+    {
+        let client = client.clone();
+        thread::spawn(move || classify(&server, client, snapshots, &result_topic, qos));
+    }
+
+    info!("Classifying images of {} on {}:{}", topic, host, port);
+
+    for event in connection.iter() {
+        match event {
+            // Subscribing again on every connection, in case the broker
+            // did not keep the session
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to {}:{}", host, port);
+                client.subscribe(&topic, qos)?;
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let snapshot = (publish.topic, publish.payload.to_vec());
+
+                match sender.try_send(snapshot) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full((topic, _))) => {
+                        warn!("Dropping image of {}, too many pending", topic)
+                    }
+                    Err(TrySendError::Disconnected(_)) => {
+                        return Err("Classification thread exited".into())
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                warn!("MQTT connection failed, reconnecting: {}", err);
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+
+    Ok(())
+}
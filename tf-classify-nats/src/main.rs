@@ -0,0 +1,126 @@
+//! Worker replying to classification requests sent over NATS, a lighter
+//! alternative to HTTP inside a service mesh.
+//!
+//! Requests are those of the HTTP frontends: the raw image, or a JSON
+//! `{"url": ...}` or `{"image_b64": ...}` body with inline options. Replies
+//! are the JSON classification or error. Workers subscribe in a queue group,
+//! so that requests are spread over every replica.
+//!
+//! Configured by the environment:
+//!
+//! - `NATS_URL`: server to connect to, `nats://localhost:4222` by default.
+//! - `NATS_SUBJECT`: subject of requests, `tf.classify` by default.
+//! - `NATS_QUEUE_GROUP`: queue group of the replicas, `tf-classify` by
+//!   default. Empty to have every replica reply to every request.
+//! - `TF_WORKERS`: subscriptions, and so requests handled at the same time,
+//!   1 by default.
+//! - `RESULT_SINK`: where results are also written, under the request ID,
+//!   as by `tf_serve::result_sink`.
+//! - `TF_MODEL_DIR`, `TF_MODEL_LABELS`: the model, `/var/task/model` and
+//!   its `labels.txt` by default.
+
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use log::{info, warn};
+use tf_serve::{
+    env_parse, ClassifierOptions, HttpRequest, HttpServer, ImageClassifier, ServerOptions,
+};
+
+/// Request of a message, a JSON one if it looks like JSON
+fn request(data: Vec<u8>) -> HttpRequest {
+    let content_type = if data.first() == Some(&b'{') {
+        "application/json"
+    } else {
+        "application/octet-stream"
+    };
+
+    HttpRequest::new(
+        "POST",
+        "/",
+        vec![("content-type".to_owned(), content_type.to_owned())],
+        data,
+    )
+}
+
+/// Reply to the requests of a subscription until it is closed
+fn serve(server: &HttpServer, subscription: nats::Subscription) {
+    for mut message in subscription.messages() {
+        if message.reply.is_none() {
+            warn!(
+                "Ignoring message without reply subject on {}",
+                message.subject
+            );
+            continue;
+        }
+
+        let response = server.handle(&request(std::mem::take(&mut message.data)));
+
+        if let Err(err) = message.respond(&response.body) {
+            warn!("Could not reply on {}: {}", message.subject, err);
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    let url = env::var("NATS_URL").unwrap_or_else(|_| "nats://localhost:4222".to_owned());
+    let subject = env::var("NATS_SUBJECT").unwrap_or_else(|_| "tf.classify".to_owned());
+    let queue_group = env::var("NATS_QUEUE_GROUP").unwrap_or_else(|_| "tf-classify".to_owned());
+    let workers = env_parse("TF_WORKERS").unwrap_or(1usize).max(1);
+
+    let export_dir =
+        PathBuf::from(env::var("TF_MODEL_DIR").unwrap_or_else(|_| "/var/task/model".to_owned()));
+    let tags_path = env::var("TF_MODEL_LABELS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| export_dir.join("labels.txt"));
+
+    let options = ServerOptions {
+        // Every worker waits for its turn rather than failing its request
+        max_queued: workers,
+        ..ServerOptions::from_env()
+    };
+
+    let mut init = tf_serve::Timer::new_start("Loading model");
+    let classifier =
+        ImageClassifier::with_options(&export_dir, &tags_path, &ClassifierOptions::from_env())?;
+    classifier.warm_up()?;
+    let server = Arc::new(HttpServer::new(Arc::new(classifier), options));
+    init.stop();
+
+    // Reconnecting forever, with subscriptions restored by the client
+    let connection = nats::Options::new()
+        .with_name("tf-classify")
+        .max_reconnects(None)
+        .connect(&url)?;
+
+    let threads = (0..workers)
+        .map(|_| {
+            let subscription = if queue_group.is_empty() {
+                connection.subscribe(&subject)?
+            } else {
+                connection.queue_subscribe(&subject, &queue_group)?
+            };
+            let server = server.clone();
+
+            Ok(thread::spawn(move || serve(&server, subscription)))
+        })
+        .collect::<Result<Vec<_>, std::io::Error>>()?;
+
+    info!(
+        "Replying to requests on {} of {} with {} workers",
+        subject, url, workers
+    );
+
+    for thread in threads {
+        if thread.join().is_err() {
+            warn!("NATS worker panicked");
+        }
+    }
+
+    Ok(())
+}
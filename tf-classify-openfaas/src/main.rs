@@ -0,0 +1,192 @@
+//! OpenFaaS function serving classifications behind the of-watchdog in HTTP
+//! mode: the watchdog starts this binary as its `fprocess` and forwards
+//! requests to `http_port`.
+//!
+//! In the `serializing` mode of the of-watchdog, behind the classic watchdog,
+//! or when run with `--stdin`, it instead classifies a single request body
+//! read from stdin and writes the JSON result to stdout, which also makes
+//! pipelines like `cat cat.jpg | tf-classify-openfaas --stdin` work.
+//!
+//! To be exposed beyond a trusted network, requests can be required to carry
+//! an API key, in an `X-Api-Key` or `Authorization: Bearer` header, and
+//! limited in rate per key:
+//!
+//! - `TF_API_KEYS`: comma-separated keys.
+//! - `TF_API_KEYS_FILE`: file of keys, like a secret mounted under
+//!   `/var/openfaas/secrets`, one per line, each optionally followed by the
+//!   name of its caller for the logs. Blank lines and `#` comments are
+//!   skipped.
+//! - `TF_JWKS_URL`: with the `jwt` feature, JSON Web Key Set of an OIDC
+//!   issuer, for bearer tokens to be JWTs signed by it, checked against
+//!   `TF_JWT_AUDIENCE`, which must be set along, and `TF_JWT_ISSUER` if set.
+//!   Their claims are logged at debug level.
+//! - `TF_RATE_LIMIT`: requests per second of every key or token subject, or
+//!   of all callers without either, on average.
+//! - `TF_RATE_BURST`: most requests at once of every key, the rate limit by
+//!   default.
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use log::info;
+use tf_serve::{
+    env_parse, AuthRegistry, ClassifierOptions, HttpRequest, HttpServer, ImageClassifier,
+    RateLimiter, ServerOptions, StaticKeys,
+};
+#[cfg(feature = "jwt")]
+use tf_serve::{JwtOptions, JwtValidator};
+
+/// API keys of `TF_API_KEYS` and `TF_API_KEYS_FILE`, if any
+fn api_keys() -> io::Result<Option<StaticKeys>> {
+    let mut keys = StaticKeys::new();
+    let mut count = 0;
+
+    if let Ok(list) = env::var("TF_API_KEYS") {
+        for key in list.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+            keys.insert(key, &format!("key-{}", count));
+            count += 1;
+        }
+    }
+
+    if let Ok(path) = env::var("TF_API_KEYS_FILE") {
+        let contents = fs::read_to_string(&path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path, err)))?;
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let key = fields.next().unwrap_or_default();
+            let subject = fields
+                .next()
+                .map_or_else(|| format!("key-{}", count), str::to_owned);
+            keys.insert(key, &subject);
+            count += 1;
+        }
+    }
+
+    Ok(if count > 0 { Some(keys) } else { None })
+}
+
+/// Handle the request read from stdin. The classic watchdog passes the
+/// method, path, query string and content type in `Http_*` variables.
+fn serve_stdin(server: &HttpServer) -> Result<(), Box<dyn Error>> {
+    let mut body = vec![];
+    io::stdin().read_to_end(&mut body)?;
+
+    let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+
+    let mut url = var("Http_Path").unwrap_or_else(|| "/".to_owned());
+    if let Some(query) = var("Http_Query") {
+        url.push('?');
+        url.push_str(&query);
+    }
+    let headers = [
+        ("content-type", "Http_Content_Type"),
+        ("authorization", "Http_Authorization"),
+        ("x-api-key", "Http_X_Api_Key"),
+    ]
+    .iter()
+    .filter_map(|&(header, name)| var(name).map(|value| (header.to_owned(), value)))
+    .collect();
+
+    let request = HttpRequest::new(
+        &var("Http_Method").unwrap_or_else(|| "POST".to_owned()),
+        &url,
+        headers,
+        body,
+    );
+    let response = server.handle(&request);
+
+    let mut stdout = io::stdout();
+    stdout.write_all(&response.body)?;
+    stdout.write_all(b"\n")?;
+    stdout.flush()?;
+
+    // The watchdog reports failed processes as errors
+    if response.status >= 400 {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    let mode = env::var("mode").unwrap_or_else(|_| "http".to_owned());
+    // The classic watchdog runs the process once per request
+    let stdin = env::args().any(|arg| arg == "--stdin")
+        || mode == "serializing"
+        || env::var("Http_Method").is_ok();
+    if !stdin && mode != "http" {
+        return Err(format!(
+            "Unsupported of-watchdog mode '{}', expected 'http' or 'serializing'",
+            mode
+        )
+        .into());
+    }
+    let port = env::var("http_port").unwrap_or_else(|_| "8082".to_owned());
+
+    let export_dir =
+        PathBuf::from(env::var("TF_MODEL_DIR").unwrap_or_else(|_| "/home/app/model".to_owned()));
+    let tags_path = env::var("TF_MODEL_LABELS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| export_dir.join("labels.txt"));
+
+    let options = ServerOptions::from_env();
+
+    let mut init = tf_serve::Timer::new_start("Loading model");
+
+    let classifier =
+        ImageClassifier::with_options(&export_dir, &tags_path, &ClassifierOptions::from_env())?;
+
+    let mut server = HttpServer::new(Arc::new(classifier), options);
+    server.load_models_from_env()?;
+    let mut auth = AuthRegistry::new();
+    // Tokens first, for the keys not to take them for unknown keys
+    #[cfg(feature = "jwt")]
+    {
+        if let Ok(jwks_url) = env::var("TF_JWKS_URL") {
+            let audience = env::var("TF_JWT_AUDIENCE")
+                .map_err(|_| "TF_JWT_AUDIENCE must be set along with TF_JWKS_URL")?;
+            let options = JwtOptions {
+                issuer: env::var("TF_JWT_ISSUER").ok(),
+                ..JwtOptions::new(&jwks_url, &audience)
+            };
+            auth.register(Arc::new(JwtValidator::new(reqwest::Client::new(), options)));
+        }
+    }
+    if let Some(keys) = api_keys()? {
+        auth.register(Arc::new(keys));
+    }
+    server.set_auth(auth);
+    if let Some(rate) = env_parse::<f64>("TF_RATE_LIMIT").filter(|&rate| rate > 0.0) {
+        let burst = env_parse("TF_RATE_BURST").unwrap_or(rate.ceil() as u32);
+        server.set_rate_limit(RateLimiter::new(rate, burst));
+    }
+
+    let server = Arc::new(server);
+    if stdin {
+        init.stop();
+        return serve_stdin(&server);
+    }
+
+    server.warm_up()?;
+
+    init.stop();
+
+    // Listening only once the model is loaded, so that the watchdog does not
+    // forward requests before then
+    let addr = format!("0.0.0.0:{}", port);
+    info!("Serving on {}", addr);
+
+    Ok(server.serve(&addr)?)
+}
@@ -0,0 +1,236 @@
+//! Apache OpenWhisk action serving classifications, run as the action proxy
+//! of a Docker action: the invoker posts to `/init` once, then activations
+//! to `/run`, on port 8080.
+//!
+//! The parameters of an activation are those of a JSON classification
+//! request, `{"url": ...}` or `{"image_b64": ...}` with inline options, and
+//! its result the classification. Web actions may instead post the raw image,
+//! which OpenWhisk passes on in `__ow_body`, and get an HTTP response back.
+//!
+//! The model is loaded with the options of the environment of the container.
+//! The environment passed to `/init`, such as bound parameters, may set
+//! `TF_REQUEST_TIMEOUT_MS`, `TF_MAX_IN_FLIGHT` and `TF_MAX_QUEUED` as well.
+
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use log::info;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tf_serve::{
+    ClassifierOptions, Detector, HttpRequest, HttpResponse, HttpServer, ImageClassifier, Segmenter,
+    ServerOptions,
+};
+
+/// Body of `/init`
+#[derive(Deserialize)]
+struct InitRequest {
+    #[serde(default)]
+    value: InitValue,
+}
+
+#[derive(Default, Deserialize)]
+struct InitValue {
+    /// Environment of the action, such as its bound parameters
+    #[serde(default)]
+    env: Map<String, Value>,
+}
+
+/// Body of `/run`
+#[derive(Deserialize)]
+struct RunRequest {
+    #[serde(default)]
+    value: Map<String, Value>,
+
+    #[serde(default)]
+    activation_id: String,
+}
+
+/// Value of `name` in the environment of `/init`, if set and valid
+fn init_param<T: FromStr>(env: &Map<String, Value>, name: &str) -> Option<T> {
+    match env.get(name)? {
+        Value::String(value) => value.parse().ok(),
+        value => value.to_string().parse().ok(),
+    }
+}
+
+/// Action proxy around an `HttpServer`, created by `/init`
+struct ActionProxy {
+    classifier: Arc<ImageClassifier>,
+
+    /// Object detection model, if any, see `Detector::from_env`
+    detector: Option<Arc<Detector>>,
+
+    /// Semantic segmentation model, if any, see `Segmenter::from_env`
+    segmenter: Option<Arc<Segmenter>>,
+
+    /// Options of the server, before those of `/init`
+    options: ServerOptions,
+
+    server: RwLock<Option<HttpServer>>,
+}
+
+impl ActionProxy {
+    fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/init") => self.init(request),
+            ("POST", "/run") => self.run(request),
+            _ => HttpResponse::json(
+                404,
+                &json!({ "error": format!("Cannot {} {}", request.method, request.path) }),
+            ),
+        }
+    }
+
+    fn init(&self, request: &HttpRequest) -> HttpResponse {
+        let mut server = self.server.write().unwrap();
+        if server.is_some() {
+            return HttpResponse::json(
+                403,
+                &json!({ "error": "Cannot initialize the action more than once." }),
+            );
+        }
+
+        // The code is part of the image, leaving only the environment
+        let env = serde_json::from_slice::<InitRequest>(&request.body)
+            .map(|init| init.value.env)
+            .unwrap_or_default();
+
+        let options = ServerOptions {
+            timeout: init_param(&env, "TF_REQUEST_TIMEOUT_MS")
+                .map(Duration::from_millis)
+                .or(self.options.timeout),
+            max_in_flight: init_param(&env, "TF_MAX_IN_FLIGHT")
+                .unwrap_or(self.options.max_in_flight),
+            max_queued: init_param(&env, "TF_MAX_QUEUED").unwrap_or(self.options.max_queued),
+            ..self.options.clone()
+        };
+        let mut http = HttpServer::new(self.classifier.clone(), options);
+        if let Some(detector) = &self.detector {
+            http.set_detector(detector.clone());
+        }
+        if let Some(segmenter) = &self.segmenter {
+            http.set_segmenter(segmenter.clone());
+        }
+        *server = Some(http);
+
+        HttpResponse::json(200, &json!({ "ok": true }))
+    }
+
+    fn run(&self, request: &HttpRequest) -> HttpResponse {
+        let server = self.server.read().unwrap();
+        let server = match &*server {
+            Some(server) => server,
+            None => {
+                return HttpResponse::json(
+                    503,
+                    &json!({ "error": "The action is not initialized." }),
+                )
+            }
+        };
+
+        let run: RunRequest = match serde_json::from_slice(&request.body) {
+            Ok(run) => run,
+            Err(err) => {
+                return HttpResponse::json(
+                    400,
+                    &json!({ "error": format!("Invalid activation: {}", err) }),
+                )
+            }
+        };
+        info!("Activation {}", run.activation_id);
+
+        let web = run.value.contains_key("__ow_method");
+        let mut params = run.value;
+
+        // Raw web actions pass the body base64 encoded
+        if let Some(Value::String(body)) = params.remove("__ow_body") {
+            if !params.contains_key("url") && !params.contains_key("image_b64") {
+                params.insert("image_b64".to_owned(), Value::String(body));
+            }
+        }
+        let params: Map<String, Value> = params
+            .into_iter()
+            .filter(|(name, _)| !name.starts_with("__ow_"))
+            .collect();
+
+        let response = server.handle(&HttpRequest {
+            method: "POST".to_owned(),
+            path: "/run".to_owned(),
+            query: vec![],
+            headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+            body: Value::Object(params).to_string().into_bytes(),
+        });
+        let body: Value = serde_json::from_slice(&response.body).unwrap_or(Value::Null);
+
+        // Results are JSON objects, failures with an `error` in them, or the
+        // HTTP response of web actions
+        let result = if web {
+            let headers: Map<String, Value> = response
+                .headers
+                .into_iter()
+                .map(|(name, value)| (name, Value::String(value)))
+                .collect();
+
+            json!({ "statusCode": response.status, "headers": headers, "body": body })
+        } else {
+            body
+        };
+
+        HttpResponse::json(200, &result)
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    let export_dir =
+        PathBuf::from(env::var("TF_MODEL_DIR").unwrap_or_else(|_| "/model".to_owned()));
+    let tags_path = env::var("TF_MODEL_LABELS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| export_dir.join("labels.txt"));
+
+    let options = ServerOptions::from_env();
+
+    let mut init = tf_serve::Timer::new_start("Loading model");
+
+    let classifier =
+        ImageClassifier::with_options(&export_dir, &tags_path, &ClassifierOptions::from_env())?;
+    classifier.warm_up()?;
+
+    let detector = Detector::from_env()?;
+    if let Some(detector) = &detector {
+        detector.warm_up()?;
+    }
+    let segmenter = Segmenter::from_env()?;
+    if let Some(segmenter) = &segmenter {
+        segmenter.warm_up()?;
+    }
+
+    init.stop();
+
+    let threads = options.threads;
+    // Images are base64 encoded and wrapped in the activation
+    let max_body_size = options.max_body_size * 4 / 3 + 4096;
+    let proxy = Arc::new(ActionProxy {
+        classifier: Arc::new(classifier),
+        detector: detector.map(Arc::new),
+        segmenter: segmenter.map(Arc::new),
+        options,
+        server: RwLock::new(None),
+    });
+
+    let addr = "0.0.0.0:8080";
+    info!("Serving on {}", addr);
+
+    Ok(tf_serve::serve_http(
+        addr,
+        threads,
+        max_body_size,
+        move |request| proxy.handle(request),
+    )?)
+}
@@ -0,0 +1,233 @@
+//! Worker classifying jobs queued in Redis, for deployments already built
+//! around Redis-backed queues.
+//!
+//! Jobs are JSON classification requests, `{"url": ...}` or
+//! `{"image_b64": ...}` with inline options, along with the `reply_key` of
+//! the list their JSON result or error is pushed to, for the client to pop
+//! it. They are popped from a list, or read from a stream by a consumer
+//! group, in the `job` field of its entries. Entries of a stream are
+//! acknowledged once replied to, and those of a worker that did not get to
+//! are handled again when it restarts.
+//!
+//! Configured by the environment:
+//!
+//! - `REDIS_URL`: server to connect to, `redis://localhost:6379` by default.
+//! - `REDIS_LIST`: list of jobs to `BRPOP`, or else
+//! - `REDIS_STREAM`: stream of jobs, read by the consumer group
+//!   `REDIS_GROUP` (`tf-classify` by default) as `REDIS_CONSUMER` (the
+//!   hostname by default).
+//! - `REDIS_REPLY_TTL_SECS`: expiry of reply lists, an hour by default.
+//! - `TF_WORKERS`: connections, and so jobs handled at the same time, 1 by
+//!   default.
+//! - `RESULT_SINK`: where results are also written, under the request ID,
+//!   as by `tf_serve::result_sink`.
+//! - `TF_MODEL_DIR`, `TF_MODEL_LABELS`: the model, `/var/task/model` and
+//!   its `labels.txt` by default.
+
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use redis::streams::{StreamReadOptions, StreamReadReply};
+use redis::{Commands, Connection, RedisResult};
+use serde_json::Value;
+use tf_serve::{
+    env_parse, ClassifierOptions, HttpRequest, HttpServer, ImageClassifier, ServerOptions,
+};
+
+/// Longest wait for a job before polling again, in seconds
+const BLOCK_SECS: usize = 5;
+
+/// Where jobs are queued
+#[derive(Clone)]
+enum Source {
+    List(String),
+    Stream {
+        key: String,
+        group: String,
+        consumer: String,
+    },
+}
+
+/// Classifies jobs and replies to them
+struct Worker {
+    server: Arc<HttpServer>,
+    reply_ttl: usize,
+}
+
+impl Worker {
+    /// Classify a job, and push its result to its reply key
+    fn handle(&self, connection: &mut Connection, job: &[u8]) -> RedisResult<()> {
+        let reply_key = serde_json::from_slice::<Value>(job)
+            .ok()
+            .and_then(|job| job.get("reply_key")?.as_str().map(str::to_owned));
+
+        let response = self.server.handle(&HttpRequest::new(
+            "POST",
+            "/",
+            vec![("content-type".to_owned(), "application/json".to_owned())],
+            job.to_vec(),
+        ));
+
+        match reply_key {
+            Some(key) => {
+                let _: () = connection.rpush(&key, response.body)?;
+                let _: () = connection.expire(&key, self.reply_ttl)?;
+            }
+            None => warn!(
+                "Dropping the result of a job without reply_key: {}",
+                String::from_utf8_lossy(&response.body)
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Handle the jobs popped from a list until the connection fails
+    fn serve_list(&self, connection: &mut Connection, key: &str) -> RedisResult<()> {
+        loop {
+            let popped: Option<(String, Vec<u8>)> = connection.brpop(key, BLOCK_SECS)?;
+
+            if let Some((_, job)) = popped {
+                self.handle(connection, &job)?;
+            }
+        }
+    }
+
+    /// Handle the entries read from a stream until the connection fails,
+    /// starting with those read before but not acknowledged
+    fn serve_stream(
+        &self,
+        connection: &mut Connection,
+        key: &str,
+        group: &str,
+        consumer: &str,
+    ) -> RedisResult<()> {
+        // Fails with BUSYGROUP if the group exists already
+        let _: RedisResult<()> = connection.xgroup_create_mkstream(key, group, "$");
+
+        let mut pending = true;
+        loop {
+            let mut options = StreamReadOptions::default().group(group, consumer).count(1);
+            if !pending {
+                options = options.block(BLOCK_SECS * 1000);
+            }
+
+            let id = if pending { "0" } else { ">" };
+            let reply: StreamReadReply = connection.xread_options(&[key], &[id], &options)?;
+
+            let entries: Vec<_> = reply.keys.into_iter().flat_map(|key| key.ids).collect();
+            if entries.is_empty() {
+                pending = false;
+                continue;
+            }
+
+            for entry in entries {
+                match entry.get::<Vec<u8>>("job") {
+                    Some(job) => self.handle(connection, &job)?,
+                    None => warn!("Dropping entry {} of {} without job", entry.id, key),
+                }
+
+                let _: () = connection.xack(key, group, &[&entry.id])?;
+            }
+        }
+    }
+
+    /// Handle jobs, reconnecting whenever the connection fails
+    fn run(&self, client: &redis::Client, source: &Source) {
+        loop {
+            let result = client
+                .get_connection()
+                .and_then(|mut connection| match source {
+                    Source::List(key) => self.serve_list(&mut connection, key),
+                    Source::Stream {
+                        key,
+                        group,
+                        consumer,
+                    } => self.serve_stream(&mut connection, key, group, consumer),
+                });
+
+            if let Err(err) = result {
+                warn!("Redis failed, reconnecting: {}", err);
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    let url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost:6379".to_owned());
+    let source = match (env::var("REDIS_LIST"), env::var("REDIS_STREAM")) {
+        (Ok(key), _) => Source::List(key),
+        (_, Ok(key)) => Source::Stream {
+            key,
+            group: env::var("REDIS_GROUP").unwrap_or_else(|_| "tf-classify".to_owned()),
+            consumer: env::var("REDIS_CONSUMER")
+                .or_else(|_| env::var("HOSTNAME"))
+                .unwrap_or_else(|_| "tf-classify".to_owned()),
+        },
+        _ => return Err("Either REDIS_LIST or REDIS_STREAM must be set".into()),
+    };
+    let workers = env_parse("TF_WORKERS").unwrap_or(1usize).max(1);
+
+    let export_dir =
+        PathBuf::from(env::var("TF_MODEL_DIR").unwrap_or_else(|_| "/var/task/model".to_owned()));
+    let tags_path = env::var("TF_MODEL_LABELS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| export_dir.join("labels.txt"));
+
+    let options = ServerOptions {
+        // Every worker waits for its turn rather than failing its job
+        max_queued: workers,
+        ..ServerOptions::from_env()
+    };
+
+    let mut init = tf_serve::Timer::new_start("Loading model");
+    let classifier =
+        ImageClassifier::with_options(&export_dir, &tags_path, &ClassifierOptions::from_env())?;
+    classifier.warm_up()?;
+    let worker = Arc::new(Worker {
+        server: Arc::new(HttpServer::new(Arc::new(classifier), options)),
+        reply_ttl: env_parse("REDIS_REPLY_TTL_SECS").unwrap_or(3600),
+    });
+    init.stop();
+
+    let client = redis::Client::open(url.as_str())?;
+    info!("Handling jobs of {} with {} workers", url, workers);
+
+    let threads: Vec<_> = (0..workers)
+        .map(|index| {
+            let (worker, client) = (worker.clone(), client.clone());
+
+            // Consumers of a group must have names of their own
+            let source = match &source {
+                Source::Stream {
+                    key,
+                    group,
+                    consumer,
+                } if workers > 1 => Source::Stream {
+                    key: key.clone(),
+                    group: group.clone(),
+                    consumer: format!("{}-{}", consumer, index),
+                },
+                source => source.clone(),
+            };
+
+            thread::spawn(move || worker.run(&client, &source))
+        })
+        .collect();
+
+    for thread in threads {
+        if thread.join().is_err() {
+            warn!("Redis worker panicked");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,328 @@
+//! Lambda custom runtime serving classifications, polling the Runtime API
+//! itself rather than through `lambda_http`, so that it can be the only
+//! binary of a `FROM scratch` container image or a `provided.al2` function.
+//!
+//! Events of API Gateway, in either payload format, and of function URLs
+//! are handled as HTTP requests, and replied to with the HTTP response.
+//! Any other event is taken for the JSON body of a classification request,
+//! for functions invoked directly.
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tf_serve::{
+    ClassifierOptions, HttpRequest, HttpResponse, HttpServer, ImageClassifier, ServerOptions,
+};
+
+/// Time kept to send the response before the deadline of an invocation
+const RESPONSE_MARGIN: Duration = Duration::from_millis(200);
+
+const API_VERSION: &str = "2018-06-01";
+
+/// HTTP event of API Gateway or a function URL
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProxyRequest {
+    /// Method of the REST API payload format
+    http_method: Option<String>,
+    request_context: Option<RequestContext>,
+
+    path: Option<String>,
+    raw_path: Option<String>,
+
+    query_string_parameters: Option<HashMap<String, String>>,
+    raw_query_string: Option<String>,
+
+    headers: Option<HashMap<String, String>>,
+
+    body: Option<String>,
+
+    #[serde(default)]
+    is_base64_encoded: bool,
+}
+
+#[derive(Deserialize)]
+struct RequestContext {
+    http: Option<HttpContext>,
+}
+
+/// Method of the HTTP API payload format
+#[derive(Deserialize)]
+struct HttpContext {
+    method: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProxyResponse {
+    status_code: u16,
+    headers: HashMap<String, String>,
+    body: String,
+    is_base64_encoded: bool,
+}
+
+/// Failure reported to the Runtime API
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorRequest {
+    error_message: String,
+    error_type: String,
+}
+
+impl ProxyRequest {
+    fn into_request(self) -> Result<HttpRequest, String> {
+        let method = match (
+            self.http_method,
+            self.request_context.and_then(|ctx| ctx.http),
+        ) {
+            (Some(method), _) => method,
+            (None, Some(http)) => http.method,
+            (None, None) => return Err("Not an HTTP event".to_owned()),
+        };
+
+        let mut url = self
+            .raw_path
+            .or(self.path)
+            .unwrap_or_else(|| "/".to_owned());
+        let query = match (self.raw_query_string, self.query_string_parameters) {
+            (Some(query), _) => query,
+            (None, Some(params)) => params
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("&"),
+            (None, None) => String::new(),
+        };
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+
+        let body = match self.body {
+            Some(body) if self.is_base64_encoded => {
+                base64::decode(&body).map_err(|_| "Invalid base64 body".to_owned())?
+            }
+            Some(body) => body.into_bytes(),
+            None => vec![],
+        };
+
+        Ok(HttpRequest::new(
+            &method,
+            &url,
+            self.headers.unwrap_or_default().into_iter().collect(),
+            body,
+        ))
+    }
+}
+
+impl From<HttpResponse> for ProxyResponse {
+    fn from(response: HttpResponse) -> Self {
+        ProxyResponse {
+            status_code: response.status,
+            headers: response.headers.into_iter().collect(),
+            body: String::from_utf8_lossy(&response.body).into_owned(),
+            is_base64_encoded: false,
+        }
+    }
+}
+
+/// Client of the Runtime API
+struct Runtime {
+    client: reqwest::Client,
+    endpoint: String,
+}
+
+/// Invocation of the function
+struct Invocation {
+    request_id: String,
+    deadline: Instant,
+    event: Vec<u8>,
+}
+
+impl Runtime {
+    fn from_env() -> Result<Self, Box<dyn Error>> {
+        let api = env::var("AWS_LAMBDA_RUNTIME_API")
+            .map_err(|_| "AWS_LAMBDA_RUNTIME_API is not set, not running on Lambda")?;
+
+        Ok(Runtime {
+            // Waiting for the next invocation blocks for as long as it takes
+            client: reqwest::Client::builder().timeout(None).build()?,
+            endpoint: format!("http://{}/{}/runtime", api, API_VERSION),
+        })
+    }
+
+    /// Wait for the next invocation
+    fn next(&self) -> Result<Invocation, Box<dyn Error>> {
+        let mut resp = self
+            .client
+            .get(&format!("{}/invocation/next", self.endpoint))
+            .send()?
+            .error_for_status()?;
+
+        let header = |name: &str| {
+            resp.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+
+        let request_id =
+            header("lambda-runtime-aws-request-id").ok_or("Invocation without a request ID")?;
+        let deadline_ms: u64 = header("lambda-runtime-deadline-ms")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0);
+
+        // Picked up by the tracing of requests to other services
+        match header("lambda-runtime-trace-id") {
+            Some(trace_id) => env::set_var("_X_AMZN_TRACE_ID", trace_id),
+            None => env::remove_var("_X_AMZN_TRACE_ID"),
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let remaining = Duration::from_millis(deadline_ms.saturating_sub(now))
+            .checked_sub(RESPONSE_MARGIN)
+            .unwrap_or_default();
+
+        let mut event = vec![];
+        resp.read_to_end(&mut event)?;
+
+        Ok(Invocation {
+            request_id,
+            deadline: Instant::now() + remaining,
+            event,
+        })
+    }
+
+    fn respond(&self, request_id: &str, response: &Value) -> reqwest::Result<()> {
+        self.client
+            .post(&format!(
+                "{}/invocation/{}/response",
+                self.endpoint, request_id
+            ))
+            .json(response)
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Report a failure of an invocation, or of the initialization of the
+    /// function without a request ID
+    fn fail(&self, request_id: Option<&str>, err: &dyn Error) -> reqwest::Result<()> {
+        let url = match request_id {
+            Some(request_id) => format!("{}/invocation/{}/error", self.endpoint, request_id),
+            None => format!("{}/init/error", self.endpoint),
+        };
+
+        self.client
+            .post(&url)
+            .header("Lambda-Runtime-Function-Error-Type", "Runtime.Error")
+            .json(&ErrorRequest {
+                error_message: err.to_string(),
+                error_type: "Runtime.Error".to_owned(),
+            })
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+/// Response to the event of an invocation
+fn handle(server: &HttpServer, invocation: &Invocation) -> Result<Value, Box<dyn Error>> {
+    let event: Value = serde_json::from_slice(&invocation.event)?;
+    let deadline = Some(invocation.deadline);
+
+    let http = serde_json::from_value::<ProxyRequest>(event.clone())
+        .map_err(|err| err.to_string())
+        .and_then(ProxyRequest::into_request);
+
+    match http {
+        Ok(request) => {
+            let response = server.handle_with_deadline(&request, deadline);
+            Ok(serde_json::to_value(ProxyResponse::from(response))?)
+        }
+        Err(_) => {
+            let request = HttpRequest::new(
+                "POST",
+                "/",
+                vec![("content-type".to_owned(), "application/json".to_owned())],
+                invocation.event.clone(),
+            );
+            let response = server.handle_with_deadline(&request, deadline);
+
+            Ok(serde_json::from_slice(&response.body)?)
+        }
+    }
+}
+
+/// Classifier from the environment
+fn load_server() -> Result<HttpServer, Box<dyn Error>> {
+    let task_root = env::var("LAMBDA_TASK_ROOT").unwrap_or_else(|_| ".".to_owned());
+    let export_dir = env::var("TF_MODEL_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(task_root).join("model"));
+    let tags_path = env::var("TF_MODEL_LABELS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| export_dir.join("labels.txt"));
+
+    let options = ServerOptions {
+        // Lambda runs a single invocation at a time
+        max_in_flight: 1,
+        max_queued: 0,
+        ..ServerOptions::from_env()
+    };
+
+    let classifier =
+        ImageClassifier::with_options(&export_dir, &tags_path, &ClassifierOptions::from_env())?;
+
+    let mut server = HttpServer::new(Arc::new(classifier), options);
+    server.load_models_from_env()?;
+    server.warm_up()?;
+
+    Ok(server)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    let runtime = Runtime::from_env()?;
+
+    let mut init = tf_serve::Timer::new_start("Loading model");
+    let server = match load_server() {
+        Ok(server) => server,
+        Err(err) => {
+            runtime.fail(None, &*err)?;
+            return Err(err);
+        }
+    };
+    init.stop();
+
+    info!("Polling {}", runtime.endpoint);
+
+    loop {
+        let invocation = runtime.next()?;
+
+        let result = match handle(&server, &invocation) {
+            Ok(response) => runtime.respond(&invocation.request_id, &response),
+            Err(err) => runtime.fail(Some(&invocation.request_id), &*err),
+        };
+
+        if let Err(err) = result {
+            warn!(
+                "Could not reply to invocation {}: {}",
+                invocation.request_id, err
+            );
+        }
+    }
+}
@@ -1,40 +1,1422 @@
 use std::error::Error;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use indicatif::ProgressBar;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
 use structopt::StructOpt;
-use tf_serve::ImageClassifier;
+use tf_serve::wire::WireOptions;
+use tf_serve::{
+    inspect_model, wire, CalibrationOptions, Classification, ClassifierOptions, ClassifyOptions,
+    ConcurrencyLimit, Device, ErrorBody, FrameSelection, HttpOptions, HttpServer, ImageClassifier,
+    Metrics, PostProcessing, Region, ResultRecord, ResultSink, ServerOptions, Storage,
+};
 
 extern crate serde_json;
 
-use log::info;
+use log::{info, warn};
 
 #[derive(StructOpt, Debug)]
 #[structopt(
     name = "tf-classify",
     about = "CLI app to perform image classification with TensorFlow"
 )]
-struct CmdArgs {
+enum CmdArgs {
+    #[structopt(about = "Classify an image, the command run when none is given")]
+    Classify {
+        #[structopt(flatten)]
+        model: ModelArgs,
+
+        #[structopt(
+            help = "URL to fetch image from, path of a local image, or - to read it from stdin"
+        )]
+        image: ImageInput,
+
+        #[structopt(flatten)]
+        classify: ClassifyArgs,
+
+        #[structopt(
+            long,
+            default_value = "json",
+            help = "Format of the result (json, json-pretty, csv, table)"
+        )]
+        output: OutputFormat,
+    },
+
+    #[structopt(about = "Classify every image of a directory")]
+    Batch {
+        #[structopt(flatten)]
+        model: ModelArgs,
+
+        #[structopt(help = "Directory of the images")]
+        dir: PathBuf,
+
+        #[structopt(flatten)]
+        batch: BatchArgs,
+
+        #[structopt(flatten)]
+        classify: ClassifyArgs,
+    },
+
+    #[structopt(about = "Classify the images added to a directory as they appear")]
+    Watch {
+        #[structopt(flatten)]
+        model: ModelArgs,
+
+        #[structopt(help = "Directory to watch")]
+        dir: PathBuf,
+
+        #[structopt(short, long, help = "Also watch the subdirectories")]
+        recursive: bool,
+
+        #[structopt(
+            long,
+            help = "Only classify files with names matching this glob, like '*.jpg'"
+        )]
+        pattern: Option<glob::Pattern>,
+
+        #[structopt(
+            long,
+            default_value = "1000",
+            help = "Time to let a new file settle before classifying it, in milliseconds"
+        )]
+        delay_ms: u64,
+
+        #[structopt(flatten)]
+        classify: ClassifyArgs,
+    },
+
+    #[structopt(about = "Measure the latency of every stage of classifying an image")]
+    Bench {
+        #[structopt(flatten)]
+        model: ModelArgs,
+
+        #[structopt(long, default_value = "100", help = "Number of timed classifications")]
+        iterations: usize,
+
+        #[structopt(
+            long,
+            default_value = "10",
+            help = "Number of classifications before the timed ones"
+        )]
+        warmup: usize,
+
+        #[structopt(
+            help = "URL to fetch image from, path of a local image, or - to read it from stdin"
+        )]
+        image: ImageInput,
+
+        #[structopt(flatten)]
+        classify: ClassifyArgs,
+    },
+
+    #[structopt(about = "List the tag sets and signatures of a SavedModel")]
+    Inspect {
+        #[structopt(help = "Export directory of TensorFlow SavedModel")]
+        export_dir: PathBuf,
+
+        #[structopt(long, help = "Print the signatures as JSON")]
+        json: bool,
+    },
+
+    #[structopt(about = "Compare the classifications of two models on the same images")]
+    Compare {
+        #[structopt(flatten)]
+        model: ModelArgs,
+
+        #[structopt(
+            long,
+            help = "Export directory of the second SavedModel, loaded with the same options"
+        )]
+        model_b: PathBuf,
+
+        #[structopt(
+            long,
+            help = "Tags of the second model [default: labels.txt of its directory]"
+        )]
+        tags_b: Option<PathBuf>,
+
+        #[structopt(
+            required = true,
+            help = "URLs to fetch images from, paths of local images, or - to read one from stdin"
+        )]
+        images: Vec<ImageInput>,
+
+        #[structopt(flatten)]
+        classify: ClassifyArgs,
+    },
+
+    #[structopt(about = "Compare local classifications against a remote server")]
+    Shadow {
+        #[structopt(flatten)]
+        model: ModelArgs,
+
+        #[structopt(long, help = "Classification endpoint of the remote server")]
+        remote: String,
+
+        #[structopt(
+            long,
+            default_value = "0.01",
+            help = "Maximum tolerated probability difference"
+        )]
+        tolerance: f32,
+
+        #[structopt(required = true, help = "URLs to fetch images from")]
+        image_urls: Vec<String>,
+    },
+
+    #[structopt(about = "Serve classifications over the binary wire protocol")]
+    ServeWire {
+        #[structopt(flatten)]
+        model: ModelArgs,
+
+        #[structopt(
+            long,
+            default_value = "0.0.0.0:7000",
+            help = "Address to listen on: TCP host:port, unix:<path> or vsock:<port>"
+        )]
+        listen: Listen,
+
+        #[structopt(
+            long,
+            default_value = "1",
+            help = "Most classifications to run at the same time"
+        )]
+        max_in_flight: usize,
+
+        #[structopt(
+            long,
+            default_value = "32",
+            help = "Most classifications to queue before refusing new ones"
+        )]
+        max_queued: usize,
+
+        #[structopt(
+            long,
+            default_value = "6291456",
+            help = "Largest request frame accepted, in bytes"
+        )]
+        max_frame_size: usize,
+
+        #[structopt(long, default_value = "64", help = "Most connections open at once")]
+        max_connections: usize,
+
+        #[structopt(
+            long,
+            requires = "tls-key",
+            help = "PEM certificate chain to serve TLS with"
+        )]
+        tls_cert: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            requires = "tls-cert",
+            help = "PEM private key of the certificate"
+        )]
+        tls_key: Option<PathBuf>,
+    },
+
+    #[structopt(about = "Serve classifications over HTTP, as the OpenFaaS function does")]
+    Serve {
+        #[structopt(flatten)]
+        model: ModelArgs,
+
+        #[structopt(long, default_value = "127.0.0.1", help = "Address to listen on")]
+        host: String,
+
+        #[structopt(long, default_value = "8080", help = "Port to listen on")]
+        port: u16,
+
+        #[structopt(
+            long,
+            default_value = "1",
+            help = "Most classifications to run at the same time"
+        )]
+        max_in_flight: usize,
+
+        #[structopt(
+            long,
+            default_value = "32",
+            help = "Most classifications to queue before refusing new ones"
+        )]
+        max_queued: usize,
+
+        #[structopt(long, help = "Longest time to spend on a request, in milliseconds")]
+        timeout_ms: Option<u64>,
+
+        #[structopt(
+            long,
+            requires = "tls-key",
+            help = "PEM certificate chain to serve HTTPS with"
+        )]
+        tls_cert: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            requires = "tls-cert",
+            help = "PEM private key of the certificate"
+        )]
+        tls_key: Option<PathBuf>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+struct ModelArgs {
     #[structopt(help = "Export directory of TensorFlow SavedModel")]
     export_dir: String,
 
     #[structopt(help = "Path to tags translation file")]
     tags_path: String,
 
-    #[structopt(help = "URL to fetch image from")]
-    image_url: String,
+    #[structopt(
+        long,
+        default_value = "default",
+        help = "Device to run inference on (default, cpu, gpu:<index>)"
+    )]
+    device: Device,
+
+    #[structopt(
+        long,
+        default_value = "auto",
+        help = "Post-processing of the model output (none, softmax, sigmoid, auto)"
+    )]
+    post_processing: PostProcessing,
+
+    #[structopt(
+        long,
+        help = "Treat the model as multi-label, reporting every class above this probability"
+    )]
+    multi_label: Option<f32>,
+
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Class index of the first label, for models with a background class"
+    )]
+    label_offset: usize,
+
+    #[structopt(long, help = "User-Agent of image fetches")]
+    user_agent: Option<String>,
+
+    #[structopt(
+        long = "header",
+        parse(try_from_str = parse_header),
+        number_of_values = 1,
+        help = "Additional header of image fetches, as 'Name: value'"
+    )]
+    headers: Vec<(String, String)>,
+
+    #[structopt(long, help = "Connect timeout of image fetches, in milliseconds")]
+    connect_timeout_ms: Option<u64>,
+
+    #[structopt(long, help = "Timeout of image fetches, in milliseconds")]
+    timeout_ms: Option<u64>,
+
+    #[structopt(long, help = "Largest accepted image download, in bytes")]
+    max_download_size: Option<usize>,
+
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Number of retries of failed image fetches"
+    )]
+    retries: u32,
+
+    #[structopt(long, help = "Refuse to fetch images from internal addresses")]
+    block_private_addresses: bool,
+
+    #[structopt(
+        long = "allow-host",
+        number_of_values = 1,
+        help = "Only fetch images from this host and its subdomains"
+    )]
+    allowed_hosts: Vec<String>,
+
+    #[structopt(
+        long = "deny-host",
+        number_of_values = 1,
+        help = "Never fetch images from this host and its subdomains"
+    )]
+    denied_hosts: Vec<String>,
+
+    #[structopt(
+        long = "translation",
+        parse(try_from_str = parse_translation),
+        number_of_values = 1,
+        help = "Labels file of another language, as 'lang=path'"
+    )]
+    translations: Vec<(String, String)>,
+
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Fraction of classifications to store as calibration samples"
+    )]
+    calibration_rate: f32,
+
+    #[structopt(
+        long,
+        default_value = "calibration",
+        help = "Directory or s3://bucket/prefix to store calibration samples in"
+    )]
+    calibration_location: String,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
-    let args = CmdArgs::from_args();
+/// Parse a 'Name: value' HTTP header
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    match s.find(':') {
+        Some(pos) => Ok((s[..pos].trim().to_owned(), s[pos + 1..].trim().to_owned())),
+        None => Err(format!("Invalid header '{}', expected 'Name: value'", s)),
+    }
+}
+
+/// Parse a 'lang=path' labels translation
+fn parse_translation(s: &str) -> Result<(String, String), String> {
+    match s.find('=') {
+        Some(pos) => Ok((s[..pos].to_owned(), s[pos + 1..].to_owned())),
+        None => Err(format!("Invalid translation '{}', expected 'lang=path'", s)),
+    }
+}
+
+impl ModelArgs {
+    fn load(&self) -> Result<ImageClassifier, Box<dyn Error>> {
+        self.load_with(&self.options())
+    }
+
+    fn load_with(&self, options: &ClassifierOptions) -> Result<ImageClassifier, Box<dyn Error>> {
+        let export_dir = PathBuf::from(&self.export_dir);
+        let tags_path = PathBuf::from(&self.tags_path);
+
+        Ok(ImageClassifier::with_options(
+            &export_dir,
+            &tags_path,
+            options,
+        )?)
+    }
+
+    /// Options of the models loaded from these arguments
+    fn options(&self) -> ClassifierOptions {
+        ClassifierOptions {
+            device: self.device,
+            post_processing: self.post_processing,
+            multi_label: self.multi_label,
+            label_offset: self.label_offset,
+            translations: self.translations.clone(),
+            calibration: Some(CalibrationOptions {
+                rate: self.calibration_rate,
+                location: self.calibration_location.clone(),
+            })
+            .filter(|calibration| calibration.rate > 0.0),
+            http: HttpOptions {
+                user_agent: self.user_agent.clone(),
+                headers: self.headers.clone(),
+                connect_timeout: self.connect_timeout_ms.map(Duration::from_millis),
+                timeout: self.timeout_ms.map(Duration::from_millis),
+                max_size: self.max_download_size,
+                retries: self.retries,
+                block_private: self.block_private_addresses,
+                allowed_hosts: self.allowed_hosts.clone(),
+                denied_hosts: self.denied_hosts.clone(),
+                ..Default::default()
+            },
+            local_images: true,
+            ..Default::default()
+        }
+    }
+
+    /// Options of the models of servers, whose clients must not reach the
+    /// files of the host: images are fetched as by the environment, see
+    /// `HttpOptions::from_env`, and these arguments
+    fn server_options(&self) -> ClassifierOptions {
+        let env = HttpOptions::from_env();
+        let options = self.options();
+
+        ClassifierOptions {
+            http: HttpOptions {
+                timeout: options.http.timeout.or(env.timeout),
+                max_size: options.http.max_size.or(env.max_size),
+                block_private: options.http.block_private || env.block_private,
+                buckets: env.buckets,
+                ..options.http
+            },
+            local_images: false,
+            ..options
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct ClassifyArgs {
+    #[structopt(long, default_value = "1", help = "Number of predictions to report")]
+    top_k: usize,
+
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Minimum probability of a reported prediction"
+    )]
+    min_probability: f32,
+
+    #[structopt(long, help = "Report the raw model output of every prediction")]
+    logits: bool,
+
+    #[structopt(
+        long,
+        help = "Report the result as ambiguous when the two best probabilities are within this margin"
+    )]
+    ambiguity_margin: Option<f32>,
+
+    #[structopt(long, help = "Language of the reported tags")]
+    lang: Option<String>,
+
+    #[structopt(
+        long,
+        default_value = "first",
+        help = "Frames of animated images to classify: first, all, or an index"
+    )]
+    frames: FrameSelection,
+
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Average the probabilities of this many crops and flips of the image"
+    )]
+    tta: usize,
+
+    #[structopt(
+        long,
+        help = "Classify only this region of the image: x,y,width,height"
+    )]
+    roi: Option<Region>,
+}
+
+impl ClassifyArgs {
+    fn options(&self) -> ClassifyOptions {
+        ClassifyOptions {
+            top_k: self.top_k,
+            min_probability: self.min_probability,
+            logits: self.logits,
+            ambiguity_margin: self.ambiguity_margin,
+            lang: self.lang.clone(),
+            frames: self.frames,
+            tta: self.tta,
+            roi: self.roi,
+            ..Default::default()
+        }
+    }
+}
 
-    let export_dir = PathBuf::from(args.export_dir);
-    let tags_path = PathBuf::from(args.tags_path);
+/// Image to classify
+#[derive(Debug)]
+enum ImageInput {
+    Url(String),
+    Path(PathBuf),
+    Stdin,
+}
+
+impl FromStr for ImageInput {
+    type Err = String;
+
+    /// `-` for stdin, URLs of any scheme the storage backends handle, and
+    /// local paths otherwise
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(if s == "-" {
+            ImageInput::Stdin
+        } else if s.contains("://") || s.starts_with("data:") {
+            ImageInput::Url(s.to_owned())
+        } else {
+            ImageInput::Path(PathBuf::from(s))
+        })
+    }
+}
 
-    let classifier = ImageClassifier::new(&export_dir, &tags_path)?;
+impl ImageInput {
+    /// Encoded image, from `storage` if at a URL
+    fn read(&self, storage: &dyn Storage) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut data = vec![];
 
-    let classification = classifier.classify_from_url(&args.image_url)?;
+        match self {
+            ImageInput::Url(url) => {
+                data = storage.read(url)?;
+            }
+            ImageInput::Path(path) => {
+                data = fs::read(path)
+                    .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+            }
+            ImageInput::Stdin => {
+                io::stdin().read_to_end(&mut data)?;
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+/// Format of printed classifications
+#[derive(Clone, Copy, Debug)]
+enum OutputFormat {
+    Json,
+    JsonPretty,
+    Csv,
+    Table,
+}
 
-    info!("{}", serde_json::to_string(&classification).unwrap());
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "json-pretty" => Ok(OutputFormat::JsonPretty),
+            "csv" => Ok(OutputFormat::Csv),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(format!("Invalid output format '{}'", s)),
+        }
+    }
+}
+
+/// Print a classification to stdout, its predictions one per row in the
+/// tabular formats
+fn print_classification(
+    classification: &Classification,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string(classification)?),
+        OutputFormat::JsonPretty => println!("{}", serde_json::to_string_pretty(classification)?),
+        OutputFormat::Csv => {
+            println!("rank,index,tag,probability");
+            for (rank, prediction) in classification.predictions().iter().enumerate() {
+                println!(
+                    "{},{},{},{}",
+                    rank + 1,
+                    prediction.index(),
+                    csv_field(prediction.tag()),
+                    prediction.probability()
+                );
+            }
+        }
+        OutputFormat::Table => {
+            println!("{:<6} {:<8} {:<12} TAG", "RANK", "INDEX", "PROBABILITY");
+            for (rank, prediction) in classification.predictions().iter().enumerate() {
+                println!(
+                    "{:<6} {:<8} {:<12.4} {}",
+                    rank + 1,
+                    prediction.index(),
+                    prediction.probability(),
+                    prediction.tag()
+                );
+            }
+        }
+    }
 
     Ok(())
 }
+
+fn classify(
+    model: &ModelArgs,
+    image: &ImageInput,
+    args: &ClassifyArgs,
+    output: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let classifier = model.load()?;
+    let options = args.options();
+
+    // Fetched through the storage backends of the classifier
+    let classification = match image {
+        ImageInput::Url(url) => classifier.classify_from_url_with_options(url, &options)?,
+        _ => classifier
+            .classify_from_raw_with_options(&image.read(classifier.storage())?, &options)?,
+    };
+
+    print_classification(&classification, output)
+}
+
+/// Classify the files created in or moved into `dir`, printing JSON Lines.
+/// Files should be complete within `delay` of their creation, or be written
+/// elsewhere and moved in.
+fn watch(
+    model: &ModelArgs,
+    dir: &Path,
+    recursive: bool,
+    pattern: Option<&glob::Pattern>,
+    delay: Duration,
+    args: &ClassifyArgs,
+) -> Result<(), Box<dyn Error>> {
+    let classifier = model.load()?;
+    let options = args.options();
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::watcher(sender, delay)?;
+    let mode = if recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    watcher.watch(dir, mode)?;
+    info!("Watching {}", dir.display());
+
+    for event in receiver {
+        let path = match event {
+            DebouncedEvent::Create(path) | DebouncedEvent::Rename(_, path) => path,
+            DebouncedEvent::Error(err, _) => return Err(err.into()),
+            _ => continue,
+        };
+
+        if !path.is_file() || !name_matches(pattern, &path) {
+            continue;
+        }
+
+        let result = fs::read(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|data| {
+                classifier
+                    .classify_from_raw_with_options(&data, &options)
+                    .map_err(|err| err.to_string())
+            });
+
+        println!("{}", json_line(&path, &result));
+    }
+
+    Ok(())
+}
+
+fn bench(
+    model: &ModelArgs,
+    image: &ImageInput,
+    iterations: usize,
+    warmup: usize,
+    args: &ClassifyArgs,
+) -> Result<(), Box<dyn Error>> {
+    let classifier = model.load()?;
+    let options = args.options();
+    let data = image.read(classifier.storage())?;
+
+    for _ in 0..warmup {
+        classifier.classify_from_raw_with_options(&data, &options)?;
+    }
+
+    let metrics = Metrics::new();
+    let start = Instant::now();
+
+    for _ in 0..iterations {
+        let t = Instant::now();
+        let classification = classifier.classify_from_raw_with_options(&data, &options)?;
+        metrics.record("total", t.elapsed().as_secs_f64() * 1000.0);
+
+        for (stage, duration) in classification.stages().to_vec() {
+            metrics.record(&stage, duration);
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+
+    println!(
+        "{:<20} {:>6} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "stage", "count", "min_ms", "mean_ms", "p50_ms", "p95_ms", "p99_ms", "max_ms"
+    );
+    for (stage, stats) in metrics.snapshot() {
+        println!(
+            "{:<20} {:>6} {:>10.3} {:>10.3} {:>10.3} {:>10.3} {:>10.3} {:>10.3}",
+            stage,
+            stats.count,
+            stats.min_ms,
+            stats.mean_ms,
+            stats.p50_ms,
+            stats.p95_ms,
+            stats.p99_ms,
+            stats.max_ms
+        );
+    }
+
+    if elapsed > 0.0 {
+        println!("{:.2} images/s", iterations as f64 / elapsed);
+    }
+
+    Ok(())
+}
+
+#[derive(StructOpt, Debug)]
+struct BatchArgs {
+    #[structopt(short, long, help = "Also classify the images of subdirectories")]
+    recursive: bool,
+
+    #[structopt(
+        long,
+        help = "Only classify files with names matching this glob, like '*.jpg'"
+    )]
+    pattern: Option<glob::Pattern>,
+
+    #[structopt(
+        long,
+        default_value = "16",
+        help = "Images classified in a single session run"
+    )]
+    batch_size: usize,
+
+    #[structopt(
+        short = "j",
+        long,
+        default_value = "1",
+        help = "Batches classified at the same time"
+    )]
+    concurrency: usize,
+
+    #[structopt(
+        long,
+        default_value = "jsonl",
+        help = "Format of the results (jsonl, csv)"
+    )]
+    format: BatchFormat,
+
+    #[structopt(short, long, help = "File to write the results to, instead of stdout")]
+    output: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Also write results to this sink: - for stdout, s3://bucket/prefix or a postgres:// URL"
+    )]
+    sink: Option<String>,
+}
+
+/// Format of the results of a batch
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BatchFormat {
+    JsonLines,
+    Csv,
+}
+
+impl FromStr for BatchFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(BatchFormat::JsonLines),
+            "csv" => Ok(BatchFormat::Csv),
+            _ => Err(format!("Invalid format '{}'", s)),
+        }
+    }
+}
+
+/// Whether the file name of `path` matches `pattern`, if any
+fn name_matches(pattern: Option<&glob::Pattern>, path: &Path) -> bool {
+    pattern.map_or(true, |pattern| {
+        path.file_name()
+            .map_or(false, |name| pattern.matches(&name.to_string_lossy()))
+    })
+}
+
+/// Write the result of a file to a sink, with its path as ID
+fn write_result(sink: &dyn ResultSink, path: &str, result: &Result<Classification, String>) {
+    let error = result
+        .as_ref()
+        .err()
+        .map(|err| ErrorBody::new("unknown", err).error);
+    let record = ResultRecord {
+        id: path,
+        request_id: None,
+        source: Some(path),
+        classification: result.as_ref().ok(),
+        error: error.as_ref(),
+    };
+
+    if let Err(err) = sink.write(&record) {
+        warn!("Could not write the result of {}: {}", path, err);
+    }
+}
+
+/// JSON Lines record of the classification of a file
+fn json_line(path: &Path, result: &Result<Classification, String>) -> serde_json::Value {
+    let path = path.display().to_string();
+
+    match result {
+        Ok(classification) => serde_json::json!({ "path": path, "classification": classification }),
+        Err(err) => serde_json::json!({ "path": path, "error": err }),
+    }
+}
+
+/// Add the files under `dir` with names matching `pattern` to `images`
+fn find_images(
+    dir: &Path,
+    recursive: bool,
+    pattern: Option<&glob::Pattern>,
+    images: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        // Links to directories are not followed, as they may loop
+        if entry.file_type()?.is_dir() {
+            if recursive {
+                find_images(&path, recursive, pattern, images)?;
+            }
+        } else if !path.is_dir() && name_matches(pattern, &path) {
+            images.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Read and classify files with a single session run. Errors are strings,
+/// as `Status` does not cross threads.
+fn classify_files(
+    classifier: &ImageClassifier,
+    paths: &[PathBuf],
+    options: &ClassifyOptions,
+) -> Vec<(PathBuf, Result<Classification, String>)> {
+    let read: Vec<_> = paths
+        .iter()
+        .map(|path| fs::read(path).map_err(|err| err.to_string()))
+        .collect();
+
+    let images: Vec<&[u8]> = read
+        .iter()
+        .filter_map(|data| data.as_deref().ok())
+        .collect();
+    let mut classified = classifier
+        .classify_batch_from_raw(&images, options)
+        .into_iter();
+
+    paths
+        .iter()
+        .cloned()
+        .zip(read.iter().map(|data| {
+            match data {
+                Ok(_) => classified
+                    .next()
+                    .expect("Missing classification")
+                    .map_err(|err| err.to_string()),
+                Err(err) => Err(err.clone()),
+            }
+        }))
+        .collect()
+}
+
+/// Quote a CSV field if needed
+fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn batch(
+    model: &ModelArgs,
+    dir: &Path,
+    args: &BatchArgs,
+    classify: &ClassifyArgs,
+) -> Result<(), Box<dyn Error>> {
+    let sink = args
+        .sink
+        .as_deref()
+        .map(tf_serve::result_sink)
+        .transpose()?;
+
+    let mut images = vec![];
+    find_images(dir, args.recursive, args.pattern.as_ref(), &mut images)?;
+    images.sort();
+
+    let classifier = Arc::new(model.load()?);
+    let options = Arc::new(classify.options());
+    let batches: Arc<Vec<Vec<PathBuf>>> = Arc::new(
+        images
+            .chunks(args.batch_size.max(1))
+            .map(<[PathBuf]>::to_vec)
+            .collect(),
+    );
+    let next = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+
+    let workers: Vec<_> = (0..args.concurrency.max(1))
+        .map(|_| {
+            let classifier = classifier.clone();
+            let options = options.clone();
+            let batches = batches.clone();
+            let next = next.clone();
+            let sender = sender.clone();
+
+            thread::spawn(move || {
+                while let Some(batch) = batches.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    for result in classify_files(&classifier, batch, &options) {
+                        if sender.send(result).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout()),
+    };
+    if args.format == BatchFormat::Csv {
+        writeln!(out, "path,tag,probability,error")?;
+    }
+
+    let progress = ProgressBar::new(images.len() as u64);
+    let mut failures = 0;
+
+    for (path, result) in receiver {
+        if result.is_err() {
+            failures += 1;
+        }
+
+        let name = path.display().to_string();
+        if let Some(sink) = &sink {
+            write_result(sink.as_ref(), &name, &result);
+        }
+
+        match (args.format, &result) {
+            (BatchFormat::JsonLines, _) => writeln!(out, "{}", json_line(&path, &result))?,
+            (BatchFormat::Csv, Ok(classification)) => writeln!(
+                out,
+                "{},{},{},",
+                csv_field(&name),
+                csv_field(classification.tag()),
+                classification.probability()
+            )?,
+            (BatchFormat::Csv, Err(err)) => {
+                writeln!(out, "{},,,{}", csv_field(&name), csv_field(err))?
+            }
+        }
+
+        progress.inc(1);
+    }
+
+    progress.finish_and_clear();
+    out.flush()?;
+
+    for worker in workers {
+        worker
+            .join()
+            .map_err(|_| "Classification thread panicked")?;
+    }
+
+    eprintln!("Classified {} images, {} failed", images.len(), failures);
+
+    Ok(())
+}
+
+fn inspect(export_dir: &Path, json: bool) -> Result<(), Box<dyn Error>> {
+    let info = inspect_model(export_dir)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+
+    for meta_graph in &info.meta_graphs {
+        println!("Tag set: {}", meta_graph.tags.join(", "));
+
+        for signature in &meta_graph.signatures {
+            println!(
+                "  Signature '{}' ({})",
+                signature.name, signature.method_name
+            );
+
+            let tensors = signature
+                .inputs
+                .iter()
+                .map(|tensor| ("input", tensor))
+                .chain(signature.outputs.iter().map(|tensor| ("output", tensor)));
+            for (kind, tensor) in tensors {
+                let shape = match &tensor.shape {
+                    Some(dims) => format!(
+                        "[{}]",
+                        dims.iter()
+                            .map(|dim| dim.map_or("?".to_owned(), |dim| dim.to_string()))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                    None => "unknown".to_owned(),
+                };
+
+                println!(
+                    "    {:<6} '{}': {} {} {}",
+                    kind, tensor.key, tensor.name, tensor.dtype, shape
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Classify an image, also returning the time it took in milliseconds
+fn timed_classify(
+    classifier: &ImageClassifier,
+    data: &[u8],
+    options: &ClassifyOptions,
+) -> Result<(Classification, f64), Box<dyn Error>> {
+    let t = Instant::now();
+    let classification = classifier.classify_from_raw_with_options(data, options)?;
+
+    Ok((classification, t.elapsed().as_secs_f64() * 1000.0))
+}
+
+fn compare(
+    model: &ModelArgs,
+    (model_b, tags_b): (&Path, Option<&Path>),
+    images: &[ImageInput],
+    args: &ClassifyArgs,
+) -> Result<(), Box<dyn Error>> {
+    let a = model.load()?;
+    let tags_b = tags_b.map_or_else(|| model_b.join("labels.txt"), Path::to_path_buf);
+    let b = ImageClassifier::with_options(model_b, &tags_b, &model.options())?;
+    let options = args.options();
+
+    let mut agreements = 0;
+    let (mut latency_a, mut latency_b) = (0.0, 0.0);
+
+    for image in images {
+        let name = match image {
+            ImageInput::Url(url) => url.clone(),
+            ImageInput::Path(path) => path.display().to_string(),
+            ImageInput::Stdin => "-".to_owned(),
+        };
+        let data = image.read(a.storage())?;
+
+        let (result_a, time_a) = timed_classify(&a, &data, &options)?;
+        let (result_b, time_b) = timed_classify(&b, &data, &options)?;
+        latency_a += time_a;
+        latency_b += time_b;
+
+        if result_a.tag() == result_b.tag() {
+            agreements += 1;
+            println!(
+                "AGREE    {}: '{}' (a {:.4}, b {:.4}, delta {:.4})",
+                name,
+                result_a.tag(),
+                result_a.probability(),
+                result_b.probability(),
+                (result_a.probability() - result_b.probability()).abs()
+            );
+        } else {
+            println!(
+                "DISAGREE {}: a '{}' ({:.4}), b '{}' ({:.4})",
+                name,
+                result_a.tag(),
+                result_a.probability(),
+                result_b.tag(),
+                result_b.probability()
+            );
+        }
+    }
+
+    let count = images.len() as f64;
+    println!(
+        "{} of {} images agreed ({:.1}%)",
+        agreements,
+        images.len(),
+        agreements as f64 * 100.0 / count
+    );
+    println!(
+        "Mean latency: a {:.2} ms, b {:.2} ms",
+        latency_a / count,
+        latency_b / count
+    );
+
+    Ok(())
+}
+
+fn shadow(
+    model: &ModelArgs,
+    remote: &str,
+    tolerance: f32,
+    image_urls: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let classifier = model.load()?;
+    let client = reqwest::Client::new();
+
+    let mut divergences = 0;
+
+    for url in image_urls {
+        let mut image: Vec<u8> = vec![];
+        client
+            .get(url.as_str())
+            .send()?
+            .error_for_status()?
+            .copy_to(&mut image)?;
+
+        let ours = classifier.classify_from_raw(&image)?;
+
+        let theirs: Classification = client
+            .post(remote)
+            .body(image)
+            .send()?
+            .error_for_status()?
+            .json()?;
+
+        let delta = (ours.probability() - theirs.probability()).abs();
+
+        if ours.tag() != theirs.tag() || delta > tolerance {
+            divergences += 1;
+            println!(
+                "DIVERGED {}: local '{}' ({:.4}), remote '{}' ({:.4})",
+                url,
+                ours.tag(),
+                ours.probability(),
+                theirs.tag(),
+                theirs.probability()
+            );
+        } else {
+            println!("OK       {}: '{}' (delta {:.4})", url, ours.tag(), delta);
+        }
+    }
+
+    println!(
+        "{} of {} images diverged from {}",
+        divergences,
+        image_urls.len(),
+        remote
+    );
+
+    if divergences > 0 {
+        return Err(format!("{} divergences", divergences).into());
+    }
+
+    Ok(())
+}
+
+/// Address to serve on
+#[derive(Debug)]
+enum Listen {
+    Tcp(String),
+    Unix(PathBuf),
+    Vsock(u32),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Ok(Listen::Unix(PathBuf::from(path)))
+        } else if let Some(port) = s.strip_prefix("vsock:") {
+            port.parse()
+                .map(Listen::Vsock)
+                .map_err(|_| format!("Invalid vsock port '{}'", port))
+        } else {
+            Ok(Listen::Tcp(s.to_owned()))
+        }
+    }
+}
+
+fn serve_wire(
+    model: &ModelArgs,
+    listen: &Listen,
+    max_in_flight: usize,
+    max_queued: usize,
+    options: WireOptions,
+    tls: Option<(&Path, &Path)>,
+) -> Result<(), Box<dyn Error>> {
+    let classifier = Arc::new(model.load()?);
+    let limit = Arc::new(ConcurrencyLimit::new(max_in_flight, max_queued));
+
+    match (listen, tls) {
+        (Listen::Tcp(addr), None) => {
+            let listener = TcpListener::bind(addr)?;
+            info!("Serving wire protocol on {}", addr);
+
+            Ok(wire::serve_tcp(classifier, limit, listener, options)?)
+        }
+        #[cfg(feature = "tls")]
+        (Listen::Tcp(addr), Some((cert, key))) => {
+            let config = wire::tls_config(cert, key)?;
+            let listener = TcpListener::bind(addr)?;
+            info!("Serving wire protocol over TLS on {}", addr);
+
+            Ok(wire::serve_tls(
+                classifier, limit, listener, config, options,
+            )?)
+        }
+        #[cfg(not(feature = "tls"))]
+        (Listen::Tcp(_), Some(_)) => Err("Cannot serve TLS: built without the tls feature".into()),
+        (Listen::Unix(path), None) => {
+            // Replace the socket of a previous run
+            if fs::symlink_metadata(path).map_or(false, |meta| meta.file_type().is_socket()) {
+                fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            info!("Serving wire protocol on {}", path.display());
+
+            Ok(wire::serve_unix(classifier, limit, listener, options)?)
+        }
+        #[cfg(feature = "vsock")]
+        (Listen::Vsock(port), None) => {
+            info!("Serving wire protocol on vsock port {}", port);
+
+            Ok(wire::serve_vsock(classifier, limit, *port, options)?)
+        }
+        #[cfg(not(feature = "vsock"))]
+        (Listen::Vsock(_), None) => {
+            Err("Cannot serve on vsock: built without the vsock feature".into())
+        }
+        (_, Some(_)) => Err("TLS is only served over TCP".into()),
+    }
+}
+
+fn serve(
+    model: &ModelArgs,
+    addr: &str,
+    options: ServerOptions,
+    tls: Option<(&Path, &Path)>,
+) -> Result<(), Box<dyn Error>> {
+    #[cfg(feature = "tls")]
+    let options = ServerOptions {
+        tls_cert: tls.map(|(cert, _)| cert.to_owned()),
+        tls_key: tls.map(|(_, key)| key.to_owned()),
+        ..options
+    };
+    #[cfg(not(feature = "tls"))]
+    {
+        if tls.is_some() {
+            return Err("Cannot serve TLS: built without the tls feature".into());
+        }
+    }
+
+    let classifier = model.load_with(&model.server_options())?;
+    let server = Arc::new(HttpServer::new(Arc::new(classifier), options));
+    server.classifier().warm_up()?;
+
+    info!("Serving on {}", addr);
+
+    Ok(server.serve(addr)?)
+}
+
+/// Arguments of the command line, with `classify` as the default command,
+/// as in `tf-classify <export_dir> <tags_path> <image>`
+fn parse_args() -> CmdArgs {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let err = match CmdArgs::from_iter_safe(&args) {
+        Ok(cmd) => return cmd,
+        Err(err) => err,
+    };
+
+    // Options, like --help, are not arguments of `classify`
+    match args.get(1) {
+        Some(arg) if !arg.to_string_lossy().starts_with('-') => {}
+        _ => err.exit(),
+    }
+
+    let mut classify = args.clone();
+    classify.insert(1, "classify".into());
+
+    match CmdArgs::from_iter_safe(&classify) {
+        Ok(cmd) => cmd,
+        // Errors of `classify` for a model, of the subcommands otherwise
+        Err(classify_err) if Path::new(&args[1]).is_dir() => classify_err.exit(),
+        Err(_) => err.exit(),
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    match parse_args() {
+        CmdArgs::Classify {
+            model,
+            image,
+            classify: args,
+            output,
+        } => classify(&model, &image, &args, output),
+        CmdArgs::Batch {
+            model,
+            dir,
+            batch: batch_args,
+            classify: args,
+        } => batch(&model, &dir, &batch_args, &args),
+        CmdArgs::Watch {
+            model,
+            dir,
+            recursive,
+            pattern,
+            delay_ms,
+            classify: args,
+        } => watch(
+            &model,
+            &dir,
+            recursive,
+            pattern.as_ref(),
+            Duration::from_millis(delay_ms),
+            &args,
+        ),
+        CmdArgs::Bench {
+            model,
+            iterations,
+            warmup,
+            image,
+            classify: args,
+        } => bench(&model, &image, iterations, warmup, &args),
+        CmdArgs::Inspect { export_dir, json } => inspect(&export_dir, json),
+        CmdArgs::Compare {
+            model,
+            model_b,
+            tags_b,
+            images,
+            classify: args,
+        } => compare(&model, (&model_b, tags_b.as_deref()), &images, &args),
+        CmdArgs::Shadow {
+            model,
+            remote,
+            tolerance,
+            image_urls,
+        } => shadow(&model, &remote, tolerance, &image_urls),
+        CmdArgs::ServeWire {
+            model,
+            listen,
+            max_in_flight,
+            max_queued,
+            max_frame_size,
+            max_connections,
+            tls_cert,
+            tls_key,
+        } => serve_wire(
+            &model,
+            &listen,
+            max_in_flight,
+            max_queued,
+            WireOptions {
+                max_frame_size,
+                max_connections,
+            },
+            tls_cert.as_deref().zip(tls_key.as_deref()),
+        ),
+        CmdArgs::Serve {
+            model,
+            host,
+            port,
+            max_in_flight,
+            max_queued,
+            timeout_ms,
+            tls_cert,
+            tls_key,
+        } => serve(
+            &model,
+            &format!("{}:{}", host, port),
+            ServerOptions {
+                timeout: timeout_ms.map(Duration::from_millis),
+                max_in_flight,
+                max_queued,
+                ..Default::default()
+            },
+            tls_cert.as_deref().zip(tls_key.as_deref()),
+        ),
+    }
+}
@@ -0,0 +1,280 @@
+//! Client of the HTTP API of the tf-serve frontends: the `HttpServer` of the
+//! OpenFaaS, Cloud Run and other functions, and the Lambda handler.
+//!
+//! A `Client` is given the classification endpoint, like
+//! `https://api.example.com/v1/classify` or
+//! `http://gateway:8080/function/tf-classify`. Batches are sent to its
+//! `/batch` route.
+
+use std::error;
+use std::fmt;
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+mod types;
+
+use types::BatchItem;
+pub use types::{
+    Classification, ClassifyOptions, ErrorBody, ErrorDetail, FrameReport, Prediction, Region,
+};
+
+/// Failure of a request
+#[derive(Debug)]
+pub enum Error {
+    /// The request could not be sent, or its response read
+    Http(reqwest::Error),
+
+    /// The server answered with an error status
+    Api { status: u16, body: ErrorBody },
+
+    /// The client is misconfigured
+    Config(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(err) => write!(f, "Request failed: {}", err),
+            Error::Api { status, body } => write!(f, "Server answered {}: {}", status, body),
+            Error::Config(message) => write!(f, "Invalid client configuration: {}", message),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Http(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Configuration of a `Client`
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    /// Timeout of every attempt of a request
+    pub timeout: Option<Duration>,
+
+    /// Retries of requests that could not connect, timed out, or were shed
+    /// by the server under load
+    pub retries: u32,
+
+    /// Delay before the first retry, doubled before every next one, unless
+    /// the server asks for another with `Retry-After`
+    pub backoff: Duration,
+
+    /// Headers of every request, like API keys
+    pub headers: Vec<(String, String)>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            timeout: Some(Duration::from_secs(30)),
+            retries: 2,
+            backoff: Duration::from_millis(200),
+            headers: vec![],
+        }
+    }
+}
+
+/// Whether a request failing with `status` may succeed when retried
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 502 | 503 | 504)
+}
+
+/// Delay before retry `attempt`, counting from 0
+fn backoff(options: &ClientOptions, attempt: u32, retry_after: Option<Duration>) -> Duration {
+    retry_after.unwrap_or_else(|| options.backoff * 2u32.saturating_pow(attempt))
+}
+
+/// Client of a classification endpoint
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    endpoint: String,
+    options: ClientOptions,
+}
+
+impl Client {
+    pub fn new(endpoint: &str) -> Result<Self> {
+        Client::with_options(endpoint, &ClientOptions::default())
+    }
+
+    pub fn with_options(endpoint: &str, options: &ClientOptions) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &options.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| Error::Config(format!("Invalid header name '{}'", name)))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| Error::Config(format!("Invalid value of header '{}'", name)))?;
+            headers.insert(name, value);
+        }
+
+        let mut builder = reqwest::Client::builder().default_headers(headers);
+        if let Some(timeout) = options.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        Ok(Client {
+            http: builder.build()?,
+            endpoint: endpoint.trim_end_matches('/').to_owned(),
+            options: options.clone(),
+        })
+    }
+
+    /// Classify an encoded image
+    pub async fn classify_bytes(
+        &self,
+        image: Vec<u8>,
+        options: &ClassifyOptions,
+    ) -> Result<Classification> {
+        let request = self
+            .http
+            .post(&self.endpoint)
+            .query(&options.query())
+            .header("content-type", "application/octet-stream")
+            .body(image);
+
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    /// Classify the image at `url`, fetched by the server
+    pub async fn classify_url(
+        &self,
+        url: &str,
+        options: &ClassifyOptions,
+    ) -> Result<Classification> {
+        let mut body = serde_json::to_value(options).expect("Options serialize to JSON");
+        body["url"] = url.into();
+
+        let request = self.http.post(&self.endpoint).json(&body);
+
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    /// Classify the images at `urls` with a single request, failing each
+    /// image on its own
+    pub async fn classify_batch(
+        &self,
+        urls: &[String],
+        options: &ClassifyOptions,
+    ) -> Result<Vec<std::result::Result<Classification, ErrorBody>>> {
+        let request = self
+            .http
+            .post(&format!("{}/batch", self.endpoint))
+            .query(&options.query())
+            .json(urls);
+
+        let items: Vec<BatchItem> = self.send(request).await?.json().await?;
+
+        Ok(items
+            .into_iter()
+            .map(|item| match item {
+                BatchItem::Classified(classification) => Ok(classification),
+                BatchItem::Failed(err) => Err(err),
+            })
+            .collect())
+    }
+
+    /// Send a request, retrying it as configured, and fail unless it
+    /// succeeds
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let mut attempt = 0;
+
+        loop {
+            let retry = attempt < self.options.retries;
+            let this_attempt = request.try_clone().expect("Requests have in-memory bodies");
+
+            let response = match this_attempt.send().await {
+                Ok(response) => response,
+                Err(err) if retry && (err.is_connect() || err.is_timeout()) => {
+                    tokio::time::sleep(backoff(&self.options, attempt, None)).await;
+                    attempt += 1;
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            let status = response.status();
+            if status.is_success() {
+                return Ok(response);
+            }
+
+            if retry && is_retryable(status) {
+                let retry_after = response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().parse().ok())
+                    .map(Duration::from_secs);
+
+                tokio::time::sleep(backoff(&self.options, attempt, retry_after)).await;
+                attempt += 1;
+                continue;
+            }
+
+            // Gateways answer with bodies of their own
+            let text = response.text().await.unwrap_or_default();
+            let body = serde_json::from_str(&text)
+                .unwrap_or_else(|_| ErrorBody::new("unknown", text.trim()));
+
+            return Err(Error::Api {
+                status: status.as_u16(),
+                body,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_and_responses() {
+        let options = ClassifyOptions {
+            top_k: Some(3),
+            roi: Some(Region {
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.query(),
+            vec![("top_k", "3".to_owned()), ("roi", "1,2,3,4".to_owned())]
+        );
+
+        let items: Vec<BatchItem> = serde_json::from_str(
+            r#"[{"tag": "cat", "probability": 0.9},
+                {"error": {"code": "unavailable", "message": "Not found"}}]"#,
+        )
+        .unwrap();
+        assert!(matches!(&items[0], BatchItem::Classified(c) if c.tag == "cat"));
+        assert!(matches!(&items[1], BatchItem::Failed(err) if err.error.code == "unavailable"));
+
+        let client_options = ClientOptions::default();
+        assert_eq!(
+            backoff(&client_options, 2, None),
+            Duration::from_millis(800)
+        );
+        assert_eq!(
+            backoff(&client_options, 2, Some(Duration::from_secs(1))),
+            Duration::from_secs(1)
+        );
+    }
+}
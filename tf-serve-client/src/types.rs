@@ -0,0 +1,210 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Prediction {
+    /// Class index in the model output
+    pub index: usize,
+
+    /// Classification tag
+    pub tag: String,
+
+    /// Classification probability
+    pub probability: f32,
+
+    /// Raw model output for the class, when requested
+    pub logit: Option<f32>,
+}
+
+/// Frames of an animated image a classification is of
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct FrameReport {
+    /// Frames in the image
+    pub count: usize,
+
+    /// How the classified frames were picked: `first`, `all` or an index
+    pub selection: String,
+
+    /// Indices of the classified frames
+    pub classified: Vec<usize>,
+}
+
+/// Result of a classification, as returned by the server
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Classification {
+    /// Class index of the image in the model output
+    pub index: usize,
+
+    /// Classification tag of the image
+    pub tag: String,
+
+    /// Classification probability
+    pub probability: f32,
+
+    /// Raw model output for the class, when requested
+    pub logit: Option<f32>,
+
+    /// Best predictions, filtered according to `ClassifyOptions`
+    pub predictions: Vec<Prediction>,
+
+    /// Whether the two best predictions are too close to call
+    pub ambiguous: bool,
+
+    /// The two best predictions, when the result is ambiguous
+    pub candidates: Vec<Prediction>,
+
+    /// Whether a fallback model produced the result instead of the primary
+    pub degraded: bool,
+
+    /// Whether the result was served from the cache of the server
+    pub cached: bool,
+
+    /// Frames classified, for animated images
+    pub frames: Option<FrameReport>,
+
+    /// Views of the image averaged, with test-time augmentation
+    pub views: Option<usize>,
+
+    /// ID of the request, for it to be found in the logs of the server
+    pub request_id: Option<String>,
+
+    /// Time spent fetching the image from its URL, in milliseconds
+    pub time_url_fetch: i64,
+
+    /// Time spent decoding the image, in milliseconds
+    pub time_image_load: i64,
+
+    /// Time spent resizing the image, in milliseconds
+    pub time_image_resize: i64,
+
+    /// Time spent running the model, in milliseconds
+    pub time_session_run: i64,
+}
+
+/// Code and message of a failure
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    pub code: String,
+    pub message: String,
+
+    /// ID of the failed request, for it to be found in the logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Body of a failed request, `{"error": {"code": "...", "message": "..."}}`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+}
+
+impl ErrorBody {
+    pub fn new(code: &str, message: &str) -> Self {
+        ErrorBody {
+            error: ErrorDetail {
+                code: code.to_owned(),
+                message: message.to_owned(),
+                request_id: None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.error.code, self.error.message)
+    }
+}
+
+/// Rectangle of an image to classify, in pixels of the upright image
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Options of a classification. Those left to `None` take the defaults of
+/// the server.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ClassifyOptions {
+    /// Maximum number of predictions to return
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+
+    /// Minimum probability of a returned prediction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_probability: Option<f32>,
+
+    /// Include the raw model output of every prediction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logits: Option<bool>,
+
+    /// Report the result as ambiguous when the two best probabilities are
+    /// within this margin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ambiguity_margin: Option<f32>,
+
+    /// Language of the returned tags
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+
+    /// Frames of animated images to classify: `first`, `all` or an index
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frames: Option<String>,
+
+    /// Crops and flips of the image to average the probabilities of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tta: Option<usize>,
+
+    /// Region of the image to classify, instead of all of it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roi: Option<Region>,
+}
+
+impl ClassifyOptions {
+    /// Options as query string parameters
+    pub(crate) fn query(&self) -> Vec<(&'static str, String)> {
+        let mut query = vec![];
+        let mut push = |name, value: Option<String>| {
+            if let Some(value) = value {
+                query.push((name, value));
+            }
+        };
+
+        push("top_k", self.top_k.map(|value| value.to_string()));
+        push(
+            "min_probability",
+            self.min_probability.map(|value| value.to_string()),
+        );
+        push("logits", self.logits.map(|value| value.to_string()));
+        push(
+            "ambiguity_margin",
+            self.ambiguity_margin.map(|value| value.to_string()),
+        );
+        push("lang", self.lang.clone());
+        push("frames", self.frames.clone());
+        push("tta", self.tta.map(|value| value.to_string()));
+        push(
+            "roi",
+            self.roi
+                .map(|roi| format!("{},{},{},{}", roi.x, roi.y, roi.width, roi.height)),
+        );
+
+        query
+    }
+}
+
+/// Item of the response to a batch request
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum BatchItem {
+    // First, as every object deserializes as a `Classification`
+    Failed(ErrorBody),
+    Classified(Classification),
+}
@@ -0,0 +1,229 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::writer::BackgroundWriter;
+
+/// Most alerts waiting to be sent
+const MAX_PENDING_ALERTS: usize = 16;
+
+/// Thresholds of an `AlertMonitor`
+#[derive(Clone, Debug)]
+pub struct AlertOptions {
+    /// Length of the sliding window
+    pub window: Duration,
+
+    /// Fewest requests in the window to alert on, so that a single failure
+    /// of a quiet deployment does not alert
+    pub min_requests: usize,
+
+    /// Error rate, from 0 to 1, above which to alert
+    pub max_error_rate: Option<f32>,
+
+    /// 99th percentile latency above which to alert
+    pub max_p99_latency: Option<Duration>,
+
+    /// Least time between two alerts
+    pub cooldown: Duration,
+}
+
+impl Default for AlertOptions {
+    fn default() -> Self {
+        AlertOptions {
+            window: Duration::from_secs(60),
+            min_requests: 20,
+            max_error_rate: Some(0.1),
+            max_p99_latency: None,
+            cooldown: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Threshold crossed over the sliding window
+#[derive(Clone, Debug, Serialize)]
+pub struct Alert {
+    /// What crossed its threshold, `error_rate` or `p99_latency`
+    pub reason: &'static str,
+
+    /// Requests in the window
+    pub requests: usize,
+
+    /// Fraction of failed requests in the window
+    pub error_rate: f32,
+
+    /// 99th percentile latency of the window, in milliseconds
+    pub p99_latency_ms: u64,
+
+    /// Length of the window, in seconds
+    pub window_secs: u64,
+}
+
+/// Destination of alerts
+pub trait AlertSink: Send + Sync {
+    fn send(&self, alert: &Alert) -> tensorflow::Result<()>;
+}
+
+struct Window {
+    /// Time, latency and success of every request in the window, oldest
+    /// first
+    samples: VecDeque<(Instant, Duration, bool)>,
+
+    last_alert: Option<Instant>,
+}
+
+/// Error rate and latency of recent requests, alerting the registered sinks
+/// when they cross their thresholds
+pub struct AlertMonitor {
+    options: AlertOptions,
+    sinks: Vec<Arc<dyn AlertSink>>,
+    window: Mutex<Window>,
+    writer: BackgroundWriter,
+}
+
+impl AlertMonitor {
+    pub fn new(options: AlertOptions) -> Self {
+        AlertMonitor {
+            options,
+            sinks: vec![],
+            window: Mutex::new(Window {
+                samples: VecDeque::new(),
+                last_alert: None,
+            }),
+            writer: BackgroundWriter::new("alert", MAX_PENDING_ALERTS),
+        }
+    }
+
+    pub fn add_sink(&mut self, sink: Arc<dyn AlertSink>) {
+        self.sinks.push(sink);
+    }
+
+    /// Record the outcome of a request, sending an alert in the background
+    /// if it makes the window cross a threshold. Returns the alert, if any.
+    pub fn record(&self, latency: Duration, ok: bool) -> Option<Alert> {
+        let alert = self.record_at(Instant::now(), latency, ok)?;
+
+        warn!(
+            "Alert: {} over the last {}s ({} requests, {:.1}% errors, p99 {} msec)",
+            alert.reason,
+            alert.window_secs,
+            alert.requests,
+            alert.error_rate * 100.0,
+            alert.p99_latency_ms
+        );
+
+        let sinks = self.sinks.clone();
+        let sent = alert.clone();
+        self.writer.run(move || {
+            for sink in &sinks {
+                if let Err(err) = sink.send(&sent) {
+                    warn!("Could not send alert: {}", err);
+                }
+            }
+        });
+
+        Some(alert)
+    }
+
+    fn record_at(&self, now: Instant, latency: Duration, ok: bool) -> Option<Alert> {
+        let mut window = self.window.lock().unwrap();
+
+        window.samples.push_back((now, latency, ok));
+        while let Some(&(time, _, _)) = window.samples.front() {
+            if now.duration_since(time) <= self.options.window {
+                break;
+            }
+            window.samples.pop_front();
+        }
+
+        let requests = window.samples.len();
+        if requests < self.options.min_requests.max(1) {
+            return None;
+        }
+
+        if let Some(last_alert) = window.last_alert {
+            if now.duration_since(last_alert) < self.options.cooldown {
+                return None;
+            }
+        }
+
+        let errors = window.samples.iter().filter(|(_, _, ok)| !ok).count();
+        let error_rate = errors as f32 / requests as f32;
+
+        let mut latencies: Vec<Duration> = window
+            .samples
+            .iter()
+            .map(|&(_, latency, _)| latency)
+            .collect();
+        latencies.sort_unstable();
+        let p99 = latencies[(requests * 99 / 100).min(requests - 1)];
+
+        let reason = if self
+            .options
+            .max_error_rate
+            .map_or(false, |max| error_rate > max)
+        {
+            "error_rate"
+        } else if self.options.max_p99_latency.map_or(false, |max| p99 > max) {
+            "p99_latency"
+        } else {
+            return None;
+        };
+
+        window.last_alert = Some(now);
+
+        Some(Alert {
+            reason,
+            requests,
+            error_rate,
+            p99_latency_ms: p99.as_millis() as u64,
+            window_secs: self.options.window.as_secs(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_window() {
+        let monitor = AlertMonitor::new(AlertOptions {
+            min_requests: 4,
+            max_error_rate: Some(0.25),
+            max_p99_latency: Some(Duration::from_millis(500)),
+            ..Default::default()
+        });
+
+        let start = Instant::now();
+        let fast = Duration::from_millis(50);
+
+        for i in 0..3 {
+            assert!(monitor.record_at(start, fast, i != 0).is_none());
+        }
+
+        // One error in four requests is within the threshold
+        assert!(monitor.record_at(start, fast, true).is_none());
+
+        let alert = monitor.record_at(start, fast, false).unwrap();
+        assert_eq!(alert.reason, "error_rate");
+        assert_eq!(alert.requests, 5);
+
+        // Cooling down
+        assert!(monitor.record_at(start, fast, false).is_none());
+
+        // The errors leave the window, the slow requests trip the latency
+        let later = start + Duration::from_secs(400);
+        for _ in 0..3 {
+            assert!(monitor.record_at(later, fast, true).is_none());
+        }
+        let alert = monitor
+            .record_at(later, Duration::from_secs(2), true)
+            .unwrap();
+        assert_eq!(alert.reason, "p99_latency");
+        assert_eq!(alert.requests, 4);
+        assert_eq!(alert.p99_latency_ms, 2000);
+    }
+}
@@ -0,0 +1,169 @@
+//! Drawing of boxes and labels on images, for annotated detection results.
+//! Labels use a built-in 3x5 pixel font of lowercase letters, digits and a
+//! few signs, so that no font has to be shipped with the model.
+
+use image::{Rgb, RgbImage};
+
+/// Thickness of box outlines, in pixels
+const LINE_WIDTH: u32 = 2;
+
+/// Size of a font pixel, in image pixels
+const SCALE: u32 = 2;
+
+/// Colors of the boxes, by class index
+const PALETTE: [[u8; 3]; 8] = [
+    [230, 25, 75],
+    [60, 180, 75],
+    [0, 130, 200],
+    [245, 130, 48],
+    [145, 30, 180],
+    [70, 240, 240],
+    [240, 50, 230],
+    [255, 225, 25],
+];
+
+/// Color of the boxes of a class
+pub(crate) fn color(index: usize) -> Rgb<u8> {
+    Rgb(PALETTE[index % PALETTE.len()])
+}
+
+/// Rows of a character, top first, with the leftmost pixel in the third bit.
+/// Characters without a glyph are drawn as spaces.
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_lowercase() {
+        'a' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'b' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'c' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'd' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'e' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'f' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'g' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'h' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'i' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'j' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'k' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'l' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'm' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'n' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'o' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'p' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'r' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        's' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        't' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'u' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'v' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'w' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'x' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' | '_' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        _ => [0; 5],
+    }
+}
+
+/// Fill a rectangle, clipped to the image
+fn fill(image: &mut RgbImage, x: u32, y: u32, width: u32, height: u32, color: Rgb<u8>) {
+    let x_end = x.saturating_add(width).min(image.width());
+    let y_end = y.saturating_add(height).min(image.height());
+
+    for py in y.min(y_end)..y_end {
+        for px in x.min(x_end)..x_end {
+            image.put_pixel(px, py, color);
+        }
+    }
+}
+
+/// Outline the box from `(x0, y0)` to `(x1, y1)`, inclusive
+pub(crate) fn draw_box(
+    image: &mut RgbImage,
+    (x0, y0): (u32, u32),
+    (x1, y1): (u32, u32),
+    color: Rgb<u8>,
+) {
+    let (width, height) = (x1.saturating_sub(x0) + 1, y1.saturating_sub(y0) + 1);
+
+    fill(image, x0, y0, width, LINE_WIDTH, color);
+    fill(
+        image,
+        x0,
+        (y1 + 1).saturating_sub(LINE_WIDTH),
+        width,
+        LINE_WIDTH,
+        color,
+    );
+    fill(image, x0, y0, LINE_WIDTH, height, color);
+    fill(
+        image,
+        (x1 + 1).saturating_sub(LINE_WIDTH),
+        y0,
+        LINE_WIDTH,
+        height,
+        color,
+    );
+}
+
+/// Height of a label, in pixels
+pub(crate) fn label_height() -> u32 {
+    7 * SCALE
+}
+
+/// Draw `text` in white on a `color` background, with its top left corner
+/// at `(x, y)`
+pub(crate) fn draw_label(image: &mut RgbImage, (x, y): (u32, u32), text: &str, color: Rgb<u8>) {
+    let advance = 4 * SCALE;
+    let width = text.chars().count() as u32 * advance + SCALE;
+    fill(image, x, y, width, label_height(), color);
+
+    for (i, c) in text.chars().enumerate() {
+        let left = x + SCALE + i as u32 * advance;
+
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) != 0 {
+                    fill(
+                        image,
+                        left + column * SCALE,
+                        y + SCALE + row as u32 * SCALE,
+                        SCALE,
+                        SCALE,
+                        Rgb([255, 255, 255]),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boxes_and_labels() {
+        let mut image = RgbImage::new(20, 20);
+        let red = color(0);
+
+        draw_box(&mut image, (2, 2), (10, 10), red);
+        assert_eq!(*image.get_pixel(2, 6), red);
+        assert_eq!(*image.get_pixel(10, 10), red);
+        assert_eq!(*image.get_pixel(6, 6), Rgb([0, 0, 0]));
+
+        // Clipped at the edges instead of panicking
+        draw_label(&mut image, (12, 12), "cat 99%", red);
+        assert_eq!(*image.get_pixel(12, 12), red);
+        assert_eq!(*image.get_pixel(16, 14), Rgb([255, 255, 255]));
+    }
+}
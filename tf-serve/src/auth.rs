@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tensorflow::{Code, Status};
+
+/// Credentials presented by a caller. Frontends fill in whatever their
+/// transport provides.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Credentials<'a> {
+    /// API key, e.g. from an `X-Api-Key` header
+    pub api_key: Option<&'a str>,
+
+    /// Bearer token of an `Authorization` header
+    pub bearer: Option<&'a str>,
+
+    /// Subject of a verified TLS client certificate
+    pub client_subject: Option<&'a str>,
+}
+
+/// Authenticated caller
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    /// Name of the caller, for quotas and audit logs
+    pub subject: String,
+
+    /// Claims of the token presented by the caller, if any, for the logs
+    pub claims: Option<serde_json::Value>,
+}
+
+/// Authentication scheme
+pub trait AuthProvider: Send + Sync {
+    /// Authenticate the caller. Returns `None` if the credentials this
+    /// provider handles are missing, so the next provider can try, and an
+    /// error if they are present but invalid.
+    fn authenticate(&self, credentials: &Credentials) -> tensorflow::Result<Option<Identity>>;
+}
+
+fn unauthenticated(message: &str) -> Status {
+    Status::new_set_lossy(Code::Unauthenticated, message)
+}
+
+/// Fixed set of API keys, each mapped to the subject it identifies
+#[derive(Default)]
+pub struct StaticKeys {
+    keys: HashMap<String, String>,
+}
+
+impl StaticKeys {
+    pub fn new() -> Self {
+        StaticKeys::default()
+    }
+
+    /// Accept `key` as identifying `subject`
+    pub fn insert(&mut self, key: &str, subject: &str) {
+        self.keys.insert(key.to_owned(), subject.to_owned());
+    }
+}
+
+impl AuthProvider for StaticKeys {
+    fn authenticate(&self, credentials: &Credentials) -> tensorflow::Result<Option<Identity>> {
+        let key = match credentials.api_key.or(credentials.bearer) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+
+        match self.keys.get(key) {
+            Some(subject) => Ok(Some(Identity {
+                subject: subject.clone(),
+                claims: None,
+            })),
+            None => Err(unauthenticated("Invalid API key")),
+        }
+    }
+}
+
+/// Callers identified by the subject of their TLS client certificate, which
+/// the listener has already verified
+#[derive(Default)]
+pub struct ClientCertificates {
+    /// Accepted subjects, or any verified one if empty
+    subjects: Vec<String>,
+}
+
+impl ClientCertificates {
+    pub fn new(subjects: Vec<String>) -> Self {
+        ClientCertificates { subjects }
+    }
+}
+
+impl AuthProvider for ClientCertificates {
+    fn authenticate(&self, credentials: &Credentials) -> tensorflow::Result<Option<Identity>> {
+        let subject = match credentials.client_subject {
+            Some(subject) => subject,
+            None => return Ok(None),
+        };
+
+        if !self.subjects.is_empty() && !self.subjects.iter().any(|s| s == subject) {
+            return Err(Status::new_set_lossy(
+                Code::PermissionDenied,
+                &format!("Client '{}' is not allowed", subject),
+            ));
+        }
+
+        Ok(Some(Identity {
+            subject: subject.to_owned(),
+            claims: None,
+        }))
+    }
+}
+
+/// Providers tried in registration order. The first one recognizing the
+/// credentials decides.
+#[derive(Clone, Default)]
+pub struct AuthRegistry {
+    providers: Vec<Arc<dyn AuthProvider>>,
+}
+
+impl AuthRegistry {
+    pub fn new() -> Self {
+        AuthRegistry::default()
+    }
+
+    pub fn register(&mut self, provider: Arc<dyn AuthProvider>) {
+        self.providers.push(provider);
+    }
+
+    /// Whether no provider is registered, i.e. authentication is disabled
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// Authenticate the caller, failing if no provider recognizes it. Every
+    /// caller is anonymous if no provider is registered.
+    pub fn authenticate(&self, credentials: &Credentials) -> tensorflow::Result<Identity> {
+        if self.is_empty() {
+            return Ok(Identity {
+                subject: "anonymous".to_owned(),
+                claims: None,
+            });
+        }
+
+        for provider in &self.providers {
+            if let Some(identity) = provider.authenticate(credentials)? {
+                return Ok(identity);
+            }
+        }
+
+        Err(unauthenticated("Missing credentials"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_order() {
+        let mut keys = StaticKeys::new();
+        keys.insert("secret", "batch-jobs");
+
+        let mut registry = AuthRegistry::new();
+        assert!(registry.authenticate(&Credentials::default()).is_ok());
+
+        registry.register(Arc::new(keys));
+        registry.register(Arc::new(ClientCertificates::new(vec![
+            "CN=gateway".to_owned()
+        ])));
+
+        let with_key = Credentials {
+            api_key: Some("secret"),
+            ..Default::default()
+        };
+        assert_eq!(
+            registry.authenticate(&with_key).unwrap().subject,
+            "batch-jobs"
+        );
+
+        let with_cert = Credentials {
+            client_subject: Some("CN=gateway"),
+            ..Default::default()
+        };
+        assert_eq!(
+            registry.authenticate(&with_cert).unwrap().subject,
+            "CN=gateway"
+        );
+
+        let bad_key = Credentials {
+            bearer: Some("guess"),
+            client_subject: Some("CN=gateway"),
+            ..Default::default()
+        };
+        assert!(registry.authenticate(&bad_key).is_err());
+        assert!(registry.authenticate(&Credentials::default()).is_err());
+    }
+}
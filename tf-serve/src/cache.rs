@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use crate::{Classification, ClassifyOptions};
+
+/// Bounds of the result cache of an `ImageClassifier`
+#[derive(Clone, Debug)]
+pub struct CacheOptions {
+    /// Most results kept, the least recently used being evicted first
+    pub capacity: usize,
+
+    /// Time after which a result is classified again
+    pub ttl: Duration,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            capacity: 1024,
+            ttl: Duration::from_secs(600),
+        }
+    }
+}
+
+struct Entry {
+    classification: Classification,
+    inserted: Instant,
+
+    /// Value of the use counter when last used
+    used: u64,
+}
+
+struct State {
+    entries: HashMap<String, Entry>,
+    uses: u64,
+}
+
+/// LRU cache of classifications, by content hash of uploaded images, or URL
+/// of fetched ones, and options they were classified with
+pub(crate) struct ResultCache {
+    options: CacheOptions,
+    state: Mutex<State>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Options of a request that change its result
+fn options_key(options: &ClassifyOptions) -> String {
+    serde_json::to_string(options).unwrap_or_default()
+}
+
+impl ResultCache {
+    pub fn new(options: &CacheOptions) -> Self {
+        ResultCache {
+            options: options.clone(),
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                uses: 0,
+            }),
+        }
+    }
+
+    /// Key of an uploaded image, the SHA-256 of its bytes
+    pub fn raw_key(data: &[u8], options: &ClassifyOptions) -> String {
+        format!(
+            "sha256:{}:{}",
+            hex(&Sha256::digest(data)),
+            options_key(options)
+        )
+    }
+
+    /// Key of an image fetched from `url`
+    pub fn url_key(url: &str, options: &ClassifyOptions) -> String {
+        format!("url:{}:{}", url, options_key(options))
+    }
+
+    /// Result of `key`, flagged as cached, unless expired
+    pub fn get(&self, key: &str) -> Option<Classification> {
+        let mut state = self.state.lock().unwrap();
+        state.uses += 1;
+        let uses = state.uses;
+
+        match state.entries.get_mut(key) {
+            Some(entry) if entry.inserted.elapsed() < self.options.ttl => {
+                entry.used = uses;
+
+                let mut classification = entry.classification.clone();
+                classification.cached = true;
+                Some(classification)
+            }
+            Some(_) => {
+                state.entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: String, classification: &Classification) {
+        if self.options.capacity == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.uses += 1;
+        let uses = state.uses;
+
+        if !state.entries.contains_key(&key) && state.entries.len() >= self.options.capacity {
+            let ttl = self.options.ttl;
+            state
+                .entries
+                .retain(|_, entry| entry.inserted.elapsed() < ttl);
+        }
+
+        if !state.entries.contains_key(&key) && state.entries.len() >= self.options.capacity {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone());
+
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+            }
+        }
+
+        state.entries.insert(
+            key,
+            Entry {
+                classification: classification.clone(),
+                inserted: Instant::now(),
+                used: uses,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_eviction() {
+        let cache = ResultCache::new(&CacheOptions {
+            capacity: 2,
+            ttl: Duration::from_secs(60),
+        });
+        let options = ClassifyOptions::default();
+
+        let key = |data: &[u8]| ResultCache::raw_key(data, &options);
+        assert_ne!(
+            key(b"a"),
+            ResultCache::raw_key(
+                b"a",
+                &ClassifyOptions {
+                    top_k: 5,
+                    ..Default::default()
+                }
+            )
+        );
+
+        cache.insert(key(b"a"), &Classification::default());
+        cache.insert(key(b"b"), &Classification::default());
+        assert!(cache.get(&key(b"a")).unwrap().cached);
+
+        // "b" is the least recently used
+        cache.insert(key(b"c"), &Classification::default());
+        assert!(cache.get(&key(b"b")).is_none());
+        assert!(cache.get(&key(b"a")).is_some());
+        assert!(cache.get(&key(b"c")).is_some());
+
+        let expiring = ResultCache::new(&CacheOptions {
+            capacity: 2,
+            ttl: Duration::from_secs(0),
+        });
+        expiring.insert(key(b"a"), &Classification::default());
+        assert!(expiring.get(&key(b"a")).is_none());
+    }
+}
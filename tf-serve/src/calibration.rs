@@ -0,0 +1,149 @@
+//! Sampling of real traffic into a calibration dataset for post-training
+//! quantization.
+//!
+//! Every sampled request stores two objects under the configured location,
+//! named after the same `<unix millis>-<random hex>` stem:
+//!
+//! - `<stem>.npy`: the preprocessed model input, a little-endian `float32`
+//!   NumPy array of shape `(1, height, width, 3)`, loadable with
+//!   `numpy.load`.
+//! - `<stem>.json`: the `Classification` the model produced for it.
+//!
+//! A TFLite converter's representative dataset is then a generator over the
+//! `.npy` files.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::trace::random_u64;
+use crate::writer::BackgroundWriter;
+use crate::{Classification, Storage};
+
+/// Most samples waiting to be stored
+const MAX_PENDING_SAMPLES: usize = 64;
+
+/// Configuration of calibration sampling
+#[derive(Clone, Debug)]
+pub struct CalibrationOptions {
+    /// Fraction of requests to sample, from 0 to 1
+    pub rate: f32,
+
+    /// Directory, local or `s3://bucket/prefix`, to store samples in
+    pub location: String,
+}
+
+/// NumPy `.npy` (format version 1.0) encoding of `float32` values
+fn npy(shape: &[usize], values: &[f32]) -> Vec<u8> {
+    let dims: Vec<String> = shape.iter().map(usize::to_string).collect();
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({},), }}",
+        dims.join(", ")
+    );
+
+    // Magic, version and header length take 10 bytes, and the header ends
+    // with a newline, padding the whole to a multiple of 64 bytes
+    let padding = 64 - (10 + header.len() + 1) % 64;
+    header.push_str(&" ".repeat(padding % 64));
+    header.push('\n');
+
+    let mut data = Vec::with_capacity(10 + header.len() + values.len() * 4);
+    data.extend_from_slice(b"\x93NUMPY\x01\x00");
+    data.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    data.extend_from_slice(header.as_bytes());
+    for value in values {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    data
+}
+
+/// Calibration sampling storing its samples in the background
+pub(crate) struct CalibrationSampler {
+    options: CalibrationOptions,
+    writer: BackgroundWriter,
+}
+
+impl CalibrationSampler {
+    pub(crate) fn new(options: CalibrationOptions) -> Self {
+        CalibrationSampler {
+            options,
+            writer: BackgroundWriter::new("calibration sample", MAX_PENDING_SAMPLES),
+        }
+    }
+
+    /// Whether to sample the current request
+    fn sample(&self) -> bool {
+        (random_u64() as f64 / u64::MAX as f64) < self.options.rate as f64
+    }
+
+    /// Store a sample of `input`, of `(width, height)`, and its
+    /// classification with the configured probability, off the request
+    /// path. Failures are only logged, as sampling must not fail requests.
+    pub(crate) fn record(
+        &self,
+        storage: &Arc<dyn Storage>,
+        input: &[f32],
+        input_size: (u32, u32),
+        classification: &Classification,
+    ) {
+        if !self.sample() {
+            return;
+        }
+
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+        let stem = format!(
+            "{}/{}-{:016x}",
+            self.options.location.trim_end_matches('/'),
+            millis,
+            random_u64()
+        );
+
+        let (width, height) = input_size;
+        let tensor = npy(&[1, height as usize, width as usize, 3], input);
+        let json = serde_json::to_vec(classification);
+        let storage = storage.clone();
+
+        self.writer.run(move || {
+            let result = json.map_err(|err| err.to_string()).and_then(|json| {
+                storage
+                    .write(&format!("{}.npy", stem), &tensor)
+                    .and_then(|_| storage.write(&format!("{}.json", stem), &json))
+                    .map_err(|err| err.to_string())
+            });
+
+            if let Err(err) = result {
+                warn!("Could not store calibration sample {}: {}", stem, err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn npy_header() {
+        let data = npy(&[1, 2, 2, 3], &[0.5; 12]);
+
+        assert_eq!(&data[..8], b"\x93NUMPY\x01\x00");
+
+        let header_len = u16::from_le_bytes([data[8], data[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+
+        let header = std::str::from_utf8(&data[10..10 + header_len]).unwrap();
+        assert!(header
+            .starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (1, 2, 2, 3,), }"));
+        assert!(header.ends_with('\n'));
+
+        assert_eq!(data.len(), 10 + header_len + 12 * 4);
+        assert_eq!(
+            &data[10 + header_len..14 + header_len],
+            &0.5f32.to_le_bytes()
+        );
+    }
+}
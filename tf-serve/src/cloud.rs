@@ -0,0 +1,496 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tensorflow::{Code, Status};
+
+use crate::http::read_body;
+use crate::{Alert, AlertSink, Storage};
+
+/// Percent-encode everything but unreserved characters, and `/` unless
+/// `encode_slash`
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b'/' if !encode_slash => "/".to_owned(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Split a `scheme://bucket/key` location into bucket and key
+fn bucket_and_key<'a>(location: &'a str, scheme: &str) -> tensorflow::Result<(&'a str, &'a str)> {
+    let invalid = || {
+        Status::new_set_lossy(
+            Code::InvalidArgument,
+            &format!("Invalid {} location '{}'", scheme, location),
+        )
+    };
+
+    let pos = location.find("://").ok_or_else(invalid)?;
+    let path = &location[pos + 3..];
+    let slash = path.find('/').ok_or_else(invalid)?;
+
+    match (&path[..slash], &path[slash + 1..]) {
+        ("", _) | (_, "") => Err(invalid()),
+        (bucket, key) => Ok((bucket, key)),
+    }
+}
+
+/// Fail unless `bucket` is one of `buckets`, or `buckets` is empty
+fn check_bucket(buckets: &[String], bucket: &str) -> tensorflow::Result<()> {
+    if buckets.is_empty() || buckets.iter().any(|allowed| allowed == bucket) {
+        Ok(())
+    } else {
+        Err(Status::new_set_lossy(
+            Code::PermissionDenied,
+            &format!("Bucket '{}' is not allowed", bucket),
+        ))
+    }
+}
+
+/// Read the whole body of a successful response, up to `max_size` bytes
+fn read_response(
+    resp: reqwest::Response,
+    location: &str,
+    max_size: Option<usize>,
+) -> tensorflow::Result<Vec<u8>> {
+    if !resp.status().is_success() {
+        return Err(Status::new_set_lossy(
+            Code::Unavailable,
+            &format!("Could not read '{}': {}", location, resp.status()),
+        ));
+    }
+
+    read_body(resp, max_size)
+}
+
+/// AWS credentials of the execution role, as exported to Lambda functions
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    fn from_env() -> tensorflow::Result<Self> {
+        match (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Ok(access_key_id), Ok(secret_access_key)) => Ok(AwsCredentials {
+                access_key_id,
+                secret_access_key,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            }),
+            _ => Err(Status::new_set_lossy(
+                Code::FailedPrecondition,
+                "No AWS credentials in the environment",
+            )),
+        }
+    }
+}
+
+/// Signature V4 signing key of a day, region and service
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret).as_bytes(), date);
+    let key = hmac_sha256(&key, region);
+    let key = hmac_sha256(&key, service);
+    hmac_sha256(&key, "aws4_request")
+}
+
+/// Region of the environment, as exported to Lambda functions
+fn aws_region() -> String {
+    std::env::var("AWS_REGION")
+        .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+        .unwrap_or_else(|_| "us-east-1".to_owned())
+}
+
+/// Request to `https://host/path` signed with AWS Signature Version 4.
+/// `path` must already be URI-encoded.
+#[allow(clippy::too_many_arguments)]
+fn signed_request(
+    client: &reqwest::Client,
+    method: reqwest::Method,
+    host: &str,
+    path: &str,
+    service: &str,
+    region: &str,
+    payload: &[u8],
+    content_type: Option<&str>,
+) -> tensorflow::Result<reqwest::RequestBuilder> {
+    let credentials = AwsCredentials::from_env()?;
+
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let payload_hash = hex(&Sha256::digest(payload));
+
+    // Canonical headers must be sorted by name
+    let mut headers = vec![];
+    if let Some(content_type) = content_type {
+        headers.push(("content-type", content_type.to_owned()));
+    }
+    headers.push(("host", host.to_owned()));
+    headers.push(("x-amz-content-sha256", payload_hash.clone()));
+    headers.push(("x-amz-date", amz_date.clone()));
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<&str>>()
+        .join(";");
+
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\n{}",
+        method, path, canonical_headers, signed_headers, payload_hash
+    );
+
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = signing_key(&credentials.secret_access_key, &date, region, service);
+    let signature = hex(&hmac_sha256(&key, &string_to_sign));
+
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, scope, signed_headers, signature
+    );
+
+    let mut request = client
+        .request(method, &format!("https://{}{}", host, path))
+        .header("authorization", authorization);
+    for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+        request = request.header(name, value);
+    }
+
+    Ok(request.body(payload.to_vec()))
+}
+
+/// `s3://bucket/key` objects, read with the credentials and region of the
+/// environment
+pub struct S3Storage {
+    client: reqwest::Client,
+
+    /// Buckets objects may be read from, any if empty
+    buckets: Vec<String>,
+
+    /// Largest object read
+    max_size: Option<usize>,
+}
+
+impl Default for S3Storage {
+    fn default() -> Self {
+        S3Storage::new(reqwest::Client::new())
+    }
+}
+
+impl S3Storage {
+    pub fn new(client: reqwest::Client) -> Self {
+        S3Storage::restricted(client, &[], None)
+    }
+
+    /// Storage of the objects of `buckets` only, any if empty, up to
+    /// `max_size` bytes, for locations given by clients
+    pub fn restricted(
+        client: reqwest::Client,
+        buckets: &[String],
+        max_size: Option<usize>,
+    ) -> Self {
+        S3Storage {
+            client,
+            buckets: buckets.to_vec(),
+            max_size,
+        }
+    }
+
+    /// Send a request signed with AWS Signature Version 4
+    fn send(
+        &self,
+        method: reqwest::Method,
+        location: &str,
+        payload: &[u8],
+        content_type: Option<&str>,
+    ) -> tensorflow::Result<reqwest::Response> {
+        let (bucket, key) = bucket_and_key(location, "S3")?;
+        check_bucket(&self.buckets, bucket)?;
+        let region = aws_region();
+
+        // Dotted bucket names do not match the wildcard certificate of
+        // virtual-hosted endpoints
+        let (host, path) = if bucket.contains('.') {
+            (
+                format!("s3.{}.amazonaws.com", region),
+                format!("/{}/{}", bucket, uri_encode(key, false)),
+            )
+        } else {
+            (
+                format!("{}.s3.{}.amazonaws.com", bucket, region),
+                format!("/{}", uri_encode(key, false)),
+            )
+        };
+
+        signed_request(
+            &self.client,
+            method,
+            &host,
+            &path,
+            "s3",
+            &region,
+            payload,
+            content_type,
+        )?
+        .send()
+        .map_err(|_| Status::new_set_lossy(Code::Unavailable, "Could not reach S3"))
+    }
+
+    /// Store `data` as the object at `location`
+    pub fn write(&self, location: &str, data: &[u8], content_type: &str) -> tensorflow::Result<()> {
+        let resp = self.send(reqwest::Method::PUT, location, data, Some(content_type))?;
+
+        if !resp.status().is_success() {
+            return Err(Status::new_set_lossy(
+                Code::PermissionDenied,
+                &format!("Could not write '{}': {}", location, resp.status()),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Storage for S3Storage {
+    fn read(&self, location: &str) -> tensorflow::Result<Vec<u8>> {
+        let resp = self.send(reqwest::Method::GET, location, &[], None)?;
+        read_response(resp, location, self.max_size)
+    }
+
+    fn write(&self, location: &str, data: &[u8]) -> tensorflow::Result<()> {
+        S3Storage::write(self, location, data, "application/octet-stream")
+    }
+}
+
+/// Alerts published to an SNS topic, in the topic's region
+pub struct SnsAlerts {
+    client: reqwest::Client,
+    topic_arn: String,
+}
+
+impl SnsAlerts {
+    pub fn new(client: reqwest::Client, topic_arn: &str) -> Self {
+        SnsAlerts {
+            client,
+            topic_arn: topic_arn.to_owned(),
+        }
+    }
+}
+
+impl AlertSink for SnsAlerts {
+    fn send(&self, alert: &Alert) -> tensorflow::Result<()> {
+        // arn:aws:sns:<region>:<account>:<topic>
+        let region = match self.topic_arn.split(':').nth(3) {
+            Some(region) if !region.is_empty() => region.to_owned(),
+            _ => aws_region(),
+        };
+
+        let message = serde_json::to_string(alert)
+            .map_err(|_| Status::new_set_lossy(Code::Internal, "Could not encode alert"))?;
+        let subject = format!("tf-serve alert: {}", alert.reason);
+
+        let body = format!(
+            "Action=Publish&Version=2010-03-31&TopicArn={}&Subject={}&Message={}",
+            uri_encode(&self.topic_arn, true),
+            uri_encode(&subject, true),
+            uri_encode(&message, true)
+        );
+
+        let resp = signed_request(
+            &self.client,
+            reqwest::Method::POST,
+            &format!("sns.{}.amazonaws.com", region),
+            "/",
+            "sns",
+            &region,
+            body.as_bytes(),
+            Some("application/x-www-form-urlencoded"),
+        )?
+        .send()
+        .map_err(|_| Status::new_set_lossy(Code::Unavailable, "Could not reach SNS"))?;
+
+        if !resp.status().is_success() {
+            return Err(Status::new_set_lossy(
+                Code::PermissionDenied,
+                &format!(
+                    "Could not publish to '{}': {}",
+                    self.topic_arn,
+                    resp.status()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// `gs://bucket/object` objects, read with the service account of the
+/// metadata server, or the token in `GOOGLE_OAUTH_ACCESS_TOKEN`
+pub struct GcsStorage {
+    client: reqwest::Client,
+
+    /// Buckets objects may be read from, any if empty
+    buckets: Vec<String>,
+
+    /// Largest object read
+    max_size: Option<usize>,
+
+    /// Access token from the metadata server, along with its expiry
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl GcsStorage {
+    pub fn new(client: reqwest::Client) -> Self {
+        GcsStorage::restricted(client, &[], None)
+    }
+
+    /// Storage of the objects of `buckets` only, any if empty, up to
+    /// `max_size` bytes, for locations given by clients
+    pub fn restricted(
+        client: reqwest::Client,
+        buckets: &[String],
+        max_size: Option<usize>,
+    ) -> Self {
+        GcsStorage {
+            client,
+            buckets: buckets.to_vec(),
+            max_size,
+            token: Mutex::new(None),
+        }
+    }
+
+    fn access_token(&self) -> tensorflow::Result<String> {
+        if let Ok(token) = std::env::var("GOOGLE_OAUTH_ACCESS_TOKEN") {
+            return Ok(token);
+        }
+
+        let mut cached = self.token.lock().unwrap();
+        if let Some((token, expiry)) = cached.as_ref() {
+            if Instant::now() < *expiry {
+                return Ok(token.clone());
+            }
+        }
+
+        let unavailable = || {
+            Status::new_set_lossy(
+                Code::Unavailable,
+                "Could not get an access token from the metadata server",
+            )
+        };
+
+        let token: AccessToken = self
+            .client
+            .get("http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token")
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .and_then(|mut resp| resp.json())
+            .map_err(|_| unavailable())?;
+
+        // Refresh a minute early to not race the expiry
+        let expiry = Instant::now() + Duration::from_secs(token.expires_in.saturating_sub(60));
+        *cached = Some((token.access_token.clone(), expiry));
+
+        Ok(token.access_token)
+    }
+}
+
+impl Storage for GcsStorage {
+    fn read(&self, location: &str) -> tensorflow::Result<Vec<u8>> {
+        let (bucket, object) = bucket_and_key(location, "GCS")?;
+        check_bucket(&self.buckets, bucket)?;
+
+        let url = format!(
+            "https://storage.googleapis.com/storage/v1/b/{}/o/{}?alt=media",
+            uri_encode(bucket, true),
+            uri_encode(object, true)
+        );
+
+        let resp = self
+            .client
+            .get(&url)
+            .bearer_auth(self.access_token()?)
+            .send()
+            .map_err(|_| Status::new_set_lossy(Code::Unavailable, "Could not reach GCS"))?;
+
+        read_response(resp, location, self.max_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sigv4() {
+        // Example of the AWS Signature Version 4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+
+        assert_eq!(
+            uri_encode("photos/a cat+1.jpg", false),
+            "photos/a%20cat%2B1.jpg"
+        );
+        assert_eq!(uri_encode("photos/cat.jpg", true), "photos%2Fcat.jpg");
+
+        assert_eq!(
+            bucket_and_key("s3://images/2021/cat.jpg", "S3").unwrap(),
+            ("images", "2021/cat.jpg")
+        );
+        assert!(bucket_and_key("s3://images", "S3").is_err());
+
+        let buckets = vec!["images".to_owned()];
+        assert!(check_bucket(&buckets, "images").is_ok());
+        assert!(check_bucket(&buckets, "models").is_err());
+        assert!(check_bucket(&[], "models").is_ok());
+    }
+}
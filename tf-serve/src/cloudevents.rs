@@ -0,0 +1,210 @@
+//! CloudEvents 1.0 over HTTP, in binary and structured mode, so that the
+//! HTTP frontend can sit behind Knative Eventing triggers.
+//!
+//! The data of an event is classified as the body of a request would be: an
+//! image, or JSON with a `url` or `image_b64`. The reply is a
+//! `classification.result` event in binary mode, which Knative forwards to
+//! the next step of the pipeline.
+
+use serde::{Deserialize, Serialize};
+
+use crate::trace::new_span_id;
+use crate::{HttpRequest, HttpResponse};
+
+/// Type of the events replied with
+pub const RESULT_TYPE: &str = "classification.result";
+
+/// Source of the events replied with
+pub const RESULT_SOURCE: &str = "/tf-serve";
+
+/// Context attributes of an event
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CloudEvent {
+    pub specversion: String,
+    pub id: String,
+    pub source: String,
+
+    #[serde(rename = "type")]
+    pub event_type: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub datacontenttype: Option<String>,
+}
+
+/// Event of structured mode, with its data
+#[derive(Deserialize)]
+struct StructuredEvent {
+    #[serde(flatten)]
+    event: CloudEvent,
+
+    data: Option<serde_json::Value>,
+    data_base64: Option<String>,
+}
+
+fn is_structured(request: &HttpRequest) -> bool {
+    request.header("content-type").map_or(false, |value| {
+        value
+            .trim()
+            .to_lowercase()
+            .starts_with("application/cloudevents+json")
+    })
+}
+
+/// Whether a request carries an event, in either mode
+pub fn is_cloud_event(request: &HttpRequest) -> bool {
+    request.header("ce-specversion").is_some() || is_structured(request)
+}
+
+/// Event of a request, and its data as a request to classify
+pub fn parse(request: &HttpRequest) -> Result<(CloudEvent, HttpRequest), String> {
+    let mut data_request = HttpRequest {
+        method: "POST".to_owned(),
+        path: request.path.clone(),
+        query: request.query.clone(),
+        headers: vec![],
+        body: vec![],
+    };
+
+    let event = if is_structured(request) {
+        let structured: StructuredEvent = serde_json::from_slice(&request.body)
+            .map_err(|err| format!("Invalid CloudEvent: {}", err))?;
+
+        let (content_type, body) = match (structured.data_base64, structured.data) {
+            (Some(data), _) => (
+                structured.event.datacontenttype.clone(),
+                base64::decode(data.trim()).map_err(|_| "Invalid data_base64".to_owned())?,
+            ),
+            (None, Some(serde_json::Value::String(data))) => {
+                (structured.event.datacontenttype.clone(), data.into_bytes())
+            }
+            (None, Some(data)) => (
+                Some("application/json".to_owned()),
+                serde_json::to_vec(&data).map_err(|err| format!("{}", err))?,
+            ),
+            (None, None) => return Err("CloudEvent without data".to_owned()),
+        };
+
+        if let Some(content_type) = content_type {
+            data_request
+                .headers
+                .push(("content-type".to_owned(), content_type));
+        }
+        data_request.body = body;
+
+        structured.event
+    } else {
+        let attribute = |name: &str| {
+            request
+                .header(&format!("ce-{}", name))
+                .map(str::to_owned)
+                .ok_or_else(|| format!("Missing ce-{} header", name))
+        };
+
+        let event = CloudEvent {
+            specversion: attribute("specversion")?,
+            id: attribute("id")?,
+            source: attribute("source")?,
+            event_type: attribute("type")?,
+            subject: attribute("subject").ok(),
+            datacontenttype: request.header("content-type").map(str::to_owned),
+        };
+
+        data_request.headers = request
+            .headers
+            .iter()
+            .filter(|(name, _)| !name.starts_with("ce-"))
+            .cloned()
+            .collect();
+        data_request.body = request.body.clone();
+
+        event
+    };
+
+    if !event.specversion.starts_with("1.") {
+        return Err(format!(
+            "Unsupported CloudEvents version {}",
+            event.specversion
+        ));
+    }
+
+    Ok((event, data_request))
+}
+
+/// Wrap a successful response into a `classification.result` event, in
+/// binary mode, about the same subject as `event`. Failures are returned
+/// as they are, for the broker to retry or dead-letter the event.
+pub fn reply(event: &CloudEvent, mut response: HttpResponse) -> HttpResponse {
+    if response.status >= 400 {
+        return response;
+    }
+
+    let subject = event.subject.clone().unwrap_or_else(|| event.id.clone());
+
+    response.headers.extend(vec![
+        ("ce-specversion".to_owned(), "1.0".to_owned()),
+        ("ce-id".to_owned(), new_span_id()),
+        ("ce-source".to_owned(), RESULT_SOURCE.to_owned()),
+        ("ce-type".to_owned(), RESULT_TYPE.to_owned()),
+        ("ce-subject".to_owned(), subject),
+    ]);
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn structured_and_binary() {
+        let structured = HttpRequest::new(
+            "POST",
+            "/?top_k=3",
+            vec![(
+                "Content-Type".to_owned(),
+                "application/cloudevents+json; charset=utf-8".to_owned(),
+            )],
+            br#"{"specversion": "1.0", "id": "42", "source": "/uploads",
+                "type": "image.uploaded", "data": {"url": "https://example.com/cat.jpg"}}"#
+                .to_vec(),
+        );
+        assert!(is_cloud_event(&structured));
+
+        let (event, data) = parse(&structured).unwrap();
+        assert_eq!(event.event_type, "image.uploaded");
+        assert_eq!(data.header("content-type"), Some("application/json"));
+        assert_eq!(data.query_param("top_k"), Some("3"));
+        assert_eq!(
+            data.body,
+            br#"{"url":"https://example.com/cat.jpg"}"#.to_vec()
+        );
+
+        let binary = HttpRequest::new(
+            "POST",
+            "/",
+            vec![
+                ("ce-specversion".to_owned(), "1.0".to_owned()),
+                ("ce-id".to_owned(), "43".to_owned()),
+                ("ce-source".to_owned(), "/uploads".to_owned()),
+                ("ce-type".to_owned(), "image.uploaded".to_owned()),
+                ("content-type".to_owned(), "image/jpeg".to_owned()),
+            ],
+            vec![0xff, 0xd8],
+        );
+        let (event, data) = parse(&binary).unwrap();
+        assert_eq!(event.id, "43");
+        assert_eq!(data.headers.len(), 1);
+        assert_eq!(data.body, vec![0xff, 0xd8]);
+
+        let response = reply(&event, HttpResponse::json(200, &serde_json::json!({})));
+        assert!(response
+            .headers
+            .contains(&("ce-type".to_owned(), RESULT_TYPE.to_owned())));
+        assert!(response
+            .headers
+            .contains(&("ce-subject".to_owned(), "43".to_owned())));
+    }
+}
@@ -0,0 +1,422 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use image::{ColorType, DynamicImage};
+use serde::{Deserialize, Serialize};
+use tensorflow::{Code, Graph, SavedModelBundle, Session, SessionRunArgs, Status, Tensor};
+
+use crate::annotate;
+use crate::preprocess;
+use crate::{
+    env_parse, tensor_by_name, Device, ImageLimits, LabelFormat, Labels, Storage, StorageRegistry,
+    Timer,
+};
+
+/// Configuration of a `Detector`
+#[derive(Clone, Debug)]
+pub struct DetectorOptions {
+    /// Device to run inference on
+    pub device: Device,
+
+    /// Input tensor of the model, as `operation[:index]`
+    pub input_op: String,
+
+    /// Bounding boxes output tensor, as `operation[:index]`
+    pub boxes_op: String,
+
+    /// Detection scores output tensor, as `operation[:index]`
+    pub scores_op: String,
+
+    /// Detection classes output tensor, as `operation[:index]`
+    pub classes_op: String,
+
+    /// Minimum score of a returned detection
+    pub score_threshold: f32,
+
+    /// Overlap above which the weaker of two same-class boxes is suppressed
+    pub iou_threshold: f32,
+
+    /// Maximum number of detections to return
+    pub max_detections: usize,
+
+    /// Class index of the first label, e.g. 1 for models whose "background"
+    /// class 0 is missing from the labels file. By default 0 for label maps,
+    /// whose IDs are the classes, and 1 for other labels files, as the
+    /// classes of the Object Detection API start at 1.
+    pub label_offset: Option<usize>,
+}
+
+impl Default for DetectorOptions {
+    fn default() -> Self {
+        // Output names of SavedModels exported by the TF2 Object Detection API
+        DetectorOptions {
+            device: Device::default(),
+            input_op: "serving_default_input_tensor".to_owned(),
+            boxes_op: "StatefulPartitionedCall:1".to_owned(),
+            classes_op: "StatefulPartitionedCall:2".to_owned(),
+            scores_op: "StatefulPartitionedCall:4".to_owned(),
+            score_threshold: 0.5,
+            iou_threshold: 0.5,
+            max_detections: 100,
+            label_offset: None,
+        }
+    }
+}
+
+/// Bounding box, in coordinates normalized to the image size
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub ymin: f32,
+    pub xmin: f32,
+    pub ymax: f32,
+    pub xmax: f32,
+}
+
+impl BoundingBox {
+    fn area(&self) -> f32 {
+        (self.ymax - self.ymin).max(0.0) * (self.xmax - self.xmin).max(0.0)
+    }
+
+    /// Intersection over union of two boxes
+    pub fn iou(&self, other: &BoundingBox) -> f32 {
+        let intersection = BoundingBox {
+            ymin: self.ymin.max(other.ymin),
+            xmin: self.xmin.max(other.xmin),
+            ymax: self.ymax.min(other.ymax),
+            xmax: self.xmax.min(other.xmax),
+        }
+        .area();
+
+        let union = self.area() + other.area() - intersection;
+
+        if union <= 0.0 {
+            0.0
+        } else {
+            intersection / union
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Detection {
+    /// Class index of the detected object
+    pub index: usize,
+
+    /// Tag of the detected object
+    pub tag: String,
+
+    /// Detection score
+    pub score: f32,
+
+    /// Location of the object in the image
+    pub bbox: BoundingBox,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Detections {
+    /// Detected objects, best first
+    pub detections: Vec<Detection>,
+
+    /// Time spent loading image in memory
+    pub time_image_load: i64,
+
+    /// Time spent on running session
+    pub time_session_run: i64,
+}
+
+impl Detections {
+    /// PNG of `image` with the box and the tag and score of every detection
+    /// drawn on it
+    pub fn to_png(&self, image: &DynamicImage) -> tensorflow::Result<Vec<u8>> {
+        let mut rgb = image.to_rgb();
+        let (width, height) = rgb.dimensions();
+
+        // Worst first, so that the best detections are drawn on top
+        for detection in self.detections.iter().rev() {
+            let bbox = &detection.bbox;
+            let x = |value: f32| {
+                ((value.max(0.0).min(1.0) * width as f32) as u32).min(width.saturating_sub(1))
+            };
+            let y = |value: f32| {
+                ((value.max(0.0).min(1.0) * height as f32) as u32).min(height.saturating_sub(1))
+            };
+            let (x0, y0) = (x(bbox.xmin), y(bbox.ymin));
+
+            let color = annotate::color(detection.index);
+            annotate::draw_box(&mut rgb, (x0, y0), (x(bbox.xmax), y(bbox.ymax)), color);
+
+            // Above the box, or inside it at the top of the image
+            let label_y = y0.checked_sub(annotate::label_height()).unwrap_or(y0);
+            let label = format!("{} {:.0}%", detection.tag, detection.score * 100.0);
+            annotate::draw_label(&mut rgb, (x0, label_y), &label, color);
+        }
+
+        let mut png: Vec<u8> = vec![];
+        image::png::PNGEncoder::new(&mut png)
+            .encode(&rgb, width, height, ColorType::RGB(8))
+            .map_err(|_| {
+                Status::new_set_lossy(Code::Internal, "Could not encode annotated image")
+            })?;
+
+        Ok(png)
+    }
+}
+
+/// Greedy per-class non-maximum suppression. `detections` must be sorted by
+/// descending score.
+fn non_max_suppression(detections: Vec<Detection>, iou_threshold: f32) -> Vec<Detection> {
+    let mut kept: Vec<Detection> = vec![];
+
+    for detection in detections {
+        let suppressed = kept.iter().any(|other| {
+            other.index == detection.index && other.bbox.iou(&detection.bbox) > iou_threshold
+        });
+
+        if !suppressed {
+            kept.push(detection);
+        }
+    }
+
+    kept
+}
+
+pub struct Detector {
+    /// TensorFlow model graph
+    graph: Graph,
+
+    /// TensorFlow session
+    session: Session,
+
+    /// Tags translation, indexed by class
+    tags: Labels,
+
+    /// Model configuration
+    options: DetectorOptions,
+}
+
+impl Detector {
+    pub fn new(
+        export_dir: &Path,
+        tags_path: &Path,
+        options: &DetectorOptions,
+    ) -> tensorflow::Result<Self> {
+        Detector::with_storage(
+            Arc::new(StorageRegistry::local()),
+            &export_dir.to_string_lossy(),
+            &tags_path.to_string_lossy(),
+            options,
+        )
+    }
+
+    /// Model of `TF_DETECTOR_DIR`, if set, with the labels of
+    /// `TF_DETECTOR_LABELS`, `labels.txt` in it by default, on the device of
+    /// `TF_DEVICE`
+    pub fn from_env() -> tensorflow::Result<Option<Self>> {
+        let export_dir = match std::env::var("TF_DETECTOR_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => return Ok(None),
+        };
+        let tags_path = std::env::var("TF_DETECTOR_LABELS")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| export_dir.join("labels.txt"));
+        let options = DetectorOptions {
+            device: env_parse("TF_DEVICE").unwrap_or_default(),
+            ..Default::default()
+        };
+
+        Detector::new(&export_dir, &tags_path, &options).map(Some)
+    }
+
+    /// Load the model and labels from `storage`
+    pub fn with_storage(
+        storage: Arc<dyn Storage>,
+        export_dir: &str,
+        tags_path: &str,
+        options: &DetectorOptions,
+    ) -> tensorflow::Result<Self> {
+        let mut t = Timer::new_start("Loading detection session");
+
+        let mut graph = Graph::new();
+        let session = SavedModelBundle::load(
+            &options.device.session_options()?,
+            &["serve"],
+            &mut graph,
+            storage.local_dir(export_dir)?,
+        )?
+        .session;
+
+        t.stop();
+
+        let format = LabelFormat::from_path(tags_path);
+        let label_offset = options.label_offset.unwrap_or(match format {
+            LabelFormat::Pbtxt => 0,
+            _ => 1,
+        });
+
+        Ok(Detector {
+            graph,
+            session,
+            tags: Labels::parse_as(&storage.read(tags_path)?, format)?.with_offset(label_offset),
+            options: options.clone(),
+        })
+    }
+
+    /// Class labels of the model
+    pub fn labels(&self) -> &Labels {
+        &self.tags
+    }
+
+    /// Run the model once on a blank image, see `ImageClassifier::warm_up`
+    pub fn warm_up(&self) -> tensorflow::Result<()> {
+        self.detect(&DynamicImage::new_rgb8(32, 32))?;
+
+        Ok(())
+    }
+
+    pub fn detect(&self, image: &DynamicImage) -> tensorflow::Result<Detections> {
+        let mut t = Timer::new_start("Running detection session");
+
+        let rgb = image.to_rgb();
+        let (width, height) = rgb.dimensions();
+
+        let input =
+            Tensor::<u8>::new(&[1, height as u64, width as u64, 3]).with_values(&rgb.into_raw())?;
+
+        let mut args = SessionRunArgs::new();
+
+        let (input_op, input_index) = tensor_by_name(&self.graph, &self.options.input_op)?;
+        args.add_feed(&input_op, input_index, &input);
+
+        let (boxes_op, boxes_index) = tensor_by_name(&self.graph, &self.options.boxes_op)?;
+        let boxes_token = args.request_fetch(&boxes_op, boxes_index);
+
+        let (scores_op, scores_index) = tensor_by_name(&self.graph, &self.options.scores_op)?;
+        let scores_token = args.request_fetch(&scores_op, scores_index);
+
+        let (classes_op, classes_index) = tensor_by_name(&self.graph, &self.options.classes_op)?;
+        let classes_token = args.request_fetch(&classes_op, classes_index);
+
+        self.session.run(&mut args)?;
+
+        let boxes: Tensor<f32> = args.fetch(boxes_token)?;
+        let scores: Tensor<f32> = args.fetch(scores_token)?;
+        let classes: Tensor<f32> = args.fetch(classes_token)?;
+
+        t.stop();
+
+        if boxes.len() < scores.len() * 4 || classes.len() < scores.len() {
+            return Err(Status::new_set_lossy(
+                Code::Internal,
+                "Mismatched detection output tensors",
+            ));
+        }
+
+        let mut candidates = scores
+            .iter()
+            .enumerate()
+            .filter(|(_, score)| **score >= self.options.score_threshold)
+            .map(|(i, &score)| -> tensorflow::Result<Detection> {
+                let index = classes[i] as usize;
+
+                Ok(Detection {
+                    index,
+                    tag: self.tags.get(index).map(str::to_owned).ok_or_else(|| {
+                        Status::new_set_lossy(
+                            Code::OutOfRange,
+                            &format!("No tag for class {}", index),
+                        )
+                    })?,
+                    score,
+                    bbox: BoundingBox {
+                        ymin: boxes[i * 4],
+                        xmin: boxes[i * 4 + 1],
+                        ymax: boxes[i * 4 + 2],
+                        xmax: boxes[i * 4 + 3],
+                    },
+                })
+            })
+            .collect::<tensorflow::Result<Vec<Detection>>>()?;
+
+        candidates.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        let mut detections = non_max_suppression(candidates, self.options.iou_threshold);
+        detections.truncate(self.options.max_detections);
+
+        Ok(Detections {
+            detections,
+            time_session_run: t.duration(),
+            ..Default::default()
+        })
+    }
+
+    pub fn detect_from_raw(&self, data: &[u8]) -> tensorflow::Result<Detections> {
+        let mut t = Timer::new_start("Load image from memory");
+
+        let image = preprocess::decode(data, &ImageLimits::default())?;
+
+        t.stop();
+
+        let mut detections = self.detect(&image)?;
+        detections.time_image_load = t.duration();
+
+        Ok(detections)
+    }
+
+    /// PNG of an image with its detections drawn on it, see
+    /// `Detections::to_png`
+    pub fn annotated_png_from_raw(&self, data: &[u8]) -> tensorflow::Result<Vec<u8>> {
+        let image = preprocess::decode(data, &ImageLimits::default())?;
+
+        self.detect(&image)?.to_png(&image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detection(index: usize, score: f32, xmin: f32) -> Detection {
+        Detection {
+            index,
+            score,
+            bbox: BoundingBox {
+                ymin: 0.0,
+                xmin,
+                ymax: 1.0,
+                xmax: xmin + 0.5,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn nms() {
+        let unit = BoundingBox {
+            ymin: 0.0,
+            xmin: 0.0,
+            ymax: 1.0,
+            xmax: 1.0,
+        };
+        assert_eq!(unit.iou(&unit), 1.0);
+
+        let detections = vec![
+            detection(1, 0.9, 0.0),
+            detection(1, 0.8, 0.1),
+            detection(2, 0.7, 0.1),
+            detection(1, 0.6, 0.5),
+        ];
+
+        let kept: Vec<(usize, f32)> = non_max_suppression(detections, 0.5)
+            .iter()
+            .map(|d| (d.index, d.score))
+            .collect();
+
+        assert_eq!(kept, vec![(1, 0.9), (2, 0.7), (1, 0.6)]);
+    }
+}
@@ -0,0 +1,144 @@
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::{json, Map, Value};
+
+use crate::Classification;
+
+/// Metrics of classifications in the CloudWatch Embedded Metric Format,
+/// logged to stdout for CloudWatch Logs to extract them without an agent
+pub struct EmfLogger {
+    namespace: String,
+
+    /// Dimensions of every metric, such as the name of the function
+    dimensions: Vec<(String, String)>,
+}
+
+impl EmfLogger {
+    pub fn new(namespace: &str) -> Self {
+        EmfLogger {
+            namespace: namespace.to_owned(),
+            dimensions: vec![],
+        }
+    }
+
+    /// Logger to the namespace of `TF_EMF_NAMESPACE`, if set, with the name
+    /// of the Lambda function as dimension when running on Lambda
+    pub fn from_env() -> Option<Self> {
+        let mut logger = EmfLogger::new(&std::env::var("TF_EMF_NAMESPACE").ok()?);
+
+        if let Ok(function) = std::env::var("AWS_LAMBDA_FUNCTION_NAME") {
+            logger = logger.with_dimension("FunctionName", &function);
+        }
+
+        Some(logger)
+    }
+
+    pub fn with_dimension(mut self, name: &str, value: &str) -> Self {
+        self.dimensions.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Log the inference latency and probability of `classification`, and
+    /// the size of its image when it was uploaded rather than fetched
+    pub fn record(&self, classification: &Classification, image_size: Option<usize>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+
+        let mut stdout = io::stdout();
+        let line = format!("{}\n", self.document(classification, image_size, timestamp));
+        if let Err(err) = stdout.write_all(line.as_bytes()) {
+            warn!("Could not log metrics: {}", err);
+        }
+    }
+
+    fn document(
+        &self,
+        classification: &Classification,
+        image_size: Option<usize>,
+        timestamp: u64,
+    ) -> Value {
+        // Stages are to the microsecond, unlike the timings of old results
+        let inference = classification.stages().get("inference").unwrap_or_else(|| {
+            classification
+                .timings()
+                .iter()
+                .find(|(stage, _)| *stage == "inference")
+                .map_or(0.0, |&(_, duration)| duration as f64)
+        });
+
+        let mut metrics = vec![
+            ("InferenceLatency", "Milliseconds", json!(inference)),
+            ("Probability", "None", json!(classification.probability())),
+        ];
+        if let Some(size) = image_size {
+            metrics.push(("ImageSize", "Bytes", json!(size)));
+        }
+
+        let mut document = Map::new();
+        document.insert(
+            "_aws".to_owned(),
+            json!({
+                "Timestamp": timestamp,
+                "CloudWatchMetrics": [{
+                    "Namespace": self.namespace,
+                    "Dimensions": [self
+                        .dimensions
+                        .iter()
+                        .map(|(name, _)| name)
+                        .collect::<Vec<_>>()],
+                    "Metrics": metrics
+                        .iter()
+                        .map(|(name, unit, _)| json!({ "Name": name, "Unit": unit }))
+                        .collect::<Vec<_>>(),
+                }],
+            }),
+        );
+        for (name, value) in &self.dimensions {
+            document.insert(name.clone(), json!(value));
+        }
+        for (name, _, value) in metrics {
+            document.insert(name.to_owned(), value);
+        }
+        // Searchable in Logs Insights, without the cardinality of a dimension
+        document.insert("Tag".to_owned(), json!(classification.tag()));
+
+        Value::Object(document)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_metrics() {
+        let classification: Classification = serde_json::from_value(json!({
+            "index": 1, "tag": "cat", "probability": 0.5, "predictions": [],
+            "ambiguous": false, "degraded": false, "time_url_fetch": 0,
+            "time_image_load": 3, "time_image_resize": 2, "time_session_run": 40,
+        }))
+        .unwrap();
+
+        let logger = EmfLogger::new("tf-serve").with_dimension("FunctionName", "classify");
+        let document = logger.document(&classification, Some(1024), 1_600_000_000_000);
+
+        assert_eq!(
+            document["_aws"]["CloudWatchMetrics"][0]["Dimensions"],
+            json!([["FunctionName"]])
+        );
+        assert_eq!(
+            document["_aws"]["CloudWatchMetrics"][0]["Metrics"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+        assert_eq!(document["FunctionName"], "classify");
+        assert_eq!(document["InferenceLatency"], 40.0);
+        assert_eq!(document["ImageSize"], 1024);
+        assert_eq!(document["Tag"], "cat");
+    }
+}
@@ -0,0 +1,199 @@
+use std::str::FromStr;
+
+use image::DynamicImage;
+use tensorflow::{Code, Status};
+
+use crate::preprocess;
+use crate::{Classification, ClassifyOptions, ImageClassifier, ImageLimits, Timer};
+
+/// How the probability vectors of ensemble members are combined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregation {
+    /// Average the probabilities of every class
+    Mean,
+
+    /// Every member votes for its best class. The probability of a class is
+    /// the fraction of votes it got.
+    Vote,
+}
+
+impl Default for Aggregation {
+    fn default() -> Self {
+        Aggregation::Mean
+    }
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mean" => Ok(Aggregation::Mean),
+            "vote" => Ok(Aggregation::Vote),
+            _ => Err(format!("Invalid aggregation '{}'", s)),
+        }
+    }
+}
+
+impl Aggregation {
+    /// Combine the probability vectors of all members
+    pub(crate) fn aggregate(&self, outputs: &[Vec<f32>]) -> Vec<f32> {
+        let classes = outputs.first().map_or(0, Vec::len);
+        let mut combined = vec![0f32; classes];
+
+        for output in outputs {
+            match self {
+                Aggregation::Mean => combined
+                    .iter_mut()
+                    .zip(output)
+                    .for_each(|(total, probability)| *total += probability),
+                Aggregation::Vote => {
+                    let best = output
+                        .iter()
+                        .enumerate()
+                        .max_by(|(_, a), (_, b)| {
+                            a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal)
+                        })
+                        .map(|(index, _)| index);
+
+                    if let Some(best) = best {
+                        combined[best] += 1.0;
+                    }
+                }
+            }
+        }
+
+        combined
+            .iter_mut()
+            .for_each(|total| *total /= outputs.len() as f32);
+
+        combined
+    }
+}
+
+/// Several classifiers sharing a label set, combined into a single verdict
+pub struct Ensemble {
+    members: Vec<ImageClassifier>,
+    aggregation: Aggregation,
+}
+
+impl Ensemble {
+    pub fn new(
+        members: Vec<ImageClassifier>,
+        aggregation: Aggregation,
+    ) -> tensorflow::Result<Self> {
+        let classes = match members.first() {
+            None => {
+                return Err(Status::new_set_lossy(
+                    Code::InvalidArgument,
+                    "Ensemble needs at least one classifier",
+                ))
+            }
+            Some(first) => first.labels().len(),
+        };
+
+        if members
+            .iter()
+            .any(|member| member.labels().len() != classes)
+        {
+            return Err(Status::new_set_lossy(
+                Code::InvalidArgument,
+                "Ensemble members must share the same labels",
+            ));
+        }
+
+        Ok(Ensemble {
+            members,
+            aggregation,
+        })
+    }
+
+    pub fn classify(&self, image: &DynamicImage) -> tensorflow::Result<Classification> {
+        self.classify_with_options(image, &ClassifyOptions::default())
+    }
+
+    pub fn classify_with_options(
+        &self,
+        image: &DynamicImage,
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        let mut t = Timer::new_start("Resizing image");
+
+        // Members may expect differently sized or normalized inputs, so every
+        // one of them gets its own copy unless it matches the previous one.
+        let mut inputs: Vec<Vec<f32>> = vec![];
+        for (i, member) in self.members.iter().enumerate() {
+            let input = match i.checked_sub(1).map(|prev| &self.members[prev]) {
+                Some(prev) if prev.preprocessing == member.preprocessing => inputs[i - 1].clone(),
+                _ => member.preprocess(image),
+            };
+            inputs.push(input);
+        }
+
+        t.stop();
+
+        let mut run = Timer::new_start("Running ensemble sessions");
+
+        let outputs = self
+            .members
+            .iter()
+            .zip(&inputs)
+            .map(|(member, input)| {
+                member
+                    .probabilities(input)
+                    .map(|(_, probabilities)| probabilities)
+            })
+            .collect::<tensorflow::Result<Vec<Vec<f32>>>>()?;
+
+        run.stop();
+
+        let first = &self.members[0];
+
+        let probabilities = self.aggregation.aggregate(&outputs);
+
+        let mut classification = first.get_classification(None, probabilities, options)?;
+        classification.time_image_resize = t.duration();
+        classification.time_session_run = run.duration();
+
+        Ok(classification)
+    }
+
+    pub fn classify_from_raw(&self, data: &[u8]) -> tensorflow::Result<Classification> {
+        self.classify_from_raw_with_options(data, &ClassifyOptions::default())
+    }
+
+    pub fn classify_from_raw_with_options(
+        &self,
+        data: &[u8],
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        let mut t = Timer::new_start("Load image from memory");
+
+        let image = preprocess::decode(data, &ImageLimits::default())?;
+
+        t.stop();
+
+        let mut classification = self.classify_with_options(&image, options)?;
+        classification.time_image_load = t.duration();
+
+        Ok(classification)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate() {
+        let outputs = vec![vec![0.6, 0.4], vec![0.2, 0.8], vec![0.7, 0.3]];
+
+        let mean = Aggregation::Mean.aggregate(&outputs);
+        assert!((mean[0] - 0.5).abs() < 1e-6);
+        assert!((mean[1] - 0.5).abs() < 1e-6);
+
+        let votes = Aggregation::Vote.aggregate(&outputs);
+        assert!((votes[0] - 2.0 / 3.0).abs() < 1e-6);
+        assert!((votes[1] - 1.0 / 3.0).abs() < 1e-6);
+    }
+}
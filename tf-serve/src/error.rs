@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use tensorflow::{Code, Status};
+
+/// HTTP status of a failure: 400 for images that do not decode and other
+/// invalid input, 502 for images that could not be fetched, 413 for
+/// oversized payloads, 503 for requests shed under load and 500 for failures
+/// of the model itself
+pub fn http_status(code: Code) -> u16 {
+    match code {
+        Code::InvalidArgument | Code::Unimplemented => 400,
+        Code::Unauthenticated => 401,
+        Code::PermissionDenied => 403,
+        Code::NotFound => 404,
+        Code::ResourceExhausted => 413,
+        Code::Unavailable | Code::DataLoss => 502,
+        Code::Aborted => 503,
+        Code::DeadlineExceeded => 504,
+        _ => 500,
+    }
+}
+
+/// Seconds after which to retry a failure, for the `Retry-After` header, if
+/// retrying may succeed
+pub fn retry_after(code: Code) -> Option<u32> {
+    match code {
+        Code::Aborted => Some(1),
+        _ => None,
+    }
+}
+
+/// Name of a code in error bodies
+pub fn code_name(code: Code) -> &'static str {
+    match code {
+        Code::Cancelled => "cancelled",
+        Code::InvalidArgument => "invalid_argument",
+        Code::DeadlineExceeded => "deadline_exceeded",
+        Code::NotFound => "not_found",
+        Code::AlreadyExists => "already_exists",
+        Code::PermissionDenied => "permission_denied",
+        Code::Unauthenticated => "unauthenticated",
+        Code::ResourceExhausted => "resource_exhausted",
+        Code::FailedPrecondition => "failed_precondition",
+        Code::Aborted => "aborted",
+        Code::OutOfRange => "out_of_range",
+        Code::Unimplemented => "unimplemented",
+        Code::Internal => "internal",
+        Code::Unavailable => "unavailable",
+        Code::DataLoss => "data_loss",
+        _ => "unknown",
+    }
+}
+
+/// Code and message of a failure
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    pub code: String,
+    pub message: String,
+
+    /// ID of the failed request, for it to be found in the logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Body of a failed request, `{"error": {"code": "...", "message": "..."}}`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+}
+
+impl ErrorBody {
+    pub fn new(code: &str, message: &str) -> Self {
+        ErrorBody {
+            error: ErrorDetail {
+                code: code.to_owned(),
+                message: message.to_owned(),
+                request_id: None,
+            },
+        }
+    }
+}
+
+impl From<&Status> for ErrorBody {
+    fn from(status: &Status) -> Self {
+        ErrorBody::new(code_name(status.code()), status.message().unwrap_or(""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_body() {
+        let status = Status::new_set_lossy(Code::Unavailable, "Could not reach S3");
+        assert_eq!(http_status(status.code()), 502);
+
+        let json = serde_json::to_value(ErrorBody::from(&status)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "error": { "code": "unavailable", "message": "Could not reach S3" }
+            })
+        );
+
+        assert_eq!(http_status(Code::InvalidArgument), 400);
+        assert_eq!(http_status(Code::ResourceExhausted), 413);
+        assert_eq!(http_status(Code::Internal), 500);
+        assert_eq!(http_status(Code::Aborted), 503);
+        assert_eq!(retry_after(Code::Aborted), Some(1));
+    }
+}
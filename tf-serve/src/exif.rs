@@ -0,0 +1,126 @@
+//! EXIF orientation of JPEGs, which phones tag photos with instead of
+//! rotating their pixels. Only the orientation tag of the first IFD is read.
+
+use image::DynamicImage;
+
+/// Tag of the orientation in IFD0
+const ORIENTATION: u16 = 0x0112;
+
+/// Orientation of a JPEG, from 1 (upright) to 8, if tagged
+pub(crate) fn orientation(data: &[u8]) -> Option<u16> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xff {
+            return None;
+        }
+
+        let marker = data[pos + 1];
+        match marker {
+            // Fill byte
+            0xff => {
+                pos += 1;
+                continue;
+            }
+            // Start of scan or end of image: no more metadata
+            0xda | 0xd9 => return None,
+            _ => {}
+        }
+
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let segment = data.get(pos + 4..pos + 2 + len)?;
+
+        if marker == 0xe1 && segment.starts_with(b"Exif\0\0") {
+            return tiff_orientation(&segment[6..]);
+        }
+
+        pos += 2 + len;
+    }
+
+    None
+}
+
+/// Orientation tag of the TIFF structure of an EXIF segment
+fn tiff_orientation(tiff: &[u8]) -> Option<u16> {
+    let little_endian = match tiff.get(..2)? {
+        [b'I', b'I'] => true,
+        [b'M', b'M'] => false,
+        _ => return None,
+    };
+
+    let u16_at = |pos: usize| {
+        let bytes = [*tiff.get(pos)?, *tiff.get(pos + 1)?];
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let u32_at = |pos: usize| {
+        let (first, second) = (u16_at(pos)? as u32, u16_at(pos + 2)? as u32);
+        Some(if little_endian {
+            (second << 16) | first
+        } else {
+            (first << 16) | second
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(ORIENTATION))
+        // A single SHORT, at the start of the value field
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// Rotate and flip an image of EXIF `orientation` upright
+pub(crate) fn orient(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Whether an image of EXIF `orientation` is stored with its width and
+/// height swapped
+pub(crate) fn is_transposed(orientation: u16) -> bool {
+    (5..=8).contains(&orientation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    #[test]
+    fn orientation_tag() {
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1, 0x00, 0x22];
+        jpeg.extend_from_slice(b"Exif\0\0");
+        // Big-endian TIFF header, IFD0 at offset 8 with a single entry
+        jpeg.extend_from_slice(&[b'M', b'M', 0x00, 0x2a, 0x00, 0x00, 0x00, 0x08]);
+        jpeg.extend_from_slice(&[0x00, 0x01]);
+        jpeg.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01]);
+        jpeg.extend_from_slice(&[0x00, 0x06, 0x00, 0x00]);
+        jpeg.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0xff, 0xda]);
+
+        assert_eq!(orientation(&jpeg), Some(6));
+        assert_eq!(orientation(&[0xff, 0xd8, 0xff, 0xda, 0x00, 0x02]), None);
+        assert_eq!(orientation(b"\x89PNG\r\n"), None);
+
+        let rotated = orient(DynamicImage::new_rgb8(4, 2), 6);
+        assert_eq!(rotated.dimensions(), (2, 4));
+        assert!(is_transposed(6));
+    }
+}
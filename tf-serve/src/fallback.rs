@@ -0,0 +1,106 @@
+use std::sync::{Arc, RwLock};
+
+use log::warn;
+use tensorflow::{Code, Status};
+
+use crate::{Classification, ClassifyOptions, ImageClassifier, Timer};
+
+/// Whether a failure is down to the request rather than the model, so that
+/// the fallback model would fail the same way
+fn is_request_error(err: &Status) -> bool {
+    matches!(
+        err.code(),
+        Code::InvalidArgument
+            | Code::NotFound
+            | Code::PermissionDenied
+            | Code::ResourceExhausted
+            | Code::DeadlineExceeded
+    )
+}
+
+/// Primary model backed by a small fallback model, which classifies while
+/// the primary is not loaded, e.g. during a rollout, or when it fails.
+/// Classifications of the fallback are flagged `degraded`.
+pub struct DegradableClassifier {
+    primary: RwLock<Option<Arc<ImageClassifier>>>,
+    fallback: ImageClassifier,
+}
+
+impl DegradableClassifier {
+    /// Classifier without a primary model yet
+    pub fn new(fallback: ImageClassifier) -> Self {
+        DegradableClassifier {
+            primary: RwLock::new(None),
+            fallback,
+        }
+    }
+
+    /// Replace the primary model, or unload it with `None`
+    pub fn set_primary(&self, primary: Option<Arc<ImageClassifier>>) {
+        *self.primary.write().unwrap() = primary;
+    }
+
+    /// The primary model, if loaded
+    pub fn primary(&self) -> Option<Arc<ImageClassifier>> {
+        self.primary.read().unwrap().clone()
+    }
+
+    pub fn fallback(&self) -> &ImageClassifier {
+        &self.fallback
+    }
+
+    /// Classify with the primary model, or else the fallback
+    fn with_fallback<F>(&self, classify: F) -> tensorflow::Result<Classification>
+    where
+        F: Fn(&ImageClassifier) -> tensorflow::Result<Classification>,
+    {
+        match self.primary() {
+            None => warn!("Primary model not loaded, classifying with the fallback"),
+            Some(primary) => match classify(&primary) {
+                Err(err) if !is_request_error(&err) => {
+                    warn!(
+                        "Primary model failed ({}), classifying with the fallback",
+                        err
+                    )
+                }
+                result => return result,
+            },
+        }
+
+        let mut classification = classify(&self.fallback)?;
+        classification.degraded = true;
+
+        Ok(classification)
+    }
+
+    pub fn classify_from_raw_with_options(
+        &self,
+        data: &[u8],
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        self.with_fallback(|classifier| classifier.classify_from_raw_with_options(data, options))
+    }
+
+    /// Fetch an image once and classify it, with the storage of the fallback
+    /// model
+    pub fn classify_from_url_with_options(
+        &self,
+        url: &str,
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        options.check_deadline("fetching")?;
+
+        let t = Timer::scoped(&format!("Fetching image from {}", url));
+        let buf = match &options.trace {
+            Some(trace) => self.fallback.storage.read_traced(url, trace)?,
+            None => self.fallback.storage.read(url)?,
+        };
+        let fetch = t.finish();
+
+        let mut classification = self.classify_from_raw_with_options(&buf, options)?;
+        classification.time_url_fetch = fetch as i64;
+        classification.stages.record("fetch", fetch);
+
+        Ok(classification)
+    }
+}
@@ -0,0 +1,153 @@
+//! Frames of animated GIFs. Decoding them as a single image silently keeps
+//! the first frame only, so they are decoded frame by frame instead, for the
+//! frames to classify to be picked per request.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Cursor;
+use std::str::FromStr;
+
+use image::{AnimationDecoder, DynamicImage};
+use serde::{Deserialize, Serialize};
+use tensorflow::{Code, Status};
+
+/// Most frames classified by `FrameSelection::All`, the first ones
+pub const MAX_FRAMES: usize = 32;
+
+/// Frames of animated images to classify
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum FrameSelection {
+    /// The first frame only, as for still images
+    First,
+
+    /// A single frame, by index
+    Index(usize),
+
+    /// Every frame, up to `MAX_FRAMES`, averaging their probabilities
+    All,
+}
+
+impl Default for FrameSelection {
+    fn default() -> Self {
+        FrameSelection::First
+    }
+}
+
+impl FromStr for FrameSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "first" => Ok(FrameSelection::First),
+            "all" => Ok(FrameSelection::All),
+            other => other
+                .parse()
+                .map(FrameSelection::Index)
+                .map_err(|_| format!("Invalid frame selection '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for FrameSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameSelection::First => write!(f, "first"),
+            FrameSelection::Index(index) => write!(f, "{}", index),
+            FrameSelection::All => write!(f, "all"),
+        }
+    }
+}
+
+impl From<FrameSelection> for String {
+    fn from(selection: FrameSelection) -> Self {
+        selection.to_string()
+    }
+}
+
+impl TryFrom<String> for FrameSelection {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Frames of an animated image a classification is of
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FrameReport {
+    /// Frames in the image
+    pub count: usize,
+
+    /// How the classified frames were picked
+    pub selection: FrameSelection,
+
+    /// Indices of the classified frames
+    pub classified: Vec<usize>,
+}
+
+/// Whether `data` starts like a GIF
+pub(crate) fn is_gif(data: &[u8]) -> bool {
+    data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")
+}
+
+/// Frames of a GIF picked by `selection`, with their index, and the number
+/// of frames in it
+pub(crate) fn decode_gif(
+    data: &[u8],
+    selection: FrameSelection,
+) -> tensorflow::Result<(Vec<(usize, DynamicImage)>, usize)> {
+    let invalid = || Status::new_set_lossy(Code::InvalidArgument, "Could not decode GIF frames");
+
+    let decoder = image::gif::Decoder::new(Cursor::new(data)).map_err(|_| invalid())?;
+
+    let mut frames = vec![];
+    let mut count = 0;
+    for (index, frame) in decoder.into_frames().enumerate() {
+        let frame = frame.map_err(|_| invalid())?;
+        count = index + 1;
+
+        let picked = match selection {
+            FrameSelection::First => index == 0,
+            FrameSelection::Index(picked) => index == picked,
+            FrameSelection::All => index < MAX_FRAMES,
+        };
+        if picked {
+            frames.push((index, DynamicImage::ImageRgba8(frame.into_buffer())));
+        }
+    }
+
+    if frames.is_empty() {
+        return Err(Status::new_set_lossy(
+            Code::InvalidArgument,
+            &format!("No frame {} in an image of {} frames", selection, count),
+        ));
+    }
+
+    Ok((frames, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_selection() {
+        assert_eq!("first".parse(), Ok(FrameSelection::First));
+        assert_eq!("ALL".parse(), Ok(FrameSelection::All));
+        assert_eq!("3".parse(), Ok(FrameSelection::Index(3)));
+        assert!("last".parse::<FrameSelection>().is_err());
+
+        assert_eq!(
+            serde_json::to_value(FrameSelection::Index(3)).unwrap(),
+            serde_json::json!("3")
+        );
+        assert_eq!(
+            serde_json::from_value::<FrameSelection>(serde_json::json!("all")).unwrap(),
+            FrameSelection::All
+        );
+
+        assert!(is_gif(b"GIF89a\x01\x00"));
+        assert!(decode_gif(b"GIF89a", FrameSelection::First).is_err());
+    }
+}
@@ -0,0 +1,513 @@
+use std::io::Read;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use log::warn;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ETAG, LAST_MODIFIED, USER_AGENT};
+use reqwest::StatusCode;
+use tensorflow::{Code, Status};
+
+use crate::{
+    env_parse, Alert, AlertSink, Storage, TraceContext, Validators, DEFAULT_MAX_BODY_SIZE,
+};
+
+/// User agent of outbound requests, unless configured otherwise
+const DEFAULT_USER_AGENT: &str = concat!("tf-serve/", env!("CARGO_PKG_VERSION"));
+
+/// Configuration of outbound HTTP requests
+#[derive(Clone, Debug)]
+pub struct HttpOptions {
+    /// User-Agent header sent with every request
+    pub user_agent: Option<String>,
+
+    /// Additional headers sent with every request
+    pub headers: Vec<(String, String)>,
+
+    /// Maximum time to establish a connection
+    pub connect_timeout: Option<Duration>,
+
+    /// Maximum time of a whole request, reqwest's 30 seconds if unset
+    pub timeout: Option<Duration>,
+
+    /// Largest accepted response body, in bytes
+    pub max_size: Option<usize>,
+
+    /// Number of times a request is retried after a connection failure or a
+    /// server error
+    pub retries: u32,
+
+    /// Delay before the first retry, doubled on every following one
+    pub retry_backoff: Duration,
+
+    /// Refuse URLs resolving to loopback, private, link-local or otherwise
+    /// internal addresses, for servers fetching user-supplied URLs
+    pub block_private: bool,
+
+    /// Only fetch from these hosts and their subdomains, if not empty
+    pub allowed_hosts: Vec<String>,
+
+    /// Never fetch from these hosts and their subdomains
+    pub denied_hosts: Vec<String>,
+
+    /// `s3://` and `gs://` buckets images may be read from, with the
+    /// credentials of the environment. None if empty.
+    pub buckets: Vec<String>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            user_agent: None,
+            headers: vec![],
+            connect_timeout: None,
+            timeout: None,
+            max_size: None,
+            retries: 0,
+            retry_backoff: Duration::from_millis(100),
+            block_private: false,
+            allowed_hosts: vec![],
+            denied_hosts: vec![],
+            buckets: vec![],
+        }
+    }
+}
+
+impl HttpOptions {
+    /// Options of the image fetches of servers, bounded like their requests
+    /// by `TF_MAX_BODY_SIZE` and `TF_REQUEST_TIMEOUT_MS`, and refusing
+    /// internal addresses unless `TF_BLOCK_PRIVATE_ADDRESSES` is 0. Images
+    /// are read from the comma-separated `TF_IMAGE_BUCKETS` too.
+    pub fn from_env() -> Self {
+        HttpOptions {
+            buckets: std::env::var("TF_IMAGE_BUCKETS")
+                .map(|buckets| {
+                    buckets
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|bucket| !bucket.is_empty())
+                        .map(str::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            timeout: env_parse("TF_REQUEST_TIMEOUT_MS").map(Duration::from_millis),
+            max_size: Some(env_parse("TF_MAX_BODY_SIZE").unwrap_or(DEFAULT_MAX_BODY_SIZE)),
+            block_private: std::env::var("TF_BLOCK_PRIVATE_ADDRESSES")
+                .map_or(true, |value| value != "0"),
+            ..Default::default()
+        }
+    }
+}
+
+/// Whether `ip` is not a public unicast address
+fn is_internal(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+                || octets[0] == 0
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local, fc00::/7
+                || (segments[0] & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10
+                || (segments[0] & 0xffc0) == 0xfe80
+                // IPv4-mapped, ::ffff:0:0/96
+                || match segments {
+                    [0, 0, 0, 0, 0, 0xffff, hi, lo] => is_internal(&IpAddr::V4(
+                        [(hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8].into(),
+                    )),
+                    _ => false,
+                }
+        }
+    }
+}
+
+/// Whether `host` is `domain` or one of its subdomains
+fn host_matches(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_start_matches('.');
+
+    host.eq_ignore_ascii_case(domain)
+        || (host.len() > domain.len()
+            && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+            && host.as_bytes()[host.len() - domain.len() - 1] == b'.')
+}
+
+/// Hosts outbound requests may go to, guarding against server-side request
+/// forgery. Addresses are checked on every request and redirect, though a
+/// host changing its DNS answer between the check and the connection can
+/// still get through.
+#[derive(Clone, Debug, Default)]
+struct HostFilter {
+    block_private: bool,
+    allowed_hosts: Vec<String>,
+    denied_hosts: Vec<String>,
+}
+
+impl HostFilter {
+    fn is_enabled(&self) -> bool {
+        self.block_private || !self.allowed_hosts.is_empty() || !self.denied_hosts.is_empty()
+    }
+
+    fn check(&self, url: &reqwest::Url) -> Result<(), String> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| "URL without host".to_owned())?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        if self
+            .denied_hosts
+            .iter()
+            .any(|denied| host_matches(host, denied))
+        {
+            return Err(format!("Host '{}' is denied", host));
+        }
+
+        if !self.allowed_hosts.is_empty()
+            && !self
+                .allowed_hosts
+                .iter()
+                .any(|allowed| host_matches(host, allowed))
+        {
+            return Err(format!("Host '{}' is not allowed", host));
+        }
+
+        if self.block_private {
+            let port = url.port_or_known_default().unwrap_or(80);
+            let addrs = (host, port)
+                .to_socket_addrs()
+                .map_err(|_| format!("Could not resolve '{}'", host))?;
+
+            for addr in addrs {
+                if is_internal(&addr.ip()) {
+                    return Err(format!("Host '{}' resolves to internal address", host));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// HTTP(S) URLs. The client, and so its pool of kept-alive connections, is
+/// shared by all requests.
+pub struct HttpStorage {
+    client: reqwest::Client,
+    max_size: Option<usize>,
+    retries: u32,
+    retry_backoff: Duration,
+    filter: HostFilter,
+}
+
+impl Default for HttpStorage {
+    fn default() -> Self {
+        HttpStorage::with_options(&HttpOptions::default()).expect("Invalid default HTTP options")
+    }
+}
+
+impl HttpStorage {
+    pub fn new(client: reqwest::Client) -> Self {
+        let defaults = HttpOptions::default();
+
+        HttpStorage {
+            client,
+            max_size: defaults.max_size,
+            retries: defaults.retries,
+            retry_backoff: defaults.retry_backoff,
+            filter: HostFilter::default(),
+        }
+    }
+
+    pub fn with_options(options: &HttpOptions) -> tensorflow::Result<Self> {
+        let invalid = |name: &str| {
+            Status::new_set_lossy(
+                Code::InvalidArgument,
+                &format!("Invalid HTTP header '{}'", name),
+            )
+        };
+
+        let mut headers = HeaderMap::new();
+
+        let user_agent = options.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT);
+        headers.insert(
+            USER_AGENT,
+            HeaderValue::from_str(user_agent).map_err(|_| invalid("User-Agent"))?,
+        );
+
+        for (name, value) in &options.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid(name))?,
+                HeaderValue::from_str(value).map_err(|_| invalid(name))?,
+            );
+        }
+
+        let filter = HostFilter {
+            block_private: options.block_private,
+            allowed_hosts: options.allowed_hosts.clone(),
+            denied_hosts: options.denied_hosts.clone(),
+        };
+
+        let mut builder = reqwest::Client::builder().default_headers(headers);
+
+        if filter.is_enabled() {
+            let filter = filter.clone();
+            builder = builder.redirect(reqwest::RedirectPolicy::custom(move |attempt| {
+                if attempt.previous().len() >= 10 {
+                    attempt.too_many_redirects()
+                } else if filter.check(attempt.url()).is_err() {
+                    attempt.stop()
+                } else {
+                    attempt.follow()
+                }
+            }));
+        }
+
+        if let Some(timeout) = options.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        if let Some(timeout) = options.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        let client = builder
+            .build()
+            .map_err(|_| Status::new_set_lossy(Code::Internal, "Could not create HTTP client"))?;
+
+        Ok(HttpStorage {
+            client,
+            max_size: options.max_size,
+            retries: options.retries,
+            retry_backoff: options.retry_backoff,
+            filter,
+        })
+    }
+
+    /// Underlying client, to share its connection pool
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Send a GET request with additional `headers`, retrying connection
+    /// failures and server errors. `304 Not Modified` responses to
+    /// conditional requests are returned as they are.
+    fn get(
+        &self,
+        location: &str,
+        headers: &[(&str, String)],
+    ) -> tensorflow::Result<reqwest::Response> {
+        let url = reqwest::Url::parse(location)
+            .map_err(|_| Status::new_set_lossy(Code::InvalidArgument, "Invalid URL"))?;
+
+        if self.filter.is_enabled() {
+            self.filter
+                .check(&url)
+                .map_err(|reason| Status::new_set_lossy(Code::PermissionDenied, &reason))?;
+        }
+
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+
+        loop {
+            let mut request = self.client.get(url.clone());
+            for (name, value) in headers {
+                request = request.header(*name, value.as_str());
+            }
+
+            let retryable = match request.send() {
+                Ok(resp) if resp.status().is_server_error() => {
+                    format!("server error {}", resp.status())
+                }
+                Ok(resp)
+                    if !resp.status().is_success() && resp.status() != StatusCode::NOT_MODIFIED =>
+                {
+                    return Err(Status::new_set_lossy(
+                        Code::Unavailable,
+                        &format!("Could not fetch URL: {}", resp.status()),
+                    ))
+                }
+                Ok(resp) => return Ok(resp),
+                Err(err) => err.to_string(),
+            };
+
+            if attempt >= self.retries {
+                return Err(Status::new_set_lossy(
+                    Code::Unavailable,
+                    &format!("Could not fetch URL: {}", retryable),
+                ));
+            }
+
+            warn!(
+                "Fetching '{}' failed ({}), retrying in {:?}",
+                location, retryable, backoff
+            );
+
+            thread::sleep(backoff);
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// Read the whole response body, up to `max_size`
+    fn fetch(&self, location: &str, headers: &[(&str, String)]) -> tensorflow::Result<Vec<u8>> {
+        let resp = self.get(location, headers)?;
+        self.body(resp)
+    }
+
+    /// Read the whole body of `resp`, up to `max_size`
+    fn body(&self, resp: reqwest::Response) -> tensorflow::Result<Vec<u8>> {
+        read_body(resp, self.max_size)
+    }
+}
+
+/// Read the whole body of `resp`, up to `max_size` bytes
+pub(crate) fn read_body(
+    resp: reqwest::Response,
+    max_size: Option<usize>,
+) -> tensorflow::Result<Vec<u8>> {
+    let too_large = |max_size: usize| {
+        Status::new_set_lossy(
+            Code::ResourceExhausted,
+            &format!("Response larger than {} bytes", max_size),
+        )
+    };
+
+    if let (Some(max_size), Some(len)) = (max_size, resp.content_length()) {
+        if len > max_size as u64 {
+            return Err(too_large(max_size));
+        }
+    }
+
+    // Read one byte past the limit to tell a body of exactly `max_size`
+    // bytes from a larger one
+    let limit = max_size.map_or(u64::MAX, |max_size| max_size as u64 + 1);
+
+    let mut buf: Vec<u8> = vec![];
+    resp.take(limit)
+        .read_to_end(&mut buf)
+        .map_err(|_| Status::new_set_lossy(Code::DataLoss, "Could not read image from URL"))?;
+
+    match max_size {
+        Some(max_size) if buf.len() > max_size => Err(too_large(max_size)),
+        _ => Ok(buf),
+    }
+}
+
+impl Storage for HttpStorage {
+    fn read(&self, location: &str) -> tensorflow::Result<Vec<u8>> {
+        self.fetch(location, &[])
+    }
+
+    fn read_traced(&self, location: &str, trace: &TraceContext) -> tensorflow::Result<Vec<u8>> {
+        self.fetch(location, &trace.headers())
+    }
+
+    fn read_if_modified(
+        &self,
+        location: &str,
+        validators: &Validators,
+        trace: Option<&TraceContext>,
+    ) -> tensorflow::Result<Option<(Vec<u8>, Validators)>> {
+        let mut headers = trace.map(TraceContext::headers).unwrap_or_default();
+        if let Some(etag) = &validators.etag {
+            headers.push(("if-none-match", etag.clone()));
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            headers.push(("if-modified-since", last_modified.clone()));
+        }
+
+        let resp = self.get(location, &headers)?;
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+
+        let header = |name: HeaderName| {
+            resp.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let validators = Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+
+        Ok(Some((self.body(resp)?, validators)))
+    }
+}
+
+/// Alerts POSTed as JSON to a webhook
+pub struct WebhookAlerts {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookAlerts {
+    pub fn new(client: reqwest::Client, url: &str) -> Self {
+        WebhookAlerts {
+            client,
+            url: url.to_owned(),
+        }
+    }
+}
+
+impl AlertSink for WebhookAlerts {
+    fn send(&self, alert: &Alert) -> tensorflow::Result<()> {
+        let resp = self
+            .client
+            .post(&self.url)
+            .json(alert)
+            .send()
+            .map_err(|_| Status::new_set_lossy(Code::Unavailable, "Could not reach webhook"))?;
+
+        if !resp.status().is_success() {
+            return Err(Status::new_set_lossy(
+                Code::Unavailable,
+                &format!("Webhook returned {}", resp.status()),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_filter() {
+        let filter = HostFilter {
+            block_private: true,
+            allowed_hosts: vec![],
+            denied_hosts: vec!["internal.example.com".to_owned()],
+        };
+
+        let check = |url: &str| filter.check(&reqwest::Url::parse(url).unwrap());
+
+        assert!(check("http://127.0.0.1/image.jpg").is_err());
+        assert!(check("http://169.254.169.254/latest/meta-data").is_err());
+        assert!(check("http://10.1.2.3:8080/").is_err());
+        assert!(check("http://[::1]/").is_err());
+        assert!(check("http://[::ffff:192.168.0.1]/").is_err());
+        assert!(check("http://a.internal.example.com/").is_err());
+        assert!(check("http://93.184.216.34/image.jpg").is_ok());
+
+        assert!(host_matches("images.example.com", "example.com"));
+        assert!(!host_matches("badexample.com", "example.com"));
+    }
+}
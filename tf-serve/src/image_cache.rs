@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::Validators;
+
+struct Entry {
+    data: Arc<Vec<u8>>,
+    validators: Validators,
+    size: usize,
+
+    /// Value of the use counter when last used
+    used: u64,
+}
+
+struct State {
+    entries: HashMap<String, Entry>,
+    size: usize,
+    uses: u64,
+}
+
+/// Images fetched from URLs, as fetched, revalidated with their `ETag` or
+/// `Last-Modified` date on every use instead of downloaded again. Images
+/// without either are not kept. The least recently used images are evicted
+/// beyond `max_size` bytes.
+///
+/// A cache can be shared by several classifiers, so that comparing models on
+/// the same images downloads each of them once.
+pub struct ImageCache {
+    max_size: usize,
+    state: Mutex<State>,
+}
+
+impl ImageCache {
+    pub fn new(max_size: usize) -> Self {
+        ImageCache {
+            max_size,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                size: 0,
+                uses: 0,
+            }),
+        }
+    }
+
+    /// Image fetched from `url`, with the validators to revalidate it with
+    pub(crate) fn get(&self, url: &str) -> Option<(Arc<Vec<u8>>, Validators)> {
+        let mut state = self.state.lock().unwrap();
+        state.uses += 1;
+        let uses = state.uses;
+
+        state.entries.get_mut(url).map(|entry| {
+            entry.used = uses;
+            (entry.data.clone(), entry.validators.clone())
+        })
+    }
+
+    /// Keep the image fetched from `url`, if it can be revalidated and fits
+    pub(crate) fn insert(&self, url: &str, data: Arc<Vec<u8>>, validators: Validators) {
+        let size = data.len();
+        if validators.is_empty() || size > self.max_size {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.uses += 1;
+        let uses = state.uses;
+
+        if let Some(entry) = state.entries.remove(url) {
+            state.size -= entry.size;
+        }
+
+        while state.size + size > self.max_size {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(url, _)| url.clone());
+
+            match oldest.and_then(|oldest| state.entries.remove(&oldest)) {
+                Some(entry) => state.size -= entry.size,
+                None => break,
+            }
+        }
+
+        state.size += size;
+        state.entries.insert(
+            url.to_owned(),
+            Entry {
+                data,
+                validators,
+                size,
+                used: uses,
+            },
+        );
+    }
+
+    /// Number of images kept
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for ImageCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+
+        f.debug_struct("ImageCache")
+            .field("max_size", &self.max_size)
+            .field("size", &state.size)
+            .field("images", &state.entries.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_bounded() {
+        // Room for two images of 1 KiB
+        let cache = ImageCache::new(2 << 10);
+        let image = || Arc::new(vec![0u8; 1 << 10]);
+        let validators = Validators {
+            etag: Some("\"v1\"".to_owned()),
+            last_modified: None,
+        };
+
+        cache.insert("https://example.com/a.jpg", image(), validators.clone());
+        cache.insert("https://example.com/b.jpg", image(), validators.clone());
+        assert!(cache.get("https://example.com/a.jpg").is_some());
+
+        // "b" is the least recently used
+        cache.insert("https://example.com/c.jpg", image(), validators.clone());
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("https://example.com/b.jpg").is_none());
+
+        let (_, cached) = cache.get("https://example.com/c.jpg").unwrap();
+        assert_eq!(cached, validators);
+
+        // Nothing to revalidate with
+        cache.insert("https://example.com/d.jpg", image(), Validators::default());
+        assert!(cache.get("https://example.com/d.jpg").is_none());
+    }
+}
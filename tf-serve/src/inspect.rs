@@ -0,0 +1,201 @@
+//! Signatures of SavedModels, for finding the tensor names to configure
+//! without Python's `saved_model_cli`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+use tensorflow::{Code, Graph, SavedModelBundle, SessionOptions, Status, TensorInfo};
+
+/// Tag sets and signatures of a SavedModel
+#[derive(Clone, Debug, Serialize)]
+pub struct ModelInfo {
+    pub meta_graphs: Vec<MetaGraphInfo>,
+}
+
+/// Signatures of the meta graph of a tag set
+#[derive(Clone, Debug, Serialize)]
+pub struct MetaGraphInfo {
+    pub tags: Vec<String>,
+    pub signatures: Vec<SignatureInfo>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SignatureInfo {
+    pub name: String,
+    pub method_name: String,
+    pub inputs: Vec<TensorSpec>,
+    pub outputs: Vec<TensorSpec>,
+}
+
+/// Input or output of a signature
+#[derive(Clone, Debug, Serialize)]
+pub struct TensorSpec {
+    /// Key of the tensor in the signature
+    pub key: String,
+
+    /// Tensor in the graph, as `operation:index`, the form `input_op` and
+    /// `output_op` take
+    pub name: String,
+
+    pub dtype: String,
+
+    /// Dimensions, `None` where unknown, or `None` altogether if the rank
+    /// is unknown
+    pub shape: Option<Vec<Option<i64>>>,
+}
+
+impl TensorSpec {
+    fn new(key: &str, info: &TensorInfo) -> Self {
+        let shape = info.shape();
+
+        TensorSpec {
+            key: key.to_owned(),
+            name: format!("{}:{}", info.name().name, info.name().index),
+            dtype: format!("{:?}", info.dtype()),
+            shape: shape
+                .dims()
+                .map(|dims| (0..dims).map(|i| shape[i]).collect()),
+        }
+    }
+}
+
+/// Load every meta graph of the SavedModel in `export_dir` and list its
+/// signatures
+pub fn inspect_model(export_dir: &Path) -> tensorflow::Result<ModelInfo> {
+    let data = fs::read(export_dir.join("saved_model.pb")).map_err(|err| {
+        Status::new_set_lossy(
+            Code::NotFound,
+            &format!("Could not read saved_model.pb: {}", err),
+        )
+    })?;
+
+    let tag_sets = tag_sets(&data)
+        .ok_or_else(|| Status::new_set_lossy(Code::InvalidArgument, "Invalid saved_model.pb"))?;
+
+    let meta_graphs = tag_sets
+        .into_iter()
+        .map(|tags| {
+            let mut graph = Graph::new();
+            let bundle =
+                SavedModelBundle::load(&SessionOptions::new(), &tags, &mut graph, export_dir)?;
+
+            let mut signatures: Vec<SignatureInfo> = bundle
+                .meta_graph_def()
+                .signatures()
+                .iter()
+                .map(|(name, signature)| {
+                    let specs = |tensors: &HashMap<String, TensorInfo>| {
+                        let mut specs: Vec<TensorSpec> = tensors
+                            .iter()
+                            .map(|(key, info)| TensorSpec::new(key, info))
+                            .collect();
+                        specs.sort_by(|a, b| a.key.cmp(&b.key));
+                        specs
+                    };
+
+                    SignatureInfo {
+                        name: name.clone(),
+                        method_name: signature.method_name().to_owned(),
+                        inputs: specs(signature.inputs()),
+                        outputs: specs(signature.outputs()),
+                    }
+                })
+                .collect();
+            signatures.sort_by(|a, b| a.name.cmp(&b.name));
+
+            Ok(MetaGraphInfo { tags, signatures })
+        })
+        .collect::<tensorflow::Result<Vec<_>>>()?;
+
+    Ok(ModelInfo { meta_graphs })
+}
+
+/// Tags of every meta graph of a `saved_model.pb`, read straight from the
+/// protocol buffer, as TensorFlow only loads a tag set it is given
+fn tag_sets(data: &[u8]) -> Option<Vec<Vec<String>>> {
+    // SavedModel.meta_graphs
+    fields(data)?
+        .into_iter()
+        .filter(|&(field, _)| field == 2)
+        .map(|(_, meta_graph)| {
+            // MetaGraphDef.meta_info_def
+            let info = fields(meta_graph)?
+                .into_iter()
+                .find(|&(field, _)| field == 1)
+                .map_or(&[][..], |(_, info)| info);
+
+            // MetaInfoDef.tags
+            Some(
+                fields(info)?
+                    .into_iter()
+                    .filter(|&(field, _)| field == 4)
+                    .map(|(_, tag)| String::from_utf8_lossy(tag).into_owned())
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// Numbers and values of the length-delimited fields of a protocol buffer
+/// message, skipping the others
+fn fields(mut data: &[u8]) -> Option<Vec<(u64, &[u8])>> {
+    let mut fields = vec![];
+
+    while !data.is_empty() {
+        let key = varint(&mut data)?;
+
+        match key & 7 {
+            0 => {
+                varint(&mut data)?;
+            }
+            1 => data = data.get(8..)?,
+            2 => {
+                let len = varint(&mut data)? as usize;
+                fields.push((key >> 3, data.get(..len)?));
+                data = &data[len..];
+            }
+            5 => data = data.get(4..)?,
+            _ => return None,
+        }
+    }
+
+    Some(fields)
+}
+
+fn varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_model_tags() {
+        // meta_info_def { tags: "serve" tags: "gpu" }
+        let info = [&[0x22, 5][..], b"serve", &[0x22, 3], b"gpu"].concat();
+        let meta_graph = [&[0x0a, info.len() as u8][..], &info].concat();
+        // saved_model_schema_version: 1, then the meta graph
+        let saved_model = [&[0x08, 1, 0x12, meta_graph.len() as u8][..], &meta_graph].concat();
+
+        assert_eq!(
+            tag_sets(&saved_model),
+            Some(vec![vec!["serve".to_owned(), "gpu".to_owned()]])
+        );
+        assert_eq!(tag_sets(&[0x12, 10]), None);
+    }
+}
@@ -0,0 +1,331 @@
+//! Asynchronous batch jobs, for workloads outlasting the timeouts of
+//! gateways: submitted at once, then polled for their progress and results.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "redis")]
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::Identity;
+
+/// Time jobs are kept for after being submitted, by default
+pub const DEFAULT_JOB_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+}
+
+/// Progress of a batch job
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+
+    /// Images of the job
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+
+    /// Submission time, in seconds since the epoch
+    pub created_at: u64,
+
+    /// Completion time, in seconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+
+    /// Subject of the caller that submitted the job, the only one allowed
+    /// to read it, if callers are identified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+impl Job {
+    /// Queued job of `total` images
+    pub fn new(id: &str, total: usize) -> Self {
+        Job {
+            id: id.to_owned(),
+            status: JobStatus::Queued,
+            total,
+            succeeded: 0,
+            failed: 0,
+            created_at: now(),
+            finished_at: None,
+            owner: None,
+        }
+    }
+
+    /// Whether the caller of `identity` may read the job: its owner, or
+    /// anyone if it has none
+    pub fn is_visible_to(&self, identity: Option<&Identity>) -> bool {
+        match &self.owner {
+            Some(owner) => identity.map_or(false, |identity| &identity.subject == owner),
+            None => true,
+        }
+    }
+
+    /// Images classified so far, successfully or not
+    pub fn done(&self) -> usize {
+        self.succeeded + self.failed
+    }
+
+    pub(crate) fn finish(&mut self) {
+        self.status = JobStatus::Completed;
+        self.finished_at = Some(now());
+    }
+}
+
+/// Seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Keeps jobs and their results, as JSON, until they expire
+pub trait JobStore: Send + Sync {
+    /// Store a job, replacing that of the same ID
+    fn put(&self, job: &Job) -> tensorflow::Result<()>;
+
+    fn get(&self, id: &str) -> tensorflow::Result<Option<Job>>;
+
+    /// Append results of a job, in the order of its images
+    fn append_results(&self, id: &str, results: &[String]) -> tensorflow::Result<()>;
+
+    /// Results of a job so far
+    fn results(&self, id: &str) -> tensorflow::Result<Vec<String>>;
+}
+
+/// Store configured by the environment: Redis at `JOBS_REDIS_URL` with the
+/// `redis` feature, otherwise memory, keeping jobs for `JOBS_TTL_SECS`
+pub(crate) fn job_store_from_env() -> Arc<dyn JobStore> {
+    let ttl = std::env::var("JOBS_TTL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map_or(DEFAULT_JOB_TTL, Duration::from_secs);
+
+    #[cfg(feature = "redis")]
+    {
+        if let Ok(url) = std::env::var("JOBS_REDIS_URL") {
+            match RedisJobStore::new(&url, ttl) {
+                Ok(store) => return Arc::new(store),
+                Err(err) => warn!("Keeping jobs in memory: {}", err),
+            }
+        }
+    }
+
+    Arc::new(MemoryJobStore::new(ttl))
+}
+
+/// Jobs in memory, lost when the process exits
+pub struct MemoryJobStore {
+    ttl: Duration,
+    jobs: RwLock<HashMap<String, (Job, Vec<String>)>>,
+}
+
+impl MemoryJobStore {
+    pub fn new(ttl: Duration) -> Self {
+        MemoryJobStore {
+            ttl,
+            jobs: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl Default for MemoryJobStore {
+    fn default() -> Self {
+        MemoryJobStore::new(DEFAULT_JOB_TTL)
+    }
+}
+
+impl JobStore for MemoryJobStore {
+    fn put(&self, job: &Job) -> tensorflow::Result<()> {
+        let mut jobs = self.jobs.write().unwrap();
+
+        // Expired jobs are dropped as new ones come
+        let oldest = now().saturating_sub(self.ttl.as_secs());
+        jobs.retain(|_, (job, _)| job.created_at >= oldest);
+
+        jobs.entry(job.id.clone())
+            .or_insert_with(|| (job.clone(), vec![]))
+            .0 = job.clone();
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> tensorflow::Result<Option<Job>> {
+        Ok(self
+            .jobs
+            .read()
+            .unwrap()
+            .get(id)
+            .map(|(job, _)| job.clone()))
+    }
+
+    fn append_results(&self, id: &str, results: &[String]) -> tensorflow::Result<()> {
+        if let Some((_, stored)) = self.jobs.write().unwrap().get_mut(id) {
+            stored.extend_from_slice(results);
+        }
+        Ok(())
+    }
+
+    fn results(&self, id: &str) -> tensorflow::Result<Vec<String>> {
+        Ok(self
+            .jobs
+            .read()
+            .unwrap()
+            .get(id)
+            .map(|(_, results)| results.clone())
+            .unwrap_or_default())
+    }
+}
+
+/// Jobs in Redis, surviving restarts and shared by replicas for polling
+#[cfg(feature = "redis")]
+pub struct RedisJobStore {
+    client: redis::Client,
+    ttl: Duration,
+    connection: std::sync::Mutex<Option<redis::Connection>>,
+}
+
+#[cfg(feature = "redis")]
+impl RedisJobStore {
+    /// Store of the Redis server at `url`, like `redis://host:6379/0`
+    pub fn new(url: &str, ttl: Duration) -> tensorflow::Result<Self> {
+        let client = redis::Client::open(url).map_err(|err| {
+            tensorflow::Status::new_set_lossy(
+                tensorflow::Code::InvalidArgument,
+                &format!("Invalid Redis URL '{}': {}", url, err),
+            )
+        })?;
+
+        Ok(RedisJobStore {
+            client,
+            ttl,
+            connection: std::sync::Mutex::new(None),
+        })
+    }
+
+    /// Run a command on the connection, connecting first if there is none,
+    /// and dropping it on failure for the next command to reconnect
+    fn query<T: redis::FromRedisValue>(&self, cmd: &redis::Cmd) -> tensorflow::Result<T> {
+        let mut connection = self.connection.lock().unwrap();
+
+        let result = match connection.as_mut() {
+            Some(connection) => cmd.query(connection),
+            None => self.client.get_connection().and_then(|mut new| {
+                let result = cmd.query(&mut new);
+                *connection = Some(new);
+                result
+            }),
+        };
+
+        result.map_err(|err| {
+            *connection = None;
+            tensorflow::Status::new_set_lossy(
+                tensorflow::Code::Unavailable,
+                &format!("Redis failed: {}", err),
+            )
+        })
+    }
+
+    fn key(id: &str) -> String {
+        format!("tf-serve:jobs:{}", id)
+    }
+
+    fn results_key(id: &str) -> String {
+        format!("tf-serve:jobs:{}:results", id)
+    }
+}
+
+#[cfg(feature = "redis")]
+impl JobStore for RedisJobStore {
+    fn put(&self, job: &Job) -> tensorflow::Result<()> {
+        let json = serde_json::to_string(job).expect("Jobs serialize to JSON");
+
+        self.query(
+            redis::cmd("SET")
+                .arg(RedisJobStore::key(&job.id))
+                .arg(json)
+                .arg("EX")
+                .arg(self.ttl.as_secs()),
+        )
+    }
+
+    fn get(&self, id: &str) -> tensorflow::Result<Option<Job>> {
+        let json: Option<String> = self.query(redis::cmd("GET").arg(RedisJobStore::key(id)))?;
+
+        Ok(json.and_then(|json| serde_json::from_str(&json).ok()))
+    }
+
+    fn append_results(&self, id: &str, results: &[String]) -> tensorflow::Result<()> {
+        if results.is_empty() {
+            return Ok(());
+        }
+
+        let key = RedisJobStore::results_key(id);
+        self.query(redis::cmd("RPUSH").arg(&key).arg(results))?;
+        self.query(redis::cmd("EXPIRE").arg(&key).arg(self.ttl.as_secs()))
+    }
+
+    fn results(&self, id: &str) -> tensorflow::Result<Vec<String>> {
+        self.query(
+            redis::cmd("LRANGE")
+                .arg(RedisJobStore::results_key(id))
+                .arg(0)
+                .arg(-1),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_store() {
+        let store = MemoryJobStore::default();
+        let mut job = Job::new("abc", 2);
+        store.put(&job).unwrap();
+
+        store.append_results("abc", &["{}".to_owned()]).unwrap();
+        job.succeeded = 1;
+        store.put(&job).unwrap();
+        assert_eq!(store.get("abc").unwrap().unwrap().done(), 1);
+        assert_eq!(store.results("abc").unwrap(), vec!["{}".to_owned()]);
+
+        assert_eq!(
+            serde_json::to_value(&job).unwrap()["status"],
+            serde_json::json!("queued")
+        );
+        assert_eq!(store.get("xyz").unwrap(), None);
+
+        let expired = MemoryJobStore::new(Duration::from_secs(0));
+        expired
+            .put(&Job {
+                created_at: 0,
+                ..job
+            })
+            .unwrap();
+        expired.put(&Job::new("def", 1)).unwrap();
+        assert_eq!(expired.get("abc").unwrap(), None);
+
+        let owned = Job {
+            owner: Some("alice".to_owned()),
+            ..Job::new("ghi", 1)
+        };
+        let identity = |subject: &str| Identity {
+            subject: subject.to_owned(),
+            claims: None,
+        };
+        assert!(owned.is_visible_to(Some(&identity("alice"))));
+        assert!(!owned.is_visible_to(Some(&identity("bob"))));
+        assert!(!owned.is_visible_to(None));
+        assert!(job.is_visible_to(None));
+    }
+}
@@ -0,0 +1,239 @@
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use serde::Deserialize;
+use tensorflow::{Code, Status};
+
+use crate::{AuthProvider, Credentials, Identity};
+
+/// Least time between two fetches of the keys, for tokens signed by unknown
+/// keys or failed fetches, so that such tokens cannot flood the issuer
+const MIN_REFRESH: Duration = Duration::from_secs(30);
+
+/// Checks of the tokens of a `JwtValidator`
+#[derive(Clone, Debug)]
+pub struct JwtOptions {
+    /// URL of the JSON Web Key Set of the issuer, like
+    /// `https://issuer/.well-known/jwks.json`
+    pub jwks_url: String,
+
+    /// Audience tokens must be issued for, so that tokens of the issuer for
+    /// other services are refused
+    pub audience: String,
+
+    /// Issuer tokens must be issued by, if checked
+    pub issuer: Option<String>,
+
+    /// Clock skew tolerated on the expiry and start of tokens
+    pub leeway: Duration,
+
+    /// Time the keys are used for before they are fetched again
+    pub refresh: Duration,
+}
+
+impl JwtOptions {
+    pub fn new(jwks_url: &str, audience: &str) -> Self {
+        JwtOptions {
+            jwks_url: jwks_url.to_owned(),
+            audience: audience.to_owned(),
+            issuer: None,
+            leeway: Duration::from_secs(60),
+            refresh: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Key of a JSON Web Key Set
+#[derive(Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// RSA signing keys of the issuer, by ID
+struct Keys {
+    keys: Vec<(Option<String>, DecodingKey<'static>)>,
+    fetched: Instant,
+}
+
+impl Keys {
+    /// Key of ID `kid`, or the only key for tokens without ID
+    fn find(&self, kid: Option<&str>) -> Option<DecodingKey<'static>> {
+        match kid {
+            Some(kid) => self
+                .keys
+                .iter()
+                .find(|(id, _)| id.as_deref() == Some(kid))
+                .map(|(_, key)| key.clone()),
+            None if self.keys.len() == 1 => Some(self.keys[0].1.clone()),
+            None => None,
+        }
+    }
+}
+
+fn unauthenticated(message: &str) -> Status {
+    Status::new_set_lossy(Code::Unauthenticated, message)
+}
+
+/// Callers identified by the subject of the JWT of their
+/// `Authorization: Bearer` header, signed with RSA by an OIDC issuer, and
+/// carrying its claims
+pub struct JwtValidator {
+    options: JwtOptions,
+    client: reqwest::Client,
+    keys: RwLock<Option<Keys>>,
+
+    /// Time of the last fetch, successful or not, locked while fetching so
+    /// that concurrent callers wait for a single fetch
+    attempted: Mutex<Option<Instant>>,
+}
+
+impl JwtValidator {
+    pub fn new(client: reqwest::Client, options: JwtOptions) -> Self {
+        JwtValidator {
+            options,
+            client,
+            keys: RwLock::new(None),
+            attempted: Mutex::new(None),
+        }
+    }
+
+    /// Fetch the keys of the issuer
+    fn fetch(&self) -> tensorflow::Result<Keys> {
+        let url = &self.options.jwks_url;
+        let unavailable = |err: &dyn std::fmt::Display| {
+            Status::new_set_lossy(
+                Code::Unavailable,
+                &format!("Could not fetch keys from {}: {}", url, err),
+            )
+        };
+
+        let set: JwkSet = self
+            .client
+            .get(url)
+            .send()
+            .and_then(|resp| resp.error_for_status())
+            .and_then(|mut resp| resp.json())
+            .map_err(|err| unavailable(&err))?;
+
+        let keys: Vec<_> = set
+            .keys
+            .into_iter()
+            .filter(|jwk| jwk.kty == "RSA" && jwk.usage.as_deref() != Some("enc"))
+            .filter_map(|jwk| {
+                let key = DecodingKey::from_rsa_components(jwk.n.as_ref()?, jwk.e.as_ref()?);
+                Some((jwk.kid, key.into_static()))
+            })
+            .collect();
+        info!("Fetched {} signing keys from {}", keys.len(), url);
+
+        Ok(Keys {
+            keys,
+            fetched: Instant::now(),
+        })
+    }
+
+    /// Key of ID `kid` among the keys fetched, if any, and whether they are
+    /// not due yet
+    fn cached(&self, kid: Option<&str>) -> (Option<DecodingKey<'static>>, bool) {
+        match &*self.keys.read().unwrap() {
+            Some(keys) => (
+                keys.find(kid),
+                keys.fetched.elapsed() < self.options.refresh,
+            ),
+            None => (None, false),
+        }
+    }
+
+    /// Key of ID `kid`, fetching the keys again once they are due, or for
+    /// an unknown ID
+    fn key(&self, kid: Option<&str>) -> tensorflow::Result<DecodingKey<'static>> {
+        if let (Some(key), true) = self.cached(kid) {
+            return Ok(key);
+        }
+
+        let mut attempted = self.attempted.lock().unwrap();
+
+        // Fetched while waiting for the lock
+        let cached = match self.cached(kid) {
+            (Some(key), true) => return Ok(key),
+            (key, _) => key,
+        };
+
+        if attempted.map_or(false, |attempted| attempted.elapsed() < MIN_REFRESH) {
+            return cached.ok_or_else(|| match &*self.keys.read().unwrap() {
+                Some(_) => unauthenticated("Unknown signing key"),
+                None => Status::new_set_lossy(
+                    Code::Unavailable,
+                    &format!("Keys from {} not fetched yet", self.options.jwks_url),
+                ),
+            });
+        }
+        *attempted = Some(Instant::now());
+
+        match self.fetch() {
+            Ok(keys) => {
+                let key = keys.find(kid);
+                *self.keys.write().unwrap() = Some(keys);
+                key.ok_or_else(|| unauthenticated("Unknown signing key"))
+            }
+            // Keys that were valid until now still are, while the issuer is
+            // unreachable
+            Err(err) => {
+                warn!("{}", err);
+                cached.ok_or(err)
+            }
+        }
+    }
+}
+
+impl AuthProvider for JwtValidator {
+    fn authenticate(&self, credentials: &Credentials) -> tensorflow::Result<Option<Identity>> {
+        // Bearer tokens other than JWTs are left to other providers
+        let token = match credentials.bearer {
+            Some(token) if token.matches('.').count() == 2 => token,
+            _ => return Ok(None),
+        };
+
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|err| unauthenticated(&format!("Invalid token: {}", err)))?;
+        match header.alg {
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 => {}
+            Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => {}
+            alg => {
+                return Err(unauthenticated(&format!(
+                    "Unsupported token algorithm {:?}",
+                    alg
+                )))
+            }
+        }
+
+        let key = self.key(header.kid.as_deref())?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.options.leeway.as_secs();
+        validation.iss = self.options.issuer.clone();
+        validation.set_audience(&[&self.options.audience]);
+
+        let claims = jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation)
+            .map_err(|err| unauthenticated(&format!("Invalid token: {}", err)))?
+            .claims;
+        let subject = claims["sub"].as_str().unwrap_or("unknown").to_owned();
+
+        Ok(Some(Identity {
+            subject,
+            claims: Some(claims),
+        }))
+    }
+}
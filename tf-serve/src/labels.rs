@@ -0,0 +1,454 @@
+use std::cmp::Ordering;
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::Value;
+use tensorflow::{Code, Status};
+
+/// Label matching a prefix search
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LabelMatch {
+    /// Class index of the label
+    pub index: usize,
+
+    /// The label itself
+    pub tag: String,
+}
+
+/// Format of a labels file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LabelFormat {
+    /// One label per line, line number is the class index
+    Text,
+
+    /// JSON object mapping class indices to labels (`{"0": "tench"}`), or a
+    /// JSON array of labels
+    Json,
+
+    /// Tab-separated synset ID and label per line
+    Tsv,
+
+    /// Object detection API `label_map.pbtxt`, with explicit class ids
+    Pbtxt,
+}
+
+impl LabelFormat {
+    /// Detect the format of a labels file from its extension
+    pub fn from_path(path: &str) -> Self {
+        let extension = path.rsplit('.').next().unwrap_or("").to_lowercase();
+
+        match extension.as_str() {
+            "json" => LabelFormat::Json,
+            "tsv" => LabelFormat::Tsv,
+            "pbtxt" => LabelFormat::Pbtxt,
+            _ => LabelFormat::Text,
+        }
+    }
+}
+
+fn invalid_labels(reason: &str) -> Status {
+    Status::new_set_lossy(
+        Code::DataLoss,
+        &format!("Could not read tags file: {}", reason),
+    )
+}
+
+/// Place `(id, label)` pairs at their ids, leaving gaps empty
+fn sparse_labels(entries: Vec<(usize, String)>) -> Vec<String> {
+    let len = entries.iter().map(|(id, _)| id + 1).max().unwrap_or(0);
+    let mut tags = vec![String::new(); len];
+
+    for (id, label) in entries {
+        tags[id] = label;
+    }
+
+    tags
+}
+
+fn parse_json(text: &str) -> tensorflow::Result<Vec<String>> {
+    let value: Value = serde_json::from_str(text).map_err(|_| invalid_labels("invalid JSON"))?;
+
+    match value {
+        Value::Array(labels) => labels
+            .into_iter()
+            .map(|label| match label {
+                Value::String(label) => Ok(label),
+                _ => Err(invalid_labels("labels must be strings")),
+            })
+            .collect(),
+        Value::Object(map) => {
+            let entries = map
+                .into_iter()
+                .map(|(id, label)| match (id.parse(), label) {
+                    (Ok(id), Value::String(label)) => Ok((id, label)),
+                    _ => Err(invalid_labels(
+                        "expected class index keys and string labels",
+                    )),
+                })
+                .collect::<tensorflow::Result<Vec<(usize, String)>>>()?;
+
+            Ok(sparse_labels(entries))
+        }
+        _ => Err(invalid_labels("expected a JSON object or array")),
+    }
+}
+
+/// Parse TSV lines of `synset<TAB>label`, returning labels and synsets
+fn parse_tsv(text: &str) -> (Vec<String>, Vec<String>) {
+    text.lines()
+        .map(|line| match line.find('\t') {
+            Some(pos) => (line[pos + 1..].trim().to_owned(), line[..pos].to_owned()),
+            None => (line.trim().to_owned(), String::new()),
+        })
+        .unzip()
+}
+
+/// Token of a label map: a bare word, a quoted string, or one of `{`, `}`
+/// and `:`
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Punct(char),
+}
+
+/// Split a label map into tokens, dropping comments and the commas and
+/// semicolons that may separate fields
+fn tokenize(text: &str) -> tensorflow::Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => while chars.next().map_or(false, |c| c != '\n') {},
+            '{' | '}' | ':' => tokens.push(Token::Punct(c)),
+            '"' | '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => value.extend(chars.next()),
+                        Some(quote) if quote == c => break,
+                        Some(c) => value.push(c),
+                        None => return Err(invalid_labels("unterminated string in label map")),
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            c if c.is_whitespace() || c == ',' || c == ';' => {}
+            c => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "{}:,;#\"'".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Skip the rest of a nested message, after its opening brace
+fn skip_message(tokens: &mut impl Iterator<Item = Token>) -> tensorflow::Result<()> {
+    let mut depth = 1;
+
+    while depth > 0 {
+        match tokens.next() {
+            Some(Token::Punct('{')) => depth += 1,
+            Some(Token::Punct('}')) => depth -= 1,
+            Some(_) => {}
+            None => return Err(invalid_labels("unterminated label map item")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse the `item { id: ... display_name: ... }` entries of a label map
+fn parse_pbtxt(text: &str) -> tensorflow::Result<Vec<String>> {
+    let malformed = || invalid_labels("malformed label map item");
+    let mut tokens = tokenize(text)?.into_iter().peekable();
+    let mut entries = vec![];
+
+    while let Some(token) = tokens.next() {
+        if token != Token::Word("item".to_owned()) {
+            return Err(malformed());
+        }
+        // Messages may follow a colon in the text format
+        if tokens.peek() == Some(&Token::Punct(':')) {
+            tokens.next();
+        }
+        if tokens.next() != Some(Token::Punct('{')) {
+            return Err(malformed());
+        }
+
+        let mut id = None;
+        let mut name = None;
+        let mut display_name = None;
+
+        loop {
+            let key = match tokens.next() {
+                Some(Token::Punct('}')) => break,
+                Some(Token::Word(key)) => key,
+                _ => return Err(malformed()),
+            };
+
+            let value = match tokens.next() {
+                Some(Token::Punct(':')) => match tokens.next() {
+                    Some(Token::Word(value)) | Some(Token::Str(value)) => Some(value),
+                    Some(Token::Punct('{')) => None,
+                    _ => return Err(malformed()),
+                },
+                Some(Token::Punct('{')) => None,
+                _ => return Err(malformed()),
+            };
+
+            // Nested messages, like the keypoints of a class, are skipped
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    skip_message(&mut tokens)?;
+                    continue;
+                }
+            };
+
+            match key.as_str() {
+                "id" => id = value.parse::<usize>().ok(),
+                "name" => name = Some(value),
+                "display_name" => display_name = Some(value),
+                _ => {}
+            }
+        }
+
+        match (id, display_name.or(name)) {
+            (Some(id), Some(label)) => entries.push((id, label)),
+            _ => return Err(invalid_labels("label map item without id or name")),
+        }
+    }
+
+    Ok(sparse_labels(entries))
+}
+
+/// Class labels of a model, with an index for prefix lookups
+pub struct Labels {
+    /// Labels, indexed by class
+    tags: Vec<String>,
+
+    /// Synset IDs, indexed by class, if the labels file has them
+    synsets: Vec<String>,
+
+    /// Class index of the first label, e.g. 1 for models with a leading
+    /// "background" class missing from the labels file
+    offset: usize,
+
+    /// Lowercase words of every label along with its class, sorted
+    tokens: Vec<(String, usize)>,
+}
+
+impl Labels {
+    pub fn new(tags: Vec<String>) -> Self {
+        let mut tokens: Vec<(String, usize)> = tags
+            .iter()
+            .enumerate()
+            .filter(|(_, tag)| !tag.is_empty())
+            .flat_map(|(index, tag)| {
+                let tag = tag.to_lowercase();
+                let words: Vec<(String, usize)> = tag
+                    .split(|c: char| !c.is_alphanumeric())
+                    .filter(|word| !word.is_empty())
+                    .map(|word| (word.to_owned(), index))
+                    .collect();
+
+                std::iter::once((tag, index)).chain(words)
+            })
+            .collect();
+
+        tokens.sort();
+        tokens.dedup();
+
+        Labels {
+            tags,
+            synsets: vec![],
+            offset: 0,
+            tokens,
+        }
+    }
+
+    /// Shift class indices by `offset`, so that class `offset` gets the first
+    /// label
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Parse newline-separated labels
+    pub fn parse(data: &[u8]) -> tensorflow::Result<Self> {
+        Labels::parse_as(data, LabelFormat::Text)
+    }
+
+    /// Parse labels in the given format
+    pub fn parse_as(data: &[u8], format: LabelFormat) -> tensorflow::Result<Self> {
+        let text = std::str::from_utf8(data).map_err(|_| invalid_labels("not UTF-8"))?;
+
+        match format {
+            LabelFormat::Text => Ok(Labels::new(text.lines().map(str::to_owned).collect())),
+            LabelFormat::Json => Ok(Labels::new(parse_json(text)?)),
+            LabelFormat::Tsv => {
+                let (tags, synsets) = parse_tsv(text);
+
+                let mut labels = Labels::new(tags);
+                labels.synsets = synsets;
+
+                Ok(labels)
+            }
+            LabelFormat::Pbtxt => Ok(Labels::new(parse_pbtxt(text)?)),
+        }
+    }
+
+    /// Load labels from a file, detecting its format from the extension
+    pub fn load(path: &Path) -> tensorflow::Result<Self> {
+        let data = std::fs::read(path)
+            .map_err(|_| Status::new_set_lossy(Code::NotFound, "Could not open tags file"))?;
+
+        Labels::parse_as(&data, LabelFormat::from_path(&path.to_string_lossy()))
+    }
+
+    /// Label of a class
+    pub fn get(&self, index: usize) -> Option<&str> {
+        index
+            .checked_sub(self.offset)
+            .and_then(|index| self.tags.get(index))
+            .map(String::as_str)
+    }
+
+    /// Synset ID of a class, if known
+    pub fn synset(&self, index: usize) -> Option<&str> {
+        index
+            .checked_sub(self.offset)
+            .and_then(|index| self.synsets.get(index))
+            .map(String::as_str)
+            .filter(|synset| !synset.is_empty())
+    }
+
+    /// Number of labels
+    pub fn len(&self) -> usize {
+        self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    /// Labels with a word starting with `prefix` (case insensitive), ordered
+    /// by class index
+    pub fn search(&self, prefix: &str) -> Vec<LabelMatch> {
+        let prefix = prefix.to_lowercase();
+
+        // Comparator never returns `Equal`, so this yields the lower bound
+        let start = self
+            .tokens
+            .binary_search_by(|(token, _)| {
+                if token.as_str() < prefix.as_str() {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                }
+            })
+            .unwrap_or_else(|i| i);
+
+        let mut indices: Vec<usize> = self.tokens[start..]
+            .iter()
+            .take_while(|(token, _)| token.starts_with(&prefix))
+            .map(|&(_, index)| index)
+            .collect();
+
+        indices.sort_unstable();
+        indices.dedup();
+
+        indices
+            .into_iter()
+            .map(|index| LabelMatch {
+                index: index + self.offset,
+                tag: self.tags[index].clone(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search() {
+        let labels = Labels::new(vec![
+            "tench".to_owned(),
+            "goldfish".to_owned(),
+            "great white shark".to_owned(),
+            "tiger shark".to_owned(),
+        ]);
+
+        assert_eq!(labels.get(1), Some("goldfish"));
+        assert_eq!(labels.get(4), None);
+
+        let found: Vec<usize> = labels.search("Shar").iter().map(|m| m.index).collect();
+        assert_eq!(found, vec![2, 3]);
+
+        let found: Vec<usize> = labels.search("great w").iter().map(|m| m.index).collect();
+        assert_eq!(found, vec![2]);
+
+        assert_eq!(labels.search("t").len(), 2);
+        assert!(labels.search("zebra").is_empty());
+
+        let labels = labels.with_offset(1);
+        assert_eq!(labels.get(0), None);
+        assert_eq!(labels.get(1), Some("tench"));
+        assert_eq!(labels.search("tench")[0].index, 1);
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(
+            LabelFormat::from_path("labels/map.PBTXT"),
+            LabelFormat::Pbtxt
+        );
+        assert_eq!(
+            LabelFormat::from_path("ImageNetLabels.txt"),
+            LabelFormat::Text
+        );
+
+        let json = Labels::parse_as(br#"{"0": "tench", "2": "shark"}"#, LabelFormat::Json).unwrap();
+        assert_eq!(json.get(0), Some("tench"));
+        assert_eq!(json.get(1), Some(""));
+        assert_eq!(json.get(2), Some("shark"));
+
+        let tsv =
+            Labels::parse_as(b"n01440764\ttench\nn01443537\tgoldfish", LabelFormat::Tsv).unwrap();
+        assert_eq!(tsv.get(1), Some("goldfish"));
+        assert_eq!(tsv.synset(0), Some("n01440764"));
+
+        let pbtxt = Labels::parse_as(
+            b"item {\n  name: \"/m/01g317\"\n  id: 1\n  display_name: \"person\"\n}\n\
+              item {\n  id: 3\n  name: 'car'\n}\n",
+            LabelFormat::Pbtxt,
+        )
+        .unwrap();
+        assert_eq!(pbtxt.get(1), Some("person"));
+        assert_eq!(pbtxt.get(3), Some("car"));
+
+        let pbtxt = Labels::parse_as(
+            b"# Classes\nitem {\n  id: 1\n  display_name: \"line item, {boxed}\"\n\
+              keypoints { id: 0 label: \"nose\" }\n}\nitem: { id: 2, name: \"item\" }\n",
+            LabelFormat::Pbtxt,
+        )
+        .unwrap();
+        assert_eq!(pbtxt.get(1), Some("line item, {boxed}"));
+        assert_eq!(pbtxt.get(2), Some("item"));
+
+        assert!(Labels::parse_as(b"item { id: 1 name: \"x\"", LabelFormat::Pbtxt).is_err());
+    }
+}
@@ -1,18 +1,194 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+#[cfg(feature = "decode")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "decode")]
+use std::sync::mpsc;
+use std::sync::Arc;
+#[cfg(feature = "decode")]
+use std::thread;
+use std::time::Instant;
 
+#[cfg(feature = "timing")]
 use chrono::{DateTime, Duration, Utc};
-use image::DynamicImage;
-use log::{debug, info};
-use serde::Serialize;
+#[cfg(feature = "decode")]
+use image::{DynamicImage, GenericImageView};
+use log::debug;
+use serde::{Deserialize, Serialize};
 use tensorflow::{
-    Code, Graph, SavedModelBundle, Session, SessionOptions, SessionRunArgs, Status, Tensor,
+    Code, Graph, Operation, SavedModelBundle, Session, SessionOptions, SessionRunArgs, Status,
+    Tensor,
 };
 
+mod alert;
+#[cfg(feature = "decode")]
+mod annotate;
+mod auth;
+mod cache;
+mod calibration;
+#[cfg(feature = "fetch")]
+mod cloud;
+#[cfg(feature = "server")]
+pub mod cloudevents;
+#[cfg(feature = "decode")]
+mod detection;
+mod emf;
+#[cfg(feature = "decode")]
+mod ensemble;
+mod error;
+#[cfg(feature = "decode")]
+mod exif;
+#[cfg(feature = "decode")]
+mod fallback;
+#[cfg(feature = "decode")]
+mod frames;
+#[cfg(feature = "fetch")]
+mod http;
+#[cfg(feature = "decode")]
+mod image_cache;
+mod inspect;
+#[cfg(feature = "server")]
+mod jobs;
+#[cfg(feature = "jwt")]
+mod jwt;
+mod labels;
+mod limit;
+mod metrics;
+pub mod multipart;
+#[cfg(feature = "fetch")]
+mod otlp;
+#[cfg(feature = "decode")]
+mod pipeline;
+mod pool;
+#[cfg(feature = "decode")]
+mod preprocess;
+mod rate;
+#[cfg(feature = "decode")]
+mod region;
+#[cfg(feature = "decode")]
+mod segmentation;
+#[cfg(feature = "server")]
+mod server;
+mod sink;
+mod storage;
+mod tensor;
+mod timings;
+mod trace;
+#[cfg(feature = "decode")]
+mod tta;
+pub mod wire;
+mod writer;
+
+pub use alert::{Alert, AlertMonitor, AlertOptions, AlertSink};
+pub use auth::{AuthProvider, AuthRegistry, ClientCertificates, Credentials, Identity, StaticKeys};
+pub use cache::CacheOptions;
+pub use calibration::CalibrationOptions;
+#[cfg(feature = "fetch")]
+pub use cloud::{GcsStorage, S3Storage, SnsAlerts};
+#[cfg(feature = "decode")]
+pub use detection::{BoundingBox, Detection, Detections, Detector, DetectorOptions};
+pub use emf::EmfLogger;
+#[cfg(feature = "decode")]
+pub use ensemble::{Aggregation, Ensemble};
+pub use error::{code_name, http_status, retry_after, ErrorBody, ErrorDetail};
+#[cfg(feature = "decode")]
+pub use fallback::DegradableClassifier;
+#[cfg(feature = "decode")]
+pub use frames::{FrameReport, FrameSelection, MAX_FRAMES};
+#[cfg(feature = "fetch")]
+pub use http::{HttpOptions, HttpStorage, WebhookAlerts};
+#[cfg(feature = "decode")]
+pub use image_cache::ImageCache;
+pub use inspect::{inspect_model, MetaGraphInfo, ModelInfo, SignatureInfo, TensorSpec};
+#[cfg(feature = "redis")]
+pub use jobs::RedisJobStore;
+#[cfg(feature = "server")]
+pub use jobs::{Job, JobStatus, JobStore, MemoryJobStore, DEFAULT_JOB_TTL};
+#[cfg(feature = "jwt")]
+pub use jwt::{JwtOptions, JwtValidator};
+pub use labels::{LabelFormat, LabelMatch, Labels};
+pub use limit::{ConcurrencyLimit, OwnedPermit, Permit};
+pub use metrics::{Metrics, StageStats};
+#[cfg(feature = "fetch")]
+pub use otlp::{OtlpExporter, Span};
+#[cfg(feature = "decode")]
+pub use pipeline::{Pipeline, PipelineItem, PipelineResult};
+#[cfg(feature = "decode")]
+pub use preprocess::{accepted_formats, ImageLimits};
+pub use rate::RateLimiter;
+#[cfg(feature = "decode")]
+pub use region::Region;
+#[cfg(feature = "decode")]
+pub use segmentation::{Run, Segmentation, SegmentationMask, Segmenter, SegmenterOptions};
+#[cfg(feature = "server")]
+pub use server::{serve_http, CorsOptions, HttpRequest, HttpResponse, HttpServer, ServerOptions};
+#[cfg(feature = "postgres")]
+pub use sink::PostgresSink;
+#[cfg(feature = "fetch")]
+pub use sink::S3Sink;
+pub use sink::{result_sink, result_sink_from_env, JsonLinesSink, ResultRecord, ResultSink};
+pub use storage::{DataStorage, LocalStorage, MemoryStorage, Storage, StorageRegistry, Validators};
+pub use tensor::{is_npy, parse_npy, parse_raw, parse_shape, InputTensor};
+pub use timings::{ScopedTimer, Timings};
+pub use trace::{new_id, new_span_id, TraceContext};
+#[cfg(feature = "decode")]
+pub use tta::MAX_TTA_VIEWS;
+
+use cache::ResultCache;
+use calibration::CalibrationSampler;
+use pool::TensorPool;
+
+/// Largest request body of servers, and image they fetch, by default
+pub const DEFAULT_MAX_BODY_SIZE: usize = 6 << 20;
+
+/// Most images of a batch fetched at the same time
+#[cfg(feature = "decode")]
+const MAX_CONCURRENT_FETCHES: usize = 8;
+
+/// Parse an environment variable, if set
+pub fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+}
+
+/// Decode `%XX` escapes and `+` of URL-encoded text, such as query string
+/// components and the object keys of S3 events
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let digit = |byte: u8| (byte as char).to_digit(16);
+
+                match (digit(bytes[i + 1]), digit(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Named stopwatch logging its durations. Without the `timing` feature it
+/// only logs start and stop, and every duration is 0.
 pub struct Timer {
     name: String,
+    #[cfg(feature = "timing")]
     tstamp: Option<DateTime<Utc>>,
+    #[cfg(feature = "timing")]
     duration: Option<Duration>,
 }
 
@@ -21,7 +197,9 @@ impl Timer {
     pub fn new(name: &str) -> Self {
         Timer {
             name: name.to_owned(),
+            #[cfg(feature = "timing")]
             tstamp: None,
+            #[cfg(feature = "timing")]
             duration: None,
         }
     }
@@ -34,13 +212,17 @@ impl Timer {
 
     /// Start the timer
     pub fn start(&mut self) {
-        info!("{}: starting", self.name);
+        debug!("{}: starting", self.name);
 
-        self.tstamp = Some(Utc::now());
-        self.duration = None;
+        #[cfg(feature = "timing")]
+        {
+            self.tstamp = Some(Utc::now());
+            self.duration = None;
+        }
     }
 
     /// Stop the timer
+    #[cfg(feature = "timing")]
     pub fn stop(&mut self) {
         match self.tstamp {
             None => debug!("{}: not running!", self.name),
@@ -49,16 +231,437 @@ impl Timer {
 
                 self.duration = Some(d);
                 self.tstamp = None;
-                info!("{} duration: {} msec", self.name, d.num_milliseconds());
+                debug!("{} duration: {:.3} msec", self.name, self.duration_ms());
             }
         }
     }
 
-    /// Get duration in milliseconds
+    /// Stop the timer
+    #[cfg(not(feature = "timing"))]
+    pub fn stop(&mut self) {
+        debug!("{}: stopped", self.name);
+    }
+
+    /// Get duration in whole milliseconds
     fn duration(&self) -> i64 {
-        match self.duration {
-            None => 0,
-            Some(dur) => dur.num_milliseconds(),
+        self.duration_us() / 1000
+    }
+
+    /// Get duration in milliseconds, to the microsecond
+    fn duration_ms(&self) -> f64 {
+        self.duration_us() as f64 / 1000.0
+    }
+
+    /// Get duration in microseconds
+    #[cfg(feature = "timing")]
+    fn duration_us(&self) -> i64 {
+        self.duration
+            .and_then(|dur| dur.num_microseconds())
+            .unwrap_or(0)
+    }
+
+    /// Get duration in microseconds
+    #[cfg(not(feature = "timing"))]
+    fn duration_us(&self) -> i64 {
+        0
+    }
+}
+
+/// Version and build of the library, for capability reports
+#[derive(Clone, Debug, Serialize)]
+pub struct BuildInfo {
+    /// Crate version
+    pub version: &'static str,
+
+    /// Commit the library was built from, if `GIT_COMMIT` was set at build
+    /// time
+    pub git_commit: Option<&'static str>,
+
+    /// Version of the TensorFlow C library
+    pub tensorflow: String,
+
+    /// Enabled cargo features
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: option_env!("GIT_COMMIT"),
+            tensorflow: tensorflow::version().unwrap_or_else(|_| "unknown".to_owned()),
+            features: vec![
+                #[cfg(feature = "fetch")]
+                "fetch",
+                #[cfg(feature = "decode")]
+                "decode",
+                #[cfg(feature = "timing")]
+                "timing",
+                #[cfg(feature = "server")]
+                "server",
+                #[cfg(feature = "tls")]
+                "tls",
+                #[cfg(feature = "vsock")]
+                "vsock",
+            ],
+        }
+    }
+}
+
+/// JPEG preview of an image fitting in `max_size` x `max_size`, keeping its
+/// aspect ratio. Images smaller than that are only re-encoded.
+#[cfg(feature = "decode")]
+pub fn thumbnail(image: &DynamicImage, max_size: u32, quality: u8) -> tensorflow::Result<Vec<u8>> {
+    let (width, height) = image.dimensions();
+
+    let preview = if width > max_size || height > max_size {
+        image.thumbnail(max_size, max_size)
+    } else {
+        image.clone()
+    };
+
+    let rgb = preview.to_rgb();
+
+    let mut jpeg: Vec<u8> = vec![];
+    image::jpeg::JPEGEncoder::new_with_quality(&mut jpeg, quality.max(1).min(100))
+        .encode(&rgb, rgb.width(), rgb.height(), image::ColorType::RGB(8))
+        .map_err(|_| Status::new_set_lossy(Code::Internal, "Could not encode thumbnail"))?;
+
+    Ok(jpeg)
+}
+
+/// Device the TensorFlow session is placed on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Device {
+    /// Let TensorFlow pick (all visible GPUs, falling back to CPU)
+    Default,
+
+    /// Hide all GPUs and run inference on the CPU
+    Cpu,
+
+    /// Make only the GPU with this index visible to the session
+    Gpu(u32),
+}
+
+impl Default for Device {
+    fn default() -> Self {
+        Device::Default
+    }
+}
+
+impl FromStr for Device {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "" | "default" => Ok(Device::Default),
+            "cpu" => Ok(Device::Cpu),
+            other => other
+                .trim_start_matches("gpu")
+                .trim_start_matches(':')
+                .parse()
+                .map(Device::Gpu)
+                .map_err(|_| format!("Invalid device '{}'", s)),
+        }
+    }
+}
+
+/// Append a protobuf length-delimited field to `buf`
+fn encode_proto_bytes(buf: &mut Vec<u8>, field: u8, data: &[u8]) {
+    buf.push(field << 3 | 2);
+
+    let mut len = data.len();
+    while len >= 0x80 {
+        buf.push((len as u8) | 0x80);
+        len >>= 7;
+    }
+    buf.push(len as u8);
+
+    buf.extend_from_slice(data);
+}
+
+impl Device {
+    /// Serialized `ConfigProto` restricting the devices visible to the session
+    fn config_proto(&self) -> Vec<u8> {
+        let mut config = vec![];
+
+        match self {
+            Device::Default => {}
+            Device::Cpu => {
+                // device_count { key: "GPU" value: 0 }
+                let mut entry = vec![];
+                encode_proto_bytes(&mut entry, 1, b"GPU");
+                entry.extend_from_slice(&[2 << 3, 0]);
+                encode_proto_bytes(&mut config, 1, &entry);
+            }
+            Device::Gpu(index) => {
+                // gpu_options { visible_device_list: "<index>" }
+                let mut gpu_options = vec![];
+                encode_proto_bytes(&mut gpu_options, 5, index.to_string().as_bytes());
+                encode_proto_bytes(&mut config, 6, &gpu_options);
+            }
+        }
+
+        config
+    }
+
+    /// TensorFlow session options for this device
+    fn session_options(&self) -> tensorflow::Result<SessionOptions> {
+        let mut options = SessionOptions::new();
+        let config = self.config_proto();
+
+        if !config.is_empty() {
+            options.set_config(&config)?;
+        }
+
+        Ok(options)
+    }
+}
+
+/// Post-processing applied to the model output before looking up tags
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PostProcessing {
+    /// Use the model output as is
+    None,
+
+    /// Apply softmax across all classes
+    Softmax,
+
+    /// Apply the logistic function to every class independently
+    Sigmoid,
+
+    /// Apply softmax only if the output is not already a probability distribution
+    Auto,
+}
+
+impl Default for PostProcessing {
+    fn default() -> Self {
+        PostProcessing::Auto
+    }
+}
+
+impl FromStr for PostProcessing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(PostProcessing::None),
+            "softmax" => Ok(PostProcessing::Softmax),
+            "sigmoid" => Ok(PostProcessing::Sigmoid),
+            "auto" => Ok(PostProcessing::Auto),
+            _ => Err(format!("Invalid post-processing '{}'", s)),
+        }
+    }
+}
+
+impl PostProcessing {
+    /// Apply post-processing to the raw model output in place
+    fn apply(&self, scores: &mut [f32]) {
+        match self {
+            PostProcessing::None => {}
+            PostProcessing::Softmax => {
+                let max = scores.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                scores.iter_mut().for_each(|x| *x = (*x - max).exp());
+
+                let sum: f32 = scores.iter().sum();
+                scores.iter_mut().for_each(|x| *x /= sum);
+            }
+            PostProcessing::Sigmoid => {
+                scores
+                    .iter_mut()
+                    .for_each(|x| *x = 1.0 / (1.0 + (-*x).exp()));
+            }
+            PostProcessing::Auto => {
+                let sum: f32 = scores.iter().sum();
+                let in_range = scores.iter().all(|x| (0.0..=1.0).contains(x));
+
+                if !in_range || (sum - 1.0).abs() > 0.01 {
+                    debug!("Model output is not a distribution, applying softmax");
+                    PostProcessing::Softmax.apply(scores);
+                }
+            }
+        }
+    }
+}
+
+/// Look up a tensor given as `operation[:index]` in a model graph
+fn tensor_by_name(graph: &Graph, name: &str) -> tensorflow::Result<(Operation, i32)> {
+    let (op, index) = match name.rfind(':') {
+        Some(pos) => {
+            let index = name[pos + 1..].parse().map_err(|_| {
+                Status::new_set_lossy(
+                    Code::InvalidArgument,
+                    &format!("Invalid tensor name '{}'", name),
+                )
+            })?;
+
+            (&name[..pos], index)
+        }
+        None => (name, 0),
+    };
+
+    Ok((graph.operation_by_name_required(op)?, index))
+}
+
+/// Resizing and normalization of images before inference
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preprocessing {
+    /// Width and height of the model input
+    pub input_size: (u32, u32),
+
+    /// Per-channel mean subtracted from pixel values scaled to [0, 1]
+    pub mean: [f32; 3],
+
+    /// Per-channel standard deviation dividing the centered pixel values
+    pub std: [f32; 3],
+
+    /// Rotate and flip JPEGs upright according to their EXIF orientation
+    pub auto_orient: bool,
+}
+
+impl Default for Preprocessing {
+    fn default() -> Self {
+        Preprocessing {
+            input_size: (224, 224),
+            mean: [0.0; 3],
+            std: [1.0; 3],
+            auto_orient: true,
+        }
+    }
+}
+
+impl Preprocessing {
+    /// Resize and normalize an image to the model input
+    #[cfg(feature = "decode")]
+    pub fn apply(&self, image: &DynamicImage) -> Vec<f32> {
+        let (width, height) = self.input_size;
+        let mut input = vec![0f32; (width * height * 3) as usize];
+
+        self.apply_into(image, &mut input);
+
+        input
+    }
+
+    /// Resize and normalize an image into `input`, which holds as many
+    /// values as the model input
+    #[cfg(feature = "decode")]
+    pub fn apply_into(&self, image: &DynamicImage, input: &mut [f32]) {
+        self.apply_rgb_into(&image.to_rgb(), input);
+    }
+
+    /// EXIF orientation of an encoded image to apply, if any
+    #[cfg(feature = "decode")]
+    fn orientation(&self, data: &[u8]) -> Option<u16> {
+        if self.auto_orient {
+            exif::orientation(data).filter(|&orientation| orientation != 1)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(feature = "decode")]
+    fn apply_rgb_into(&self, rgb: &image::RgbImage, input: &mut [f32]) {
+        let (width, height) = self.input_size;
+
+        let resized = preprocess::resize(rgb, width, height);
+        preprocess::normalize(&resized, input, &self.mean, &self.std);
+    }
+}
+
+/// Configuration of an `ImageClassifier`
+#[derive(Clone, Debug)]
+pub struct ClassifierOptions {
+    /// Device to run inference on
+    pub device: Device,
+
+    /// Preparation of images for the model input
+    pub preprocessing: Preprocessing,
+
+    /// Post-processing of the model output
+    pub post_processing: PostProcessing,
+
+    /// Treat the model as multi-label, returning every class with at least
+    /// this probability instead of the best ones
+    pub multi_label: Option<f32>,
+
+    /// Input tensor of the model, as `operation[:index]`
+    pub input_op: String,
+
+    /// Classification output tensor of the model, as `operation[:index]`
+    pub output_op: String,
+
+    /// Tensor to fetch image embeddings from, as `operation[:index]`
+    pub embedding_op: Option<String>,
+
+    /// Configuration of image fetches over HTTP
+    #[cfg(feature = "fetch")]
+    pub http: HttpOptions,
+
+    /// Read images from wherever the model and labels may be, local paths,
+    /// `file://` URLs and any bucket, for local tools only: URLs of network
+    /// clients must not reach the files of the host
+    pub local_images: bool,
+
+    /// Class index of the first label, e.g. 1 for models whose "background"
+    /// class 0 is missing from the labels file
+    pub label_offset: usize,
+
+    /// Labels files in other languages, as `(language, location)`, holding
+    /// the same classes in the same order as the main labels file
+    pub translations: Vec<(String, String)>,
+
+    /// Sampling of model inputs and predictions for quantization
+    /// calibration, disabled if unset
+    pub calibration: Option<CalibrationOptions>,
+
+    /// Caching of results by image content or URL, disabled if unset
+    pub cache: Option<CacheOptions>,
+
+    /// Cache of images fetched from URLs, which may be shared with other
+    /// classifiers
+    #[cfg(feature = "decode")]
+    pub image_cache: Option<Arc<ImageCache>>,
+
+    /// Largest images decoded
+    #[cfg(feature = "decode")]
+    pub limits: ImageLimits,
+}
+
+impl ClassifierOptions {
+    /// Options of servers, fetching images as configured by the environment,
+    /// see `HttpOptions::from_env`, on the device of `TF_DEVICE` (`cpu`,
+    /// `gpu:N`), if set
+    pub fn from_env() -> Self {
+        ClassifierOptions {
+            device: env_parse("TF_DEVICE").unwrap_or_default(),
+            #[cfg(feature = "fetch")]
+            http: HttpOptions::from_env(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for ClassifierOptions {
+    fn default() -> Self {
+        ClassifierOptions {
+            device: Device::default(),
+            preprocessing: Preprocessing::default(),
+            post_processing: PostProcessing::default(),
+            multi_label: None,
+            input_op: "serving_default_input_1".to_owned(),
+            output_op: "StatefulPartitionedCall".to_owned(),
+            embedding_op: None,
+            #[cfg(feature = "fetch")]
+            http: HttpOptions::default(),
+            local_images: false,
+            label_offset: 0,
+            translations: vec![],
+            calibration: None,
+            cache: None,
+            #[cfg(feature = "decode")]
+            image_cache: None,
+            #[cfg(feature = "decode")]
+            limits: ImageLimits::default(),
         }
     }
 }
@@ -70,18 +673,249 @@ pub struct ImageClassifier {
     /// TensorFlow session
     session: Session,
 
-    /// Tags translation file
-    tags: PathBuf,
+    /// Tags translation, indexed by class
+    tags: Labels,
+
+    /// Localized tags, by language
+    translations: HashMap<String, Labels>,
+
+    /// Preparation of images for the model input
+    preprocessing: Preprocessing,
+
+    /// Post-processing of the model output
+    post_processing: PostProcessing,
+
+    /// Probability threshold of multi-label models
+    multi_label: Option<f32>,
+
+    /// Input tensor name
+    input_op: String,
+
+    /// Classification output tensor name
+    output_op: String,
+
+    /// Embedding tensor name
+    embedding_op: Option<String>,
+
+    /// Source of images to classify
+    storage: Arc<dyn Storage>,
+
+    /// Destination of calibration samples
+    files: Arc<dyn Storage>,
+
+    /// Sampling of calibration data
+    calibration: Option<CalibrationSampler>,
+
+    /// Results of images already classified
+    cache: Option<ResultCache>,
+
+    /// Images fetched from URLs
+    #[cfg(feature = "decode")]
+    image_cache: Option<Arc<ImageCache>>,
+
+    /// Largest images decoded
+    #[cfg(feature = "decode")]
+    limits: ImageLimits,
+
+    /// Input tensors of single images, reused across requests
+    inputs: TensorPool,
+}
+
+/// Image of a batch, either preprocessed, with its fetch, load and resize
+/// times, or classified on its own
+#[cfg(feature = "decode")]
+enum Prepared {
+    Input(Vec<f32>, [i64; 3]),
+    Classified(Classification),
+}
+
+/// Image fetched by `ImageClassifier::fetch_image` or `fetch_batch`, for
+/// classifying apart from fetching it, as within a concurrency limit
+#[cfg(feature = "decode")]
+pub struct FetchedImage {
+    data: Arc<Vec<u8>>,
+
+    /// Time spent fetching it, in msec
+    fetch_time: f64,
+
+    /// Key of its classification in the result cache, if cached
+    key: Option<String>,
+
+    /// Its classification, if already in the result cache
+    cached: Option<Classification>,
+}
+
+/// Image already in memory
+#[cfg(feature = "decode")]
+impl From<Vec<u8>> for FetchedImage {
+    fn from(data: Vec<u8>) -> Self {
+        FetchedImage {
+            data: Arc::new(data),
+            fetch_time: 0.0,
+            key: None,
+            cached: None,
+        }
+    }
 }
 
-#[derive(Default, Serialize)]
+/// Per-request classification options
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClassifyOptions {
+    /// Maximum number of predictions to return
+    pub top_k: usize,
+
+    /// Minimum probability of a returned prediction
+    pub min_probability: f32,
+
+    /// Include the raw model output of every prediction
+    pub logits: bool,
+
+    /// Report the result as ambiguous when the two best probabilities are
+    /// within this margin
+    pub ambiguity_margin: Option<f32>,
+
+    /// Language of the returned tags, if not the default labels
+    pub lang: Option<String>,
+
+    /// Frames of animated images to classify
+    #[cfg(feature = "decode")]
+    pub frames: FrameSelection,
+
+    /// Views of the image to run the model on, averaging their
+    /// probabilities: the image, its center and corner crops, and their
+    /// flips, up to `MAX_TTA_VIEWS`. 0 and 1 run it on the image alone.
+    #[cfg(feature = "decode")]
+    pub tta: usize,
+
+    /// Region of the image to classify, instead of all of it
+    #[cfg(feature = "decode")]
+    pub roi: Option<Region>,
+
+    /// Trace the request belongs to, propagated to image fetches
+    #[serde(skip)]
+    pub trace: Option<TraceContext>,
+
+    /// Time by which the classification must be done. It is checked before
+    /// every stage, failing with `DeadlineExceeded` once passed.
+    #[serde(skip)]
+    pub deadline: Option<Instant>,
+}
+
+impl Default for ClassifyOptions {
+    fn default() -> Self {
+        ClassifyOptions {
+            top_k: 1,
+            min_probability: 0.0,
+            logits: false,
+            ambiguity_margin: None,
+            lang: None,
+            #[cfg(feature = "decode")]
+            frames: FrameSelection::default(),
+            #[cfg(feature = "decode")]
+            tta: 0,
+            #[cfg(feature = "decode")]
+            roi: None,
+            trace: None,
+            deadline: None,
+        }
+    }
+}
+
+impl ClassifyOptions {
+    /// Fail if the deadline passed before `stage`
+    pub(crate) fn check_deadline(&self, stage: &str) -> tensorflow::Result<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Status::new_set_lossy(
+                Code::DeadlineExceeded,
+                &format!("Deadline exceeded before {}", stage),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Prediction {
+    /// Class index in the model output
+    index: usize,
+
+    /// Classification tag
+    tag: String,
+
+    /// Classification probability
+    probability: f32,
+
+    /// Raw model output for the class, before post-processing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logit: Option<f32>,
+}
+
+impl Prediction {
+    /// Class index in the model output
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Classification tag
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Classification probability
+    pub fn probability(&self) -> f32 {
+        self.probability
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Classification {
+    /// Class index of the image in the model output
+    index: usize,
+
     /// Classification tag of the image
     tag: String,
 
     /// Classification probability
     probability: f32,
 
+    /// Raw model output for the class, before post-processing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logit: Option<f32>,
+
+    /// Best predictions, filtered according to `ClassifyOptions`
+    predictions: Vec<Prediction>,
+
+    /// Whether the two best predictions are too close to call
+    ambiguous: bool,
+
+    /// The two best predictions, when the result is ambiguous
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    candidates: Vec<Prediction>,
+
+    /// Whether a fallback model produced the result instead of the primary
+    degraded: bool,
+
+    /// Whether the result was served from the cache of the classifier
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cached: bool,
+
+    /// Frames classified, for animated images
+    #[cfg(feature = "decode")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frames: Option<FrameReport>,
+
+    /// Views of the image averaged, with test-time augmentation
+    #[cfg(feature = "decode")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    views: Option<usize>,
+
+    /// ID of the request that asked for the classification, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+
     /// Time spent fetching image from URL
     time_url_fetch: i64,
 
@@ -93,131 +927,1164 @@ pub struct Classification {
 
     /// Time spent on running session
     time_session_run: i64,
+
+    /// Durations of the stages, nested ones included, by name. Unlike the
+    /// `time_*` fields, which are whole milliseconds, these are to the
+    /// microsecond.
+    #[serde(skip_serializing_if = "Timings::is_empty")]
+    stages: Timings,
+}
+
+impl Classification {
+    /// Class index of the image in the model output
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Classification tag of the image
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Classification probability
+    pub fn probability(&self) -> f32 {
+        self.probability
+    }
+
+    /// Best predictions, filtered according to `ClassifyOptions`
+    pub fn predictions(&self) -> &[Prediction] {
+        &self.predictions
+    }
+
+    /// Whether a fallback model produced the result instead of the primary
+    pub fn degraded(&self) -> bool {
+        self.degraded
+    }
+
+    /// Whether the result was served from the cache of the classifier
+    pub fn cached(&self) -> bool {
+        self.cached
+    }
+
+    /// Frames classified, for animated images
+    #[cfg(feature = "decode")]
+    pub fn frames(&self) -> Option<&FrameReport> {
+        self.frames.as_ref()
+    }
+
+    /// Views of the image averaged, with test-time augmentation
+    #[cfg(feature = "decode")]
+    pub fn views(&self) -> Option<usize> {
+        self.views
+    }
+
+    /// ID of the request that asked for the classification, if any
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    pub fn set_request_id(&mut self, request_id: &str) {
+        self.request_id = Some(request_id.to_owned());
+    }
+
+    /// Durations of the stages that produced the classification, in
+    /// milliseconds and in the order they ran
+    pub fn timings(&self) -> [(&'static str, i64); 4] {
+        [
+            ("fetch", self.time_url_fetch),
+            ("decode", self.time_image_load),
+            ("resize", self.time_image_resize),
+            ("inference", self.time_session_run),
+        ]
+    }
+
+    /// Durations of the stages, nested ones included, by name
+    pub fn stages(&self) -> &Timings {
+        &self.stages
+    }
 }
 
 impl ImageClassifier {
     pub fn new(export_dir: &Path, tags_path: &Path) -> tensorflow::Result<Self> {
+        ImageClassifier::with_options(export_dir, tags_path, &ClassifierOptions::default())
+    }
+
+    pub fn with_options(
+        export_dir: &Path,
+        tags_path: &Path,
+        options: &ClassifierOptions,
+    ) -> tensorflow::Result<Self> {
+        #[cfg(feature = "fetch")]
+        let (files, images) = {
+            let http = HttpStorage::with_options(&options.http)?;
+            let client = http.client().clone();
+            let mut images = StorageRegistry::new(http);
+
+            // The model, labels and calibration samples may be in local files
+            // or any bucket, unlike images
+            let mut files = images.clone();
+            files.allow_local();
+            files.allow_buckets(&client, &[], None);
+
+            if !options.http.buckets.is_empty() {
+                images.allow_buckets(&client, &options.http.buckets, options.http.max_size);
+            }
+
+            (files, images)
+        };
+        #[cfg(not(feature = "fetch"))]
+        let (files, images) = (StorageRegistry::local(), StorageRegistry::default());
+
+        let images = if options.local_images {
+            files.clone()
+        } else {
+            images
+        };
+
+        ImageClassifier::load(
+            Arc::new(files),
+            Arc::new(images),
+            &export_dir.to_string_lossy(),
+            &tags_path.to_string_lossy(),
+            options,
+        )
+    }
+
+    /// Load the model, labels and, later on, images from `storage`
+    pub fn with_storage(
+        storage: Arc<dyn Storage>,
+        export_dir: &str,
+        tags_path: &str,
+        options: &ClassifierOptions,
+    ) -> tensorflow::Result<Self> {
+        ImageClassifier::load(storage.clone(), storage, export_dir, tags_path, options)
+    }
+
+    /// Load the model and labels from `files`, and later on images from
+    /// `storage`
+    fn load(
+        files: Arc<dyn Storage>,
+        storage: Arc<dyn Storage>,
+        export_dir: &str,
+        tags_path: &str,
+        options: &ClassifierOptions,
+    ) -> tensorflow::Result<Self> {
         let mut t = Timer::new_start("Loading session");
 
         let mut graph = Graph::new();
-        let session =
-            SavedModelBundle::load(&SessionOptions::new(), &["serve"], &mut graph, export_dir)?
-                .session;
+        let session = SavedModelBundle::load(
+            &options.device.session_options()?,
+            &["serve"],
+            &mut graph,
+            files.local_dir(export_dir)?,
+        )?
+        .session;
 
         t.stop();
 
+        let load_labels = |location: &str| -> tensorflow::Result<Labels> {
+            Ok(
+                Labels::parse_as(&files.read(location)?, LabelFormat::from_path(location))?
+                    .with_offset(options.label_offset),
+            )
+        };
+
+        let tags = load_labels(tags_path)?;
+
+        let mut translations = HashMap::new();
+        for (lang, location) in &options.translations {
+            let labels = load_labels(location)?;
+
+            if labels.len() != tags.len() {
+                return Err(Status::new_set_lossy(
+                    Code::InvalidArgument,
+                    &format!(
+                        "Labels for '{}' have {} classes instead of {}",
+                        lang,
+                        labels.len(),
+                        tags.len()
+                    ),
+                ));
+            }
+
+            translations.insert(lang.to_lowercase(), labels);
+        }
+
+        let (width, height) = options.preprocessing.input_size;
+
         Ok(ImageClassifier {
             graph,
             session,
-            tags: tags_path.to_path_buf(),
+            tags,
+            translations,
+            preprocessing: options.preprocessing.clone(),
+            post_processing: options.post_processing,
+            multi_label: options.multi_label,
+            input_op: options.input_op.clone(),
+            output_op: options.output_op.clone(),
+            embedding_op: options.embedding_op.clone(),
+            storage,
+            files,
+            calibration: options.calibration.clone().map(CalibrationSampler::new),
+            cache: options.cache.as_ref().map(ResultCache::new),
+            #[cfg(feature = "decode")]
+            image_cache: options.image_cache.clone(),
+            #[cfg(feature = "decode")]
+            limits: options.limits,
+            inputs: TensorPool::new(&[1, height as u64, width as u64, 3]),
         })
     }
 
-    fn get_tag(&self, tensor: Tensor<f32>) -> tensorflow::Result<Classification> {
-        let file = File::open(self.tags.clone())
-            .map_err(|_| Status::new_set_lossy(Code::NotFound, "Could not open tags file"))?;
+    fn get_tag(&self, index: usize, lang: Option<&str>) -> tensorflow::Result<String> {
+        self.labels_in(lang)?
+            .get(index)
+            .map(str::to_owned)
+            .ok_or_else(|| {
+                Status::new_set_lossy(Code::OutOfRange, &format!("No tag for class {}", index))
+            })
+    }
 
-        let mut tags = BufReader::new(file).lines();
+    /// Storage of the images at URLs, as allowed by the options
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
 
-        let best = tensor
-            .iter()
-            .enumerate()
-            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
-            .unwrap();
+    /// Class labels of the model
+    pub fn labels(&self) -> &Labels {
+        &self.tags
+    }
+
+    /// Class labels in `lang`, or the default ones if `None`
+    pub fn labels_in(&self, lang: Option<&str>) -> tensorflow::Result<&Labels> {
+        match lang {
+            None => Ok(&self.tags),
+            Some(lang) => self.translations.get(&lang.to_lowercase()).ok_or_else(|| {
+                Status::new_set_lossy(
+                    Code::NotFound,
+                    &format!("No labels for language '{}'", lang),
+                )
+            }),
+        }
+    }
+
+    /// Languages with localized labels
+    pub fn languages(&self) -> Vec<&str> {
+        let mut languages: Vec<&str> = self.translations.keys().map(String::as_str).collect();
+        languages.sort_unstable();
+        languages
+    }
+
+    /// Apply the configured post-processing to the model output in place
+    fn post_process(&self, scores: &mut [f32]) {
+        match (self.post_processing, self.multi_label) {
+            // Multi-label heads are never a distribution, so only squash
+            // values that are not probabilities already.
+            (PostProcessing::Auto, Some(_)) => {
+                if !scores.iter().all(|x| (0.0..=1.0).contains(x)) {
+                    debug!("Model output is not a probability, applying sigmoid");
+                    PostProcessing::Sigmoid.apply(scores);
+                }
+            }
+            (post_processing, _) => post_processing.apply(scores),
+        }
+    }
+
+    /// Build the classification of an image from its post-processed class
+    /// probabilities and, if available, the raw model output
+    fn get_classification(
+        &self,
+        logits: Option<&[f32]>,
+        probabilities: Vec<f32>,
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        let mut scores: Vec<(usize, f32)> = probabilities.into_iter().enumerate().collect();
+        scores.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+
+        let prediction = |&(index, probability): &(usize, f32)| -> tensorflow::Result<Prediction> {
+            Ok(Prediction {
+                index,
+                tag: self.get_tag(index, options.lang.as_deref())?,
+                probability,
+                logit: logits
+                    .filter(|_| options.logits)
+                    .map(|logits| logits[index]),
+            })
+        };
+
+        let best = prediction(
+            scores
+                .first()
+                .ok_or_else(|| Status::new_set_lossy(Code::Internal, "Empty model output"))?,
+        )?;
+
+        let predictions = match self.multi_label {
+            Some(threshold) => {
+                let threshold = threshold.max(options.min_probability);
+
+                scores
+                    .iter()
+                    .take_while(|(_, probability)| *probability >= threshold)
+                    .map(prediction)
+                    .collect::<tensorflow::Result<Vec<Prediction>>>()?
+            }
+            None => scores
+                .iter()
+                .take(options.top_k)
+                .take_while(|(_, probability)| *probability >= options.min_probability)
+                .map(prediction)
+                .collect::<tensorflow::Result<Vec<Prediction>>>()?,
+        };
+
+        let ambiguous = match (options.ambiguity_margin, scores.get(1)) {
+            (Some(margin), Some(&(_, second))) => best.probability - second <= margin,
+            _ => false,
+        };
+
+        let candidates = if ambiguous {
+            scores
+                .iter()
+                .take(2)
+                .map(prediction)
+                .collect::<tensorflow::Result<Vec<Prediction>>>()?
+        } else {
+            vec![]
+        };
 
         Ok(Classification {
-            tag: tags.nth(best.0).unwrap().unwrap(),
-            probability: *best.1,
+            index: best.index,
+            tag: best.tag,
+            probability: best.probability,
+            logit: best.logit,
+            predictions,
+            ambiguous,
+            candidates,
             ..Default::default()
         })
     }
 
-    pub fn run(&self, image: &[f32]) -> tensorflow::Result<Classification> {
-        let mut t = Timer::new_start("Running session");
-
-        let input = Tensor::new(&[1, 224, 224, 3])
-            .with_values(&image)
-            .expect("Bad image size");
+    /// Feed a preprocessed image to the model and fetch the `output` tensor
+    fn session_run(&self, image: &[f32], output: &str) -> tensorflow::Result<Tensor<f32>> {
+        self.session_run_batch(image, 1, output)
+    }
 
+    /// Feed the `input` tensor to the model and fetch the `output` tensor
+    fn session_run_tensor(
+        &self,
+        input: &Tensor<f32>,
+        output: &str,
+    ) -> tensorflow::Result<Tensor<f32>> {
         let mut args = SessionRunArgs::new();
 
-        args.add_feed(
-            &self
-                .graph
-                .operation_by_name_required("serving_default_input_1")?,
-            0,
-            &input,
-        );
+        let (input_op, input_index) = tensor_by_name(&self.graph, &self.input_op)?;
+        args.add_feed(&input_op, input_index, input);
 
-        let result = args.request_fetch(
-            &self
-                .graph
-                .operation_by_name_required("StatefulPartitionedCall")?,
-            0,
-        );
+        let (output_op, output_index) = tensor_by_name(&self.graph, output)?;
+        let result = args.request_fetch(&output_op, output_index);
 
         self.session.run(&mut args)?;
-        let output = args.fetch(result)?;
+        args.fetch(result)
+    }
 
-        t.stop();
+    /// Feed `count` preprocessed images, one after the other in `images`, to
+    /// the model as a single batch and fetch the `output` tensor
+    fn session_run_batch(
+        &self,
+        images: &[f32],
+        count: usize,
+        output: &str,
+    ) -> tensorflow::Result<Tensor<f32>> {
+        if count == 1 {
+            let mut input = self.inputs.take();
+            input.copy_from_slice(images);
+
+            return self.session_run_tensor(&input, output);
+        }
+
+        let (width, height) = self.preprocessing.input_size;
+        let input = Tensor::new(&[count as u64, height as u64, width as u64, 3])
+            .with_values(&images)
+            .expect("Bad image size");
+
+        self.session_run_tensor(&input, output)
+    }
+
+    /// Raw model output and post-processed class probabilities of a
+    /// preprocessed image
+    fn probabilities(&self, image: &[f32]) -> tensorflow::Result<(Vec<f32>, Vec<f32>)> {
+        let logits = self.session_run(image, &self.output_op)?.to_vec();
+
+        let mut probabilities = logits.clone();
+        self.post_process(&mut probabilities);
+
+        Ok((logits, probabilities))
+    }
+
+    pub fn run(&self, image: &[f32]) -> tensorflow::Result<Classification> {
+        self.run_with_options(image, &ClassifyOptions::default())
+    }
+
+    pub fn run_with_options(
+        &self,
+        image: &[f32],
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        let mut input = self.inputs.take();
+        input.copy_from_slice(image);
+
+        self.run_input(&input, options)
+    }
+
+    /// Classify the preprocessed image in the `input` tensor
+    fn run_input(
+        &self,
+        input: &Tensor<f32>,
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        options.check_deadline("inference")?;
 
-        let mut classification = self.get_tag(output)?;
-        classification.time_session_run = t.duration();
+        let stages = Timings::new();
+        let run = Timer::scoped("Running session").record_into(&stages, "inference");
+
+        let logits = {
+            let _session = run.child("session");
+            self.session_run_tensor(input, &self.output_op)?.to_vec()
+        };
+
+        let mut probabilities = logits.clone();
+        {
+            let _post_process = run.child("post_process");
+            self.post_process(&mut probabilities);
+        }
+
+        let session_run = run.finish();
+
+        let mut classification = self.get_classification(Some(&logits), probabilities, options)?;
+        classification.time_session_run = session_run as i64;
+        classification.stages = stages;
+
+        if let Some(calibration) = &self.calibration {
+            calibration.record(
+                &self.files,
+                input,
+                self.preprocessing.input_size,
+                &classification,
+            );
+        }
 
         Ok(classification)
     }
 
-    pub fn classify(&self, image: &DynamicImage) -> tensorflow::Result<Classification> {
-        let mut t = Timer::new_start("Resizing image");
+    /// Classify a preprocessed image of `shape`, `[1, height, width, 3]` or
+    /// `[height, width, 3]` at the input size of the model
+    pub fn classify_tensor(
+        &self,
+        values: &[f32],
+        shape: &[u64],
+    ) -> tensorflow::Result<Classification> {
+        self.classify_tensor_with_options(values, shape, &ClassifyOptions::default())
+    }
 
-        let rgb = image.to_rgb();
+    pub fn classify_tensor_with_options(
+        &self,
+        values: &[f32],
+        shape: &[u64],
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        let (width, height) = self.preprocessing.input_size;
+        let expected = [1, height as u64, width as u64, 3];
 
-        let resized =
-            image::imageops::resize(&rgb, 224, 224, image::imageops::FilterType::Triangle);
+        if shape != expected && shape != &expected[1..] {
+            return Err(Status::new_set_lossy(
+                Code::InvalidArgument,
+                &format!("Expected a tensor of shape {:?}, not {:?}", expected, shape),
+            ));
+        }
 
-        let raw_image: Vec<f32> = resized
-            .into_raw()
-            .iter()
-            .map(|x| *x as f32 / 255f32)
-            .collect();
+        if values.len() as u64 != expected.iter().product::<u64>() {
+            return Err(Status::new_set_lossy(
+                Code::InvalidArgument,
+                &format!("Tensor of shape {:?} with {} values", shape, values.len()),
+            ));
+        }
+
+        self.run_with_options(values, options)
+    }
+
+    /// Classify preprocessed images in a single session run. Models without
+    /// a batch dimension get one run per image instead.
+    pub fn run_batch_with_options(
+        &self,
+        images: &[Vec<f32>],
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Vec<Classification>> {
+        if images.len() <= 1 {
+            return images
+                .iter()
+                .map(|image| self.run_with_options(image, options))
+                .collect();
+        }
+
+        options.check_deadline("inference")?;
+
+        let mut t = Timer::new_start(&format!("Running session on {} images", images.len()));
+
+        let batch = images.concat();
+        let output = match self.session_run_batch(&batch, images.len(), &self.output_op) {
+            Ok(output) => output,
+            Err(err) => {
+                debug!(
+                    "Batched session run failed ({}), running images one by one",
+                    err
+                );
+                return images
+                    .iter()
+                    .map(|image| self.run_with_options(image, options))
+                    .collect();
+            }
+        };
 
         t.stop();
 
-        let mut classification = self.run(&raw_image)?;
-        classification.time_image_resize = t.duration();
+        if output.is_empty() || output.len() % images.len() != 0 {
+            return Err(Status::new_set_lossy(
+                Code::Internal,
+                "Model output does not match the batch size",
+            ));
+        }
+
+        let classes = output.len() / images.len();
+
+        output
+            .chunks(classes)
+            .zip(images)
+            .map(|(logits, image)| {
+                let mut probabilities = logits.to_vec();
+                self.post_process(&mut probabilities);
+
+                let mut classification =
+                    self.get_classification(Some(logits), probabilities, options)?;
+                classification.time_session_run = t.duration();
+
+                if let Some(calibration) = &self.calibration {
+                    calibration.record(
+                        &self.files,
+                        image,
+                        self.preprocessing.input_size,
+                        &classification,
+                    );
+                }
+
+                Ok(classification)
+            })
+            .collect()
+    }
+
+    /// Decode an image, upright unless orientation is disabled
+    #[cfg(feature = "decode")]
+    fn load_image(&self, data: &[u8]) -> tensorflow::Result<DynamicImage> {
+        let image = preprocess::decode(data, &self.limits)?;
+
+        Ok(match self.preprocessing.orientation(data) {
+            Some(orientation) => exif::orient(image, orientation),
+            None => image,
+        })
+    }
+
+    /// Resize and normalize an image to the model input
+    #[cfg(feature = "decode")]
+    fn preprocess(&self, image: &DynamicImage) -> Vec<f32> {
+        self.preprocessing.apply(image)
+    }
+
+    #[cfg(feature = "decode")]
+    pub fn classify(&self, image: &DynamicImage) -> tensorflow::Result<Classification> {
+        self.classify_with_options(image, &ClassifyOptions::default())
+    }
+
+    #[cfg(feature = "decode")]
+    pub fn classify_with_options(
+        &self,
+        image: &DynamicImage,
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        options.check_deadline("resizing")?;
+
+        let cropped;
+        let image = match &options.roi {
+            Some(region) => {
+                cropped = region.crop(image)?;
+                &cropped
+            }
+            None => image,
+        };
+
+        if options.tta > 1 {
+            return self.classify_views(image, options);
+        }
+
+        let t = Timer::scoped("Resizing image");
+        let mut input = self.inputs.take();
+        self.preprocessing.apply_into(image, &mut input);
+        let resize = t.finish();
+
+        let mut classification = self.run_input(&input, options)?;
+        classification.time_image_resize = resize as i64;
+        classification.stages.record("resize", resize);
 
         Ok(classification)
     }
 
-    pub fn classify_from_raw(&self, data: &[u8]) -> tensorflow::Result<Classification> {
-        let mut t = Timer::new_start("Load image from memory");
+    /// Run the model once on a blank input, so that TensorFlow allocates its
+    /// buffers and picks its kernels before the first real request
+    pub fn warm_up(&self) -> tensorflow::Result<()> {
+        let (width, height) = self.preprocessing.input_size;
+        let blank = vec![0f32; (width * height * 3) as usize];
+
+        self.session_run(&blank, &self.output_op)?;
 
-        let image = image::load_from_memory(&data).map_err(|_| {
-            Status::new_set_lossy(Code::InvalidArgument, "Could create image from raw data")
+        Ok(())
+    }
+
+    /// Extract the feature vector of an image from the configured embedding tensor
+    #[cfg(feature = "decode")]
+    pub fn embed(&self, image: &DynamicImage) -> tensorflow::Result<Vec<f32>> {
+        let embedding_op = self.embedding_op.as_ref().ok_or_else(|| {
+            Status::new_set_lossy(Code::FailedPrecondition, "No embedding tensor configured")
         })?;
 
+        let mut t = Timer::new_start("Extracting embedding");
+
+        let raw_image = self.preprocess(image);
+        let output = self.session_run(&raw_image, embedding_op)?;
+
         t.stop();
 
-        let mut classification = self.classify(&image)?;
-        classification.time_image_load = t.duration();
+        Ok(output.to_vec())
+    }
+
+    #[cfg(feature = "decode")]
+    pub fn embed_from_raw(&self, data: &[u8]) -> tensorflow::Result<Vec<f32>> {
+        let image = self.load_image(&data)?;
+
+        self.embed(&image)
+    }
+
+    #[cfg(feature = "decode")]
+    pub fn classify_from_raw(&self, data: &[u8]) -> tensorflow::Result<Classification> {
+        self.classify_from_raw_with_options(data, &ClassifyOptions::default())
+    }
+
+    #[cfg(feature = "decode")]
+    pub fn classify_from_raw_with_options(
+        &self,
+        data: &[u8],
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        options.check_deadline("decoding")?;
+
+        let key = self
+            .cache
+            .as_ref()
+            .map(|_| ResultCache::raw_key(data, options));
+        if let (Some(cache), Some(key)) = (&self.cache, &key) {
+            if let Some(classification) = cache.get(key) {
+                return Ok(classification);
+            }
+        }
+
+        self.limits.check(data)?;
+
+        // Images decoded other than into a single `DynamicImage`
+        let direct = if frames::is_gif(data) {
+            Some(self.classify_frames(data, options))
+        } else {
+            self.classify_jpeg(data, options)
+        };
+
+        let classification = match direct {
+            Some(classification) => classification?,
+            None => {
+                let t = Timer::scoped("Load image from memory");
+                let image = self.load_image(&data)?;
+                let load = t.finish();
+
+                let mut classification = self.classify_with_options(&image, options)?;
+                classification.time_image_load = load as i64;
+                classification.stages.record("decode", load);
+
+                classification
+            }
+        };
+
+        if let (Some(cache), Some(key)) = (&self.cache, key) {
+            cache.insert(key, &classification);
+        }
+
+        Ok(classification)
+    }
+
+    /// Classify the frames of a GIF picked by the options, averaging their
+    /// probabilities if there are several
+    #[cfg(feature = "decode")]
+    fn classify_frames(
+        &self,
+        data: &[u8],
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        let t = Timer::scoped("Decoding frames");
+        let (frames, count) = frames::decode_gif(data, options.frames)?;
+        let load = t.finish();
+
+        let mut classification = match frames.as_slice() {
+            [(_, image)] => self.classify_with_options(image, options)?,
+            _ => match &options.roi {
+                Some(region) => {
+                    let cropped = frames
+                        .iter()
+                        .map(|(_, image)| region.crop(image))
+                        .collect::<tensorflow::Result<Vec<_>>>()?;
+                    self.classify_mean(cropped.iter(), options)?
+                }
+                None => self.classify_mean(frames.iter().map(|(_, image)| image), options)?,
+            },
+        };
+
+        classification.time_image_load = load as i64;
+        classification.stages.record("decode", load);
+        classification.frames = Some(FrameReport {
+            count,
+            selection: options.frames,
+            classified: frames.iter().map(|(index, _)| *index).collect(),
+        });
 
         Ok(classification)
     }
 
+    /// Classify the views of test-time augmentation of an image
+    #[cfg(feature = "decode")]
+    fn classify_views(
+        &self,
+        image: &DynamicImage,
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        let views = tta::views(image, options.tta);
+
+        let mut classification = self.classify_mean(views.iter(), options)?;
+        classification.views = Some(views.len());
+
+        Ok(classification)
+    }
+
+    /// Classify several images, averaging their probabilities
+    #[cfg(feature = "decode")]
+    fn classify_mean<'a>(
+        &self,
+        images: impl ExactSizeIterator<Item = &'a DynamicImage>,
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        options.check_deadline("inference")?;
+
+        let t = Timer::scoped(&format!("Running session on {} images", images.len()));
+        let outputs = images
+            .map(|image| Ok(self.probabilities(&self.preprocess(image))?.1))
+            .collect::<tensorflow::Result<Vec<_>>>()?;
+        let session_run = t.finish();
+
+        let mut classification =
+            self.get_classification(None, Aggregation::Mean.aggregate(&outputs), options)?;
+        classification.time_session_run = session_run as i64;
+        classification.stages.record("inference", session_run);
+
+        Ok(classification)
+    }
+
+    /// Classify a JPEG decoded at the smallest DCT scale still covering the
+    /// model input, which skips most of the decoding work on large photos
+    /// and never holds them in memory at full size. `None` for other
+    /// images, and JPEGs the scaled decoder does not handle.
+    #[cfg(feature = "decode")]
+    fn classify_jpeg(
+        &self,
+        data: &[u8],
+        options: &ClassifyOptions,
+    ) -> Option<tensorflow::Result<Classification>> {
+        // Regions and the crops of test-time augmentation are taken from
+        // the full image
+        if !preprocess::is_jpeg(data) || options.roi.is_some() || options.tta > 1 {
+            return None;
+        }
+
+        let (width, height) = self.preprocessing.input_size;
+        let orientation = self.preprocessing.orientation(data);
+
+        let t = Timer::scoped("Decode scaled JPEG");
+        let rgb = match orientation {
+            // Stored sideways, so scaled to cover the input once rotated
+            Some(orientation) if exif::is_transposed(orientation) => {
+                preprocess::decode_jpeg(data, height, width)?
+            }
+            _ => preprocess::decode_jpeg(data, width, height)?,
+        };
+        let rgb = match orientation {
+            Some(orientation) => exif::orient(DynamicImage::ImageRgb8(rgb), orientation).to_rgb(),
+            None => rgb,
+        };
+        let load = t.finish();
+
+        if let Err(err) = options.check_deadline("resizing") {
+            return Some(Err(err));
+        }
+
+        let t = Timer::scoped("Resizing image");
+        let mut input = self.inputs.take();
+        self.preprocessing.apply_rgb_into(&rgb, &mut input);
+        let resize = t.finish();
+
+        Some(self.run_input(&input, options).map(|mut classification| {
+            classification.time_image_load = load as i64;
+            classification.time_image_resize = resize as i64;
+            classification.stages.record("decode", load);
+            classification.stages.record("resize", resize);
+
+            classification
+        }))
+    }
+
+    /// Classify an image and also return a JPEG preview of it, fitting in
+    /// `max_size` x `max_size`
+    #[cfg(feature = "decode")]
+    pub fn classify_with_thumbnail_from_raw(
+        &self,
+        data: &[u8],
+        options: &ClassifyOptions,
+        max_size: u32,
+        quality: u8,
+    ) -> tensorflow::Result<(Classification, Vec<u8>)> {
+        let t = Timer::scoped("Load image from memory");
+        let image = self.load_image(&data)?;
+        let load = t.finish();
+
+        let mut classification = self.classify_with_options(&image, options)?;
+        classification.time_image_load = load as i64;
+        classification.stages.record("decode", load);
+
+        Ok((classification, thumbnail(&image, max_size, quality)?))
+    }
+
+    /// Classify encoded images with a single session run, failing each
+    /// image on its own
+    #[cfg(feature = "decode")]
+    pub fn classify_batch_from_raw(
+        &self,
+        images: &[&[u8]],
+        options: &ClassifyOptions,
+    ) -> Vec<tensorflow::Result<Classification>> {
+        let fetched = images.iter().map(|data| Ok((data.to_vec(), 0))).collect();
+
+        self.classify_batch(fetched, options)
+    }
+
+    /// Fetch images concurrently, up to `MAX_CONCURRENT_FETCHES` at a
+    /// time, and classify them with a single session run, failing each
+    /// image on its own
+    #[cfg(feature = "decode")]
+    pub fn classify_batch_from_urls(
+        &self,
+        urls: &[String],
+        options: &ClassifyOptions,
+    ) -> Vec<tensorflow::Result<Classification>> {
+        let images = self.fetch_batch(urls, options);
+
+        self.classify_fetched_batch(images, options)
+    }
+
+    /// Classify images of `fetch_batch`, or already in memory, with a
+    /// single session run, failing each image on its own
+    #[cfg(feature = "decode")]
+    pub fn classify_fetched_batch(
+        &self,
+        images: Vec<tensorflow::Result<FetchedImage>>,
+        options: &ClassifyOptions,
+    ) -> Vec<tensorflow::Result<Classification>> {
+        let fetched = images
+            .into_iter()
+            .map(|image| {
+                image.map(|image| {
+                    let data = Arc::try_unwrap(image.data).unwrap_or_else(|data| (*data).clone());
+                    (data, image.fetch_time as i64)
+                })
+            })
+            .collect();
+
+        self.classify_batch(fetched, options)
+    }
+
+    /// Fetch images concurrently, up to `MAX_CONCURRENT_FETCHES` at a
+    /// time, for `classify_fetched_batch`
+    #[cfg(feature = "decode")]
+    pub fn fetch_batch(
+        &self,
+        urls: &[String],
+        options: &ClassifyOptions,
+    ) -> Vec<tensorflow::Result<FetchedImage>> {
+        let urls = Arc::new(urls.to_vec());
+        let next = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = mpsc::channel();
+
+        for _ in 0..urls.len().min(MAX_CONCURRENT_FETCHES) {
+            let storage = self.storage.clone();
+            let (urls, next, sender) = (urls.clone(), next.clone(), sender.clone());
+            let trace = options.trace.clone();
+
+            thread::spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let url = match urls.get(index) {
+                    Some(url) => url,
+                    None => break,
+                };
+
+                let mut t = Timer::new_start(&format!("Fetching image from {}", url));
+
+                let buf = match &trace {
+                    Some(trace) => storage.read_traced(url, trace),
+                    None => storage.read(url),
+                };
+
+                t.stop();
+
+                // Status does not cross threads
+                let fetched = buf
+                    .map(|buf| (buf, t.duration()))
+                    .map_err(|err| (err.code(), err.message().unwrap_or("").to_owned()));
+                if sender.send((index, fetched)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        // Images of fetches that panicked are left failed
+        let mut fetched: Vec<tensorflow::Result<FetchedImage>> = urls
+            .iter()
+            .map(|_| {
+                Err(Status::new_set_lossy(
+                    Code::Internal,
+                    "Image fetch panicked",
+                ))
+            })
+            .collect();
+        for (index, result) in receiver {
+            fetched[index] = match result {
+                Ok((data, fetch_time)) => Ok(FetchedImage {
+                    fetch_time: fetch_time as f64,
+                    ..FetchedImage::from(data)
+                }),
+                Err((code, message)) => Err(Status::new_set_lossy(code, &message)),
+            };
+        }
+
+        fetched
+    }
+
+    /// Decode and preprocess fetched images, along with their fetch time,
+    /// and classify those that made it in one batch. Regions, views and the
+    /// frames of GIFs are only handled one image at a time, so images
+    /// needing them are classified on their own.
+    #[cfg(feature = "decode")]
+    fn classify_batch(
+        &self,
+        fetched: Vec<tensorflow::Result<(Vec<u8>, i64)>>,
+        options: &ClassifyOptions,
+    ) -> Vec<tensorflow::Result<Classification>> {
+        let single = options.roi.is_some() || options.tta > 1;
+
+        let prepared: Vec<tensorflow::Result<Prepared>> = fetched
+            .into_iter()
+            .map(|fetched| {
+                let (data, fetch_time) = fetched?;
+
+                if single || frames::is_gif(&data) {
+                    let mut classification = self.classify_from_raw_with_options(&data, options)?;
+                    classification.time_url_fetch = fetch_time;
+                    return Ok(Prepared::Classified(classification));
+                }
+
+                options.check_deadline("decoding")?;
+
+                let mut load = Timer::new_start("Load image from memory");
+                let image = self.load_image(&data)?;
+                load.stop();
+
+                let mut resize = Timer::new_start("Resizing image");
+                let input = self.preprocess(&image);
+                resize.stop();
+
+                Ok(Prepared::Input(
+                    input,
+                    [fetch_time, load.duration(), resize.duration()],
+                ))
+            })
+            .collect();
+
+        let inputs: Vec<Vec<f32>> = prepared
+            .iter()
+            .filter_map(|prepared| match prepared {
+                Ok(Prepared::Input(input, _)) => Some(input.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let mut classifications = match self.run_batch_with_options(&inputs, options) {
+            Ok(classifications) => classifications.into_iter(),
+            Err(err) => {
+                let (code, message) = (err.code(), err.message().unwrap_or("").to_owned());
+                return prepared
+                    .into_iter()
+                    .map(|prepared| match prepared? {
+                        Prepared::Classified(classification) => Ok(classification),
+                        Prepared::Input(..) => Err(Status::new_set_lossy(code, &message)),
+                    })
+                    .collect();
+            }
+        };
+
+        prepared
+            .into_iter()
+            .map(|prepared| {
+                let [fetch_time, load_time, resize_time] = match prepared? {
+                    Prepared::Classified(classification) => return Ok(classification),
+                    Prepared::Input(_, times) => times,
+                };
+
+                let mut classification = classifications.next().ok_or_else(|| {
+                    Status::new_set_lossy(Code::Internal, "Missing batch classification")
+                })?;
+                classification.time_url_fetch = fetch_time;
+                classification.time_image_load = load_time;
+                classification.time_image_resize = resize_time;
+
+                Ok(classification)
+            })
+            .collect()
+    }
+
+    /// Image at `url`, revalidated if cached in `images` and fetched
+    /// otherwise, with the time spent fetching it
+    #[cfg(feature = "decode")]
+    fn fetch_cached(
+        &self,
+        images: &ImageCache,
+        url: &str,
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<(Arc<Vec<u8>>, f64)> {
+        let cached = images.get(url);
+        let validators = cached
+            .as_ref()
+            .map(|(_, validators)| validators.clone())
+            .unwrap_or_default();
+
+        let t = Timer::scoped(&format!("Fetching image from {}", url));
+        let fetched = self
+            .storage
+            .read_if_modified(url, &validators, options.trace.as_ref())?;
+        let fetch = t.finish();
+
+        match (fetched, cached) {
+            (Some((buf, validators)), _) => {
+                let buf = Arc::new(buf);
+                images.insert(url, buf.clone(), validators);
+
+                Ok((buf, fetch))
+            }
+            (None, Some((buf, _))) => {
+                debug!("Image from {} not modified", url);
+                Ok((buf, fetch))
+            }
+            (None, None) => Err(Status::new_set_lossy(
+                Code::Unavailable,
+                &format!("Unexpected 304 Not Modified from {}", url),
+            )),
+        }
+    }
+
+    #[cfg(feature = "decode")]
     pub fn classify_from_url(&self, url: &str) -> tensorflow::Result<Classification> {
-        let mut t = Timer::new_start(&format!("Fetching image from {}", url));
+        self.classify_from_url_with_options(url, &ClassifyOptions::default())
+    }
 
-        let mut resp =
-            reqwest::get(url).map_err(|_| Status::new_set_lossy(Code::NotFound, "Invalid URL"))?;
+    #[cfg(feature = "decode")]
+    pub fn classify_from_url_with_options(
+        &self,
+        url: &str,
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        let image = self.fetch_image(url, options)?;
 
-        let mut buf: Vec<u8> = vec![];
-        resp.copy_to(&mut buf)
-            .map_err(|_| Status::new_set_lossy(Code::DataLoss, "Could not read image from URL"))?;
+        self.classify_fetched(&image, options)
+    }
 
-        t.stop();
+    /// Fetch the image at `url` for `classify_fetched`, unless its
+    /// classification is cached
+    #[cfg(feature = "decode")]
+    pub fn fetch_image(
+        &self,
+        url: &str,
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<FetchedImage> {
+        options.check_deadline("fetching")?;
 
-        let mut classification = self.classify_from_raw(&buf)?;
-        classification.time_url_fetch = t.duration();
+        let key = self
+            .cache
+            .as_ref()
+            .map(|_| ResultCache::url_key(url, options));
+        if let (Some(cache), Some(key)) = (&self.cache, &key) {
+            if let Some(classification) = cache.get(key) {
+                return Ok(FetchedImage {
+                    cached: Some(classification),
+                    ..FetchedImage::from(vec![])
+                });
+            }
+        }
+
+        let (data, fetch_time) = match &self.image_cache {
+            Some(images) => self.fetch_cached(images, url, options)?,
+            None => {
+                let t = Timer::scoped(&format!("Fetching image from {}", url));
+                let buf = match &options.trace {
+                    Some(trace) => self.storage.read_traced(url, trace)?,
+                    None => self.storage.read(url)?,
+                };
+
+                (Arc::new(buf), t.finish())
+            }
+        };
+
+        Ok(FetchedImage {
+            data,
+            fetch_time,
+            key,
+            cached: None,
+        })
+    }
+
+    /// Classify an image of `fetch_image`, or already in memory
+    #[cfg(feature = "decode")]
+    pub fn classify_fetched(
+        &self,
+        image: &FetchedImage,
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        if let Some(classification) = &image.cached {
+            return Ok(classification.clone());
+        }
+
+        let mut classification = self.classify_from_raw_with_options(&image.data, options)?;
+        classification.time_url_fetch = image.fetch_time as i64;
+        classification.stages.record("fetch", image.fetch_time);
+
+        if let (Some(cache), Some(key)) = (&self.cache, &image.key) {
+            cache.insert(key.clone(), &classification);
+        }
 
         Ok(classification)
     }
@@ -225,8 +2092,65 @@ impl ImageClassifier {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn device_config_proto() {
+        assert_eq!("cpu".parse(), Ok(Device::Cpu));
+        assert_eq!("gpu:1".parse(), Ok(Device::Gpu(1)));
+        assert_eq!("2".parse(), Ok(Device::Gpu(2)));
+        assert!("tpu".parse::<Device>().is_err());
+
+        assert!(Device::Default.config_proto().is_empty());
+        assert_eq!(
+            Device::Cpu.config_proto(),
+            vec![0x0a, 0x07, 0x0a, 0x03, b'G', b'P', b'U', 0x10, 0x00]
+        );
+        assert_eq!(
+            Device::Gpu(1).config_proto(),
+            vec![0x32, 0x03, 0x2a, 0x01, b'1']
+        );
+    }
+
+    #[test]
+    fn post_processing() {
+        let mut scores = vec![1.0, 2.0, 3.0];
+        PostProcessing::Softmax.apply(&mut scores);
+        assert!((scores.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(scores[2] > scores[1] && scores[1] > scores[0]);
+
+        let mut scores = vec![0.0];
+        PostProcessing::Sigmoid.apply(&mut scores);
+        assert_eq!(scores, vec![0.5]);
+
+        let mut scores = vec![0.2, 0.7, 0.1];
+        PostProcessing::Auto.apply(&mut scores);
+        assert_eq!(scores, vec![0.2, 0.7, 0.1]);
+
+        let mut scores = vec![-1.0, 4.0];
+        PostProcessing::Auto.apply(&mut scores);
+        assert!((scores.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+
+    #[cfg(feature = "decode")]
+    #[test]
+    fn preprocessing() {
+        let image = DynamicImage::new_rgb8(8, 4);
+
+        let profile = Preprocessing {
+            input_size: (2, 3),
+            mean: [0.5; 3],
+            std: [0.25; 3],
+            auto_orient: true,
+        };
+
+        let input = profile.apply(&image);
+        assert_eq!(input.len(), 2 * 3 * 3);
+        assert!(input.iter().all(|x| (x + 2.0).abs() < 1e-6));
+    }
 }
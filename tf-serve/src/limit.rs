@@ -0,0 +1,188 @@
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+use tensorflow::{Code, Status};
+
+struct State {
+    in_flight: usize,
+    queued: usize,
+}
+
+/// Bound on concurrent inferences, queueing a limited number of requests
+/// beyond it and shedding the rest. TensorFlow sessions serialize runs
+/// anyway, so more concurrency only builds latency.
+pub struct ConcurrencyLimit {
+    max_in_flight: usize,
+    max_queued: usize,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+/// Slot of a `ConcurrencyLimit`, released when dropped
+pub struct Permit<'a> {
+    limit: &'a ConcurrencyLimit,
+}
+
+/// Slot of a shared `ConcurrencyLimit`, released when dropped, for holders
+/// outliving a borrow of the limit, like threads
+pub struct OwnedPermit {
+    limit: Arc<ConcurrencyLimit>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max_in_flight: usize, max_queued: usize) -> Self {
+        ConcurrencyLimit {
+            max_in_flight: max_in_flight.max(1),
+            max_queued,
+            state: Mutex::new(State {
+                in_flight: 0,
+                queued: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Limit that never sheds
+    pub fn unlimited() -> Self {
+        ConcurrencyLimit::new(usize::MAX, 0)
+    }
+
+    /// Take a slot, waiting in the queue while all are taken. Fails with
+    /// `Aborted` when the queue is full as well, for the client to retry
+    /// later.
+    pub fn acquire(&self) -> tensorflow::Result<Permit<'_>> {
+        self.acquire_until(None)
+    }
+
+    /// Take a slot as by `acquire`, failing with `DeadlineExceeded` if still
+    /// queued at `deadline`
+    pub fn acquire_until(&self, deadline: Option<Instant>) -> tensorflow::Result<Permit<'_>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.in_flight >= self.max_in_flight {
+            if state.queued >= self.max_queued {
+                return Err(Status::new_set_lossy(
+                    Code::Aborted,
+                    &format!(
+                        "Too many requests ({} in flight, {} queued)",
+                        state.in_flight, state.queued
+                    ),
+                ));
+            }
+
+            state.queued += 1;
+            while state.in_flight >= self.max_in_flight {
+                let deadline = match deadline {
+                    Some(deadline) => deadline,
+                    None => {
+                        state = self.released.wait(state).unwrap();
+                        continue;
+                    }
+                };
+
+                let now = Instant::now();
+                if now >= deadline {
+                    state.queued -= 1;
+                    // Passed on, in case this waiter took the wakeup
+                    self.released.notify_one();
+                    return Err(Status::new_set_lossy(
+                        Code::DeadlineExceeded,
+                        "Deadline exceeded while queued",
+                    ));
+                }
+                state = self.released.wait_timeout(state, deadline - now).unwrap().0;
+            }
+            state.queued -= 1;
+        }
+
+        state.in_flight += 1;
+
+        Ok(Permit { limit: self })
+    }
+
+    /// Take a slot as by `acquire`, with a permit keeping the limit alive
+    pub fn acquire_owned(self: Arc<Self>) -> tensorflow::Result<OwnedPermit> {
+        // Released by the owned permit instead
+        mem::forget(self.acquire()?);
+
+        Ok(OwnedPermit { limit: self })
+    }
+
+    /// Number of requests holding a slot
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    fn release(&self) {
+        self.state.lock().unwrap().in_flight -= 1;
+        self.released.notify_one();
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limit.release();
+    }
+}
+
+impl Drop for OwnedPermit {
+    fn drop(&mut self) {
+        self.limit.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn load_shedding() {
+        let limit = Arc::new(ConcurrencyLimit::new(1, 1));
+
+        let permit = limit.acquire().unwrap();
+        assert_eq!(limit.in_flight(), 1);
+
+        let queued = {
+            let limit = limit.clone();
+            thread::spawn(move || limit.acquire().map(|_| ()).is_ok())
+        };
+        while limit.state.lock().unwrap().queued == 0 {
+            thread::yield_now();
+        }
+
+        // Both the slot and the queue are taken
+        assert_eq!(limit.acquire().err().unwrap().code(), Code::Aborted);
+
+        drop(permit);
+        assert!(queued.join().unwrap());
+        assert_eq!(limit.in_flight(), 0);
+    }
+
+    #[test]
+    fn queue_deadline() {
+        let limit = ConcurrencyLimit::new(1, 1);
+        let _permit = limit.acquire().unwrap();
+
+        let deadline = Instant::now() + std::time::Duration::from_millis(10);
+        let err = limit.acquire_until(Some(deadline)).err().unwrap();
+
+        assert_eq!(err.code(), Code::DeadlineExceeded);
+        assert_eq!(limit.state.lock().unwrap().queued, 0);
+    }
+
+    #[test]
+    fn owned_permits() {
+        let limit = Arc::new(ConcurrencyLimit::new(1, 0));
+
+        let permit = limit.clone().acquire_owned().unwrap();
+        assert_eq!(
+            limit.clone().acquire_owned().err().unwrap().code(),
+            Code::Aborted
+        );
+
+        thread::spawn(move || drop(permit)).join().unwrap();
+        assert_eq!(limit.in_flight(), 0);
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use hdrhistogram::Histogram;
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+/// Longest recorded duration, an hour in microseconds. Longer ones are
+/// recorded as that.
+const MAX_DURATION_US: u64 = 3_600_000_000;
+
+lazy_static! {
+    static ref GLOBAL: Metrics = Metrics::new();
+}
+
+/// Latency aggregates of a stage, in milliseconds
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StageStats {
+    pub count: u64,
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+/// Registry of latency histograms, one per stage, to the microsecond with 3
+/// significant digits. Every stage recorded into `Timings` by a
+/// `ScopedTimer` is aggregated into the global registry.
+pub struct Metrics {
+    stages: Mutex<BTreeMap<String, Histogram<u64>>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            stages: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Registry of the process
+    pub fn global() -> &'static Metrics {
+        &GLOBAL
+    }
+
+    /// Record a duration of `stage`, in milliseconds
+    pub fn record(&self, stage: &str, duration_ms: f64) {
+        let mut stages = self.stages.lock().unwrap();
+
+        if !stages.contains_key(stage) {
+            match Histogram::new_with_bounds(1, MAX_DURATION_US, 3) {
+                Ok(histogram) => stages.insert(stage.to_owned(), histogram),
+                Err(_) => return,
+            };
+        }
+
+        if let Some(histogram) = stages.get_mut(stage) {
+            histogram.saturating_record((duration_ms.max(0.0) * 1000.0) as u64);
+        }
+    }
+
+    /// Aggregates of every stage recorded since the start, or the last reset
+    pub fn snapshot(&self) -> BTreeMap<String, StageStats> {
+        let ms = |us: u64| us as f64 / 1000.0;
+
+        self.stages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(stage, histogram)| {
+                let stats = StageStats {
+                    count: histogram.len(),
+                    min_ms: ms(histogram.min()),
+                    mean_ms: histogram.mean() / 1000.0,
+                    p50_ms: ms(histogram.value_at_quantile(0.5)),
+                    p95_ms: ms(histogram.value_at_quantile(0.95)),
+                    p99_ms: ms(histogram.value_at_quantile(0.99)),
+                    max_ms: ms(histogram.max()),
+                };
+
+                (stage.clone(), stats)
+            })
+            .collect()
+    }
+
+    /// Forget every recorded duration
+    pub fn reset(&self) {
+        self.stages.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let metrics = Metrics::new();
+
+        for i in 1..=100 {
+            metrics.record("inference", i as f64);
+        }
+        metrics.record("resize", 0.25);
+
+        let snapshot = metrics.snapshot();
+        let inference = &snapshot["inference"];
+        assert_eq!(inference.count, 100);
+        assert!((inference.p50_ms - 50.0).abs() < 0.1);
+        assert!((inference.p99_ms - 99.0).abs() < 0.1);
+        assert!((inference.min_ms - 1.0).abs() < 0.01);
+        assert!((inference.max_ms - 100.0).abs() < 0.1);
+        assert!((snapshot["resize"].p50_ms - 0.25).abs() < 0.001);
+
+        metrics.reset();
+        assert!(metrics.snapshot().is_empty());
+    }
+}
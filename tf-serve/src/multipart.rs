@@ -0,0 +1,148 @@
+//! Parsing of `multipart/form-data` bodies, as sent by browser forms and
+//! `curl -F`.
+
+use tensorflow::{Code, Status};
+
+/// Part of a `multipart/form-data` body
+#[derive(Debug, PartialEq)]
+pub struct Part<'a> {
+    /// Form field name
+    pub name: Option<String>,
+
+    /// Name of the uploaded file, if the part is one
+    pub filename: Option<String>,
+
+    /// Content type of the part
+    pub content_type: Option<String>,
+
+    /// Body of the part
+    pub data: &'a [u8],
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from > haystack.len() {
+        return None;
+    }
+
+    haystack[from..]
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| pos + from)
+}
+
+/// Value of the `name` parameter of a header value like
+/// `form-data; name="image"; filename="cat.jpg"`
+fn parameter(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let pos = param.find('=')?;
+
+        if param[..pos].trim().eq_ignore_ascii_case(name) {
+            Some(param[pos + 1..].trim().trim_matches('"').to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// Boundary of a `multipart/form-data` content type, if it is one
+pub fn boundary(content_type: &str) -> Option<String> {
+    let media_type = content_type.split(';').next()?.trim();
+
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    parameter(content_type, "boundary").filter(|boundary| !boundary.is_empty())
+}
+
+/// Split a body into its parts
+pub fn parse<'a>(body: &'a [u8], boundary: &str) -> tensorflow::Result<Vec<Part<'a>>> {
+    let invalid = |reason: &str| {
+        Status::new_set_lossy(
+            Code::InvalidArgument,
+            &format!("Malformed multipart body: {}", reason),
+        )
+    };
+
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let next_delimiter = format!("\r\n--{}", boundary).into_bytes();
+
+    let mut pos = find(body, &delimiter, 0).ok_or_else(|| invalid("no boundary"))?;
+    let mut parts = vec![];
+
+    loop {
+        pos += delimiter.len();
+
+        // The last delimiter is followed by "--"
+        if body[pos..].starts_with(b"--") {
+            return Ok(parts);
+        }
+
+        let headers_start = find(body, b"\r\n", pos).ok_or_else(|| invalid("truncated"))? + 2;
+        let headers_end =
+            find(body, b"\r\n\r\n", headers_start).ok_or_else(|| invalid("truncated headers"))?;
+        let data_end = find(body, &next_delimiter, headers_end + 4)
+            .ok_or_else(|| invalid("missing closing boundary"))?;
+
+        let headers = std::str::from_utf8(&body[headers_start..headers_end])
+            .map_err(|_| invalid("headers not UTF-8"))?;
+
+        let mut part = Part {
+            name: None,
+            filename: None,
+            content_type: None,
+            data: &body[headers_end + 4..data_end],
+        };
+
+        for line in headers.split("\r\n") {
+            let colon = match line.find(':') {
+                Some(colon) => colon,
+                None => continue,
+            };
+            let (name, value) = (line[..colon].trim(), line[colon + 1..].trim());
+
+            if name.eq_ignore_ascii_case("content-disposition") {
+                part.name = parameter(value, "name");
+                part.filename = parameter(value, "filename");
+            } else if name.eq_ignore_ascii_case("content-type") {
+                part.content_type = Some(value.to_owned());
+            }
+        }
+
+        parts.push(part);
+        pos = data_end + 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn form_data() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=\"----x1\"").as_deref(),
+            Some("----x1")
+        );
+        assert!(boundary("multipart/mixed; boundary=x1").is_none());
+
+        let body = b"preamble\r\n------x1\r\n\
+            Content-Disposition: form-data; name=\"options\"\r\n\r\n\
+            {\"top_k\": 5}\r\n\
+            ------x1\r\n\
+            Content-Disposition: form-data; name=\"image\"; filename=\"cat.jpg\"\r\n\
+            Content-Type: image/jpeg\r\n\r\n\
+            \xff\xd8\r\n\xff\xd9\r\n\
+            ------x1--\r\n";
+
+        let parts = parse(body, "----x1").unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name.as_deref(), Some("options"));
+        assert_eq!(parts[0].data, b"{\"top_k\": 5}");
+        assert_eq!(parts[1].filename.as_deref(), Some("cat.jpg"));
+        assert_eq!(parts[1].content_type.as_deref(), Some("image/jpeg"));
+        assert_eq!(parts[1].data, b"\xff\xd8\r\n\xff\xd9");
+
+        assert!(parse(b"------x1\r\nno end", "----x1").is_err());
+    }
+}
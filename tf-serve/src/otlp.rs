@@ -0,0 +1,276 @@
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::{json, Value};
+use tensorflow::{Code, Status};
+
+use crate::{new_span_id, TraceContext};
+
+/// Most exports waiting to be sent, beyond which spans are dropped
+const MAX_PENDING_EXPORTS: usize = 256;
+
+/// Most spans sent in one export request
+const MAX_BATCH_SPANS: usize = 512;
+
+/// Span of a request or one of its stages
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub name: String,
+    pub span_id: String,
+
+    /// ID of the calling span, if any
+    pub parent_id: Option<String>,
+
+    pub start: SystemTime,
+    pub end: SystemTime,
+
+    /// String attributes, such as the tag of a classification
+    pub attributes: Vec<(String, String)>,
+
+    /// Message of the failure that ended the span, if any
+    pub error: Option<String>,
+}
+
+impl Span {
+    /// Span of the stages of a request, in milliseconds and in the order they
+    /// ran from `start`, as children of `parent`. The fetch stage gets the ID
+    /// propagated to the image server by `outbound`.
+    pub fn stages(
+        parent: &Span,
+        stages: &[(&str, i64)],
+        outbound: Option<&TraceContext>,
+    ) -> Vec<Span> {
+        let mut time = parent.start;
+        let mut spans = vec![];
+
+        for &(name, duration) in stages {
+            // Stages that did not run, like the fetch of an uploaded image
+            if duration <= 0 {
+                continue;
+            }
+
+            let end = time + Duration::from_millis(duration as u64);
+            let span_id = match (name, outbound.and_then(|trace| trace.parent_id.as_ref())) {
+                ("fetch", Some(id)) => id.clone(),
+                _ => new_span_id(),
+            };
+
+            spans.push(Span {
+                name: name.to_owned(),
+                span_id,
+                parent_id: Some(parent.span_id.clone()),
+                start: time,
+                end,
+                attributes: vec![],
+                error: None,
+            });
+
+            time = end;
+        }
+
+        spans
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos())
+        .to_string()
+}
+
+/// Exporter of spans to an OpenTelemetry collector, over OTLP/HTTP with the
+/// JSON encoding. Spans are sent in the background, in batches of those
+/// that ended while the previous batch was in flight, and dropped when the
+/// collector falls behind.
+pub struct OtlpExporter {
+    sender: Mutex<SyncSender<Vec<Value>>>,
+}
+
+impl OtlpExporter {
+    /// Exporter to the traces endpoint of a collector, such as
+    /// `http://localhost:4318/v1/traces`, with additional `headers`
+    pub fn new(
+        endpoint: &str,
+        headers: Vec<(String, String)>,
+        service_name: &str,
+    ) -> tensorflow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|err| Status::new_set_lossy(Code::Internal, &format!("{}", err)))?;
+
+        let (sender, receiver) = mpsc::sync_channel::<Vec<Value>>(MAX_PENDING_EXPORTS);
+        let endpoint = endpoint.to_owned();
+        let service = service_name.to_owned();
+
+        thread::spawn(move || {
+            while let Ok(mut spans) = receiver.recv() {
+                for more in receiver.try_iter() {
+                    spans.extend(more);
+                    if spans.len() >= MAX_BATCH_SPANS {
+                        break;
+                    }
+                }
+
+                let mut request = client.post(&endpoint).json(&document(&service, spans));
+                for (name, value) in &headers {
+                    request = request.header(name.as_str(), value.as_str());
+                }
+
+                match request.send() {
+                    Ok(resp) if resp.status().is_success() => {}
+                    Ok(resp) => warn!("Could not export spans: {}", resp.status()),
+                    Err(err) => warn!("Could not export spans: {}", err),
+                }
+            }
+        });
+
+        Ok(OtlpExporter {
+            sender: Mutex::new(sender),
+        })
+    }
+
+    /// Exporter configured by the standard variables of the OpenTelemetry
+    /// SDKs, if `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` or
+    /// `OTEL_EXPORTER_OTLP_ENDPOINT` is set: `OTEL_EXPORTER_OTLP_HEADERS`
+    /// as `name=value` pairs separated by commas, and `OTEL_SERVICE_NAME`
+    pub fn from_env() -> Option<Self> {
+        let endpoint = std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+            .ok()
+            .or_else(|| {
+                std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+                    .ok()
+                    .map(|endpoint| format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            })?;
+
+        let headers = std::env::var("OTEL_EXPORTER_OTLP_HEADERS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|header| {
+                let pos = header.find('=')?;
+                Some((
+                    header[..pos].trim().to_owned(),
+                    header[pos + 1..].trim().to_owned(),
+                ))
+            })
+            .collect();
+
+        let service_name =
+            std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "tf-serve".to_owned());
+
+        match OtlpExporter::new(&endpoint, headers, &service_name) {
+            Ok(exporter) => Some(exporter),
+            Err(err) => {
+                warn!("Could not export spans to {}: {}", endpoint, err);
+                None
+            }
+        }
+    }
+
+    /// Export the spans of `trace`, if it is sampled
+    pub fn export(&self, trace: &TraceContext, spans: &[Span]) {
+        if !trace.sampled {
+            return;
+        }
+
+        let spans = spans.iter().map(|span| span_json(trace, span)).collect();
+        match self.sender.lock().unwrap().try_send(spans) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("Dropped spans: too many pending exports"),
+            Err(TrySendError::Disconnected(_)) => warn!("Span exporter stopped"),
+        }
+    }
+}
+
+fn span_json(trace: &TraceContext, span: &Span) -> Value {
+    let attributes: Vec<Value> = span
+        .attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect();
+
+    // Server spans for requests, internal ones for their stages
+    let kind = if span.parent_id == trace.parent_id {
+        2
+    } else {
+        1
+    };
+
+    let status = match &span.error {
+        Some(message) => json!({ "code": 2, "message": message }),
+        None => json!({ "code": 1 }),
+    };
+
+    json!({
+        "traceId": trace.trace_id,
+        "spanId": span.span_id,
+        "parentSpanId": span.parent_id.as_deref().unwrap_or(""),
+        "name": span.name,
+        "kind": kind,
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(span.end),
+        "attributes": attributes,
+        "status": status,
+    })
+}
+
+/// Body of an export request
+fn document(service_name: &str, spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": service_name } },
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "tf-serve", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stage_spans() {
+        let trace = TraceContext::from_traceparent(
+            "00-5759e988bd862e3fe1be46a994272793-53995c3f42cd8ad8-01",
+        )
+        .unwrap();
+        let outbound = trace.child();
+
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let request = Span {
+            name: "POST /".to_owned(),
+            span_id: new_span_id(),
+            parent_id: trace.parent_id.clone(),
+            start,
+            end: start + Duration::from_millis(100),
+            attributes: vec![("tag".to_owned(), "cat".to_owned())],
+            error: None,
+        };
+
+        let stages = Span::stages(
+            &request,
+            &[("fetch", 20), ("decode", 0), ("inference", 50)],
+            Some(&outbound),
+        );
+        assert_eq!(stages.len(), 2);
+        assert_eq!(Some(&stages[0].span_id), outbound.parent_id.as_ref());
+        assert_eq!(stages[1].start, start + Duration::from_millis(20));
+
+        let json = span_json(&trace, &request);
+        assert_eq!(json["traceId"], "5759e988bd862e3fe1be46a994272793");
+        assert_eq!(json["parentSpanId"], "53995c3f42cd8ad8");
+        assert_eq!(json["kind"], 2);
+        assert_eq!(json["startTimeUnixNano"], "1600000000000000000");
+        assert_eq!(span_json(&trace, &stages[1])["kind"], 1);
+    }
+}
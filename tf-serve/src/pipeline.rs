@@ -0,0 +1,120 @@
+use image::{DynamicImage, GenericImageView};
+use serde::Serialize;
+
+use crate::preprocess;
+use crate::{
+    Classification, ClassifyOptions, Detection, Detector, ImageClassifier, ImageLimits, Timer,
+};
+
+/// Detected object along with the classification of its crop
+#[derive(Serialize)]
+pub struct PipelineItem {
+    pub detection: Detection,
+    pub classification: Classification,
+}
+
+#[derive(Default, Serialize)]
+pub struct PipelineResult {
+    /// Detected and classified objects, best detection first
+    pub items: Vec<PipelineItem>,
+
+    /// Time spent loading image in memory
+    pub time_image_load: i64,
+
+    /// Time spent detecting objects
+    pub time_detection: i64,
+
+    /// Time spent classifying all crops
+    pub time_classification: i64,
+}
+
+impl PipelineResult {
+    /// Durations of the stages of the pipeline, in milliseconds and in the
+    /// order they ran
+    pub fn timings(&self) -> [(&'static str, i64); 3] {
+        [
+            ("decode", self.time_image_load),
+            ("detection", self.time_detection),
+            ("classification", self.time_classification),
+        ]
+    }
+}
+
+/// Two-stage pipeline feeding the crops of every detected object to a
+/// classifier
+pub struct Pipeline<'a> {
+    detector: &'a Detector,
+    classifier: &'a ImageClassifier,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(detector: &'a Detector, classifier: &'a ImageClassifier) -> Self {
+        Pipeline {
+            detector,
+            classifier,
+        }
+    }
+
+    pub fn run(
+        &self,
+        image: &DynamicImage,
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<PipelineResult> {
+        let mut t = Timer::new_start("Detecting objects");
+
+        let detections = self.detector.detect(image)?.detections;
+
+        t.stop();
+
+        let mut c = Timer::new_start("Classifying crops");
+
+        let (width, height) = image.dimensions();
+        let mut source = image.clone();
+
+        let items = detections
+            .into_iter()
+            .map(|detection| -> tensorflow::Result<PipelineItem> {
+                let bbox = &detection.bbox;
+
+                let x = ((bbox.xmin.max(0.0) * width as f32) as u32).min(width.saturating_sub(1));
+                let y = ((bbox.ymin.max(0.0) * height as f32) as u32).min(height.saturating_sub(1));
+                let crop_width = ((bbox.xmax.min(1.0) * width as f32) as u32).saturating_sub(x);
+                let crop_height = ((bbox.ymax.min(1.0) * height as f32) as u32).saturating_sub(y);
+
+                let crop = source.crop(x, y, crop_width.max(1), crop_height.max(1));
+                let classification = self.classifier.classify_with_options(&crop, options)?;
+
+                Ok(PipelineItem {
+                    detection,
+                    classification,
+                })
+            })
+            .collect::<tensorflow::Result<Vec<PipelineItem>>>()?;
+
+        c.stop();
+
+        Ok(PipelineResult {
+            items,
+            time_detection: t.duration(),
+            time_classification: c.duration(),
+            ..Default::default()
+        })
+    }
+
+    pub fn run_from_raw(
+        &self,
+        data: &[u8],
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<PipelineResult> {
+        let mut t = Timer::new_start("Load image from memory");
+
+        let image = preprocess::decode(data, &ImageLimits::default())?;
+
+        t.stop();
+
+        let mut result = self.run(&image, options)?;
+        result.time_image_load = t.duration();
+
+        Ok(result)
+    }
+}
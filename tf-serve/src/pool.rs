@@ -0,0 +1,89 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+use tensorflow::Tensor;
+
+/// Input tensors of a single shape, reused across requests instead of
+/// allocated for each of them. The pool grows to the number of concurrent
+/// requests, which a `ConcurrencyLimit` keeps small.
+pub(crate) struct TensorPool {
+    dims: Vec<u64>,
+    tensors: Mutex<Vec<Tensor<f32>>>,
+}
+
+/// Tensor taken from a `TensorPool`, returned to it when dropped
+pub(crate) struct PooledTensor<'a> {
+    pool: &'a TensorPool,
+    tensor: Option<Tensor<f32>>,
+}
+
+impl TensorPool {
+    pub fn new(dims: &[u64]) -> Self {
+        TensorPool {
+            dims: dims.to_vec(),
+            tensors: Mutex::new(vec![]),
+        }
+    }
+
+    /// Tensor to fill in place. Its values are left over from its previous
+    /// use, if any.
+    pub fn take(&self) -> PooledTensor<'_> {
+        let tensor = self
+            .tensors
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Tensor::new(&self.dims));
+
+        PooledTensor {
+            pool: self,
+            tensor: Some(tensor),
+        }
+    }
+}
+
+impl Deref for PooledTensor<'_> {
+    type Target = Tensor<f32>;
+
+    fn deref(&self) -> &Tensor<f32> {
+        self.tensor.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledTensor<'_> {
+    fn deref_mut(&mut self) -> &mut Tensor<f32> {
+        self.tensor.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledTensor<'_> {
+    fn drop(&mut self) {
+        if let Some(tensor) = self.tensor.take() {
+            self.pool.tensors.lock().unwrap().push(tensor);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let pool = TensorPool::new(&[1, 2, 2, 3]);
+
+        {
+            let mut first = pool.take();
+            let second = pool.take();
+            assert_eq!(first.len(), 12);
+            assert_eq!(second.dims(), &[1, 2, 2, 3]);
+
+            first[0] = 1.0;
+        }
+        assert_eq!(pool.tensors.lock().unwrap().len(), 2);
+
+        let reused = pool.take();
+        assert_eq!(reused[0], 1.0);
+        assert_eq!(pool.tensors.lock().unwrap().len(), 1);
+    }
+}
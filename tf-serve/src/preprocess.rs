@@ -0,0 +1,490 @@
+//! Decoding, resizing and normalization kernels of `Preprocessing`. With
+//! the `accel` feature, images are resized with the SIMD convolutions of
+//! `fast_image_resize` and normalized by rows in parallel with `rayon`;
+//! otherwise with `image` and on the calling thread.
+
+use image::{DynamicImage, RgbImage};
+use jpeg_decoder::PixelFormat;
+use tensorflow::{Code, Status};
+
+/// Values normalized by each parallel task, a few rows of a 224x224 input
+#[cfg(feature = "accel")]
+const CHUNK_SIZE: usize = 224 * 3 * 16;
+
+/// Bounds on the size of images, checked against their headers before they
+/// are decoded, so that a small file claiming huge dimensions is refused
+/// instead of allocating its pixels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+
+    /// Most pixels, width times height
+    pub max_pixels: u64,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        ImageLimits {
+            max_width: 16384,
+            max_height: 16384,
+            max_pixels: 64 << 20,
+        }
+    }
+}
+
+impl ImageLimits {
+    /// Fail with `ResourceExhausted` if the image in `data` is larger than
+    /// the limits, or with `InvalidArgument` if its dimensions cannot be
+    /// read. The dimensions of PNGs, JPEGs, GIFs, BMPs, WebPs and TIFFs are
+    /// read from their headers; images of other formats are refused.
+    pub fn check(&self, data: &[u8]) -> tensorflow::Result<()> {
+        let (width, height) = dimensions(data).ok_or_else(|| {
+            let message = match format(data) {
+                Some(format) => format!("Could not read the dimensions of the {} image", format),
+                None => "Unrecognized image format".to_owned(),
+            };
+            Status::new_set_lossy(Code::InvalidArgument, &message)
+        })?;
+
+        if width > self.max_width
+            || height > self.max_height
+            || width as u64 * height as u64 > self.max_pixels
+        {
+            return Err(Status::new_set_lossy(
+                Code::ResourceExhausted,
+                &format!(
+                    "Image of {}x{} pixels exceeds the limit of {}x{} and {} pixels",
+                    width, height, self.max_width, self.max_height, self.max_pixels
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Width and height of an image, from its header
+fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let u16_le = |pos: usize| Some(u16::from_le_bytes([*data.get(pos)?, *data.get(pos + 1)?]));
+    let u16_be = |pos: usize| Some(u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]));
+    let u32_le = |pos: usize| Some(u32::from(u16_le(pos)?) | (u32::from(u16_le(pos + 2)?) << 16));
+    let u32_be = |pos: usize| Some((u32::from(u16_be(pos)?) << 16) | u32::from(u16_be(pos + 2)?));
+    let u24_le =
+        |pos: usize| Some(u32::from(u16_le(pos)?) | (u32::from(*data.get(pos + 2)?) << 16));
+
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some((u32_be(16)?, u32_be(20)?))
+    } else if data.starts_with(b"GIF8") {
+        Some((u16_le(6)?.into(), u16_le(8)?.into()))
+    } else if data.starts_with(b"BM") {
+        // Negative heights are of top-down bitmaps
+        Some((
+            (u32_le(18)? as i32).unsigned_abs(),
+            (u32_le(22)? as i32).unsigned_abs(),
+        ))
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(&b"WEBP"[..]) {
+        match data.get(12..16)? {
+            b"VP8 " => Some((
+                u32::from(u16_le(26)? & 0x3fff),
+                u32::from(u16_le(28)? & 0x3fff),
+            )),
+            b"VP8L" => {
+                let bits = u32_le(21)?;
+                Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+            }
+            b"VP8X" => Some((u24_le(24)? + 1, u24_le(27)? + 1)),
+            _ => None,
+        }
+    } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        tiff_dimensions(data)
+    } else if is_jpeg(data) {
+        jpeg_dimensions(data)
+    } else {
+        None
+    }
+}
+
+/// Width and height of a TIFF, from the entries of its first IFD
+fn tiff_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let little_endian = data.starts_with(b"II");
+    let u16_at = |pos: usize| {
+        let bytes = [*data.get(pos)?, *data.get(pos + 1)?];
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let u32_at = |pos: usize| {
+        let (first, second) = (u32::from(u16_at(pos)?), u32::from(u16_at(pos + 2)?));
+        Some(if little_endian {
+            first | (second << 16)
+        } else {
+            (first << 16) | second
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let (mut width, mut height) = (None, None);
+
+    for entry in 0..u16_at(ifd)? as usize {
+        let pos = ifd + 2 + entry * 12;
+
+        // Either a SHORT or a LONG, left-justified in the value field
+        let value = match u16_at(pos + 2)? {
+            3 => u32::from(u16_at(pos + 8)?),
+            4 => u32_at(pos + 8)?,
+            _ => continue,
+        };
+
+        match u16_at(pos)? {
+            256 => width = Some(value),
+            257 => height = Some(value),
+            _ => {}
+        }
+    }
+
+    Some((width?, height?))
+}
+
+/// Width and height of a JPEG, from its start of frame
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let mut pos = 2;
+
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xff {
+            return None;
+        }
+
+        let marker = data[pos + 1];
+        if marker == 0xff {
+            pos += 1;
+            continue;
+        }
+
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+
+        // Start of frame markers, besides DHT, JPG and DAC
+        if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+            let frame = data.get(pos + 5..pos + 9)?;
+            let height = u16::from_be_bytes([frame[0], frame[1]]);
+            let width = u16::from_be_bytes([frame[2], frame[3]]);
+
+            return Some((width.into(), height.into()));
+        }
+
+        pos += 2 + len;
+    }
+
+    None
+}
+
+/// Name of the format of an image, from its signature
+fn format(data: &[u8]) -> Option<&'static str> {
+    let brand = |brands: &[&[u8]]| {
+        data.get(4..8) == Some(&b"ftyp"[..])
+            && brands.iter().any(|brand| data.get(8..12) == Some(*brand))
+    };
+
+    if is_jpeg(data) {
+        Some("jpeg")
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if data.starts_with(b"GIF8") {
+        Some("gif")
+    } else if data.starts_with(b"BM") {
+        Some("bmp")
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(&b"WEBP"[..]) {
+        Some("webp")
+    } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        Some("tiff")
+    } else if brand(&[b"avif", b"avis"]) {
+        Some("avif")
+    } else if brand(&[b"heic", b"heix", b"mif1"]) {
+        Some("heic")
+    } else {
+        None
+    }
+}
+
+/// Formats images can be decoded from. WebP and TIFF need the features of
+/// the same names. ICO, PNM, TGA and HDR images are not decoded, since
+/// their dimensions cannot be checked before, nor are AVIF and HEIC images,
+/// which `image` has no decoders for.
+pub fn accepted_formats() -> Vec<&'static str> {
+    let mut formats = vec!["jpeg", "png", "gif", "bmp"];
+
+    if cfg!(feature = "webp") {
+        formats.push("webp");
+    }
+    if cfg!(feature = "tiff") {
+        formats.push("tiff");
+    }
+
+    formats
+}
+
+/// Decode an image, failing if it is larger than `limits`
+pub(crate) fn decode(data: &[u8], limits: &ImageLimits) -> tensorflow::Result<DynamicImage> {
+    let accepted = accepted_formats();
+    let invalid = |message: String| {
+        Status::new_set_lossy(
+            Code::InvalidArgument,
+            &format!("{} (accepted: {})", message, accepted.join(", ")),
+        )
+    };
+
+    let format = match format(data) {
+        Some(format) if accepted.contains(&format) => format,
+        Some(format) => return Err(invalid(format!("Unsupported image format: {}", format))),
+        None => return Err(invalid("Unrecognized image format".to_owned())),
+    };
+
+    limits.check(data)?;
+
+    image::load_from_memory(data)
+        .map_err(|err| invalid(format!("Could not decode {} image: {}", format, err)))
+}
+
+/// Whether `data` starts like a JPEG
+pub(crate) fn is_jpeg(data: &[u8]) -> bool {
+    data.starts_with(&[0xff, 0xd8, 0xff])
+}
+
+/// Decode a JPEG at the smallest of the 1/8, 1/4, 1/2 and full DCT scales
+/// still at least `width` x `height`, which saves the IDCT and color
+/// conversion of the pixels dropped. `None` if the JPEG is invalid or not
+/// grayscale or RGB, for it to be decoded in full instead.
+pub(crate) fn decode_jpeg(data: &[u8], width: u32, height: u32) -> Option<RgbImage> {
+    let clamp = |size: u32| size.min(u16::MAX as u32) as u16;
+
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    let (scaled_width, scaled_height) = decoder.scale(clamp(width), clamp(height)).ok()?;
+    let pixels = decoder.decode().ok()?;
+
+    let pixels = match decoder.info()?.pixel_format {
+        PixelFormat::RGB24 => pixels,
+        PixelFormat::L8 => pixels
+            .iter()
+            .flat_map(|&luma| std::iter::repeat(luma).take(3))
+            .collect(),
+        _ => return None,
+    };
+
+    RgbImage::from_raw(scaled_width as u32, scaled_height as u32, pixels)
+}
+
+/// Pixels of `rgb` resized to `width` x `height` with a bilinear filter
+#[cfg(not(feature = "accel"))]
+pub(crate) fn resize(rgb: &RgbImage, width: u32, height: u32) -> Vec<u8> {
+    image::imageops::resize(rgb, width, height, image::imageops::FilterType::Triangle).into_raw()
+}
+
+/// Pixels of `rgb` resized to `width` x `height` with a bilinear filter
+#[cfg(feature = "accel")]
+pub(crate) fn resize(rgb: &RgbImage, width: u32, height: u32) -> Vec<u8> {
+    use std::num::NonZeroU32;
+
+    use fast_image_resize as fr;
+
+    let dimensions = |width, height| Some((NonZeroU32::new(width)?, NonZeroU32::new(height)?));
+    let (src_width, src_height, dst_width, dst_height) = match (
+        dimensions(rgb.width(), rgb.height()),
+        dimensions(width, height),
+    ) {
+        (Some((src_width, src_height)), Some((dst_width, dst_height))) => {
+            (src_width, src_height, dst_width, dst_height)
+        }
+        // Nothing to resize
+        _ => return vec![0; (width * height * 3) as usize],
+    };
+
+    let src = fr::Image::from_vec_u8(src_width, src_height, rgb.to_vec(), fr::PixelType::U8x3)
+        .expect("RGB image of unexpected size");
+    let mut dst = fr::Image::new(dst_width, dst_height, fr::PixelType::U8x3);
+
+    fr::Resizer::new(fr::ResizeAlg::Convolution(fr::FilterType::Bilinear))
+        .resize(&src.view(), &mut dst.view_mut())
+        .expect("Resizing between different pixel types");
+
+    dst.into_vec()
+}
+
+/// Per-channel factors of `(x / 255 - mean) / std`, as `x * scale + offset`
+/// for the loop to vectorize
+fn factors(mean: &[f32; 3], std: &[f32; 3]) -> ([f32; 3], [f32; 3]) {
+    let mut scale = [0f32; 3];
+    let mut offset = [0f32; 3];
+
+    for ((scale, offset), (mean, std)) in scale
+        .iter_mut()
+        .zip(offset.iter_mut())
+        .zip(mean.iter().zip(std))
+    {
+        *scale = 1.0 / (255.0 * std);
+        *offset = -mean / std;
+    }
+
+    (scale, offset)
+}
+
+fn normalize_chunk(pixels: &[u8], input: &mut [f32], scale: &[f32; 3], offset: &[f32; 3]) {
+    for (values, pixel) in input.chunks_exact_mut(3).zip(pixels.chunks_exact(3)) {
+        for ((value, x), (scale, offset)) in
+            values.iter_mut().zip(pixel).zip(scale.iter().zip(offset))
+        {
+            *value = *x as f32 * scale + offset;
+        }
+    }
+}
+
+/// Normalize interleaved RGB `pixels` into `input`
+#[cfg(not(feature = "accel"))]
+pub(crate) fn normalize(pixels: &[u8], input: &mut [f32], mean: &[f32; 3], std: &[f32; 3]) {
+    let (scale, offset) = factors(mean, std);
+
+    normalize_chunk(pixels, input, &scale, &offset);
+}
+
+/// Normalize interleaved RGB `pixels` into `input`
+#[cfg(feature = "accel")]
+pub(crate) fn normalize(pixels: &[u8], input: &mut [f32], mean: &[f32; 3], std: &[f32; 3]) {
+    use rayon::prelude::*;
+
+    let (scale, offset) = factors(mean, std);
+
+    input
+        .par_chunks_mut(CHUNK_SIZE)
+        .zip(pixels.par_chunks(CHUNK_SIZE))
+        .for_each(|(input, pixels)| normalize_chunk(pixels, input, &scale, &offset));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::DynamicImage;
+
+    #[test]
+    fn scaled_jpeg() {
+        let jpeg = crate::thumbnail(&DynamicImage::new_rgb8(64, 48), 64, 90).unwrap();
+        assert!(is_jpeg(&jpeg));
+        assert!(!is_jpeg(b"\x89PNG\r\n"));
+
+        // Decoded at a quarter of the size, the smallest covering 16x12
+        let rgb = decode_jpeg(&jpeg, 16, 12).unwrap();
+        assert_eq!(rgb.dimensions(), (16, 12));
+
+        let mut input = vec![1f32; 4 * 3 * 3];
+        normalize(&resize(&rgb, 4, 3), &mut input, &[0.0; 3], &[1.0; 3]);
+        assert!(input.iter().all(|x| x.abs() < 0.05));
+    }
+
+    #[test]
+    fn image_limits() {
+        let limits = ImageLimits::default();
+
+        // Header of a 40000x40000 PNG
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&40000u32.to_be_bytes());
+        png.extend_from_slice(&40000u32.to_be_bytes());
+        assert_eq!(dimensions(&png), Some((40000, 40000)));
+        assert_eq!(
+            limits.check(&png).err().unwrap().code(),
+            Code::ResourceExhausted
+        );
+        assert_eq!(
+            decode(&png, &limits).err().unwrap().code(),
+            Code::ResourceExhausted
+        );
+
+        let jpeg = crate::thumbnail(&DynamicImage::new_rgb8(64, 48), 64, 90).unwrap();
+        assert_eq!(dimensions(&jpeg), Some((64, 48)));
+        assert!(limits.check(&jpeg).is_ok());
+        assert!(ImageLimits {
+            max_pixels: 1000,
+            ..limits
+        }
+        .check(&jpeg)
+        .is_err());
+
+        assert_eq!(dimensions(b"GIF89a\x40\x00\x30\x00"), Some((64, 48)));
+        assert_eq!(dimensions(&tiff()), Some((1, 1)));
+        assert_eq!(
+            limits.check(b"unknown").err().unwrap().code(),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            limits.check(&tiff()[..16]).err().unwrap().code(),
+            Code::InvalidArgument
+        );
+    }
+
+    /// Uncompressed 1x1 grayscale TIFF of a white pixel
+    fn tiff() -> Vec<u8> {
+        let mut tiff = b"II*\0\x08\0\0\0".to_vec();
+
+        let entries: &[(u16, u16, u32)] = &[
+            (256, 3, 1),   // ImageWidth
+            (257, 3, 1),   // ImageLength
+            (258, 3, 8),   // BitsPerSample
+            (259, 3, 1),   // Compression: none
+            (262, 3, 1),   // PhotometricInterpretation: black is zero
+            (273, 4, 122), // StripOffsets, right after the IFD
+            (277, 3, 1),   // SamplesPerPixel
+            (278, 3, 1),   // RowsPerStrip
+            (279, 4, 1),   // StripByteCounts
+        ];
+        tiff.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for (tag, kind, value) in entries {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&kind.to_le_bytes());
+            tiff.extend_from_slice(&1u32.to_le_bytes());
+            tiff.extend_from_slice(&value.to_le_bytes());
+        }
+        tiff.extend_from_slice(&[0, 0, 0, 0, 0xff]);
+
+        tiff
+    }
+
+    #[test]
+    fn image_formats() {
+        let limits = ImageLimits::default();
+
+        let unrecognized = decode(b"not an image", &limits).err().unwrap();
+        assert_eq!(unrecognized.code(), Code::InvalidArgument);
+        assert!(unrecognized
+            .message()
+            .unwrap()
+            .contains("accepted: jpeg, png, gif, bmp"));
+
+        let heic = decode(b"\0\0\0\x18ftypheic\0\0\0\0", &limits)
+            .err()
+            .unwrap();
+        assert!(heic
+            .message()
+            .unwrap()
+            .starts_with("Unsupported image format: heic"));
+
+        let jpeg = crate::thumbnail(&DynamicImage::new_rgb8(8, 8), 8, 90).unwrap();
+        assert!(decode(&jpeg, &limits).is_ok());
+        assert!(decode(&jpeg[..jpeg.len() / 2], &limits)
+            .err()
+            .unwrap()
+            .message()
+            .unwrap()
+            .starts_with("Could not decode jpeg image"));
+
+        let tiff = decode(&tiff(), &limits);
+        if cfg!(feature = "tiff") {
+            assert_eq!(tiff.unwrap().to_luma().into_raw(), vec![0xff]);
+        } else {
+            assert!(tiff
+                .err()
+                .unwrap()
+                .message()
+                .unwrap()
+                .starts_with("Unsupported image format: tiff"));
+        }
+    }
+}
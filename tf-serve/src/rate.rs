@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Most buckets kept, beyond which those refilled are dropped
+const MAX_BUCKETS: usize = 10_000;
+
+/// Least rate of a limiter, a request every 10 days, so that waits stay
+/// within the range of `Duration`
+const MIN_RATE: f64 = 1e-6;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket of every caller, allowing `rate` requests per second on
+/// average, in bursts of up to `burst`
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Limiter of `rate` requests per second, raised to one every 10 days
+    /// if lower
+    pub fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            // NaN is lower than any rate as well
+            rate: if rate >= MIN_RATE { rate } else { MIN_RATE },
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Tokens of a bucket at `now`
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+
+    /// Take a token of the bucket of `key`, or fail with the time until
+    /// there is one
+    pub fn acquire(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < self.burst);
+        }
+
+        let burst = self.burst;
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+
+        if tokens >= 1.0 {
+            bucket.tokens = tokens - 1.0;
+            Ok(())
+        } else {
+            bucket.tokens = tokens;
+            Err(Duration::from_secs_f64((1.0 - tokens) / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_buckets() {
+        let limiter = RateLimiter::new(0.5, 2);
+
+        assert!(limiter.acquire("key-0").is_ok());
+        assert!(limiter.acquire("key-0").is_ok());
+
+        let wait = limiter.acquire("key-0").unwrap_err();
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+
+        assert!(limiter.acquire("key-1").is_ok());
+
+        for rate in &[0.0, -1.0, f64::MIN_POSITIVE, f64::NAN] {
+            let limiter = RateLimiter::new(*rate, 1);
+            assert!(limiter.acquire("key-0").is_ok());
+            assert!(limiter.acquire("key-0").unwrap_err() <= Duration::from_secs(1_000_000));
+        }
+    }
+}
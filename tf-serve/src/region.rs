@@ -0,0 +1,89 @@
+//! Regions of interest, for classifying part of an image only.
+
+use std::fmt;
+use std::str::FromStr;
+
+use image::{DynamicImage, GenericImageView};
+use serde::{Deserialize, Serialize};
+use tensorflow::{Code, Status};
+
+/// Rectangle of an image, in pixels of the upright image
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// Crop the region out of an image, failing unless it is within it
+    pub(crate) fn crop(&self, image: &DynamicImage) -> tensorflow::Result<DynamicImage> {
+        let (width, height) = image.dimensions();
+
+        let within = |start: u32, len: u32, max: u32| {
+            len > 0 && start.checked_add(len).map_or(false, |end| end <= max)
+        };
+        if !within(self.x, self.width, width) || !within(self.y, self.height, height) {
+            return Err(Status::new_set_lossy(
+                Code::InvalidArgument,
+                &format!(
+                    "Region {} is not within the {}x{} image",
+                    self, width, height
+                ),
+            ));
+        }
+
+        let mut source = image.clone();
+        Ok(source.crop(self.x, self.y, self.width, self.height))
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    /// Parse `x,y,width,height`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid region '{}', expected x,y,width,height", s);
+
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| invalid())?;
+
+        match values.as_slice() {
+            &[x, y, width, height] => Ok(Region {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crop() {
+        let region: Region = "2, 1, 4, 3".parse().unwrap();
+        assert_eq!(region.to_string(), "2,1,4,3");
+        assert!("2,1,4".parse::<Region>().is_err());
+
+        let image = DynamicImage::new_rgb8(8, 4);
+        assert_eq!(region.crop(&image).unwrap().dimensions(), (4, 3));
+
+        let outside = Region { y: 2, ..region };
+        assert!(outside.crop(&image).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use image::{ColorType, DynamicImage};
+use serde::{Deserialize, Serialize};
+use tensorflow::{Code, Graph, SavedModelBundle, Session, SessionRunArgs, Status, Tensor};
+
+use crate::preprocess;
+use crate::{
+    env_parse, tensor_by_name, Device, ImageLimits, LabelFormat, Labels, Storage, StorageRegistry,
+    Timer,
+};
+
+/// Configuration of a `Segmenter`
+#[derive(Clone, Debug)]
+pub struct SegmenterOptions {
+    /// Device to run inference on
+    pub device: Device,
+
+    /// Input tensor of the model, as `operation[:index]`
+    pub input_op: String,
+
+    /// Per-pixel class scores output tensor, shaped `[1, height, width,
+    /// classes]`, as `operation[:index]`
+    pub output_op: String,
+
+    /// Width and height images are resized to before inference
+    pub input_size: (u32, u32),
+
+    /// Class index of the first label, e.g. 1 for models whose "background"
+    /// class 0 is missing from the labels file
+    pub label_offset: usize,
+}
+
+impl Default for SegmenterOptions {
+    fn default() -> Self {
+        SegmenterOptions {
+            device: Device::default(),
+            input_op: "serving_default_input_1".to_owned(),
+            output_op: "StatefulPartitionedCall".to_owned(),
+            input_size: (512, 512),
+            label_offset: 0,
+        }
+    }
+}
+
+/// Class-index mask, in row-major order
+pub struct SegmentationMask {
+    pub width: u32,
+    pub height: u32,
+    pub classes: Vec<u16>,
+}
+
+/// Run of consecutive pixels of the same class, in row-major order
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    /// Class index of the pixels
+    pub class: u16,
+
+    /// Number of pixels
+    pub length: u32,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Segmentation {
+    /// Mask width
+    pub width: u32,
+
+    /// Mask height
+    pub height: u32,
+
+    /// Tags of the classes present in the mask, by class index
+    pub tags: Vec<(u16, String)>,
+
+    /// Run-length encoded mask
+    pub runs: Vec<Run>,
+
+    /// Time spent loading image in memory
+    pub time_image_load: i64,
+
+    /// Time spent on running session
+    pub time_session_run: i64,
+}
+
+impl SegmentationMask {
+    /// Run-length encoding of the mask
+    pub fn runs(&self) -> Vec<Run> {
+        let mut runs: Vec<Run> = vec![];
+
+        for &class in &self.classes {
+            match runs.last_mut() {
+                Some(run) if run.class == class => run.length += 1,
+                _ => runs.push(Run { class, length: 1 }),
+            }
+        }
+
+        runs
+    }
+
+    /// Grayscale PNG of the mask, with the class index as pixel value. The
+    /// image is 16-bit only if there are classes above 255.
+    pub fn to_png(&self) -> tensorflow::Result<Vec<u8>> {
+        let (data, color) = if self.classes.iter().all(|&class| class <= 255) {
+            let data = self.classes.iter().map(|&class| class as u8).collect();
+            (data, ColorType::Gray(8))
+        } else {
+            let data = self
+                .classes
+                .iter()
+                .flat_map(|class| class.to_be_bytes().to_vec())
+                .collect();
+            (data, ColorType::Gray(16))
+        };
+
+        let mut png: Vec<u8> = vec![];
+        image::png::PNGEncoder::new(&mut png)
+            .encode(&data, self.width, self.height, color)
+            .map_err(|_| Status::new_set_lossy(Code::Internal, "Could not encode mask"))?;
+
+        Ok(png)
+    }
+}
+
+pub struct Segmenter {
+    /// TensorFlow model graph
+    graph: Graph,
+
+    /// TensorFlow session
+    session: Session,
+
+    /// Tags translation, indexed by class
+    tags: Labels,
+
+    /// Model configuration
+    options: SegmenterOptions,
+}
+
+impl Segmenter {
+    pub fn new(
+        export_dir: &Path,
+        tags_path: &Path,
+        options: &SegmenterOptions,
+    ) -> tensorflow::Result<Self> {
+        Segmenter::with_storage(
+            Arc::new(StorageRegistry::local()),
+            &export_dir.to_string_lossy(),
+            &tags_path.to_string_lossy(),
+            options,
+        )
+    }
+
+    /// Model of `TF_SEGMENTER_DIR`, if set, with the labels of
+    /// `TF_SEGMENTER_LABELS`, `labels.txt` in it by default, on the device of
+    /// `TF_DEVICE`
+    pub fn from_env() -> tensorflow::Result<Option<Self>> {
+        let export_dir = match std::env::var("TF_SEGMENTER_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => return Ok(None),
+        };
+        let tags_path = std::env::var("TF_SEGMENTER_LABELS")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| export_dir.join("labels.txt"));
+        let options = SegmenterOptions {
+            device: env_parse("TF_DEVICE").unwrap_or_default(),
+            ..Default::default()
+        };
+
+        Segmenter::new(&export_dir, &tags_path, &options).map(Some)
+    }
+
+    /// Load the model and labels from `storage`
+    pub fn with_storage(
+        storage: Arc<dyn Storage>,
+        export_dir: &str,
+        tags_path: &str,
+        options: &SegmenterOptions,
+    ) -> tensorflow::Result<Self> {
+        let mut t = Timer::new_start("Loading segmentation session");
+
+        let mut graph = Graph::new();
+        let session = SavedModelBundle::load(
+            &options.device.session_options()?,
+            &["serve"],
+            &mut graph,
+            storage.local_dir(export_dir)?,
+        )?
+        .session;
+
+        t.stop();
+
+        Ok(Segmenter {
+            graph,
+            session,
+            tags: Labels::parse_as(&storage.read(tags_path)?, LabelFormat::from_path(tags_path))?
+                .with_offset(options.label_offset),
+            options: options.clone(),
+        })
+    }
+
+    /// Class labels of the model
+    pub fn labels(&self) -> &Labels {
+        &self.tags
+    }
+
+    /// Run the model once on a blank image, see `ImageClassifier::warm_up`
+    pub fn warm_up(&self) -> tensorflow::Result<()> {
+        self.mask(&DynamicImage::new_rgb8(32, 32))?;
+
+        Ok(())
+    }
+
+    /// Compute the class-index mask of an image
+    pub fn mask(&self, image: &DynamicImage) -> tensorflow::Result<SegmentationMask> {
+        let (width, height) = self.options.input_size;
+
+        let rgb = image.to_rgb();
+        let resized =
+            image::imageops::resize(&rgb, width, height, image::imageops::FilterType::Triangle);
+
+        let raw_image: Vec<f32> = resized
+            .into_raw()
+            .iter()
+            .map(|x| *x as f32 / 255f32)
+            .collect();
+
+        let input = Tensor::new(&[1, height as u64, width as u64, 3]).with_values(&raw_image)?;
+
+        let mut args = SessionRunArgs::new();
+
+        let (input_op, input_index) = tensor_by_name(&self.graph, &self.options.input_op)?;
+        args.add_feed(&input_op, input_index, &input);
+
+        let (output_op, output_index) = tensor_by_name(&self.graph, &self.options.output_op)?;
+        let result = args.request_fetch(&output_op, output_index);
+
+        self.session.run(&mut args)?;
+        let output: Tensor<f32> = args.fetch(result)?;
+
+        // Classes are numbered with 16 bits
+        let dims = output.dims();
+        if dims.len() != 4 || dims[0] != 1 || dims[3] == 0 || dims[3] > 1 << 16 {
+            return Err(Status::new_set_lossy(
+                Code::InvalidArgument,
+                "Segmentation output must be shaped [1, height, width, classes]",
+            ));
+        }
+
+        let (mask_height, mask_width, num_classes) =
+            (dims[1] as u32, dims[2] as u32, dims[3] as usize);
+
+        let classes = output
+            .chunks(num_classes)
+            .map(|scores| {
+                scores
+                    .iter()
+                    .enumerate()
+                    .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+                    .map(|(class, _)| class as u16)
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        Ok(SegmentationMask {
+            width: mask_width,
+            height: mask_height,
+            classes,
+        })
+    }
+
+    pub fn segment(&self, image: &DynamicImage) -> tensorflow::Result<Segmentation> {
+        let mut t = Timer::new_start("Running segmentation session");
+
+        let mask = self.mask(image)?;
+
+        t.stop();
+
+        let mut present: Vec<u16> = mask.classes.clone();
+        present.sort_unstable();
+        present.dedup();
+
+        let tags = present
+            .into_iter()
+            .map(|class| {
+                let tag = self.tags.get(class as usize).unwrap_or_default();
+                (class, tag.to_owned())
+            })
+            .collect();
+
+        Ok(Segmentation {
+            width: mask.width,
+            height: mask.height,
+            tags,
+            runs: mask.runs(),
+            time_session_run: t.duration(),
+            ..Default::default()
+        })
+    }
+
+    pub fn segment_from_raw(&self, data: &[u8]) -> tensorflow::Result<Segmentation> {
+        let mut t = Timer::new_start("Load image from memory");
+
+        let image = load_image(data)?;
+
+        t.stop();
+
+        let mut segmentation = self.segment(&image)?;
+        segmentation.time_image_load = t.duration();
+
+        Ok(segmentation)
+    }
+
+    /// PNG-encoded class-index mask of an image
+    pub fn mask_png_from_raw(&self, data: &[u8]) -> tensorflow::Result<Vec<u8>> {
+        self.mask(&load_image(data)?)?.to_png()
+    }
+}
+
+fn load_image(data: &[u8]) -> tensorflow::Result<DynamicImage> {
+    preprocess::decode(data, &ImageLimits::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_length_encoding() {
+        let mask = SegmentationMask {
+            width: 3,
+            height: 2,
+            classes: vec![0, 0, 7, 7, 7, 0],
+        };
+
+        assert_eq!(
+            mask.runs(),
+            vec![
+                Run {
+                    class: 0,
+                    length: 2
+                },
+                Run {
+                    class: 7,
+                    length: 3
+                },
+                Run {
+                    class: 0,
+                    length: 1
+                },
+            ]
+        );
+
+        let png = mask.to_png().unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+    }
+}
@@ -0,0 +1,2324 @@
+//! HTTP frontend of an `ImageClassifier`, for running it outside of Lambda.
+//!
+//! `HttpServer::handle` maps an `HttpRequest` to an `HttpResponse`
+//! independently of any HTTP library, so that frontends wrapping requests in
+//! their own envelopes can reuse it. `HttpServer::serve` serves it over
+//! HTTP/1.1 on a pool of threads, and `serve_http` does so for any such
+//! mapping.
+//!
+//! Routes:
+//!
+//! - `GET /_/health`, `GET /healthz`: 200 once the model is loaded.
+//! - `GET /version`: the `BuildInfo` of the library.
+//! - `GET /stats`: latency percentiles of every stage since the start.
+//! - `GET /ws`: WebSocket, classifying the image of every binary message
+//!   with options in the query string, and answering each with the JSON
+//!   result or error, in order. Amortizes connection setup for streams of
+//!   video frames or bursts of images. Connections beyond
+//!   `ServerOptions::max_websockets` are answered 503, and those idle for
+//!   `ServerOptions::idle_timeout` are closed.
+//! - `POST /batch`: classify the images at the URLs of a JSON array, or the
+//!   `image` fields of a `multipart/form-data` upload, with options in the
+//!   query string or the `options` field of the upload, into an array of
+//!   results and errors. With `Accept: text/event-stream`, of up to 1000
+//!   images, sent as server-sent `result` or `error` events with the index
+//!   of the image as ID, as soon as they are classified, and a final
+//!   `summary` event.
+//! - `POST /jobs`: queue a batch of up to 10000 images, sent as to
+//!   `/batch`, as a job, answering `202 Accepted` with the `Job` and its
+//!   location. Jobs run one at a time in the background, and are kept in
+//!   memory, or in Redis at `JOBS_REDIS_URL` with the `redis` feature, for
+//!   `JOBS_TTL_SECS` (a day by default).
+//! - `GET /jobs/{id}`: status and progress of a job, only for the caller
+//!   that submitted it when callers are identified.
+//! - `GET /jobs/{id}/results`: results of a completed job, as from `/batch`.
+//! - `POST /embed`: embedding of the raw image of the body, as a JSON array,
+//!   or with `Accept: application/octet-stream` as written by
+//!   `wire::write_embedding`.
+//! - `POST /detect`: objects detected in the raw image of the body, as JSON,
+//!   or drawn on it as a PNG with `Accept: image/png`, with a detection
+//!   model set.
+//! - `POST /pipeline`: objects detected in the raw image of the body, each
+//!   along with the classification of its crop, with options in the query
+//!   string, with a detection model set.
+//! - `POST /segment`: class of every pixel of the raw image of the body, as
+//!   run-length encoded JSON, or as a PNG mask with `Accept: image/png`,
+//!   with a segmentation model set.
+//! - `GET /labels`: labels with a word starting with the `prefix` parameter,
+//!   in the language of the `lang` parameter if set.
+//! - `POST /tensor`: classify an already preprocessed model input, a `.npy`
+//!   file or flat little-endian `float32` values of the shape given in the
+//!   `X-Tensor-Shape` header, with options in the query string.
+//! - Any other `POST`: classify the raw image of the body, with options in
+//!   the query string, a JSON `{"url": ...}` or `{"image_b64": ...}` body
+//!   with inline options, or a `multipart/form-data` upload of an `image`
+//!   field. CloudEvents carry the same in their data, and get a
+//!   `classification.result` event back.
+//!
+//! With an `AuthRegistry` set, requests other than health checks must carry
+//! credentials in an `X-Api-Key` or `Authorization: Bearer` header, and are
+//! answered 401 otherwise. With a `RateLimiter` set, callers beyond their
+//! rate are answered 429, with a `Retry-After` header.
+//!
+//! With the `tls` feature, and `ServerOptions::tls_cert` and `tls_key` set,
+//! as by `TF_TLS_CERT` and `TF_TLS_KEY`, `HttpServer::serve` serves HTTPS.
+//!
+//! With `TF_DETECTOR_DIR` set, `HttpServer::load_models_from_env`, called
+//! by the serverless frontends, loads the detection model of `/detect` and
+//! `/pipeline` from it, as by `Detector::from_env`, and with
+//! `TF_SEGMENTER_DIR` set, the segmentation model of `/segment`, as by
+//! `Segmenter::from_env`.
+//!
+//! With `CORS_ALLOWED_ORIGINS` set, browsers of those origins are allowed
+//! to call the server, as by `CorsOptions`.
+//!
+//! With `RESULT_SINK` set, the results of classifications and jobs are also
+//! written to that `ResultSink`, under the request ID the server generates,
+//! or the job ID and the index of the image. Any `X-Request-Id` of the
+//! client is stored alongside, as clients may reuse those of others.
+//!
+//! With `OTEL_EXPORTER_OTLP_ENDPOINT` set, and the `fetch` feature, the
+//! spans of classifications and their stages, batches, jobs and WebSocket
+//! messages are exported to that OpenTelemetry collector, continuing the
+//! trace of any `traceparent`.
+
+use std::borrow::Cow;
+#[cfg(feature = "tls")]
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+#[cfg(feature = "fetch")]
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tensorflow::{Code, Status};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::{Role, WebSocketConfig};
+use tungstenite::{Error as WsError, Message, WebSocket};
+
+use crate::jobs::job_store_from_env;
+use crate::sink::result_sink_from_env;
+use crate::{
+    cloudevents, env_parse, http_status, is_npy, multipart, new_id, parse_npy, parse_raw,
+    percent_decode, retry_after, wire, AuthRegistry, BuildInfo, Classification, ClassifyOptions,
+    ConcurrencyLimit, Credentials, Detector, ErrorBody, FetchedImage, Identity, ImageClassifier,
+    InputTensor, Job, JobStatus, JobStore, Metrics, Pipeline, RateLimiter, ResultRecord,
+    ResultSink, Segmenter, DEFAULT_MAX_BODY_SIZE,
+};
+#[cfg(feature = "fetch")]
+use crate::{new_span_id, OtlpExporter, Span, TraceContext};
+
+/// Largest number of images of a batch request
+const MAX_BATCH_SIZE: usize = 32;
+
+/// Largest number of images of a batch request streaming its results
+const MAX_STREAMED_BATCH_SIZE: usize = 1000;
+
+/// Largest number of images of a job
+const MAX_JOB_SIZE: usize = 10_000;
+
+/// Images classified together in a streamed batch, trading throughput for
+/// results arriving sooner
+const STREAM_CHUNK_SIZE: usize = 8;
+
+/// HTTP request, as far as classification is concerned
+#[derive(Clone, Debug, Default)]
+pub struct HttpRequest {
+    pub method: String,
+
+    /// Path, without the query string
+    pub path: String,
+
+    /// Decoded query string parameters
+    pub query: Vec<(String, String)>,
+
+    /// Headers, with lowercase names
+    pub headers: Vec<(String, String)>,
+
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Request for `url`, a path with an optional query string
+    pub fn new(method: &str, url: &str, headers: Vec<(String, String)>, body: Vec<u8>) -> Self {
+        let (path, query) = match url.find('?') {
+            Some(pos) => (&url[..pos], &url[pos + 1..]),
+            None => (url, ""),
+        };
+
+        HttpRequest {
+            method: method.to_uppercase(),
+            path: path.to_owned(),
+            query: query
+                .split('&')
+                .filter(|param| !param.is_empty())
+                .map(|param| match param.find('=') {
+                    Some(pos) => (
+                        percent_decode(&param[..pos]),
+                        percent_decode(&param[pos + 1..]),
+                    ),
+                    None => (percent_decode(param), String::new()),
+                })
+                .collect(),
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| (name.to_lowercase(), value))
+                .collect(),
+            body,
+        }
+    }
+
+    /// Value of a header, by case-insensitive name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of a query string parameter
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the Accept header lists `content_type`
+    fn accepts(&self, content_type: &str) -> bool {
+        self.header("accept")
+            .map_or(false, |accept| accept.contains(content_type))
+    }
+
+    /// Whether the body is of `content_type`
+    fn has_content_type(&self, content_type: &str) -> bool {
+        self.header("content-type")
+            .and_then(|value| value.split(';').next())
+            .map_or(false, |value| {
+                value.trim().eq_ignore_ascii_case(content_type)
+            })
+    }
+
+    fn parse_param<T: FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        match self.query_param(name) {
+            None => Ok(None),
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("Invalid {}: '{}'", name, value)),
+        }
+    }
+
+    /// Classification options from the query string
+    fn classify_options(&self) -> Result<ClassifyOptions, String> {
+        let defaults = ClassifyOptions::default();
+
+        Ok(ClassifyOptions {
+            top_k: self.parse_param("top_k")?.unwrap_or(defaults.top_k),
+            min_probability: self
+                .parse_param("min_probability")?
+                .unwrap_or(defaults.min_probability),
+            logits: self.parse_param("logits")?.unwrap_or(defaults.logits),
+            ambiguity_margin: self.parse_param("ambiguity_margin")?,
+            lang: self.parse_param("lang")?,
+            frames: self.parse_param("frames")?.unwrap_or(defaults.frames),
+            tta: self.parse_param("tta")?.unwrap_or(defaults.tta),
+            roi: self.parse_param("roi")?,
+            ..defaults
+        })
+    }
+}
+
+/// HTTP response
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+
+    /// Headers, besides those of the transport
+    pub headers: Vec<(String, String)>,
+
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// JSON response
+    pub fn json<T: Serialize>(status: u16, value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => HttpResponse {
+                status,
+                headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+                body,
+            },
+            Err(err) => HttpResponse::error(500, "internal", &format!("{}", err), None),
+        }
+    }
+
+    /// JSON error response, `{"error": {"code": "...", "message": "..."}}`,
+    /// with the ID of its request if any
+    pub fn error(status: u16, code: &str, message: &str, request_id: Option<&str>) -> Self {
+        let mut body = ErrorBody::new(code, message);
+
+        if let Some(request_id) = request_id {
+            warn!("[{}] {}: {}", request_id, code, message);
+            body.error.request_id = Some(request_id.to_owned());
+        }
+
+        HttpResponse::json(status, &body)
+    }
+
+    /// JSON error response of a failure, with the HTTP status of its code
+    pub fn from_status(err: &Status, request_id: Option<&str>) -> Self {
+        let ErrorBody { error } = ErrorBody::from(err);
+        let mut response = HttpResponse::error(
+            http_status(err.code()),
+            &error.code,
+            &error.message,
+            request_id,
+        );
+
+        if let Some(secs) = retry_after(err.code()) {
+            response
+                .headers
+                .push(("retry-after".to_owned(), secs.to_string()));
+        }
+
+        response
+    }
+}
+
+/// JSON body of a classification request, as an alternative to the raw
+/// image: `{"url": "..."}` or `{"image_b64": "...", "top_k": 5}`
+#[derive(Deserialize)]
+struct ClassifyRequest {
+    url: Option<String>,
+    image_b64: Option<String>,
+
+    #[serde(flatten)]
+    options: ClassifyOptions,
+}
+
+/// Images of a batch request
+enum Batch<'a> {
+    Raw(Vec<Cow<'a, [u8]>>),
+    Urls(Vec<String>),
+}
+
+impl Batch<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Batch::Raw(images) => images.len(),
+            Batch::Urls(urls) => urls.len(),
+        }
+    }
+
+    fn into_owned(self) -> Batch<'static> {
+        match self {
+            Batch::Raw(images) => Batch::Raw(
+                images
+                    .into_iter()
+                    .map(|image| Cow::Owned(image.into_owned()))
+                    .collect(),
+            ),
+            Batch::Urls(urls) => Batch::Urls(urls),
+        }
+    }
+}
+
+/// Batch of a job, waiting for the worker
+struct QueuedJob {
+    job: Job,
+    batch: Batch<'static>,
+    options: ClassifyOptions,
+
+    /// Exporter of the span of the job, and trace of its submitter, when
+    /// spans are exported
+    #[cfg(feature = "fetch")]
+    trace: Option<(Arc<OtlpExporter>, TraceContext)>,
+}
+
+/// Classifier of batches a chunk at a time, within the concurrency limit
+/// and timeout of a server
+struct BatchRunner {
+    classifier: Arc<ImageClassifier>,
+    limit: Arc<ConcurrencyLimit>,
+    timeout: Option<Duration>,
+
+    /// Wait for room in the queue of the limit instead of failing the
+    /// chunks shed by it, for jobs, which have no client to retry them
+    patient: bool,
+}
+
+impl BatchRunner {
+    /// Classify the images of a batch, each chunk within the timeout, and
+    /// pass every result to `emit` with its index, stopping once it returns
+    /// `false`
+    fn classify(
+        &self,
+        batch: &Batch,
+        options: &ClassifyOptions,
+        deadline: Option<Instant>,
+        emit: &mut dyn FnMut(usize, tensorflow::Result<Classification>) -> bool,
+    ) -> BatchSummary {
+        let start = Instant::now();
+        let mut summary = BatchSummary {
+            total: batch.len(),
+            succeeded: 0,
+            failed: 0,
+            time_ms: 0,
+        };
+
+        let mut options = options.clone();
+        'chunks: for offset in (0..batch.len()).step_by(STREAM_CHUNK_SIZE) {
+            let end = (offset + STREAM_CHUNK_SIZE).min(batch.len());
+
+            options.deadline = earliest_deadline(deadline, self.timeout);
+
+            // Fetched before taking a slot, for slow hosts not to hold up
+            // the classifications of others
+            let images: Vec<tensorflow::Result<FetchedImage>> = match batch {
+                Batch::Raw(images) => images[offset..end]
+                    .iter()
+                    .map(|image| Ok(FetchedImage::from(image.to_vec())))
+                    .collect(),
+                Batch::Urls(urls) => self.classifier.fetch_batch(&urls[offset..end], &options),
+            };
+
+            let permit = loop {
+                match self.limit.acquire_until(options.deadline) {
+                    Err(err) if self.patient && err.code() == Code::Aborted => {
+                        thread::sleep(Duration::from_millis(100))
+                    }
+                    permit => break permit,
+                }
+            };
+
+            let results = match permit {
+                Ok(_permit) => self.classifier.classify_fetched_batch(images, &options),
+                Err(err) => (offset..end)
+                    .map(|_| {
+                        Err(Status::new_set_lossy(
+                            err.code(),
+                            err.message().unwrap_or(""),
+                        ))
+                    })
+                    .collect(),
+            };
+
+            for (index, result) in (offset..).zip(results) {
+                if result.is_ok() {
+                    summary.succeeded += 1;
+                } else {
+                    summary.failed += 1;
+                }
+
+                if !emit(index, result) {
+                    break 'chunks;
+                }
+            }
+        }
+
+        summary.time_ms = start.elapsed().as_millis() as u64;
+        summary
+    }
+}
+
+/// Run queued jobs one at a time, storing their progress and results a chunk
+/// at a time, until the server is dropped
+fn run_jobs(
+    runner: &BatchRunner,
+    store: &dyn JobStore,
+    sink: Option<&dyn ResultSink>,
+    queue: mpsc::Receiver<QueuedJob>,
+) {
+    let save = |job: &Job, results: &mut Vec<String>| {
+        // Results first, for them to be in once the job says they are
+        if let Err(err) = store
+            .append_results(&job.id, results)
+            .and_then(|_| store.put(job))
+        {
+            warn!("Could not save job {}: {}", job.id, err);
+        }
+        results.clear();
+    };
+
+    for queued in queue {
+        let QueuedJob {
+            mut job,
+            batch,
+            options,
+            ..
+        } = queued;
+        #[cfg(feature = "fetch")]
+        let trace = queued.trace.map(|(otlp, trace)| {
+            let mut span = started_span("job", &trace);
+            span.attributes.push(("job.id".to_owned(), job.id.clone()));
+            (otlp, trace, span)
+        });
+
+        info!("Running job {} of {} images", job.id, job.total);
+        job.status = JobStatus::Running;
+        save(&job, &mut vec![]);
+
+        let mut results = vec![];
+        let summary = runner.classify(&batch, &options, None, &mut |index, result| {
+            if let Some(sink) = sink {
+                let source = match &batch {
+                    Batch::Urls(urls) => Some(urls[index].as_str()),
+                    Batch::Raw(_) => None,
+                };
+                write_result(
+                    sink,
+                    &format!("{}/{}", job.id, index),
+                    None,
+                    source,
+                    &result,
+                );
+            }
+
+            let item = match result {
+                Ok(classification) => {
+                    job.succeeded += 1;
+                    BatchItem::Classified(classification)
+                }
+                Err(err) => {
+                    job.failed += 1;
+                    BatchItem::Failed(ErrorBody::from(&err))
+                }
+            };
+            results.push(serde_json::to_string(&item).unwrap_or_default());
+
+            if results.len() >= STREAM_CHUNK_SIZE {
+                save(&job, &mut results);
+            }
+            true
+        });
+
+        job.finish();
+        save(&job, &mut results);
+
+        #[cfg(feature = "fetch")]
+        {
+            if let Some((otlp, trace, span)) = trace {
+                otlp.export(&trace, &[batch_span(span, &summary)]);
+            }
+        }
+
+        info!(
+            "Job {} completed in {} msec, {} of {} images failed",
+            job.id, summary.time_ms, job.failed, job.total
+        );
+    }
+}
+
+/// Item of the response to a batch request
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchItem {
+    Classified(Classification),
+    Failed(ErrorBody),
+}
+
+/// Outcome of a batch request, the last event of a streamed one
+#[derive(Serialize)]
+struct BatchSummary {
+    total: usize,
+    succeeded: usize,
+    failed: usize,
+    time_ms: u64,
+}
+
+/// Image to classify
+enum ImageSource<'a> {
+    Raw(Cow<'a, [u8]>),
+    Url(String),
+    Tensor(InputTensor),
+}
+
+/// Configuration of an `HttpServer`
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    /// Largest accepted request body
+    pub max_body_size: usize,
+
+    /// Longest time to spend on a request
+    pub timeout: Option<Duration>,
+
+    /// Most classifications running at the same time
+    pub max_in_flight: usize,
+
+    /// Most classifications waiting for one of those to finish
+    pub max_queued: usize,
+
+    /// Threads handling requests
+    pub threads: usize,
+
+    /// Most WebSocket connections open at the same time, each on a thread
+    /// of its own
+    pub max_websockets: usize,
+
+    /// Longest wait for data of a connection, WebSocket or not, before
+    /// closing it, or none to wait forever
+    pub idle_timeout: Option<Duration>,
+
+    /// PEM certificate chain to serve HTTPS with, along with `tls_key`
+    #[cfg(feature = "tls")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `tls_cert`
+    #[cfg(feature = "tls")]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            timeout: None,
+            max_in_flight: 1,
+            max_queued: 32,
+            threads: 4,
+            max_websockets: 64,
+            idle_timeout: Some(Duration::from_secs(60)),
+            #[cfg(feature = "tls")]
+            tls_cert: None,
+            #[cfg(feature = "tls")]
+            tls_key: None,
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Options of `TF_MAX_BODY_SIZE`, `TF_REQUEST_TIMEOUT_MS`,
+    /// `TF_MAX_IN_FLIGHT`, `TF_MAX_QUEUED`, `TF_MAX_WEBSOCKETS`,
+    /// `TF_IDLE_TIMEOUT_SECS` (0 for none), and with the `tls` feature `TF_TLS_CERT` and
+    /// `TF_TLS_KEY`, if set
+    pub fn from_env() -> Self {
+        let defaults = ServerOptions::default();
+
+        ServerOptions {
+            max_body_size: env_parse("TF_MAX_BODY_SIZE").unwrap_or(defaults.max_body_size),
+            timeout: env_parse("TF_REQUEST_TIMEOUT_MS").map(Duration::from_millis),
+            max_in_flight: env_parse("TF_MAX_IN_FLIGHT").unwrap_or(defaults.max_in_flight),
+            max_queued: env_parse("TF_MAX_QUEUED").unwrap_or(defaults.max_queued),
+            max_websockets: env_parse("TF_MAX_WEBSOCKETS").unwrap_or(defaults.max_websockets),
+            idle_timeout: match env_parse("TF_IDLE_TIMEOUT_SECS") {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => defaults.idle_timeout,
+            },
+            #[cfg(feature = "tls")]
+            tls_cert: std::env::var_os("TF_TLS_CERT").map(PathBuf::from),
+            #[cfg(feature = "tls")]
+            tls_key: std::env::var_os("TF_TLS_KEY").map(PathBuf::from),
+            ..defaults
+        }
+    }
+}
+
+/// Cross-origin requests allowed from browsers, for web apps to send
+/// images straight to the server
+#[derive(Clone, Debug)]
+pub struct CorsOptions {
+    /// Origins allowed, like `https://app.example.com`, or `*` for any
+    pub allowed_origins: Vec<String>,
+
+    /// Methods allowed by preflight responses
+    pub allowed_methods: Vec<String>,
+
+    /// Request headers allowed by preflight responses, or `*` for any
+    pub allowed_headers: Vec<String>,
+
+    /// Time browsers may cache preflight responses for
+    pub max_age: Duration,
+}
+
+impl Default for CorsOptions {
+    fn default() -> Self {
+        let list =
+            |items: &[&str]| -> Vec<String> { items.iter().map(|&item| item.to_owned()).collect() };
+
+        CorsOptions {
+            allowed_origins: list(&["*"]),
+            allowed_methods: list(&["GET", "POST"]),
+            allowed_headers: list(&[
+                "authorization",
+                "content-type",
+                "traceparent",
+                "x-api-key",
+                "x-request-id",
+            ]),
+            max_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl CorsOptions {
+    /// Options of `CORS_ALLOWED_ORIGINS`, and of `CORS_ALLOWED_METHODS`,
+    /// `CORS_ALLOWED_HEADERS` and `CORS_MAX_AGE_SECS` if set, the lists
+    /// comma-separated, if CORS is enabled
+    pub fn from_env() -> Option<Self> {
+        let list = |name: &str| {
+            std::env::var(name).ok().map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let defaults = CorsOptions::default();
+        Some(CorsOptions {
+            allowed_origins: list("CORS_ALLOWED_ORIGINS").filter(|origins| !origins.is_empty())?,
+            allowed_methods: list("CORS_ALLOWED_METHODS").unwrap_or(defaults.allowed_methods),
+            allowed_headers: list("CORS_ALLOWED_HEADERS").unwrap_or(defaults.allowed_headers),
+            max_age: env_parse("CORS_MAX_AGE_SECS").map_or(defaults.max_age, Duration::from_secs),
+        })
+    }
+
+    /// Value of `Access-Control-Allow-Origin` for requests of `origin`, if
+    /// allowed
+    fn allow_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            Some("*")
+        } else if self
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+        {
+            Some(origin)
+        } else {
+            None
+        }
+    }
+
+    /// Response to the preflight of a request of an allowed origin
+    fn preflight(&self, request: &HttpRequest, request_id: &str) -> HttpResponse {
+        let method = request
+            .header("access-control-request-method")
+            .unwrap_or_default();
+        if !self
+            .allowed_methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+        {
+            return HttpResponse::error(
+                403,
+                "permission_denied",
+                &format!("Method '{}' is not allowed", method),
+                Some(request_id),
+            );
+        }
+
+        let headers = if self.allowed_headers.iter().any(|allowed| allowed == "*") {
+            request
+                .header("access-control-request-headers")
+                .unwrap_or_default()
+                .to_owned()
+        } else {
+            self.allowed_headers.join(", ")
+        };
+
+        HttpResponse {
+            status: 204,
+            headers: vec![
+                (
+                    "access-control-allow-methods".to_owned(),
+                    self.allowed_methods.join(", "),
+                ),
+                ("access-control-allow-headers".to_owned(), headers),
+                (
+                    "access-control-max-age".to_owned(),
+                    self.max_age.as_secs().to_string(),
+                ),
+            ],
+            body: vec![],
+        }
+    }
+}
+
+/// HTTP frontend of a classifier
+pub struct HttpServer {
+    classifier: Arc<ImageClassifier>,
+    limit: Arc<ConcurrencyLimit>,
+    options: ServerOptions,
+
+    /// WebSocket connections open
+    websockets: Arc<ConcurrencyLimit>,
+
+    /// Where jobs are kept until they expire
+    jobs: Arc<dyn JobStore>,
+
+    /// Queue of the worker running jobs, started with the first
+    job_queue: Mutex<Option<mpsc::Sender<QueuedJob>>>,
+
+    /// Where results are also written, if anywhere
+    sink: Option<Arc<dyn ResultSink>>,
+
+    /// Authentication of callers, none by default
+    auth: AuthRegistry,
+
+    /// Rate limit of every caller, if any
+    rate_limit: Option<RateLimiter>,
+
+    /// Cross-origin requests allowed, none by default
+    cors: Option<CorsOptions>,
+
+    /// Object detection model of `/detect` and `/pipeline`, if any
+    detector: Option<Arc<Detector>>,
+
+    /// Semantic segmentation model of `/segment`, if any
+    segmenter: Option<Arc<Segmenter>>,
+
+    /// Exporter of the spans of classifications, when configured by the
+    /// `OTEL_*` environment variables
+    #[cfg(feature = "fetch")]
+    otlp: Option<Arc<OtlpExporter>>,
+}
+
+impl HttpServer {
+    pub fn new(classifier: Arc<ImageClassifier>, options: ServerOptions) -> Self {
+        HttpServer {
+            classifier,
+            limit: Arc::new(ConcurrencyLimit::new(
+                options.max_in_flight,
+                options.max_queued,
+            )),
+            websockets: Arc::new(ConcurrencyLimit::new(options.max_websockets, 0)),
+            options,
+            jobs: job_store_from_env(),
+            job_queue: Mutex::new(None),
+            sink: result_sink_from_env().unwrap_or_else(|err| {
+                warn!("Not writing results to RESULT_SINK: {}", err);
+                None
+            }),
+            auth: AuthRegistry::new(),
+            rate_limit: None,
+            cors: CorsOptions::from_env(),
+            detector: None,
+            segmenter: None,
+            #[cfg(feature = "fetch")]
+            otlp: OtlpExporter::from_env().map(Arc::new),
+        }
+    }
+
+    pub fn classifier(&self) -> &ImageClassifier {
+        &self.classifier
+    }
+
+    /// Keep jobs in `store` instead of that configured by the environment
+    pub fn set_job_store(&mut self, store: Arc<dyn JobStore>) {
+        self.jobs = store;
+    }
+
+    /// Also write results to `sink` instead of that configured by the
+    /// environment
+    pub fn set_result_sink(&mut self, sink: Arc<dyn ResultSink>) {
+        self.sink = Some(sink);
+    }
+
+    /// Require callers to authenticate with one of the providers of `auth`
+    pub fn set_auth(&mut self, auth: AuthRegistry) {
+        self.auth = auth;
+    }
+
+    /// Limit the rate of requests of every authenticated caller, or of all
+    /// of them without authentication
+    pub fn set_rate_limit(&mut self, limiter: RateLimiter) {
+        self.rate_limit = Some(limiter);
+    }
+
+    /// Allow cross-origin requests as by `cors` instead of as configured by
+    /// the environment
+    pub fn set_cors(&mut self, cors: CorsOptions) {
+        self.cors = Some(cors);
+    }
+
+    /// Serve `/detect` and `/pipeline` with `detector`
+    pub fn set_detector(&mut self, detector: Arc<Detector>) {
+        self.detector = Some(detector);
+    }
+
+    /// Serve `/segment` with `segmenter`
+    pub fn set_segmenter(&mut self, segmenter: Arc<Segmenter>) {
+        self.segmenter = Some(segmenter);
+    }
+
+    /// Serve `/detect` and `/pipeline` with the model of `TF_DETECTOR_DIR`,
+    /// and `/segment` with that of `TF_SEGMENTER_DIR`, if set, see
+    /// `Detector::from_env` and `Segmenter::from_env`
+    pub fn load_models_from_env(&mut self) -> tensorflow::Result<()> {
+        if let Some(detector) = Detector::from_env()? {
+            self.set_detector(Arc::new(detector));
+        }
+        if let Some(segmenter) = Segmenter::from_env()? {
+            self.set_segmenter(Arc::new(segmenter));
+        }
+
+        Ok(())
+    }
+
+    /// Run every model once, see `ImageClassifier::warm_up`
+    pub fn warm_up(&self) -> tensorflow::Result<()> {
+        self.classifier.warm_up()?;
+        if let Some(detector) = &self.detector {
+            detector.warm_up()?;
+        }
+        if let Some(segmenter) = &self.segmenter {
+            segmenter.warm_up()?;
+        }
+
+        Ok(())
+    }
+
+    /// Respond to a request
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        self.handle_with_deadline(request, None)
+    }
+
+    /// Respond to a request, classifying before `deadline` as well as within
+    /// the timeout of the server, for frontends given the deadline of each
+    /// invocation
+    pub fn handle_with_deadline(
+        &self,
+        request: &HttpRequest,
+        deadline: Option<Instant>,
+    ) -> HttpResponse {
+        let request_id = new_id();
+        let admitted = match (request.method.as_str(), request.path.as_str()) {
+            // Checks of the platform and preflights of browsers, which carry
+            // no credentials
+            (_, "/_/health") | (_, "/healthz") => Ok(None),
+            ("OPTIONS", _) if self.cors.is_some() => Ok(None),
+            _ => self.admit(request, &request_id),
+        };
+
+        self.handle_admitted(request, request_id, deadline, admitted)
+    }
+
+    /// Respond to a request of ID `request_id` already admitted, or
+    /// rejected, by `admit`, so that it takes a single token of the rate
+    /// limit
+    fn handle_admitted(
+        &self,
+        request: &HttpRequest,
+        request_id: String,
+        deadline: Option<Instant>,
+        admitted: Result<Option<Identity>, HttpResponse>,
+    ) -> HttpResponse {
+        if let Some(client_id) = client_request_id(request) {
+            debug!("[{}] Client request ID {}", request_id, client_id);
+        }
+
+        let (identity, mut response) = match admitted {
+            Ok(identity) => {
+                if let Some(Identity {
+                    subject,
+                    claims: Some(claims),
+                }) = &identity
+                {
+                    debug!("[{}] Claims of {}: {}", request_id, subject, claims);
+                }
+                let response = self.route(request, deadline, &request_id, identity.as_ref());
+                (identity, response)
+            }
+            Err(response) => (None, response),
+        };
+
+        match identity {
+            Some(identity) => debug!(
+                "[{}] {} {} {} by {}",
+                request_id, request.method, request.path, response.status, identity.subject
+            ),
+            None => debug!(
+                "[{}] {} {} {}",
+                request_id, request.method, request.path, response.status
+            ),
+        }
+        response.headers.extend(self.cors_headers(request));
+        response
+            .headers
+            .push(("x-request-id".to_owned(), request_id));
+
+        response
+    }
+
+    /// Response of the route of a request of the caller of `identity`
+    fn route(
+        &self,
+        request: &HttpRequest,
+        deadline: Option<Instant>,
+        request_id: &str,
+        identity: Option<&Identity>,
+    ) -> HttpResponse {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/_/health") | ("GET", "/healthz") => {
+                HttpResponse::json(200, &serde_json::json!({ "status": "ok" }))
+            }
+            ("GET", "/version") => HttpResponse::json(200, &BuildInfo::current()),
+            ("GET", "/stats") => HttpResponse::json(200, &Metrics::global().snapshot()),
+            ("GET", "/labels") => self.labels(request, request_id),
+            ("POST", "/embed") => self.embed(request, deadline, request_id),
+            ("POST", "/detect") => self.detect(request, deadline, request_id),
+            ("POST", "/pipeline") => self.pipeline(request, deadline, request_id),
+            ("POST", "/segment") => self.segment(request, deadline, request_id),
+            ("POST", "/batch") => self.batch(request, deadline, request_id),
+            ("POST", "/jobs") => self.submit_job(request, request_id, identity),
+            ("GET", path) if path.starts_with("/jobs/") => {
+                self.job(&path["/jobs/".len()..], request_id, identity)
+            }
+            ("GET", "/ws") => HttpResponse::error(
+                426,
+                "invalid_argument",
+                "Expected a WebSocket upgrade",
+                Some(request_id),
+            ),
+            ("POST", _) if cloudevents::is_cloud_event(request) => {
+                match cloudevents::parse(request) {
+                    Ok((event, data)) => {
+                        cloudevents::reply(&event, self.classify(&data, deadline, request_id))
+                    }
+                    Err(err) => {
+                        HttpResponse::error(400, "invalid_argument", &err, Some(request_id))
+                    }
+                }
+            }
+            ("POST", _) => self.classify(request, deadline, request_id),
+            ("OPTIONS", _) => match (&self.cors, request.header("origin")) {
+                (Some(cors), Some(origin)) if cors.allow_origin(origin).is_some() => {
+                    cors.preflight(request, request_id)
+                }
+                (Some(_), _) => HttpResponse::error(
+                    403,
+                    "permission_denied",
+                    "Origin is not allowed",
+                    Some(request_id),
+                ),
+                (None, _) => HttpResponse::error(
+                    405,
+                    "method_not_allowed",
+                    &format!("Cannot {} {}", request.method, request.path),
+                    Some(request_id),
+                ),
+            },
+            _ => HttpResponse::error(
+                405,
+                "method_not_allowed",
+                &format!("Cannot {} {}", request.method, request.path),
+                Some(request_id),
+            ),
+        }
+    }
+
+    /// CORS headers of the response to a request, if of an allowed origin
+    fn cors_headers(&self, request: &HttpRequest) -> Vec<(String, String)> {
+        let allowed = match (&self.cors, request.header("origin")) {
+            (Some(cors), Some(origin)) => cors.allow_origin(origin),
+            _ => None,
+        };
+
+        match allowed {
+            Some(origin) => {
+                let mut headers = vec![
+                    ("access-control-allow-origin".to_owned(), origin.to_owned()),
+                    (
+                        "access-control-expose-headers".to_owned(),
+                        "retry-after, x-request-id".to_owned(),
+                    ),
+                ];
+                // Responses differ by origin unless any is allowed
+                if origin != "*" {
+                    headers.push(("vary".to_owned(), "origin".to_owned()));
+                }
+                headers
+            }
+            None => vec![],
+        }
+    }
+
+    /// Authenticate the caller of a request and take a token of its rate
+    /// limit, or the response rejecting the request. Callers are only
+    /// identified when authentication or rate limiting is enabled.
+    fn admit(
+        &self,
+        request: &HttpRequest,
+        request_id: &str,
+    ) -> Result<Option<Identity>, HttpResponse> {
+        if self.auth.is_empty() && self.rate_limit.is_none() {
+            return Ok(None);
+        }
+
+        let identity = self
+            .auth
+            .authenticate(&credentials(request))
+            .map_err(|err| HttpResponse::from_status(&err, Some(request_id)))?;
+
+        if let Some(limiter) = &self.rate_limit {
+            if let Err(wait) = limiter.acquire(&identity.subject) {
+                let mut response = HttpResponse::error(
+                    429,
+                    "rate_limited",
+                    "Rate limit exceeded",
+                    Some(request_id),
+                );
+                response.headers.push((
+                    "retry-after".to_owned(),
+                    (wait.as_secs_f64().ceil() as u64).max(1).to_string(),
+                ));
+                return Err(response);
+            }
+        }
+
+        Ok(Some(identity))
+    }
+
+    fn classify(
+        &self,
+        request: &HttpRequest,
+        deadline: Option<Instant>,
+        request_id: &str,
+    ) -> HttpResponse {
+        if let Err(response) = self.check_body_size(request, request_id) {
+            return response;
+        }
+
+        let (image, mut options) = match classify_request(request) {
+            Ok(request) => request,
+            Err(err) => {
+                return HttpResponse::error(400, "invalid_argument", &err, Some(request_id))
+            }
+        };
+        options.deadline = earliest_deadline(deadline, self.options.timeout);
+
+        // Trace of the caller, continued by the span of this request
+        #[cfg(feature = "fetch")]
+        let trace = self.start_span(request);
+        #[cfg(feature = "fetch")]
+        {
+            if let (Some((trace, span)), ImageSource::Url(_)) = (&trace, &image) {
+                let context = TraceContext {
+                    parent_id: Some(span.span_id.clone()),
+                    ..trace.clone()
+                };
+                options.trace = Some(context.child());
+            }
+        }
+
+        // Images at URLs are fetched before taking a slot, for slow hosts
+        // not to hold up the classifications of others
+        let acquire = || self.limit.acquire_until(options.deadline);
+        let result = match &image {
+            ImageSource::Raw(raw) => acquire().and_then(|_permit| {
+                self.classifier
+                    .classify_from_raw_with_options(raw, &options)
+            }),
+            ImageSource::Url(url) => {
+                self.classifier
+                    .fetch_image(url, &options)
+                    .and_then(|fetched| {
+                        let _permit = acquire()?;
+                        self.classifier.classify_fetched(&fetched, &options)
+                    })
+            }
+            ImageSource::Tensor(tensor) => acquire().and_then(|_permit| {
+                self.classifier.classify_tensor_with_options(
+                    &tensor.values,
+                    &tensor.shape,
+                    &options,
+                )
+            }),
+        };
+
+        #[cfg(feature = "fetch")]
+        {
+            if let (Some(otlp), Some((trace, span))) = (&self.otlp, trace) {
+                otlp.export(&trace, &classification_spans(span, &options, &result));
+            }
+        }
+
+        let result = result.map(|mut classification| {
+            classification.set_request_id(request_id);
+            classification
+        });
+
+        if let Some(sink) = &self.sink {
+            let source = match &image {
+                ImageSource::Url(url) => Some(url.as_str()),
+                _ => None,
+            };
+            // Under the ID of the server's, as clients choose theirs
+            let client_id = client_request_id(request);
+            write_result(sink.as_ref(), request_id, client_id, source, &result);
+        }
+
+        match result {
+            Ok(classification) => HttpResponse::json(200, &classification),
+            Err(err) => HttpResponse::from_status(&err, Some(request_id)),
+        }
+    }
+
+    /// Respond to `GET /labels` with the labels with a word starting with
+    /// the `prefix` parameter, in the language of the `lang` parameter
+    fn labels(&self, request: &HttpRequest, request_id: &str) -> HttpResponse {
+        let prefix = request.query_param("prefix").unwrap_or("");
+
+        match self.classifier.labels_in(request.query_param("lang")) {
+            Ok(labels) => HttpResponse::json(200, &labels.search(prefix)),
+            Err(err) => HttpResponse::from_status(&err, Some(request_id)),
+        }
+    }
+
+    /// Respond to `POST /embed` with the embedding of the image of the body
+    fn embed(
+        &self,
+        request: &HttpRequest,
+        deadline: Option<Instant>,
+        request_id: &str,
+    ) -> HttpResponse {
+        if let Err(response) = self.check_body_size(request, request_id) {
+            return response;
+        }
+
+        let deadline = earliest_deadline(deadline, self.options.timeout);
+        let result = self
+            .limit
+            .acquire_until(deadline)
+            .and_then(|_permit| self.classifier.embed_from_raw(&request.body));
+
+        let embedding = match result {
+            Ok(embedding) => embedding,
+            Err(err) => return HttpResponse::from_status(&err, Some(request_id)),
+        };
+
+        if !request.accepts("application/octet-stream") {
+            return HttpResponse::json(200, &embedding);
+        }
+
+        // Writes to memory do not fail
+        let mut body = vec![];
+        let _ = wire::write_embedding(&mut body, &embedding);
+
+        HttpResponse {
+            status: 200,
+            headers: vec![(
+                "content-type".to_owned(),
+                "application/octet-stream".to_owned(),
+            )],
+            body,
+        }
+    }
+
+    /// Respond to `POST /detect` with the objects detected in the image of
+    /// the body
+    fn detect(
+        &self,
+        request: &HttpRequest,
+        deadline: Option<Instant>,
+        request_id: &str,
+    ) -> HttpResponse {
+        let detector = match &self.detector {
+            Some(detector) => detector,
+            None => return no_model("detection", request_id),
+        };
+        if let Err(response) = self.check_body_size(request, request_id) {
+            return response;
+        }
+
+        let deadline = earliest_deadline(deadline, self.options.timeout);
+        let _permit = match self.limit.acquire_until(deadline) {
+            Ok(permit) => permit,
+            Err(err) => return HttpResponse::from_status(&err, Some(request_id)),
+        };
+
+        let response = if request.accepts("image/png") {
+            detector
+                .annotated_png_from_raw(&request.body)
+                .map(png_response)
+        } else {
+            detector
+                .detect_from_raw(&request.body)
+                .map(|detections| HttpResponse::json(200, &detections))
+        };
+
+        response.unwrap_or_else(|err| HttpResponse::from_status(&err, Some(request_id)))
+    }
+
+    /// Respond to `POST /pipeline` with the objects detected in the image of
+    /// the body, along with the classifications of their crops
+    fn pipeline(
+        &self,
+        request: &HttpRequest,
+        deadline: Option<Instant>,
+        request_id: &str,
+    ) -> HttpResponse {
+        let detector = match &self.detector {
+            Some(detector) => detector,
+            None => return no_model("detection", request_id),
+        };
+        if let Err(response) = self.check_body_size(request, request_id) {
+            return response;
+        }
+
+        let mut options = match request.classify_options() {
+            Ok(options) => options,
+            Err(err) => {
+                return HttpResponse::error(400, "invalid_argument", &err, Some(request_id))
+            }
+        };
+        options.deadline = earliest_deadline(deadline, self.options.timeout);
+
+        let _permit = match self.limit.acquire_until(options.deadline) {
+            Ok(permit) => permit,
+            Err(err) => return HttpResponse::from_status(&err, Some(request_id)),
+        };
+
+        match Pipeline::new(detector, &self.classifier).run_from_raw(&request.body, &options) {
+            Ok(result) => HttpResponse::json(200, &result),
+            Err(err) => HttpResponse::from_status(&err, Some(request_id)),
+        }
+    }
+
+    /// Respond to `POST /segment` with the class of every pixel of the image
+    /// of the body
+    fn segment(
+        &self,
+        request: &HttpRequest,
+        deadline: Option<Instant>,
+        request_id: &str,
+    ) -> HttpResponse {
+        let segmenter = match &self.segmenter {
+            Some(segmenter) => segmenter,
+            None => return no_model("segmentation", request_id),
+        };
+        if let Err(response) = self.check_body_size(request, request_id) {
+            return response;
+        }
+
+        let deadline = earliest_deadline(deadline, self.options.timeout);
+        let _permit = match self.limit.acquire_until(deadline) {
+            Ok(permit) => permit,
+            Err(err) => return HttpResponse::from_status(&err, Some(request_id)),
+        };
+
+        let response = if request.accepts("image/png") {
+            segmenter.mask_png_from_raw(&request.body).map(png_response)
+        } else {
+            segmenter
+                .segment_from_raw(&request.body)
+                .map(|segmentation| HttpResponse::json(200, &segmentation))
+        };
+
+        response.unwrap_or_else(|err| HttpResponse::from_status(&err, Some(request_id)))
+    }
+
+    /// Respond to a batch request with every result at once, as a JSON array
+    /// or a stream of server-sent events
+    fn batch(
+        &self,
+        request: &HttpRequest,
+        deadline: Option<Instant>,
+        request_id: &str,
+    ) -> HttpResponse {
+        if let Err(response) = self.check_body_size(request, request_id) {
+            return response;
+        }
+
+        let events = request.accepts("text/event-stream");
+        let max_size = if events {
+            MAX_STREAMED_BATCH_SIZE
+        } else {
+            MAX_BATCH_SIZE
+        };
+
+        let (batch, options) = match batch_request(request, max_size) {
+            Ok(request) => request,
+            Err(err) => {
+                return HttpResponse::error(400, "invalid_argument", &err, Some(request_id))
+            }
+        };
+
+        #[cfg(feature = "fetch")]
+        let trace = self.start_span(request);
+
+        if events {
+            let mut body = vec![];
+            let summary =
+                self.runner()
+                    .classify(&batch, &options, deadline, &mut |index, result| {
+                        body.extend_from_slice(batch_event(index, &result).as_bytes());
+                        true
+                    });
+            body.extend_from_slice(summary_event(&summary).as_bytes());
+
+            #[cfg(feature = "fetch")]
+            self.export_batch(trace, &summary);
+
+            return HttpResponse {
+                status: 200,
+                headers: vec![
+                    ("content-type".to_owned(), "text/event-stream".to_owned()),
+                    ("cache-control".to_owned(), "no-cache".to_owned()),
+                ],
+                body,
+            };
+        }
+
+        let mut items = Vec::with_capacity(batch.len());
+        let _summary = self
+            .runner()
+            .classify(&batch, &options, deadline, &mut |_, result| {
+                items.push(match result {
+                    Ok(classification) => BatchItem::Classified(classification),
+                    Err(err) => BatchItem::Failed(ErrorBody::from(&err)),
+                });
+                true
+            });
+
+        #[cfg(feature = "fetch")]
+        self.export_batch(trace, &_summary);
+
+        HttpResponse::json(200, &items)
+    }
+
+    /// Classifier of batches within the limits of the server, for the
+    /// worker of jobs to share
+    fn runner(&self) -> BatchRunner {
+        BatchRunner {
+            classifier: self.classifier.clone(),
+            limit: self.limit.clone(),
+            timeout: self.options.timeout,
+            patient: false,
+        }
+    }
+
+    /// Error response of requests with bodies beyond the largest accepted
+    fn check_body_size(&self, request: &HttpRequest, request_id: &str) -> Result<(), HttpResponse> {
+        if request.body.len() > self.options.max_body_size {
+            return Err(HttpResponse::error(
+                413,
+                "resource_exhausted",
+                &format!("Body larger than {} bytes", self.options.max_body_size),
+                Some(request_id),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Span of `request` starting now, and the trace of its caller, when
+    /// spans are exported
+    #[cfg(feature = "fetch")]
+    fn start_span(&self, request: &HttpRequest) -> Option<(TraceContext, Span)> {
+        self.otlp.as_ref().map(|_| request_span(request))
+    }
+
+    /// Export the span of a batch request, with the outcome of its images
+    #[cfg(feature = "fetch")]
+    fn export_batch(&self, trace: Option<(TraceContext, Span)>, summary: &BatchSummary) {
+        if let (Some(otlp), Some((trace, span))) = (&self.otlp, trace) {
+            otlp.export(&trace, &[batch_span(span, summary)]);
+        }
+    }
+
+    /// Queue a batch as a job of the caller of `identity`, run by a worker
+    /// started with the first
+    fn submit_job(
+        &self,
+        request: &HttpRequest,
+        request_id: &str,
+        identity: Option<&Identity>,
+    ) -> HttpResponse {
+        if let Err(response) = self.check_body_size(request, request_id) {
+            return response;
+        }
+
+        let (batch, options) = match batch_request(request, MAX_JOB_SIZE) {
+            Ok(request) => request,
+            Err(err) => {
+                return HttpResponse::error(400, "invalid_argument", &err, Some(request_id))
+            }
+        };
+
+        // IDs are unguessable, as they are all it takes to read jobs of
+        // unidentified callers
+        let job = Job {
+            owner: identity.map(|identity| identity.subject.clone()),
+            ..Job::new(&new_id(), batch.len())
+        };
+        if let Err(err) = self.jobs.put(&job) {
+            return HttpResponse::from_status(&err, Some(request_id));
+        }
+
+        let mut queue = self.job_queue.lock().unwrap();
+        let sender = queue.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            let runner = BatchRunner {
+                patient: true,
+                ..self.runner()
+            };
+            let (store, sink) = (self.jobs.clone(), self.sink.clone());
+            thread::spawn(move || run_jobs(&runner, &*store, sink.as_deref(), receiver));
+            sender
+        });
+
+        let queued = QueuedJob {
+            job: job.clone(),
+            batch: batch.into_owned(),
+            options,
+            #[cfg(feature = "fetch")]
+            trace: self
+                .otlp
+                .clone()
+                .map(|otlp| (otlp, request_span(request).0)),
+        };
+        if sender.send(queued).is_err() {
+            // Started again by the next job
+            *queue = None;
+            return HttpResponse::error(500, "internal", "The job worker exited", Some(request_id));
+        }
+
+        let mut response = HttpResponse::json(202, &job);
+        response
+            .headers
+            .push(("location".to_owned(), format!("/jobs/{}", job.id)));
+        response
+    }
+
+    /// Respond to `GET /jobs/{id}` and `GET /jobs/{id}/results`, given the
+    /// rest of the path after `/jobs/`, for the caller of `identity`
+    fn job(&self, path: &str, request_id: &str, identity: Option<&Identity>) -> HttpResponse {
+        let (id, results) = match path.strip_suffix("/results") {
+            Some(id) => (id, true),
+            None => (path, false),
+        };
+
+        // Jobs of others are as good as missing
+        let job = match self.jobs.get(id) {
+            Ok(Some(job)) if job.is_visible_to(identity) => job,
+            Ok(_) => {
+                return HttpResponse::error(
+                    404,
+                    "not_found",
+                    &format!("No job '{}'", id),
+                    Some(request_id),
+                )
+            }
+            Err(err) => return HttpResponse::from_status(&err, Some(request_id)),
+        };
+
+        if !results {
+            return HttpResponse::json(200, &job);
+        }
+
+        if job.status != JobStatus::Completed {
+            return HttpResponse::error(
+                409,
+                "failed_precondition",
+                &format!(
+                    "Job '{}' is not completed, {} of {} images done",
+                    id,
+                    job.done(),
+                    job.total
+                ),
+                Some(request_id),
+            );
+        }
+
+        match self.jobs.results(id) {
+            Ok(results) => HttpResponse {
+                status: 200,
+                headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+                body: format!("[{}]", results.join(",")).into_bytes(),
+            },
+            Err(err) => HttpResponse::from_status(&err, Some(request_id)),
+        }
+    }
+
+    /// Respond to a batch request of ID `request_id` of the caller of
+    /// `identity` with a server-sent event for every result as soon as it is
+    /// ready, before `deadline`. Invalid requests get the error response of
+    /// `handle`.
+    fn stream_batch(
+        &self,
+        request: tiny_http::Request,
+        http_request: &HttpRequest,
+        request_id: String,
+        identity: Option<Identity>,
+        deadline: Option<Instant>,
+    ) {
+        let (batch, options) = match batch_request(http_request, MAX_STREAMED_BATCH_SIZE) {
+            Ok(batch) if http_request.body.len() <= self.options.max_body_size => batch,
+            _ => {
+                let response =
+                    self.handle_admitted(http_request, request_id, deadline, Ok(identity));
+                return send_response(request, response);
+            }
+        };
+
+        debug!("[{}] POST /batch 200, streaming", request_id);
+
+        #[cfg(feature = "fetch")]
+        let trace = self.start_span(http_request);
+
+        // Written straight to the connection, closed at the end of the
+        // stream, as responses of `tiny_http` are buffered
+        let mut writer = request.into_writer();
+        let cors: String = self
+            .cors_headers(http_request)
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
+        let head = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/event-stream\r\n\
+             Cache-Control: no-cache\r\n\
+             Connection: close\r\n\
+             {}\
+             X-Request-Id: {}\r\n\r\n",
+            cors, request_id
+        );
+        let mut send = |event: String| -> io::Result<()> {
+            writer.write_all(event.as_bytes())?;
+            writer.flush()
+        };
+
+        if let Err(err) = send(head) {
+            warn!("[{}] Could not send response: {}", request_id, err);
+            return;
+        }
+
+        let mut failure = None;
+        let summary = self
+            .runner()
+            .classify(
+                &batch,
+                &options,
+                deadline,
+                &mut |index, result| match send(batch_event(index, &result)) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        failure = Some(err);
+                        false
+                    }
+                },
+            );
+
+        #[cfg(feature = "fetch")]
+        self.export_batch(trace, &summary);
+
+        match failure.map_or_else(|| send(summary_event(&summary)), Err) {
+            Ok(()) => debug!(
+                "[{}] POST /batch streamed {} of {} images",
+                request_id,
+                summary.succeeded + summary.failed,
+                summary.total
+            ),
+            Err(err) => warn!("[{}] Could not send response: {}", request_id, err),
+        }
+    }
+
+    /// Serve HTTP/1.1 on `addr`, over TLS if the options have a certificate
+    /// and key, until the listener fails
+    pub fn serve(self: Arc<Self>, addr: &str) -> io::Result<()> {
+        let (threads, max_body_size) = (self.options.threads, self.options.max_body_size);
+        let listener = listen(addr, &self.options)?;
+
+        serve_requests(listener, threads, move |mut request| {
+            let key =
+                websocket_key(&request).filter(|_| request.url().split('?').next() == Some("/ws"));
+
+            if let Some(key) = key {
+                let upgrade = match read_request(&mut request, max_body_size) {
+                    Ok(upgrade) => upgrade,
+                    Err(response) => return send_response(request, response),
+                };
+                // Answered like any other request if rejected
+                let request_id = new_id();
+                if let Err(response) = self.admit(&upgrade, &request_id) {
+                    let response = self.handle_admitted(&upgrade, request_id, None, Err(response));
+                    return send_response(request, response);
+                }
+
+                // A thread per connection, to keep the workers free, up to
+                // the most connections
+                let permit = match self.websockets.clone().acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        let response = HttpResponse::error(
+                            503,
+                            "aborted",
+                            "Too many WebSocket connections",
+                            Some(&request_id),
+                        );
+                        let response =
+                            self.handle_admitted(&upgrade, request_id, None, Err(response));
+                        return send_response(request, response);
+                    }
+                };
+                let server = self.clone();
+                thread::spawn(move || {
+                    server.stream(request, &upgrade, &key);
+                    drop(permit);
+                });
+                return;
+            }
+
+            match read_request(&mut request, max_body_size) {
+                Ok(http_request)
+                    if http_request.method == "POST"
+                        && http_request.path == "/batch"
+                        && http_request.accepts("text/event-stream") =>
+                {
+                    let request_id = new_id();
+                    match self.admit(&http_request, &request_id) {
+                        Ok(identity) => {
+                            self.stream_batch(request, &http_request, request_id, identity, None)
+                        }
+                        Err(response) => {
+                            let response = self.handle_admitted(
+                                &http_request,
+                                request_id,
+                                None,
+                                Err(response),
+                            );
+                            send_response(request, response)
+                        }
+                    }
+                }
+                Ok(http_request) => send_response(request, self.handle(&http_request)),
+                Err(response) => send_response(request, response),
+            }
+        })
+    }
+
+    /// Accept a WebSocket upgrade, and classify the image of every binary
+    /// message until the client closes the connection
+    fn stream(&self, request: tiny_http::Request, upgrade: &HttpRequest, key: &str) {
+        // Every message is classified as a POST to the URL of the upgrade,
+        // with its credentials and trace, for its options, limits, logs and
+        // spans to be those of any other
+        let url = request.url().to_owned();
+        let headers: Vec<(String, String)> = upgrade
+            .headers
+            .iter()
+            .filter(|(name, _)| {
+                name == "authorization" || name == "x-api-key" || name == "traceparent"
+            })
+            .cloned()
+            .collect();
+
+        let mut accept = tiny_http::Response::empty(101);
+        if let Ok(header) = tiny_http::Header::from_bytes(
+            &b"Sec-WebSocket-Accept"[..],
+            derive_accept_key(key.as_bytes()).as_bytes(),
+        ) {
+            accept.add_header(header);
+        }
+        let stream = request.upgrade("websocket", accept);
+
+        let config = WebSocketConfig {
+            max_message_size: Some(self.options.max_body_size),
+            max_frame_size: Some(self.options.max_body_size),
+            ..Default::default()
+        };
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+        debug!("WebSocket opened on {}", url);
+
+        loop {
+            let response = match socket.read_message() {
+                Ok(Message::Binary(image)) => {
+                    self.handle(&HttpRequest::new("POST", &url, headers.clone(), image))
+                }
+                Ok(Message::Text(_)) => HttpResponse::error(
+                    400,
+                    "invalid_argument",
+                    "Expected images in binary messages",
+                    None,
+                ),
+                // Pings are answered, and closes acknowledged, by the socket
+                Ok(_) => continue,
+                Err(WsError::ConnectionClosed) | Err(WsError::AlreadyClosed) => break,
+                Err(WsError::Io(err))
+                    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                {
+                    debug!("WebSocket on {} idle, closing", url);
+                    break;
+                }
+                Err(err) => {
+                    warn!("WebSocket on {} failed: {}", url, err);
+                    break;
+                }
+            };
+
+            let reply = String::from_utf8_lossy(&response.body).into_owned();
+            if let Err(err) = socket.write_message(Message::Text(reply)) {
+                warn!("WebSocket on {} failed: {}", url, err);
+                break;
+            }
+        }
+
+        debug!("WebSocket closed on {}", url);
+    }
+}
+
+/// Response of routes of a `kind` of model not loaded
+fn no_model(kind: &str, request_id: &str) -> HttpResponse {
+    HttpResponse::error(
+        404,
+        "not_found",
+        &format!("No {} model loaded", kind),
+        Some(request_id),
+    )
+}
+
+/// PNG response
+fn png_response(png: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status: 200,
+        headers: vec![("content-type".to_owned(), "image/png".to_owned())],
+        body: png,
+    }
+}
+
+/// Key of a request of `tiny_http` asking to switch to the WebSocket
+/// protocol, if it is one
+fn websocket_key(request: &tiny_http::Request) -> Option<String> {
+    let header = |name: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv(name))
+            .map(|header| header.value.as_str().trim())
+    };
+
+    if request.method() != &tiny_http::Method::Get
+        || !header("Upgrade").map_or(false, |value| value.eq_ignore_ascii_case("websocket"))
+    {
+        return None;
+    }
+
+    header("Sec-WebSocket-Key").map(str::to_owned)
+}
+
+/// Earliest of `deadline` and the end of `timeout` from now
+fn earliest_deadline(deadline: Option<Instant>, timeout: Option<Duration>) -> Option<Instant> {
+    match (deadline, timeout.map(|timeout| Instant::now() + timeout)) {
+        (Some(deadline), Some(timeout)) => Some(deadline.min(timeout)),
+        (deadline, timeout) => deadline.or(timeout),
+    }
+}
+
+/// Write a result to a sink, logging failures rather than failing the
+/// request
+fn write_result(
+    sink: &dyn ResultSink,
+    id: &str,
+    request_id: Option<&str>,
+    source: Option<&str>,
+    result: &tensorflow::Result<Classification>,
+) {
+    let error = result.as_ref().err().map(|err| ErrorBody::from(err).error);
+    let record = ResultRecord {
+        id,
+        request_id,
+        source,
+        classification: result.as_ref().ok(),
+        error: error.as_ref(),
+    };
+
+    if let Err(err) = sink.write(&record) {
+        warn!("[{}] Could not write result: {}", id, err);
+    }
+}
+
+/// Credentials of the `X-Api-Key` and `Authorization: Bearer` headers
+fn credentials(request: &HttpRequest) -> Credentials {
+    Credentials {
+        api_key: request.header("x-api-key"),
+        bearer: request.header("authorization").and_then(|value| {
+            let value = value.trim();
+            let scheme = value.get(..7).unwrap_or_default();
+            if value.len() > 7 && scheme.eq_ignore_ascii_case("bearer ") {
+                Some(value[7..].trim())
+            } else {
+                None
+            }
+        }),
+        ..Default::default()
+    }
+}
+
+/// ID the client or a gateway gave a request with the `X-Request-Id`
+/// header, if a sane one. Requests are identified by IDs of the server, as
+/// clients may reuse those of others.
+fn client_request_id(request: &HttpRequest) -> Option<&str> {
+    request
+        .header("x-request-id")
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()))
+}
+
+/// Span of `name` starting now, as a child of the caller of `trace`
+#[cfg(feature = "fetch")]
+fn started_span(name: &str, trace: &TraceContext) -> Span {
+    let now = SystemTime::now();
+
+    Span {
+        name: name.to_owned(),
+        span_id: new_span_id(),
+        parent_id: trace.parent_id.clone(),
+        start: now,
+        end: now,
+        attributes: vec![],
+        error: None,
+    }
+}
+
+/// Span of `request` starting now, and the trace of its caller, continued
+/// from any `traceparent`
+#[cfg(feature = "fetch")]
+fn request_span(request: &HttpRequest) -> (TraceContext, Span) {
+    let trace = request
+        .header("traceparent")
+        .and_then(TraceContext::from_traceparent)
+        .unwrap_or_else(TraceContext::new_root);
+
+    let mut span = started_span(&format!("{} {}", request.method, request.path), &trace);
+    span.attributes = vec![
+        ("http.method".to_owned(), request.method.clone()),
+        ("http.target".to_owned(), request.path.clone()),
+    ];
+
+    (trace, span)
+}
+
+/// Span of a batch ending now, with the outcome of its images
+#[cfg(feature = "fetch")]
+fn batch_span(mut span: Span, summary: &BatchSummary) -> Span {
+    span.end = SystemTime::now();
+    span.attributes
+        .push(("batch.size".to_owned(), summary.total.to_string()));
+    span.attributes
+        .push(("batch.failed".to_owned(), summary.failed.to_string()));
+    span
+}
+
+/// Spans of a classification ending now: that of its request, and those of
+/// the stages of a successful classification
+#[cfg(feature = "fetch")]
+fn classification_spans(
+    mut span: Span,
+    options: &ClassifyOptions,
+    result: &tensorflow::Result<Classification>,
+) -> Vec<Span> {
+    span.end = SystemTime::now();
+
+    match result {
+        Ok(classification) => {
+            span.attributes.push((
+                "classification.tag".to_owned(),
+                classification.tag().to_owned(),
+            ));
+
+            let mut spans = Span::stages(&span, &classification.timings(), options.trace.as_ref());
+            spans.insert(0, span);
+            spans
+        }
+        Err(err) => {
+            span.error = Some(err.message().unwrap_or("").to_owned());
+            vec![span]
+        }
+    }
+}
+
+/// Serve HTTP/1.1 on `addr` with `threads` workers until the listener fails,
+/// responding to requests with `handler`. Bodies beyond `max_body_size` are
+/// truncated to one more byte, for the handler to reject them.
+pub fn serve_http<H>(addr: &str, threads: usize, max_body_size: usize, handler: H) -> io::Result<()>
+where
+    H: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+{
+    let listener =
+        tiny_http::Server::http(addr).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+    serve_requests(listener, threads, move |request| {
+        respond(request, max_body_size, &handler)
+    })
+}
+
+/// Listener of `tiny_http` on `addr`, closing connections idle for the
+/// timeout of `options`, and serving TLS with the certificate and key of
+/// `options` if set
+fn listen(addr: &str, options: &ServerOptions) -> io::Result<tiny_http::Server> {
+    let other =
+        |err: Box<dyn std::error::Error + Send + Sync>| io::Error::new(ErrorKind::Other, err);
+
+    let listener = TcpListener::bind(addr)?;
+    set_idle_timeout(&listener, options.idle_timeout)?;
+
+    #[cfg(feature = "tls")]
+    {
+        match (&options.tls_cert, &options.tls_key) {
+            (Some(cert), Some(key)) => {
+                let config = tiny_http::SslConfig {
+                    certificate: fs::read(cert)?,
+                    private_key: fs::read(key)?,
+                };
+                return tiny_http::Server::from_listener(listener, Some(config)).map_err(other);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "TLS needs both a certificate and a key",
+                ))
+            }
+        }
+    }
+
+    tiny_http::Server::from_listener(listener, None).map_err(other)
+}
+
+/// Make reads of the connections accepted by `listener` fail after
+/// `timeout` without data. Accepted sockets inherit the read timeout of the
+/// listening one, set through a stream of a duplicate of it, as listeners
+/// have none of their own.
+#[cfg_attr(not(any(unix, windows)), allow(unused_variables))]
+fn set_idle_timeout(listener: &TcpListener, timeout: Option<Duration>) -> io::Result<()> {
+    #[cfg(unix)]
+    let socket = TcpStream::from(std::os::unix::io::OwnedFd::from(listener.try_clone()?));
+    #[cfg(windows)]
+    let socket = TcpStream::from(std::os::windows::io::OwnedSocket::from(
+        listener.try_clone()?,
+    ));
+
+    #[cfg(any(unix, windows))]
+    socket.set_read_timeout(timeout)?;
+
+    Ok(())
+}
+
+/// Serve HTTP/1.1 with `threads` workers until `listener` fails, passing
+/// requests of `tiny_http` to `dispatch`
+fn serve_requests<D>(listener: tiny_http::Server, threads: usize, dispatch: D) -> io::Result<()>
+where
+    D: Fn(tiny_http::Request) + Send + Sync + 'static,
+{
+    let server = Arc::new(listener);
+    let dispatch = Arc::new(dispatch);
+
+    let workers: Vec<_> = (0..threads.max(1))
+        .map(|_| {
+            let server = server.clone();
+            let dispatch = dispatch.clone();
+
+            thread::spawn(move || -> io::Result<()> {
+                loop {
+                    let request = server.recv()?;
+                    dispatch(request);
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        match worker.join() {
+            Ok(result) => result?,
+            Err(_) => warn!("HTTP worker panicked"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a request of `tiny_http`, and send the response of `handler`
+fn respond<H>(mut request: tiny_http::Request, max_body_size: usize, handler: &H)
+where
+    H: Fn(&HttpRequest) -> HttpResponse,
+{
+    let response = match read_request(&mut request, max_body_size) {
+        Ok(http_request) => handler(&http_request),
+        Err(response) => response,
+    };
+
+    send_response(request, response);
+}
+
+/// Read a request of `tiny_http`, with its body truncated to one more byte
+/// than `max_body_size`
+fn read_request(
+    request: &mut tiny_http::Request,
+    max_body_size: usize,
+) -> Result<HttpRequest, HttpResponse> {
+    debug!("{} {}", request.method(), request.url());
+
+    let mut body = vec![];
+    let limit = max_body_size as u64 + 1;
+
+    if let Err(err) = request.as_reader().take(limit).read_to_end(&mut body) {
+        return Err(HttpResponse::error(
+            400,
+            "invalid_argument",
+            &format!("{}", err),
+            None,
+        ));
+    }
+
+    let headers = request
+        .headers()
+        .iter()
+        .map(|header| {
+            (
+                header.field.as_str().as_str().to_owned(),
+                header.value.as_str().to_owned(),
+            )
+        })
+        .collect();
+
+    Ok(HttpRequest::new(
+        &request.method().to_string(),
+        request.url(),
+        headers,
+        body,
+    ))
+}
+
+/// Send a response to a request of `tiny_http`
+fn send_response(request: tiny_http::Request, response: HttpResponse) {
+    let mut reply = tiny_http::Response::from_data(response.body).with_status_code(response.status);
+    for (name, value) in &response.headers {
+        if let Ok(header) = tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes()) {
+            reply.add_header(header);
+        }
+    }
+
+    if let Err(err) = request.respond(reply) {
+        warn!("Could not send response: {}", err);
+    }
+}
+
+/// Images and options of a batch request of at most `max_size` images: the
+/// `image` fields of a form upload, with JSON options in an optional
+/// `options` field, or else a JSON array of URLs
+fn batch_request(
+    request: &HttpRequest,
+    max_size: usize,
+) -> Result<(Batch, ClassifyOptions), String> {
+    let (batch, options) = match request.header("content-type").and_then(multipart::boundary) {
+        Some(boundary) => {
+            let parts =
+                multipart::parse(&request.body, &boundary).map_err(|err| format!("{}", err))?;
+
+            let options = match parts
+                .iter()
+                .find(|part| part.name.as_deref() == Some("options"))
+            {
+                Some(part) => serde_json::from_slice(part.data)
+                    .map_err(|err| format!("Invalid options: {}", err))?,
+                None => request.classify_options()?,
+            };
+            let images = parts
+                .iter()
+                .filter(|part| part.name.as_deref() == Some("image"))
+                .map(|part| Cow::Borrowed(part.data))
+                .collect();
+
+            (Batch::Raw(images), options)
+        }
+        None => {
+            let urls = serde_json::from_slice(&request.body)
+                .map_err(|err| format!("Expected a JSON array of URLs: {}", err))?;
+
+            (Batch::Urls(urls), request.classify_options()?)
+        }
+    };
+
+    if batch.len() == 0 || batch.len() > max_size {
+        return Err(format!(
+            "Expected between 1 and {} images, got {}",
+            max_size,
+            batch.len()
+        ));
+    }
+
+    Ok((batch, options))
+}
+
+/// Server-sent event of the result of the image at `index` of a batch
+fn batch_event(index: usize, result: &tensorflow::Result<Classification>) -> String {
+    let (event, data) = match result {
+        Ok(classification) => ("result", serde_json::to_string(classification)),
+        Err(err) => ("error", serde_json::to_string(&ErrorBody::from(err))),
+    };
+
+    format!(
+        "event: {}\nid: {}\ndata: {}\n\n",
+        event,
+        index,
+        data.unwrap_or_default()
+    )
+}
+
+fn summary_event(summary: &BatchSummary) -> String {
+    format!(
+        "event: summary\ndata: {}\n\n",
+        serde_json::to_string(summary).unwrap_or_default()
+    )
+}
+
+/// Image and options of a classification request: a JSON `ClassifyRequest`
+/// when sent as `application/json`, a form upload when sent as
+/// `multipart/form-data`, otherwise the raw image with options in the query
+/// string
+fn classify_request(request: &HttpRequest) -> Result<(ImageSource, ClassifyOptions), String> {
+    if request.path == "/tensor" {
+        let tensor = if is_npy(&request.body) {
+            parse_npy(&request.body)?
+        } else {
+            let shape = request
+                .header("x-tensor-shape")
+                .ok_or("Missing X-Tensor-Shape header")?;
+            parse_raw(&request.body, shape)?
+        };
+
+        return Ok((ImageSource::Tensor(tensor), request.classify_options()?));
+    }
+
+    if let Some(boundary) = request.header("content-type").and_then(multipart::boundary) {
+        let parts = multipart::parse(&request.body, &boundary).map_err(|err| format!("{}", err))?;
+        let field = |name: &str| parts.iter().find(|part| part.name.as_deref() == Some(name));
+
+        let image = field("image").ok_or("Missing 'image' field")?;
+        let options = match field("options") {
+            Some(part) => serde_json::from_slice(part.data)
+                .map_err(|err| format!("Invalid options: {}", err))?,
+            None => request.classify_options()?,
+        };
+
+        return Ok((ImageSource::Raw(Cow::Borrowed(image.data)), options));
+    }
+
+    if !request.has_content_type("application/json") {
+        return Ok((
+            ImageSource::Raw(Cow::Borrowed(&request.body[..])),
+            request.classify_options()?,
+        ));
+    }
+
+    let json: ClassifyRequest =
+        serde_json::from_slice(&request.body).map_err(|err| format!("Invalid request: {}", err))?;
+
+    match (json.url, json.image_b64) {
+        (Some(url), None) => Ok((ImageSource::Url(url), json.options)),
+        (None, Some(data)) => {
+            let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+            let image = base64::decode(&data).map_err(|_| "Invalid image_b64".to_owned())?;
+
+            Ok((ImageSource::Raw(Cow::Owned(image)), json.options))
+        }
+        _ => Err("Expected exactly one of 'url' and 'image_b64'".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_parsing() {
+        let request = HttpRequest::new(
+            "post",
+            "/classify?top_k=5&lang=el%2DGR&min_probability=0.1+",
+            vec![("Content-Type".to_owned(), "image/jpeg".to_owned())],
+            vec![],
+        );
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/classify");
+        assert_eq!(request.query_param("lang"), Some("el-GR"));
+        assert_eq!(request.header("content-type"), Some("image/jpeg"));
+        assert!(request.has_content_type("image/jpeg"));
+
+        assert!(request.classify_options().is_err());
+        assert_eq!(percent_decode("a%2"), "a%2");
+        assert_eq!(percent_decode("%41%zz"), "A%zz");
+        assert_eq!(percent_decode("%+1"), "% 1");
+
+        assert_eq!(client_request_id(&request), None);
+        let traced = HttpRequest::new(
+            "GET",
+            "/",
+            vec![("X-Request-Id".to_owned(), "gw-42".to_owned())],
+            vec![],
+        );
+        assert_eq!(client_request_id(&traced), Some("gw-42"));
+    }
+
+    #[test]
+    fn cors_preflight() {
+        let cors = CorsOptions {
+            allowed_origins: vec!["https://app.example.com/".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            cors.allow_origin("https://app.example.com"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(cors.allow_origin("https://evil.example.com"), None);
+
+        let preflight = |method: &str| {
+            HttpRequest::new(
+                "OPTIONS",
+                "/",
+                vec![
+                    ("Origin".to_owned(), "https://app.example.com".to_owned()),
+                    (
+                        "Access-Control-Request-Method".to_owned(),
+                        method.to_owned(),
+                    ),
+                ],
+                vec![],
+            )
+        };
+        let response = cors.preflight(&preflight("POST"), "test");
+        assert_eq!(response.status, 204);
+        assert!(response
+            .headers
+            .contains(&("access-control-max-age".to_owned(), "3600".to_owned())));
+        assert_eq!(cors.preflight(&preflight("DELETE"), "test").status, 403);
+    }
+
+    #[test]
+    fn batch_events() {
+        let request = HttpRequest::new(
+            "POST",
+            "/batch?top_k=2",
+            vec![("Accept".to_owned(), "text/event-stream".to_owned())],
+            br#"["https://example.com/a.jpg", "s3://bucket/b.png"]"#.to_vec(),
+        );
+        assert!(request.accepts("text/event-stream"));
+
+        let (batch, options) = batch_request(&request, 2).unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!(options.top_k, 2);
+        assert!(batch_request(&request, 1).is_err());
+
+        let failed = Err(Status::new_set_lossy(
+            tensorflow::Code::NotFound,
+            "No such image",
+        ));
+        assert_eq!(
+            batch_event(3, &failed),
+            "event: error\nid: 3\ndata: \
+             {\"error\":{\"code\":\"not_found\",\"message\":\"No such image\"}}\n\n"
+        );
+    }
+}
@@ -0,0 +1,391 @@
+//! Destinations of classification results besides the replies to clients,
+//! for analytics, auditing or processing downstream.
+
+use std::io::{self, Write};
+#[cfg(feature = "postgres")]
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "postgres")]
+use std::thread;
+
+#[cfg(feature = "postgres")]
+use log::warn;
+#[cfg(feature = "postgres")]
+use native_tls::TlsConnector;
+#[cfg(feature = "postgres")]
+use postgres_native_tls::MakeTlsConnector;
+use serde::Serialize;
+use tensorflow::{Code, Status};
+
+#[cfg(feature = "fetch")]
+use crate::S3Storage;
+use crate::{Classification, ErrorDetail};
+
+/// Table results are written to in Postgres, unless given after `#`
+#[cfg(feature = "postgres")]
+const DEFAULT_TABLE: &str = "classifications";
+
+/// Threads writing to Postgres, each with its own connection
+#[cfg(feature = "postgres")]
+const POSTGRES_WRITERS: usize = 2;
+
+/// Most results waiting to be written to Postgres, beyond which they are
+/// dropped
+#[cfg(feature = "postgres")]
+const MAX_PENDING_ROWS: usize = 1024;
+
+/// Result of a classification, as written to a `ResultSink`
+#[derive(Clone, Copy, Serialize)]
+pub struct ResultRecord<'a> {
+    /// Unique ID of the result, generated by the server: a new one for a
+    /// request, or that of a job or message and the index of the image in it
+    pub id: &'a str,
+
+    /// ID the client gave the request, if any, which unlike `id` may be
+    /// reused by other clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<&'a str>,
+
+    /// URL or path of the image, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<&'a str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub classification: Option<&'a Classification>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a ErrorDetail>,
+}
+
+impl ResultRecord<'_> {
+    fn to_json(&self) -> tensorflow::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+            .map_err(|_| Status::new_set_lossy(Code::Internal, "Could not encode result"))
+    }
+}
+
+/// Destination of classification results
+pub trait ResultSink: Send + Sync {
+    fn write(&self, record: &ResultRecord) -> tensorflow::Result<()>;
+}
+
+/// Sink of `spec`: `-` or `stdout` for JSON Lines on stdout,
+/// `s3://bucket/prefix` for a JSON object per result with the `fetch`
+/// feature, or a `postgres://` URL for a row per result with the `postgres`
+/// feature, in the table after any `#`
+pub fn result_sink(spec: &str) -> tensorflow::Result<Arc<dyn ResultSink>> {
+    match spec {
+        "-" | "stdout" => Ok(Arc::new(JsonLinesSink::stdout())),
+        #[cfg(feature = "fetch")]
+        _ if spec.starts_with("s3://") => Ok(Arc::new(S3Sink::new(S3Storage::default(), spec))),
+        #[cfg(feature = "postgres")]
+        _ if spec.starts_with("postgres://") || spec.starts_with("postgresql://") => {
+            let mut parts = spec.splitn(2, '#');
+            let url = parts.next().unwrap_or_default();
+            let table = parts.next().unwrap_or(DEFAULT_TABLE);
+
+            Ok(Arc::new(PostgresSink::connect(url, table)?))
+        }
+        _ => Err(Status::new_set_lossy(
+            Code::InvalidArgument,
+            &format!("Unsupported result sink '{}'", spec),
+        )),
+    }
+}
+
+/// Sink configured by `RESULT_SINK`, if set
+pub fn result_sink_from_env() -> tensorflow::Result<Option<Arc<dyn ResultSink>>> {
+    match std::env::var("RESULT_SINK") {
+        Ok(spec) if !spec.is_empty() => result_sink(&spec).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Results as JSON Lines, one record per line
+pub struct JsonLinesSink {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonLinesSink {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        JsonLinesSink {
+            out: Mutex::new(out),
+        }
+    }
+
+    pub fn stdout() -> Self {
+        JsonLinesSink::new(Box::new(io::stdout()))
+    }
+}
+
+impl ResultSink for JsonLinesSink {
+    fn write(&self, record: &ResultRecord) -> tensorflow::Result<()> {
+        let mut line = record.to_json()?;
+        line.push(b'\n');
+
+        // Whole lines at a time, for those of threads not to interleave
+        let mut out = self.out.lock().unwrap();
+        out.write_all(&line)
+            .and_then(|_| out.flush())
+            .map_err(|err| {
+                Status::new_set_lossy(
+                    Code::Unavailable,
+                    &format!("Could not write result: {}", err),
+                )
+            })
+    }
+}
+
+/// Results as JSON objects `{prefix}/{id}.json` of S3
+#[cfg(feature = "fetch")]
+pub struct S3Sink {
+    storage: S3Storage,
+    prefix: String,
+}
+
+#[cfg(feature = "fetch")]
+impl S3Sink {
+    /// Sink of objects under `prefix`, like `s3://bucket/results`
+    pub fn new(storage: S3Storage, prefix: &str) -> Self {
+        S3Sink {
+            storage,
+            prefix: prefix.trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+#[cfg(feature = "fetch")]
+impl ResultSink for S3Sink {
+    fn write(&self, record: &ResultRecord) -> tensorflow::Result<()> {
+        let location = format!("{}/{}.json", self.prefix, record.id);
+        self.storage
+            .write(&location, &record.to_json()?, "application/json")
+    }
+}
+
+/// Results as rows of a Postgres table, one per classification, replacing
+/// that of the same ID for results delivered again. Rows are written by a
+/// few threads with a connection each, over TLS as the `sslmode` of the URL
+/// has it, TLS being preferred by default.
+#[cfg(feature = "postgres")]
+pub struct PostgresSink {
+    sender: Mutex<SyncSender<Row>>,
+}
+
+/// Result, as written to Postgres
+#[cfg(feature = "postgres")]
+struct Row {
+    id: String,
+    request_id: Option<String>,
+    source: Option<String>,
+    tag: Option<String>,
+    probability: Option<f32>,
+    error_code: Option<String>,
+    error_message: Option<String>,
+    result: serde_json::Value,
+}
+
+#[cfg(feature = "postgres")]
+fn postgres_failed(err: pg::Error) -> Status {
+    Status::new_set_lossy(Code::Unavailable, &format!("Postgres failed: {}", err))
+}
+
+#[cfg(feature = "postgres")]
+fn postgres_connect(url: &str, tls: &MakeTlsConnector) -> tensorflow::Result<pg::Client> {
+    pg::Client::connect(url, tls.clone()).map_err(|err| {
+        Status::new_set_lossy(
+            Code::Unavailable,
+            &format!("Could not connect to Postgres: {}", err),
+        )
+    })
+}
+
+/// Thread writing rows with its own connection, connecting again after
+/// failures
+#[cfg(feature = "postgres")]
+struct PostgresWriter {
+    url: String,
+    tls: MakeTlsConnector,
+    insert: String,
+    client: Option<pg::Client>,
+}
+
+#[cfg(feature = "postgres")]
+impl PostgresWriter {
+    fn run(mut self, rows: &Mutex<Receiver<Row>>) {
+        loop {
+            // Locked only while waiting, for the next row to go to another
+            // writer while this one writes
+            let row = match rows.lock().unwrap().recv() {
+                Ok(row) => row,
+                Err(_) => return,
+            };
+
+            if let Err(err) = self.write(&row) {
+                warn!("[{}] Could not write result: {}", row.id, err);
+            }
+        }
+    }
+
+    fn write(&mut self, row: &Row) -> tensorflow::Result<()> {
+        if self.client.as_ref().map_or(true, pg::Client::is_closed) {
+            self.client = Some(postgres_connect(&self.url, &self.tls)?);
+        }
+
+        let result = self.client.as_mut().expect("Connected above").execute(
+            self.insert.as_str(),
+            &[
+                &row.id,
+                &row.request_id,
+                &row.source,
+                &row.tag,
+                &row.probability,
+                &row.error_code,
+                &row.error_message,
+                &row.result,
+            ],
+        );
+
+        result.map(|_| ()).map_err(|err| {
+            self.client = None;
+            postgres_failed(err)
+        })
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl PostgresSink {
+    /// Sink of the rows of `table` in the database at `url`, creating the
+    /// table if missing
+    pub fn connect(url: &str, table: &str) -> tensorflow::Result<Self> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+        if table.is_empty() || !table.chars().all(valid) {
+            return Err(Status::new_set_lossy(
+                Code::InvalidArgument,
+                &format!("Invalid table name '{}'", table),
+            ));
+        }
+
+        let tls = MakeTlsConnector::new(TlsConnector::new().map_err(|err| {
+            Status::new_set_lossy(Code::Internal, &format!("Could not set up TLS: {}", err))
+        })?);
+
+        // Connecting up front, for a misconfigured sink to fail at startup
+        let mut client = postgres_connect(url, &tls)?;
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    id TEXT PRIMARY KEY,
+                    request_id TEXT,
+                    source TEXT,
+                    tag TEXT,
+                    probability REAL,
+                    error_code TEXT,
+                    error_message TEXT,
+                    result JSONB NOT NULL,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                )",
+                table
+            ))
+            .map_err(postgres_failed)?;
+
+        let insert = format!(
+            "INSERT INTO {}
+                (id, request_id, source, tag, probability, error_code, error_message, result)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (id) DO UPDATE SET
+                request_id = EXCLUDED.request_id,
+                source = EXCLUDED.source,
+                tag = EXCLUDED.tag,
+                probability = EXCLUDED.probability,
+                error_code = EXCLUDED.error_code,
+                error_message = EXCLUDED.error_message,
+                result = EXCLUDED.result,
+                created_at = now()",
+            table
+        );
+
+        let (sender, receiver) = mpsc::sync_channel(MAX_PENDING_ROWS);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut client = Some(client);
+
+        for _ in 0..POSTGRES_WRITERS {
+            let writer = PostgresWriter {
+                url: url.to_owned(),
+                tls: tls.clone(),
+                insert: insert.clone(),
+                client: client.take(),
+            };
+            let receiver = receiver.clone();
+
+            thread::spawn(move || writer.run(&receiver));
+        }
+
+        Ok(PostgresSink {
+            sender: Mutex::new(sender),
+        })
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl ResultSink for PostgresSink {
+    /// Queue the row of `record`, failing rather than waiting when the
+    /// writers fall behind
+    fn write(&self, record: &ResultRecord) -> tensorflow::Result<()> {
+        let row = Row {
+            id: record.id.to_owned(),
+            request_id: record.request_id.map(str::to_owned),
+            source: record.source.map(str::to_owned),
+            tag: record
+                .classification
+                .map(|classification| classification.tag().to_owned()),
+            probability: record.classification.map(Classification::probability),
+            error_code: record.error.map(|error| error.code.clone()),
+            error_message: record.error.map(|error| error.message.clone()),
+            result: serde_json::to_value(record)
+                .map_err(|_| Status::new_set_lossy(Code::Internal, "Could not encode result"))?,
+        };
+
+        match self.sender.lock().unwrap().try_send(row) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(Status::new_set_lossy(
+                Code::ResourceExhausted,
+                "Too many results waiting to be written to Postgres",
+            )),
+            Err(TrySendError::Disconnected(_)) => Err(Status::new_set_lossy(
+                Code::Unavailable,
+                "Postgres writers stopped",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorBody;
+
+    #[test]
+    fn result_records() {
+        let error = ErrorBody::new("unavailable", "Could not reach S3").error;
+        let record = ResultRecord {
+            id: "job/3",
+            request_id: None,
+            source: Some("s3://bucket/cat.jpg"),
+            classification: None,
+            error: Some(&error),
+        };
+
+        assert_eq!(
+            serde_json::to_value(&record).unwrap(),
+            serde_json::json!({
+                "id": "job/3",
+                "source": "s3://bucket/cat.jpg",
+                "error": { "code": "unavailable", "message": "Could not reach S3" }
+            })
+        );
+
+        assert!(result_sink("stdout").is_ok());
+        assert!(result_sink("ftp://host/results").is_err());
+    }
+}
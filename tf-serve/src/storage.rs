@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use tensorflow::{Code, Status};
+
+use crate::TraceContext;
+#[cfg(feature = "fetch")]
+use crate::{GcsStorage, HttpStorage, S3Storage};
+
+/// Validators of an object read, to tell whether it changed since
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Validators {
+    /// `ETag` of the object
+    pub etag: Option<String>,
+
+    /// `Last-Modified` date of the object
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Whether there is nothing to revalidate an object with
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// Source of models, labels and images
+pub trait Storage: Send + Sync {
+    /// Read the whole object at `location`
+    fn read(&self, location: &str) -> tensorflow::Result<Vec<u8>>;
+
+    /// Read the whole object at `location` as part of `trace`. Storages
+    /// reached over the network propagate the trace to the server.
+    fn read_traced(&self, location: &str, trace: &TraceContext) -> tensorflow::Result<Vec<u8>> {
+        let _ = trace;
+        self.read(location)
+    }
+
+    /// Read the object at `location` along with its validators, unless it
+    /// is unchanged since `validators` were returned, returning `None` then.
+    /// Storages that cannot tell read it every time, without validators.
+    fn read_if_modified(
+        &self,
+        location: &str,
+        validators: &Validators,
+        trace: Option<&TraceContext>,
+    ) -> tensorflow::Result<Option<(Vec<u8>, Validators)>> {
+        let _ = validators;
+        let data = match trace {
+            Some(trace) => self.read_traced(location, trace)?,
+            None => self.read(location)?,
+        };
+
+        Ok(Some((data, Validators::default())))
+    }
+
+    /// Store `data` as the object at `location`, replacing any previous one
+    fn write(&self, location: &str, data: &[u8]) -> tensorflow::Result<()> {
+        let _ = data;
+        Err(Status::new_set_lossy(
+            Code::Unimplemented,
+            &format!("Cannot write '{}' to this storage", location),
+        ))
+    }
+
+    /// Make the directory at `location` available on the local filesystem.
+    /// TensorFlow can only load SavedModels from local paths.
+    fn local_dir(&self, location: &str) -> tensorflow::Result<PathBuf> {
+        Err(Status::new_set_lossy(
+            Code::Unimplemented,
+            &format!("Cannot load directory '{}' from this storage", location),
+        ))
+    }
+}
+
+/// Scheme of a `scheme://...` or `data:...` location, if any
+fn scheme(location: &str) -> Option<&str> {
+    if location.len() >= 5 && location[..5].eq_ignore_ascii_case("data:") {
+        return Some(&location[..4]);
+    }
+
+    location.find("://").map(|pos| &location[..pos])
+}
+
+/// Local filesystem, addressed by plain paths or `file://` URLs
+#[derive(Default)]
+pub struct LocalStorage;
+
+impl LocalStorage {
+    fn path(location: &str) -> &str {
+        location.trim_start_matches("file://")
+    }
+}
+
+impl Storage for LocalStorage {
+    fn read(&self, location: &str) -> tensorflow::Result<Vec<u8>> {
+        fs::read(LocalStorage::path(location)).map_err(|_| {
+            Status::new_set_lossy(Code::NotFound, &format!("Could not read '{}'", location))
+        })
+    }
+
+    fn write(&self, location: &str, data: &[u8]) -> tensorflow::Result<()> {
+        let path = Path::new(LocalStorage::path(location));
+        let failed = || {
+            Status::new_set_lossy(
+                Code::PermissionDenied,
+                &format!("Could not write '{}'", location),
+            )
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|_| failed())?;
+        }
+
+        fs::write(path, data).map_err(|_| failed())
+    }
+
+    fn local_dir(&self, location: &str) -> tensorflow::Result<PathBuf> {
+        Ok(PathBuf::from(LocalStorage::path(location)))
+    }
+}
+
+/// Inline `data:[<media type>][;base64],<data>` URIs
+#[derive(Default)]
+pub struct DataStorage;
+
+impl Storage for DataStorage {
+    fn read(&self, location: &str) -> tensorflow::Result<Vec<u8>> {
+        let invalid = || Status::new_set_lossy(Code::InvalidArgument, "Invalid data URI");
+
+        let comma = location.find(',').ok_or_else(invalid)?;
+        let (header, data) = (&location[..comma], &location[comma + 1..]);
+
+        if header.to_lowercase().ends_with(";base64") {
+            // Tolerate line breaks and padding-free encoders
+            let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+            base64::decode_config(data.trim_end_matches('='), base64::STANDARD_NO_PAD)
+                .map_err(|_| invalid())
+        } else {
+            Ok(data.as_bytes().to_vec())
+        }
+    }
+}
+
+/// Objects kept in memory, addressed by arbitrary keys
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: RwLock<HashMap<String, Arc<Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    /// Store `data` under `location`, replacing any previous object
+    pub fn insert(&self, location: &str, data: Vec<u8>) {
+        self.objects
+            .write()
+            .unwrap()
+            .insert(location.to_owned(), Arc::new(data));
+    }
+
+    /// Remove the object stored under `location`
+    pub fn remove(&self, location: &str) {
+        self.objects.write().unwrap().remove(location);
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, location: &str) -> tensorflow::Result<Vec<u8>> {
+        self.objects
+            .read()
+            .unwrap()
+            .get(location)
+            .map(|data| data.as_ref().clone())
+            .ok_or_else(|| {
+                Status::new_set_lossy(Code::NotFound, &format!("No object '{}'", location))
+            })
+    }
+
+    fn write(&self, location: &str, data: &[u8]) -> tensorflow::Result<()> {
+        self.insert(location, data.to_vec());
+        Ok(())
+    }
+}
+
+/// Dispatches locations to storages according to their URL scheme. Locations
+/// without a scheme go to the local filesystem, once allowed.
+#[derive(Clone)]
+pub struct StorageRegistry {
+    schemes: HashMap<String, Arc<dyn Storage>>,
+    local: Option<Arc<dyn Storage>>,
+}
+
+#[cfg(feature = "fetch")]
+impl Default for StorageRegistry {
+    fn default() -> Self {
+        StorageRegistry::new(HttpStorage::default())
+    }
+}
+
+#[cfg(not(feature = "fetch"))]
+impl Default for StorageRegistry {
+    fn default() -> Self {
+        StorageRegistry::inline()
+    }
+}
+
+impl StorageRegistry {
+    /// Registry serving `data:` URIs only
+    fn inline() -> Self {
+        let mut registry = StorageRegistry {
+            schemes: HashMap::new(),
+            local: None,
+        };
+
+        registry.register("data", Arc::new(DataStorage));
+
+        registry
+    }
+
+    /// Registry serving local paths and `file://` URLs from the filesystem,
+    /// and `data:` URIs
+    pub fn local() -> Self {
+        let mut registry = StorageRegistry::inline();
+        registry.allow_local();
+
+        registry
+    }
+
+    /// Registry serving HTTP(S) URLs from `http` and `data:` URIs, but no
+    /// local files or buckets
+    #[cfg(feature = "fetch")]
+    pub fn new(http: HttpStorage) -> Self {
+        let http: Arc<dyn Storage> = Arc::new(http);
+
+        let mut registry = StorageRegistry::inline();
+
+        registry.register("http", http.clone());
+        registry.register("https", http);
+
+        registry
+    }
+
+    /// Serve `s3://` and `gs://` objects of `buckets`, or of any bucket if
+    /// empty, up to `max_size` bytes, with `client`
+    #[cfg(feature = "fetch")]
+    pub fn allow_buckets(
+        &mut self,
+        client: &reqwest::Client,
+        buckets: &[String],
+        max_size: Option<usize>,
+    ) {
+        self.register(
+            "s3",
+            Arc::new(S3Storage::restricted(client.clone(), buckets, max_size)),
+        );
+        self.register(
+            "gs",
+            Arc::new(GcsStorage::restricted(client.clone(), buckets, max_size)),
+        );
+    }
+
+    /// Serve locations of `scheme` from `storage`
+    pub fn register(&mut self, scheme: &str, storage: Arc<dyn Storage>) {
+        self.schemes.insert(scheme.to_lowercase(), storage);
+    }
+
+    /// Serve local paths and `file://` URLs from the filesystem. Registries
+    /// fetching the images of network clients must not, for those not to
+    /// read the files of the host.
+    pub fn allow_local(&mut self) {
+        let local: Arc<dyn Storage> = Arc::new(LocalStorage);
+
+        self.register("file", local.clone());
+        self.local = Some(local);
+    }
+
+    fn get(&self, location: &str) -> tensorflow::Result<&dyn Storage> {
+        match scheme(location) {
+            None => self.local.as_deref().ok_or_else(|| {
+                Status::new_set_lossy(
+                    Code::InvalidArgument,
+                    &format!("Local path '{}' is not allowed", location),
+                )
+            }),
+            Some(scheme) => self
+                .schemes
+                .get(&scheme.to_lowercase())
+                .map(|storage| storage.as_ref())
+                .ok_or_else(|| {
+                    Status::new_set_lossy(
+                        Code::Unimplemented,
+                        &format!("Unsupported scheme '{}'", scheme),
+                    )
+                }),
+        }
+    }
+}
+
+impl Storage for StorageRegistry {
+    fn read(&self, location: &str) -> tensorflow::Result<Vec<u8>> {
+        self.get(location)?.read(location)
+    }
+
+    fn read_traced(&self, location: &str, trace: &TraceContext) -> tensorflow::Result<Vec<u8>> {
+        self.get(location)?.read_traced(location, trace)
+    }
+
+    fn read_if_modified(
+        &self,
+        location: &str,
+        validators: &Validators,
+        trace: Option<&TraceContext>,
+    ) -> tensorflow::Result<Option<(Vec<u8>, Validators)>> {
+        self.get(location)?
+            .read_if_modified(location, validators, trace)
+    }
+
+    fn write(&self, location: &str, data: &[u8]) -> tensorflow::Result<()> {
+        self.get(location)?.write(location, data)
+    }
+
+    fn local_dir(&self, location: &str) -> tensorflow::Result<PathBuf> {
+        self.get(location)?.local_dir(location)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_dispatch() {
+        let memory = Arc::new(MemoryStorage::default());
+        memory.insert("mem://image", vec![1, 2, 3]);
+
+        let mut registry = StorageRegistry::default();
+        registry.register("mem", memory.clone());
+
+        assert!(registry.read("/etc/passwd").is_err());
+        assert!(registry.read("file:///etc/passwd").is_err());
+
+        assert_eq!(registry.read("mem://image").unwrap(), vec![1, 2, 3]);
+        assert!(registry.read("mem://missing").is_err());
+        assert!(registry.read("ftp://host/image").is_err());
+        assert!(registry.local_dir("mem://image").is_err());
+        assert_eq!(
+            registry.read("data:image/png;base64,AQID").unwrap(),
+            vec![1, 2, 3]
+        );
+        assert!(registry.read("data:image/png;base64,***").is_err());
+
+        registry.allow_local();
+        assert_eq!(
+            registry.local_dir("file:///models/resnet50").unwrap(),
+            PathBuf::from("/models/resnet50")
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! Preprocessed model inputs sent by clients instead of images, as NumPy
+//! `.npy` files or flat little-endian `float32` values of a given shape.
+
+/// Shape and values of a `float32` tensor
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputTensor {
+    pub shape: Vec<u64>,
+    pub values: Vec<f32>,
+}
+
+/// Whether `data` starts like a `.npy` file
+pub fn is_npy(data: &[u8]) -> bool {
+    data.starts_with(b"\x93NUMPY")
+}
+
+/// Parse a shape like `1,224,224,3`, `[1, 224, 224, 3]` or `(224, 224, 3)`
+pub fn parse_shape(shape: &str) -> Result<Vec<u64>, String> {
+    shape
+        .trim()
+        .trim_start_matches(|c| c == '[' || c == '(')
+        .trim_end_matches(|c| c == ']' || c == ')')
+        .split(',')
+        .map(str::trim)
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| dim.parse())
+        .collect::<Result<Vec<u64>, _>>()
+        .map_err(|_| format!("Invalid tensor shape '{}'", shape))
+}
+
+/// Values of `shape` from consecutive little-endian `float32`s
+fn values(data: &[u8], shape: Vec<u64>) -> Result<InputTensor, String> {
+    let size = shape
+        .iter()
+        .try_fold(4u64, |size, dim| size.checked_mul(*dim))
+        .filter(|&size| size == data.len() as u64);
+    if size.is_none() {
+        return Err(format!(
+            "Tensor of shape {:?} does not take {} bytes",
+            shape,
+            data.len()
+        ));
+    }
+
+    let values = data
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect();
+
+    Ok(InputTensor { shape, values })
+}
+
+/// Tensor of flat little-endian `float32` values of `shape`
+pub fn parse_raw(data: &[u8], shape: &str) -> Result<InputTensor, String> {
+    values(data, parse_shape(shape)?)
+}
+
+/// Tensor of a `.npy` file of little-endian `float32` values in C order
+pub fn parse_npy(data: &[u8]) -> Result<InputTensor, String> {
+    let invalid = || "Invalid .npy file".to_owned();
+
+    if !is_npy(data) {
+        return Err(invalid());
+    }
+
+    // Version 1 has a 2-byte header length, later ones a 4-byte one
+    let (header_len, start) = match data.get(6) {
+        Some(1) => {
+            let len = data.get(8..10).ok_or_else(invalid)?;
+            (u16::from_le_bytes([len[0], len[1]]) as usize, 10)
+        }
+        Some(_) => {
+            let len = data.get(8..12).ok_or_else(invalid)?;
+            (
+                u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize,
+                12,
+            )
+        }
+        None => return Err(invalid()),
+    };
+    let header = data
+        .get(start..start + header_len)
+        .and_then(|header| std::str::from_utf8(header).ok())
+        .ok_or_else(invalid)?;
+
+    // The header is a Python dict literal
+    let value = |key: &str| {
+        let pos = header.find(&format!("'{}':", key))?;
+        Some(header[pos + key.len() + 3..].trim_start())
+    };
+
+    let descr = value("descr").ok_or_else(invalid)?;
+    if !descr.starts_with("'<f4'") {
+        return Err(format!(
+            "Expected a .npy file of little-endian float32 values, not {}",
+            descr.split(',').next().unwrap_or_default()
+        ));
+    }
+
+    if !value("fortran_order").map_or(false, |order| order.starts_with("False")) {
+        return Err("Expected a .npy file in C order".to_owned());
+    }
+
+    let shape = value("shape")
+        .filter(|shape| shape.starts_with('('))
+        .and_then(|shape| shape.find(')').map(|end| &shape[..=end]))
+        .ok_or_else(invalid)?;
+
+    values(&data[start + header_len..], parse_shape(shape)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn npy_and_raw() {
+        let encode = |descr: &str| {
+            let mut npy = b"\x93NUMPY\x01\x00".to_vec();
+            let header = format!(
+                "{{'descr': '{}', 'fortran_order': False, 'shape': (1, 2), }}\n",
+                descr
+            );
+            npy.extend_from_slice(&(header.len() as u16).to_le_bytes());
+            npy.extend_from_slice(header.as_bytes());
+            npy.extend_from_slice(&0.5f32.to_le_bytes());
+            npy.extend_from_slice(&(-1f32).to_le_bytes());
+            npy
+        };
+        let npy = encode("<f4");
+
+        assert!(is_npy(&npy));
+        assert_eq!(
+            parse_npy(&npy).unwrap(),
+            InputTensor {
+                shape: vec![1, 2],
+                values: vec![0.5, -1.0],
+            }
+        );
+        assert!(parse_npy(&npy[..npy.len() - 1]).is_err());
+        assert!(parse_npy(&encode("<f8")).is_err());
+
+        assert_eq!(
+            parse_shape("[1, 224, 224, 3]").unwrap(),
+            vec![1, 224, 224, 3]
+        );
+        assert_eq!(
+            parse_raw(&npy[npy.len() - 8..], "2").unwrap().values,
+            vec![0.5, -1.0]
+        );
+        assert!(parse_raw(&[0; 8], "1,x").is_err());
+    }
+}
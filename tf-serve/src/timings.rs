@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use crate::{Metrics, Timer};
+
+/// Durations of named stages, in milliseconds to the microsecond and in the
+/// order they were recorded. Nested stages are named after their parent, as
+/// `inference/session`.
+#[derive(Default)]
+pub struct Timings {
+    /// Shared with the timers recording into it
+    stages: Arc<Mutex<Vec<(String, f64)>>>,
+}
+
+/// Copy of the durations, which timers of the original do not record into
+impl Clone for Timings {
+    fn clone(&self) -> Self {
+        Timings {
+            stages: Arc::new(Mutex::new(self.to_vec())),
+        }
+    }
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        Timings::default()
+    }
+
+    /// Record the duration of `stage`, replacing any previous one, and into
+    /// the histograms of `Metrics::global`
+    pub fn record(&self, stage: &str, duration: f64) {
+        Metrics::global().record(stage, duration);
+
+        let mut stages = self.stages.lock().unwrap();
+
+        match stages.iter_mut().find(|(name, _)| name == stage) {
+            Some((_, previous)) => *previous = duration,
+            None => stages.push((stage.to_owned(), duration)),
+        }
+    }
+
+    /// Duration of `stage`, if recorded
+    pub fn get(&self, stage: &str) -> Option<f64> {
+        self.stages
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(name, _)| name == stage)
+            .map(|&(_, duration)| duration)
+    }
+
+    pub fn to_vec(&self) -> Vec<(String, f64)> {
+        self.stages.lock().unwrap().clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.lock().unwrap().is_empty()
+    }
+
+    /// Same durations, for timers to record into
+    fn share(&self) -> Self {
+        Timings {
+            stages: self.stages.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.to_vec()).finish()
+    }
+}
+
+/// Serialized as an object of durations by stage
+impl Serialize for Timings {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let stages = self.to_vec();
+
+        let mut map = serializer.serialize_map(Some(stages.len()))?;
+        for (name, duration) in &stages {
+            map.serialize_entry(name, duration)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Timings {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let stages = BTreeMap::<String, f64>::deserialize(deserializer)?;
+
+        Ok(Timings {
+            stages: Arc::new(Mutex::new(stages.into_iter().collect())),
+        })
+    }
+}
+
+/// Timer stopped when dropped, so that early returns still log it, and
+/// optionally recording its duration into `Timings`
+pub struct ScopedTimer {
+    timer: Timer,
+
+    /// Timings to record into, and name of the stage there
+    timings: Option<(Timings, String)>,
+    running: bool,
+}
+
+impl Timer {
+    /// Start a timer stopped when the returned guard goes out of scope
+    pub fn scoped(name: &str) -> ScopedTimer {
+        ScopedTimer {
+            timer: Timer::new_start(name),
+            timings: None,
+            running: true,
+        }
+    }
+}
+
+impl ScopedTimer {
+    /// Record the duration as `stage` of `timings` when stopped
+    pub fn record_into(mut self, timings: &Timings, stage: &str) -> Self {
+        self.timings = Some((timings.share(), stage.to_owned()));
+        self
+    }
+
+    /// Start a timer nested in this one, recording into the same `Timings`
+    /// as `<stage>/<name>`
+    pub fn child(&self, name: &str) -> ScopedTimer {
+        ScopedTimer {
+            timer: Timer::new_start(&format!("{}: {}", self.timer.name, name)),
+            timings: self
+                .timings
+                .as_ref()
+                .map(|(timings, stage)| (timings.share(), format!("{}/{}", stage, name))),
+            running: true,
+        }
+    }
+
+    /// Stop the timer, returning its duration in milliseconds
+    pub fn finish(mut self) -> f64 {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> f64 {
+        if !self.running {
+            return 0.0;
+        }
+        self.running = false;
+
+        self.timer.stop();
+        let duration = self.timer.duration_ms();
+
+        if let Some((timings, stage)) = &self.timings {
+            timings.record(stage, duration);
+        }
+
+        duration
+    }
+}
+
+impl Drop for ScopedTimer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scoped_timers() {
+        let timings = Timings::new();
+
+        let failed = || -> Result<(), ()> {
+            let run = Timer::scoped("Running").record_into(&timings, "inference");
+            let _session = run.child("session");
+            Err(())
+        };
+        assert!(failed().is_err());
+
+        // Recorded on the early return, children first
+        let stages: Vec<String> = timings.to_vec().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(stages, vec!["inference/session", "inference"]);
+
+        let resize = Timer::scoped("Resizing").record_into(&timings, "resize");
+        let duration = resize.finish();
+        assert_eq!(timings.get("resize"), Some(duration));
+
+        let json = serde_json::to_value(&timings).unwrap();
+        assert!(json["inference/session"].is_f64());
+
+        let parsed: Timings = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.to_vec().len(), 3);
+    }
+}
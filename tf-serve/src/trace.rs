@@ -0,0 +1,209 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Random number, good enough for IDs and sampling but not cryptography
+pub(crate) fn random_u64() -> u64 {
+    // Every RandomState is seeded differently
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos()),
+    );
+
+    hasher.finish()
+}
+
+/// Random span ID, as 16 hex digits
+pub fn new_span_id() -> String {
+    format!("{:016x}", random_u64())
+}
+
+/// Unique and unguessable ID of 128 bits from the random source of the OS,
+/// as 32 hex digits, for IDs that give access to results
+pub fn new_id() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("No random source");
+
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Distributed trace a request belongs to, propagated to the outbound
+/// requests it makes
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceContext {
+    /// Trace ID as 32 lowercase hex digits. X-Ray trace IDs
+    /// (`1-5759e988-bd862e3fe1be46a994272793`) carry the same digits.
+    pub trace_id: String,
+
+    /// ID of the calling segment or span, if any
+    pub parent_id: Option<String>,
+
+    /// Whether the trace is being recorded
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Parse an X-Ray trace header, `Root=1-...;Parent=...;Sampled=1`
+    pub fn from_xray(header: &str) -> Option<Self> {
+        let mut root = None;
+        let mut parent = None;
+        let mut sampled = false;
+
+        for field in header.split(';') {
+            let pos = match field.find('=') {
+                Some(pos) => pos,
+                None => continue,
+            };
+
+            match (field[..pos].trim(), field[pos + 1..].trim()) {
+                ("Root", value) => root = Some(value),
+                ("Parent", value) => parent = Some(value),
+                ("Sampled", value) => sampled = value == "1",
+                _ => {}
+            }
+        }
+
+        let mut parts = root?.split('-');
+        let trace_id = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some("1"), Some(time), Some(id), None) if is_hex(time, 8) && is_hex(id, 24) => {
+                format!("{}{}", time, id).to_lowercase()
+            }
+            _ => return None,
+        };
+
+        Some(TraceContext {
+            trace_id,
+            parent_id: parent
+                .filter(|parent| is_hex(parent, 16))
+                .map(str::to_lowercase),
+            sampled,
+        })
+    }
+
+    /// Parse a W3C `traceparent` header, `00-<trace id>-<parent id>-<flags>`
+    pub fn from_traceparent(header: &str) -> Option<Self> {
+        let parts: Vec<&str> = header.trim().split('-').collect();
+
+        match parts.as_slice() {
+            [version, trace_id, parent_id, flags]
+                if is_hex(version, 2)
+                    && *version != "ff"
+                    && is_hex(trace_id, 32)
+                    && is_hex(parent_id, 16)
+                    && is_hex(flags, 2) =>
+            {
+                // All-zero IDs are invalid
+                if trace_id.bytes().all(|b| b == b'0') || parent_id.bytes().all(|b| b == b'0') {
+                    return None;
+                }
+
+                let flags = u8::from_str_radix(flags, 16).ok()?;
+
+                Some(TraceContext {
+                    trace_id: trace_id.to_lowercase(),
+                    parent_id: Some(parent_id.to_lowercase()),
+                    sampled: flags & 1 == 1,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// New trace, recorded, for requests that did not come with one
+    pub fn new_root() -> Self {
+        TraceContext {
+            trace_id: format!("{}{}", new_span_id(), new_span_id()),
+            parent_id: None,
+            sampled: true,
+        }
+    }
+
+    /// Same trace, called from a new span
+    pub fn child(&self) -> Self {
+        TraceContext {
+            parent_id: Some(new_span_id()),
+            ..self.clone()
+        }
+    }
+
+    /// Trace ID in the X-Ray format. W3C trace IDs not created by X-Ray may
+    /// not start with a timestamp, in which case X-Ray rejects them.
+    pub fn xray_trace_id(&self) -> String {
+        format!("1-{}-{}", &self.trace_id[..8], &self.trace_id[8..])
+    }
+
+    /// Headers propagating the trace to an outbound request, in both the
+    /// X-Ray and W3C formats
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut xray = format!("Root={}", self.xray_trace_id());
+        if let Some(parent_id) = &self.parent_id {
+            xray.push_str(&format!(";Parent={}", parent_id));
+        }
+        xray.push_str(if self.sampled {
+            ";Sampled=1"
+        } else {
+            ";Sampled=0"
+        });
+
+        let mut headers = vec![("x-amzn-trace-id", xray)];
+
+        // traceparent has no form without a parent
+        if let Some(parent_id) = &self.parent_id {
+            headers.push((
+                "traceparent",
+                format!(
+                    "00-{}-{}-{:02x}",
+                    self.trace_id, parent_id, self.sampled as u8
+                ),
+            ));
+        }
+
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_headers() {
+        let xray = TraceContext::from_xray(
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1",
+        )
+        .unwrap();
+        assert_eq!(xray.trace_id, "5759e988bd862e3fe1be46a994272793");
+        assert_eq!(xray.parent_id.as_deref(), Some("53995c3f42cd8ad8"));
+        assert!(xray.sampled);
+        assert_eq!(
+            xray.headers()[1].1,
+            "00-5759e988bd862e3fe1be46a994272793-53995c3f42cd8ad8-01"
+        );
+
+        let w3c = TraceContext::from_traceparent(&xray.headers()[1].1).unwrap();
+        assert_eq!(w3c, xray);
+        assert_eq!(
+            w3c.headers()[0].1,
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1"
+        );
+
+        let child = w3c.child();
+        assert_eq!(child.trace_id, w3c.trace_id);
+        assert_ne!(child.parent_id, w3c.parent_id);
+
+        assert!(TraceContext::from_xray("Root=1-5759e988").is_none());
+
+        assert!(is_hex(&new_id(), 32));
+        assert_ne!(new_id(), new_id());
+        assert!(TraceContext::from_traceparent(
+            "00-00000000000000000000000000000000-53995c3f42cd8ad8-01"
+        )
+        .is_none());
+    }
+}
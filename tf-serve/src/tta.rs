@@ -0,0 +1,63 @@
+//! Test-time augmentation: the model runs on several crops and flips of an
+//! image and their probabilities are averaged, which is more accurate than
+//! a single run at the cost of one run per view.
+
+use image::{DynamicImage, GenericImageView};
+
+/// Most views of an image classified with test-time augmentation
+pub const MAX_TTA_VIEWS: usize = 12;
+
+/// Side of the crops, as a fraction of the side of the image
+const CROP_FRACTION: f32 = 0.875;
+
+/// The first `count` views of an image, up to `MAX_TTA_VIEWS`: the whole
+/// image, then its center, top-left, top-right, bottom-left and
+/// bottom-right crops, each followed by its horizontal flip
+pub(crate) fn views(image: &DynamicImage, count: usize) -> Vec<DynamicImage> {
+    let (width, height) = image.dimensions();
+    let crop_width = ((width as f32 * CROP_FRACTION) as u32).max(1);
+    let crop_height = ((height as f32 * CROP_FRACTION) as u32).max(1);
+    let (right, bottom) = (width - crop_width, height - crop_height);
+
+    let crops = [
+        (right / 2, bottom / 2),
+        (0, 0),
+        (right, 0),
+        (0, bottom),
+        (right, bottom),
+    ];
+
+    let mut source = image.clone();
+    let mut views = vec![image.clone()];
+    views.extend(
+        crops
+            .iter()
+            .map(|&(x, y)| source.crop(x, y, crop_width, crop_height)),
+    );
+
+    views
+        .into_iter()
+        .flat_map(|view| {
+            let flipped = view.fliph();
+            vec![view, flipped]
+        })
+        .take(count.min(MAX_TTA_VIEWS))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crops_and_flips() {
+        let image = DynamicImage::new_rgb8(16, 8);
+
+        let first = views(&image, 4);
+        assert_eq!(first.len(), 4);
+        assert_eq!(first[1].dimensions(), (16, 8));
+        assert_eq!(first[2].dimensions(), (14, 7));
+
+        assert_eq!(views(&image, 100).len(), MAX_TTA_VIEWS);
+    }
+}
@@ -0,0 +1,515 @@
+//! Length-prefixed binary protocol for offloading inference over a byte
+//! stream (TCP, vsock, ...), avoiding the HTTP/JSON overhead for raw images.
+//!
+//! Every frame is a little-endian `u32` payload length, followed by a `u8`
+//! frame kind and the payload:
+//!
+//! - `KIND_CLASSIFY` request: little-endian `u32` length of the JSON-encoded
+//!   `ClassifyOptions`, the options, then the raw image bytes until the end of
+//!   the payload.
+//! - `KIND_OK` response: the JSON-encoded `Classification`.
+//! - `KIND_ERROR` response: a `u8` error code followed by the UTF-8 message.
+//!
+//! Embeddings are streamed in a compact form as well, outside of frames: a
+//! little-endian `u32` number of values followed by the little-endian `f32`
+//! values, one embedding after the other.
+
+#[cfg(feature = "tls")]
+use std::fs::File;
+#[cfg(feature = "tls")]
+use std::io::BufReader;
+use std::io::{self, Read, Write};
+#[cfg(feature = "decode")]
+use std::net::{TcpListener, TcpStream};
+#[cfg(all(feature = "decode", unix))]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(feature = "tls")]
+use std::path::Path;
+#[cfg(feature = "decode")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(any(feature = "decode", feature = "tls"))]
+use std::sync::Arc;
+#[cfg(feature = "decode")]
+use std::thread;
+
+#[cfg(feature = "decode")]
+use log::{debug, warn};
+use tensorflow::{Code, Status};
+
+use crate::{Classification, ClassifyOptions, DEFAULT_MAX_BODY_SIZE};
+#[cfg(feature = "decode")]
+use crate::{ConcurrencyLimit, ImageClassifier};
+
+/// Largest frame payload of the protocol
+pub const MAX_FRAME_SIZE: usize = 64 << 20;
+
+/// Limits of a server of the protocol
+#[derive(Clone, Copy, Debug)]
+pub struct WireOptions {
+    /// Largest accepted request frame, at most `MAX_FRAME_SIZE`
+    pub max_frame_size: usize,
+
+    /// Most connections served at the same time, each on a thread of its
+    /// own. Those beyond are closed as soon as accepted.
+    pub max_connections: usize,
+}
+
+impl Default for WireOptions {
+    fn default() -> Self {
+        WireOptions {
+            max_frame_size: DEFAULT_MAX_BODY_SIZE,
+            max_connections: 64,
+        }
+    }
+}
+
+pub const KIND_CLASSIFY: u8 = 1;
+pub const KIND_OK: u8 = 0x80;
+pub const KIND_ERROR: u8 = 0x81;
+
+fn write_frame<W: Write>(writer: &mut W, kind: u8, parts: &[&[u8]]) -> io::Result<()> {
+    let len: usize = parts.iter().map(|part| part.len()).sum::<usize>() + 1;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Frame too large",
+        ));
+    }
+
+    writer.write_all(&(len as u32).to_le_bytes())?;
+    writer.write_all(&[kind])?;
+    for part in parts {
+        writer.write_all(part)?;
+    }
+
+    writer.flush()
+}
+
+/// Read a frame of up to `max_size` bytes, or `None` if the stream ended
+/// cleanly before it
+fn read_frame<R: Read>(reader: &mut R, max_size: usize) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len == 0 || len > max_size.min(MAX_FRAME_SIZE) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid frame length",
+        ));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+
+    let kind = payload.remove(0);
+    Ok(Some((kind, payload)))
+}
+
+/// Write a length-prefixed embedding
+pub fn write_embedding<W: Write>(writer: &mut W, embedding: &[f32]) -> io::Result<()> {
+    writer.write_all(&(embedding.len() as u32).to_le_bytes())?;
+
+    let mut values = Vec::with_capacity(embedding.len() * 4);
+    for value in embedding {
+        values.extend_from_slice(&value.to_le_bytes());
+    }
+
+    writer.write_all(&values)
+}
+
+/// Read a length-prefixed embedding, or `None` if the stream ended cleanly
+/// before it
+pub fn read_embedding<R: Read>(reader: &mut R) -> io::Result<Option<Vec<f32>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len * 4 > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid embedding length",
+        ));
+    }
+
+    let mut values = vec![0u8; len * 4];
+    reader.read_exact(&mut values)?;
+
+    Ok(Some(
+        values
+            .chunks(4)
+            .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+            .collect(),
+    ))
+}
+
+#[cfg_attr(not(feature = "decode"), allow(dead_code))]
+fn code_to_wire(code: Code) -> u8 {
+    match code {
+        Code::InvalidArgument => 1,
+        Code::NotFound => 2,
+        Code::DataLoss => 3,
+        Code::OutOfRange => 4,
+        Code::FailedPrecondition => 5,
+        Code::Unimplemented => 6,
+        Code::ResourceExhausted => 7,
+        Code::Internal => 8,
+        Code::Aborted => 9,
+        _ => 0,
+    }
+}
+
+fn code_from_wire(code: u8) -> Code {
+    match code {
+        1 => Code::InvalidArgument,
+        2 => Code::NotFound,
+        3 => Code::DataLoss,
+        4 => Code::OutOfRange,
+        5 => Code::FailedPrecondition,
+        6 => Code::Unimplemented,
+        7 => Code::ResourceExhausted,
+        8 => Code::Internal,
+        9 => Code::Aborted,
+        _ => Code::Unknown,
+    }
+}
+
+fn io_status(err: io::Error) -> Status {
+    Status::new_set_lossy(Code::Unavailable, &format!("Wire protocol: {}", err))
+}
+
+/// Client side of the protocol
+pub struct WireClient<S: Read + Write> {
+    stream: S,
+}
+
+impl<S: Read + Write> WireClient<S> {
+    pub fn new(stream: S) -> Self {
+        WireClient { stream }
+    }
+
+    pub fn classify(
+        &mut self,
+        image: &[u8],
+        options: &ClassifyOptions,
+    ) -> tensorflow::Result<Classification> {
+        let options = serde_json::to_vec(options)
+            .map_err(|_| Status::new_set_lossy(Code::InvalidArgument, "Invalid options"))?;
+
+        let options_len = (options.len() as u32).to_le_bytes();
+
+        write_frame(
+            &mut self.stream,
+            KIND_CLASSIFY,
+            &[&options_len[..], &options[..], image],
+        )
+        .map_err(io_status)?;
+
+        match read_frame(&mut self.stream, MAX_FRAME_SIZE).map_err(io_status)? {
+            None => Err(Status::new_set_lossy(
+                Code::Unavailable,
+                "Wire protocol: connection closed",
+            )),
+            Some((KIND_OK, payload)) => serde_json::from_slice(&payload).map_err(|_| {
+                Status::new_set_lossy(Code::DataLoss, "Wire protocol: invalid classification")
+            }),
+            Some((KIND_ERROR, payload)) if !payload.is_empty() => Err(Status::new_set_lossy(
+                code_from_wire(payload[0]),
+                &String::from_utf8_lossy(&payload[1..]),
+            )),
+            Some(_) => Err(Status::new_set_lossy(
+                Code::DataLoss,
+                "Wire protocol: unexpected frame",
+            )),
+        }
+    }
+}
+
+/// Handle a single request payload
+#[cfg(feature = "decode")]
+fn handle_classify(
+    classifier: &ImageClassifier,
+    limit: &ConcurrencyLimit,
+    payload: &[u8],
+) -> tensorflow::Result<Vec<u8>> {
+    let invalid = || Status::new_set_lossy(Code::InvalidArgument, "Malformed request frame");
+
+    if payload.len() < 4 {
+        return Err(invalid());
+    }
+
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&payload[..4]);
+    let len = u32::from_le_bytes(len) as usize;
+
+    if payload.len() < 4 + len {
+        return Err(invalid());
+    }
+
+    let options: ClassifyOptions =
+        serde_json::from_slice(&payload[4..4 + len]).map_err(|_| invalid())?;
+
+    let permit = limit.acquire()?;
+    let classification =
+        classifier.classify_from_raw_with_options(&payload[4 + len..], &options)?;
+    drop(permit);
+
+    serde_json::to_vec(&classification)
+        .map_err(|_| Status::new_set_lossy(Code::Internal, "Could not encode classification"))
+}
+
+/// Serve requests of up to `max_frame_size` bytes on a single connection
+/// until the peer closes it, classifying within `limit`
+#[cfg(feature = "decode")]
+pub fn serve_connection<S: Read + Write>(
+    classifier: &ImageClassifier,
+    limit: &ConcurrencyLimit,
+    max_frame_size: usize,
+    mut stream: S,
+) -> io::Result<()> {
+    while let Some((kind, payload)) = read_frame(&mut stream, max_frame_size)? {
+        let result = match kind {
+            KIND_CLASSIFY => handle_classify(classifier, limit, &payload),
+            _ => Err(Status::new_set_lossy(
+                Code::Unimplemented,
+                &format!("Unknown frame kind {}", kind),
+            )),
+        };
+
+        match result {
+            Ok(classification) => write_frame(&mut stream, KIND_OK, &[&classification[..]])?,
+            Err(status) => {
+                let code = [code_to_wire(status.code())];
+                let message = status.message().unwrap_or("").to_owned();
+                write_frame(&mut stream, KIND_ERROR, &[&code[..], message.as_bytes()])?
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Connection counted as open until dropped
+#[cfg(feature = "decode")]
+struct OpenConnection(Arc<AtomicUsize>);
+
+#[cfg(feature = "decode")]
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Run `serve` on every accepted connection on its own thread, up to
+/// `max_connections` at the same time
+#[cfg(feature = "decode")]
+fn serve_incoming<S, I, F>(incoming: I, max_connections: usize, serve: F) -> io::Result<()>
+where
+    S: Send + 'static,
+    I: Iterator<Item = io::Result<S>>,
+    F: Fn(S) -> io::Result<()> + Clone + Send + 'static,
+{
+    let open = Arc::new(AtomicUsize::new(0));
+
+    for stream in incoming {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Could not accept wire connection: {}", err);
+                continue;
+            }
+        };
+
+        let connection = OpenConnection(open.clone());
+        if open.fetch_add(1, Ordering::SeqCst) >= max_connections {
+            warn!("Closing wire connection beyond {} open", max_connections);
+            continue;
+        }
+
+        let serve = serve.clone();
+
+        thread::spawn(move || {
+            debug!("Serving wire connection");
+
+            if let Err(err) = serve(stream) {
+                warn!("Wire connection failed: {}", err);
+            }
+            drop(connection);
+        });
+    }
+
+    Ok(())
+}
+
+/// Accept TCP connections and serve each one on its own thread, within
+/// `options`, sharing `limit` across connections
+#[cfg(feature = "decode")]
+pub fn serve_tcp(
+    classifier: Arc<ImageClassifier>,
+    limit: Arc<ConcurrencyLimit>,
+    listener: TcpListener,
+    options: WireOptions,
+) -> io::Result<()> {
+    serve_incoming(
+        listener.incoming(),
+        options.max_connections,
+        move |stream: TcpStream| {
+            serve_connection(&classifier, &limit, options.max_frame_size, stream)
+        },
+    )
+}
+
+/// Accept Unix domain socket connections and serve each one on its own
+/// thread, within `options`, sharing `limit` across connections
+#[cfg(all(feature = "decode", unix))]
+pub fn serve_unix(
+    classifier: Arc<ImageClassifier>,
+    limit: Arc<ConcurrencyLimit>,
+    listener: UnixListener,
+    options: WireOptions,
+) -> io::Result<()> {
+    serve_incoming(
+        listener.incoming(),
+        options.max_connections,
+        move |stream: UnixStream| {
+            serve_connection(&classifier, &limit, options.max_frame_size, stream)
+        },
+    )
+}
+
+/// Accept vsock connections on `port`, from any context ID, and serve each
+/// one on its own thread, within `options`, sharing `limit` across
+/// connections. Inside
+/// Firecracker microVMs and unikernels, vsock is often the only transport
+/// to the host.
+#[cfg(all(feature = "decode", feature = "vsock"))]
+pub fn serve_vsock(
+    classifier: Arc<ImageClassifier>,
+    limit: Arc<ConcurrencyLimit>,
+    port: u32,
+    options: WireOptions,
+) -> io::Result<()> {
+    // VMADDR_CID_ANY
+    let listener = vsock::VsockListener::bind_with_cid_port(u32::MAX, port)?;
+
+    serve_incoming(
+        listener.incoming(),
+        options.max_connections,
+        move |stream: vsock::VsockStream| {
+            serve_connection(&classifier, &limit, options.max_frame_size, stream)
+        },
+    )
+}
+
+/// Server TLS configuration from a PEM certificate chain and a PEM PKCS#8 or
+/// RSA private key
+#[cfg(feature = "tls")]
+pub fn tls_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<rustls::ServerConfig>> {
+    use rustls::internal::pemfile;
+
+    let invalid = |what: &str, path: &Path| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid {} in {}", what, path.display()),
+        )
+    };
+    let pem = |path: &Path| File::open(path).map(BufReader::new);
+
+    let certs =
+        pemfile::certs(&mut pem(cert_path)?).map_err(|_| invalid("certificate", cert_path))?;
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut pem(key_path)?)
+        .map_err(|_| invalid("private key", key_path))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut pem(key_path)?)
+            .map_err(|_| invalid("private key", key_path))?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| invalid("private key", key_path))?;
+
+    let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    Ok(Arc::new(config))
+}
+
+/// Accept TCP connections, terminate TLS and serve each one on its own
+/// thread, within `options`, sharing `limit` across connections
+#[cfg(all(feature = "decode", feature = "tls"))]
+pub fn serve_tls(
+    classifier: Arc<ImageClassifier>,
+    limit: Arc<ConcurrencyLimit>,
+    listener: TcpListener,
+    config: Arc<rustls::ServerConfig>,
+    options: WireOptions,
+) -> io::Result<()> {
+    serve_incoming(
+        listener.incoming(),
+        options.max_connections,
+        move |stream: TcpStream| {
+            let session = rustls::ServerSession::new(&config);
+            serve_connection(
+                &classifier,
+                &limit,
+                options.max_frame_size,
+                rustls::StreamOwned::new(session, stream),
+            )
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn frame_roundtrip() {
+        let mut buf: Vec<u8> = vec![];
+        write_frame(&mut buf, KIND_CLASSIFY, &[&b"ab"[..], &b"cd"[..]]).unwrap();
+        assert_eq!(buf, vec![5, 0, 0, 0, KIND_CLASSIFY, b'a', b'b', b'c', b'd']);
+
+        let mut reader = Cursor::new(buf);
+        assert_eq!(
+            read_frame(&mut reader, MAX_FRAME_SIZE).unwrap(),
+            Some((KIND_CLASSIFY, b"abcd".to_vec()))
+        );
+        assert_eq!(read_frame(&mut reader, MAX_FRAME_SIZE).unwrap(), None);
+
+        let mut truncated = Cursor::new(vec![9, 0, 0, 0, KIND_OK]);
+        assert!(read_frame(&mut truncated, MAX_FRAME_SIZE).is_err());
+
+        // Beyond the largest accepted frame
+        let mut large = Cursor::new(vec![9, 0, 0, 0, KIND_OK]);
+        assert_eq!(
+            read_frame(&mut large, 8).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        for code in &[Code::InvalidArgument, Code::NotFound, Code::Internal] {
+            assert_eq!(code_from_wire(code_to_wire(*code)), *code);
+        }
+
+        let mut buf: Vec<u8> = vec![];
+        write_embedding(&mut buf, &[1.0, -0.5]).unwrap();
+        write_embedding(&mut buf, &[]).unwrap();
+        assert_eq!(buf.len(), 4 + 8 + 4);
+
+        let mut reader = Cursor::new(buf);
+        assert_eq!(read_embedding(&mut reader).unwrap(), Some(vec![1.0, -0.5]));
+        assert_eq!(read_embedding(&mut reader).unwrap(), Some(vec![]));
+        assert_eq!(read_embedding(&mut reader).unwrap(), None);
+    }
+}
@@ -0,0 +1,56 @@
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+
+use log::warn;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// Thread running writes, such as alerts and samples, off the request path,
+/// dropping them rather than blocking requests when it falls behind
+pub(crate) struct BackgroundWriter {
+    name: &'static str,
+    sender: Mutex<SyncSender<Task>>,
+}
+
+impl BackgroundWriter {
+    /// Writer of at most `capacity` pending writes, named `name` in logs
+    pub(crate) fn new(name: &'static str, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Task>(capacity);
+
+        thread::spawn(move || {
+            while let Ok(task) = receiver.recv() {
+                task();
+            }
+        });
+
+        BackgroundWriter {
+            name,
+            sender: Mutex::new(sender),
+        }
+    }
+
+    /// Queue `task`, dropping it if the writer falls behind
+    pub(crate) fn run(&self, task: impl FnOnce() + Send + 'static) {
+        match self.sender.lock().unwrap().try_send(Box::new(task)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("Dropped {}: too many pending", self.name),
+            Err(TrySendError::Disconnected(_)) => warn!("Dropped {}: writer stopped", self.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_queued_tasks() {
+        let writer = BackgroundWriter::new("test", 4);
+        let (sender, receiver) = mpsc::channel();
+
+        writer.run(move || sender.send(42).unwrap());
+
+        assert_eq!(receiver.recv().unwrap(), 42);
+    }
+}